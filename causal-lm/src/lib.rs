@@ -4,14 +4,17 @@
 mod decoding;
 mod query_context;
 
-use common::{upos, utok};
-use digit_layout::types::U32;
+use common::{upos, utok, F8_E4M3, F8_E5M2};
+use digit_layout::{
+    types::{BF16, F16, F32, U32},
+    DigitLayout,
+};
 use std::path::Path;
 use tensor::{udim, Tensor};
 
 pub use decoding::DecodingMeta;
 pub use query_context::QueryContext;
-pub use sample::SampleArgs;
+pub use sample::{top_logprobs, Guidance, History, LogitsProcessor, ProcessorChain, SampleArgs};
 
 /// 从文件系统加载的模型。
 pub trait Model: Sized {
@@ -33,6 +36,7 @@ pub trait Model: Sized {
 /// - 对词嵌入计算前向传播（[`forward`](CausalLM::forward)）；
 /// - 解码词嵌入张量得到概率密度（[`decode`](CausalLM::decode)）；
 /// - 采样概率密度（[`sample`](CausalLM::sample)）；
+/// - 取出最终归一化的隐藏状态用于文本嵌入（[`final_hidden`](CausalLM::final_hidden)）；
 ///
 /// 这种定义根据计算的形式和特性将“一轮”推理分割为多个部分，方便灵活地实现调度。
 /// 为了在推理的不同阶段之间传递巨大的张量，需要 [`Storage`](CausalLM::Storage) 类型来约定中间变量的存储方式。
@@ -45,6 +49,20 @@ pub trait CausalLM: Model {
     fn max_seq_len(&self) -> upos;
     /// 模型定义的句子结束符。
     fn eos_token(&self) -> utok;
+    /// 词表大小，即词嵌入矩阵的行数。
+    fn vocab_size(&self) -> usize;
+    /// 隐藏状态的维度，即文本嵌入的最大维度。
+    fn hidden_size(&self) -> usize;
+    /// 模型是否加载了名为 `name` 的 LoRA 适配器。
+    #[inline]
+    fn has_adapter(&self, _name: &str) -> bool {
+        false
+    }
+    /// 模型加载的方式，用于向客户端报告模型的能力。
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        ModelInfo::default()
+    }
     /// 创建一个未填充的缓存张量（`num_layers x 2 x num_kv_head x max_seq_len x head_dim`）。
     fn new_cache(&self) -> Tensor<Self::Storage>;
     /// 复制一个有效长度为 `pos` 的缓存。
@@ -79,6 +97,15 @@ pub trait CausalLM: Model {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok>;
+    /// 计算 logits 每行的对数概率，返回每行最大的 `k` 个 token 及其对数概率，从大到小排列。
+    fn top_logprobs(&self, logits: &Tensor<Self::Storage>, k: usize) -> Vec<Vec<(utok, f32)>>;
+    /// 对前向传播得到的隐藏状态执行最终的归一化，以 `f32` 按行取出（`num_tokens x hidden_size`），用于计算文本嵌入。
+    ///
+    /// 模型不支持时返回 `None`。
+    #[inline]
+    fn final_hidden(&self, _hidden_state: Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        None
+    }
 }
 
 /// 解码的要求。
@@ -87,6 +114,42 @@ pub struct SampleMeta {
     pub num_decode: usize,
     /// 采样参数。
     pub args: SampleArgs,
+    /// 采样参考的历史。
+    pub history: History,
+}
+
+/// 模型加载的方式，未知的项为空。
+#[derive(Clone, Default, Debug)]
+pub struct ModelInfo {
+    /// 计算使用的数据类型，如 `bfloat16`。
+    pub dtype: Option<String>,
+    /// 模型加载到的设备，如 `cpu` 或 `cuda:0`，分布在多个设备上时以逗号分隔。
+    pub device: Option<String>,
+    /// 权重文件的量化格式，加载时反量化为计算使用的数据类型；没有量化时为空。
+    pub quantization: Option<String>,
+}
+
+impl ModelInfo {
+    /// 以 `stored` 类型保存、以 `dt` 类型计算、加载到 `device` 上的模型。
+    pub fn new(dt: DigitLayout, stored: DigitLayout, device: impl Into<String>) -> Self {
+        Self {
+            dtype: Some(dtype_name(dt)),
+            device: Some(device.into()),
+            quantization: (stored != dt).then(|| dtype_name(stored)),
+        }
+    }
+}
+
+/// 数据类型的名字，与 `config.json` 中 `torch_dtype` 的写法相同。
+fn dtype_name(dt: DigitLayout) -> String {
+    match dt {
+        F16 => "float16".into(),
+        F32 => "float32".into(),
+        BF16 => "bfloat16".into(),
+        F8_E4M3 => "float8_e4m3fn".into(),
+        F8_E5M2 => "float8_e5m2".into(),
+        _ => format!("{dt:?}"),
+    }
 }
 
 /// 生成位置张量。
@@ -131,6 +194,7 @@ where
         let queries = [QueryContext {
             cache: Some(&mut cache),
             range: pos..pos + prompt.len() as upos,
+            adapter: None,
         }];
         let hidden_state = CausalLM::forward(&model, queries, token_embedded);
 
@@ -143,6 +207,7 @@ where
         let args = [SampleMeta {
             num_decode: 1,
             args: SampleArgs::default(),
+            history: History::default(),
         }];
         let tokens = CausalLM::sample(&model, args, logits);
 
@@ -8,6 +8,8 @@ pub struct QueryContext<'a, Storage> {
     pub cache: Option<&'a mut Tensor<Storage>>,
     /// 查询在上下文中的位置。
     pub range: Range<upos>,
+    /// 查询使用的 LoRA 适配器，为空时只使用基础模型。
+    pub adapter: Option<&'a str>,
 }
 
 impl<'a, Storage> QueryContext<'a, Storage> {
@@ -0,0 +1,165 @@
+use crate::BetweenF32;
+use digit_layout::{AsDigit, DigitLayout};
+
+/// FP8 E4M3 的数据布局，没有无穷，最大值为 448。
+pub const F8_E4M3: DigitLayout = DigitLayout::new(1, true, 4, 3);
+/// FP8 E5M2 的数据布局，最大值为 57344。
+pub const F8_E5M2: DigitLayout = DigitLayout::new(1, true, 5, 2);
+
+/// 1 位符号、4 位阶码、3 位尾数的 8 位浮点数，与 `torch.float8_e4m3fn` 一致。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[repr(transparent)]
+pub struct f8e4m3(pub u8);
+
+/// 1 位符号、5 位阶码、2 位尾数的 8 位浮点数，与 `torch.float8_e5m2` 一致。
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[repr(transparent)]
+pub struct f8e5m2(pub u8);
+
+impl f8e4m3 {
+    /// 最大的有限值。
+    pub const MAX: f32 = 448.;
+
+    /// 从 f32 舍入到最近的偶数，超出范围时饱和到最大值。
+    #[inline]
+    pub fn from_f32(f: f32) -> Self {
+        Self(encode::<4, 3>(f, 0x7e, 0x7f))
+    }
+    /// 转换为 f32。
+    #[inline]
+    pub fn to_f32(self) -> f32 {
+        let b = self.0;
+        if b & 0x7f == 0x7f {
+            f32::NAN
+        } else {
+            decode::<4, 3>(b)
+        }
+    }
+}
+
+impl f8e5m2 {
+    /// 最大的有限值。
+    pub const MAX: f32 = 57344.;
+
+    /// 从 f32 舍入到最近的偶数，超出范围时饱和到最大值。
+    #[inline]
+    pub fn from_f32(f: f32) -> Self {
+        Self(encode::<5, 2>(f, 0x7b, 0x7f))
+    }
+    /// 转换为 f32。
+    #[inline]
+    pub fn to_f32(self) -> f32 {
+        let b = self.0;
+        match b & 0x7f {
+            0x7c => f32::INFINITY.copysign(sign(b)),
+            0x7d..=0x7f => f32::NAN,
+            _ => decode::<5, 2>(b),
+        }
+    }
+}
+
+#[inline]
+fn sign(b: u8) -> f32 {
+    if b & 0x80 == 0 {
+        1.
+    } else {
+        -1.
+    }
+}
+
+/// 解码有限值。
+fn decode<const E: u32, const M: u32>(b: u8) -> f32 {
+    let bias = (1 << (E - 1)) - 1;
+    let e = ((b & 0x7f) >> M) as i32;
+    let m = (b & ((1 << M) - 1)) as f32 / (1 << M) as f32;
+    let abs = if e == 0 {
+        m * 2f32.powi(1 - bias)
+    } else {
+        (1. + m) * 2f32.powi(e - bias)
+    };
+    abs * sign(b)
+}
+
+/// 编码 `f`，`max` 是最大有限值的编码，`nan` 是非数的编码。
+fn encode<const E: u32, const M: u32>(f: f32, max: u8, nan: u8) -> u8 {
+    let s = if f.is_sign_negative() { 0x80 } else { 0 };
+    if f.is_nan() {
+        return s | nan;
+    }
+    let bias = (1 << (E - 1)) - 1;
+    let a = f.abs();
+    let code = if a < 2f32.powi(1 - bias) {
+        // 非规格化数，舍入到最小间隔的整数倍，进位到最小规格化数时编码仍然正确
+        (a / 2f32.powi(1 - bias - M as i32)).round_ties_even() as u32
+    } else {
+        let mut e = ((a.to_bits() >> 23) & 0xff) as i32 - 127;
+        let frac = a / 2f32.powi(e);
+        let mut m = ((frac - 1.) * (1 << M) as f32).round_ties_even() as u32;
+        if m == 1 << M {
+            e += 1;
+            m = 0;
+        }
+        ((e + bias) as u32) << M | m
+    };
+    s | code.min(max as u32) as u8
+}
+
+impl AsDigit for f8e4m3 {
+    const LAYOUT: DigitLayout = F8_E4M3;
+}
+
+impl AsDigit for f8e5m2 {
+    const LAYOUT: DigitLayout = F8_E5M2;
+}
+
+impl BetweenF32 for f8e4m3 {
+    #[inline]
+    fn zero() -> Self {
+        Self(0)
+    }
+    #[inline]
+    fn cast(f: f32) -> Self {
+        Self::from_f32(f)
+    }
+    #[inline]
+    fn get(&self) -> f32 {
+        self.to_f32()
+    }
+}
+
+impl BetweenF32 for f8e5m2 {
+    #[inline]
+    fn zero() -> Self {
+        Self(0)
+    }
+    #[inline]
+    fn cast(f: f32) -> Self {
+        Self::from_f32(f)
+    }
+    #[inline]
+    fn get(&self) -> f32 {
+        self.to_f32()
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    for b in 0..=u8::MAX {
+        let x = f8e4m3(b);
+        if !x.to_f32().is_nan() {
+            assert_eq!(f8e4m3::from_f32(x.to_f32()).to_f32(), x.to_f32());
+        }
+        let x = f8e5m2(b);
+        if x.to_f32().is_finite() {
+            assert_eq!(f8e5m2::from_f32(x.to_f32()).to_f32(), x.to_f32());
+        }
+    }
+    assert_eq!(f8e4m3::from_f32(f8e4m3::MAX).to_f32(), f8e4m3::MAX);
+    assert_eq!(f8e5m2::from_f32(f8e5m2::MAX).to_f32(), f8e5m2::MAX);
+    assert_eq!(f8e4m3::from_f32(1e6).to_f32(), 448.);
+    assert_eq!(f8e5m2::from_f32(-1e6).to_f32(), -57344.);
+    assert_eq!(f8e4m3::from_f32(0.3).to_f32(), 0.3125);
+    assert_eq!(f8e4m3::from_f32(2f32.powi(-10)).to_f32(), 0.);
+}
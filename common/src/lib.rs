@@ -12,11 +12,13 @@ pub type upos = u32;
 
 mod between_f32;
 mod blob;
+mod fp8;
 pub mod safe_tensors;
 pub mod test_model;
 
 pub use between_f32::BetweenF32;
 pub use blob::Blob;
+pub use fp8::{f8e4m3, f8e5m2, F8_E4M3, F8_E5M2};
 pub use half::{bf16, f16};
 
 /// 加载 safetensors 文件可能产生的错误。
@@ -26,4 +28,6 @@ pub enum FileLoadError {
     Io(std::io::Error),
     /// Json 解析错误。
     Json(serde_json::Error),
+    /// 文件内容无效，如张量越界、大小与形状不符或校验和不匹配。
+    Invalid(String),
 }
@@ -1,12 +1,14 @@
 ﻿//! safetensors 文件的加载和访问。
 
-use crate::FileLoadError::{self, Io, Json};
+use crate::FileLoadError::{self, Invalid, Io, Json};
+#[cfg(not(target_arch = "wasm32"))]
 use memmap2::Mmap;
+use rayon::iter::*;
 use std::{
-    collections::{hash_map, HashMap},
-    fs::File,
+    collections::{hash_map, BTreeMap, HashMap},
+    fs,
     io::{Error as IoError, ErrorKind::NotFound},
-    mem::size_of_val,
+    mem::size_of,
     ops::Deref,
     path::Path,
     pin::Pin,
@@ -18,7 +20,41 @@ pub use safetensors::{tensor::TensorInfo, Dtype};
 /// safetensors 文件的统一结构。
 pub struct SafeTensors {
     tensors: HashMap<String, (usize, TensorInfo)>, // name -> (file_index, tensor_info)
-    files: Vec<(Mmap, String)>,                    // file_index -> (mmap, format)
+    files: Vec<(FileData, String)>,                // file_index -> (data, format)
+}
+
+/// 文件内容，映射自文件或由调用者直接提供。
+enum FileData {
+    #[cfg(not(target_arch = "wasm32"))]
+    Mmap(Mmap),
+    Bytes(Vec<u8>),
+}
+
+impl FileData {
+    /// 映射文件；wasm32 上没有 mmap，直接读入内存。
+    fn map(path: impl AsRef<Path>) -> Result<Self, IoError> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let file = fs::File::open(path)?;
+            unsafe { Mmap::map(&file) }.map(Self::Mmap)
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            fs::read(path).map(Self::Bytes)
+        }
+    }
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Mmap(mmap) => mmap,
+            Self::Bytes(bytes) => bytes,
+        }
+    }
 }
 
 /// safetensors 文件中的张量映射。
@@ -42,7 +78,20 @@ pub struct Iter<'a> {
 
 impl SafeTensors {
     /// 自动从路径中加载 safetensors 文件。
+    ///
+    /// 目录中有 [`CHECKSUMS_FILE`] 时，加载后校验其中记录的每个张量。
     pub fn load_from_dir(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
+        let ans = Self::load_from_dir_unchecked(&path)?;
+        match fs::read(path.as_ref().join(CHECKSUMS_FILE)) {
+            Ok(json) => ans.verify(&serde_json::from_slice(&json).map_err(Json)?)?,
+            Err(e) if e.kind() == NotFound => {}
+            Err(e) => return Err(Io(e)),
+        }
+        Ok(ans)
+    }
+
+    /// 自动从路径中加载 safetensors 文件，不校验。
+    pub fn load_from_dir_unchecked(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
         // 先尝试加载单个文件
         let single_file = path.as_ref().join("model.safetensors");
         if single_file.is_file() {
@@ -60,10 +109,47 @@ impl SafeTensors {
         )))
     }
 
+    /// 计算所有张量的校验和。
+    pub fn checksums(&self) -> Checksums {
+        Checksums {
+            algorithm: ALGORITHM.into(),
+            tensors: self
+                .tensors
+                .par_iter()
+                .map(|(name, (i, info))| (name.clone(), checksum(self.get_internal(*i, info).data)))
+                .collect(),
+        }
+    }
+
+    /// 校验 `checksums` 中记录的每个张量，张量缺失或校验和不一致时返回错误。
+    pub fn verify(&self, checksums: &Checksums) -> Result<(), FileLoadError> {
+        if checksums.algorithm != ALGORITHM {
+            return Err(Invalid(format!(
+                "unsupported checksum algorithm \"{}\"",
+                checksums.algorithm
+            )));
+        }
+        checksums
+            .tensors
+            .par_iter()
+            .try_for_each(|(name, expected)| {
+                let tensor = self
+                    .get(name)
+                    .ok_or_else(|| Invalid(format!("tensor \"{name}\" is missing")))?;
+                let actual = checksum(tensor.data);
+                if actual == *expected {
+                    Ok(())
+                } else {
+                    Err(Invalid(format!(
+                        "tensor \"{name}\" is corrupted: checksum {actual}, expected {expected}"
+                    )))
+                }
+            })
+    }
+
     /// 加载单个 `.safetensors` 文件。
     pub fn single_file(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
-        let file = File::open(path).map_err(Io)?;
-        let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
+        let file = FileData::map(path).map_err(Io)?;
         let header = load_header(&file)?;
         Ok(Self {
             tensors: header
@@ -75,6 +161,26 @@ impl SafeTensors {
         })
     }
 
+    /// 从内存中的 `.safetensors` 文件内容加载，用于没有文件系统的环境（如 wasm32）。
+    pub fn from_bytes(files: impl IntoIterator<Item = Vec<u8>>) -> Result<Self, FileLoadError> {
+        let mut tensors = HashMap::new();
+        let mut files_ = Vec::new();
+        for (i, file) in files.into_iter().enumerate() {
+            let header = load_header(&file)?;
+            for (name, info) in header.tensors {
+                if tensors.contains_key(&name) {
+                    return Err(Invalid(format!("tensor \"{name}\" is duplicated")));
+                }
+                tensors.insert(name, (i, info));
+            }
+            files_.push((FileData::Bytes(file), header.metadata.format));
+        }
+        Ok(Self {
+            tensors,
+            files: files_,
+        })
+    }
+
     /// 加载 `.safetensors.index.json` 索引文件。
     pub fn index_file(path: impl AsRef<Path>) -> Result<Self, FileLoadError> {
         use std::collections::hash_map::Entry;
@@ -85,8 +191,7 @@ impl SafeTensors {
             .ok_or(IoError::new(NotFound, "Index file has no parent directory"))
             .map_err(Io)?;
         // 加载索引文件
-        let index = File::open(&path).map_err(Io)?;
-        let index = unsafe { Mmap::map(&index) }.map_err(Io)?;
+        let index = fs::read(&path).map_err(Io)?;
         let index: SafeTensorsIndex = serde_json::from_slice(&index).map_err(Json)?;
         // 初始化状态
         let mut tensors = HashMap::new();
@@ -103,8 +208,7 @@ impl SafeTensors {
                 // 张量在新文件中
                 Entry::Vacant(e) => {
                     // 打开文件
-                    let file = File::open(dir.join(e.key())).map_err(Io)?;
-                    let file = unsafe { Mmap::map(&file) }.map_err(Io)?;
+                    let file = FileData::map(dir.join(e.key())).map_err(Io)?;
                     let header = load_header(&file)?;
                     // 迭代文件中的张量
                     let i = files.len();
@@ -178,12 +282,12 @@ impl SafeTensors {
 
     fn get_internal<'a>(&'a self, i: usize, info: &'a TensorInfo) -> SafeTensor<'a> {
         let (file, format) = &self.files[i];
-        let header_len = unsafe { *file.as_ptr().cast::<u64>() };
+        let header_len = read_header_len(file);
         let (begin, end) = info.data_offsets;
         SafeTensor {
             dtype: info.dtype,
             shape: &info.shape,
-            data: &file[size_of_val(&header_len)..][header_len as _..][begin..end],
+            data: &file[size_of::<u64>()..][header_len..][begin..end],
             format,
         }
     }
@@ -285,10 +389,89 @@ pub struct SafeTensorsHeaderMetadata {
     pub format: String,
 }
 
-fn load_header(file: &Mmap) -> Result<SafeTensorsHeader, FileLoadError> {
-    let header_len = unsafe { *file.as_ptr().cast::<u64>() };
-    let header = &file[size_of_val(&header_len)..][..header_len as _];
-    serde_json::from_slice(header).map_err(Json)
+/// 记录张量校验和的文件名，与模型文件放在同一目录。
+pub const CHECKSUMS_FILE: &str = "checksums.json";
+
+const ALGORITHM: &str = "fnv1a64";
+
+/// 每个张量数据的校验和。
+#[allow(missing_docs)]
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Checksums {
+    pub algorithm: String,
+    pub tensors: BTreeMap<String, String>,
+}
+
+/// 64 位 FNV-1a 散列。
+fn checksum(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf29ce484222325u64, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// 读取文件开头小端序的文件头长度，内存中的文件内容不保证按 8 字节对齐。
+#[inline]
+fn read_header_len(file: &[u8]) -> usize {
+    u64::from_le_bytes(file[..size_of::<u64>()].try_into().unwrap()) as _
+}
+
+/// 加载文件头，并检查每个张量的数据在文件范围内且大小与形状一致。
+fn load_header(file: &[u8]) -> Result<SafeTensorsHeader, FileLoadError> {
+    let len = file.len();
+    let header_len = if len >= size_of::<u64>() {
+        read_header_len(file)
+    } else {
+        usize::MAX
+    };
+    let data_len = len
+        .checked_sub(size_of::<u64>())
+        .and_then(|len| len.checked_sub(header_len))
+        .ok_or_else(|| Invalid(format!("file of {len} bytes is truncated")))?;
+    let header = &file[size_of::<u64>()..][..header_len];
+    let header: SafeTensorsHeader = serde_json::from_slice(header).map_err(Json)?;
+
+    for (name, info) in &header.tensors {
+        let (begin, end) = info.data_offsets;
+        if begin > end || end > data_len {
+            return Err(Invalid(format!(
+                "tensor \"{name}\" at {begin}..{end} is out of the data range of {data_len} bytes"
+            )));
+        }
+        let size = info.shape.iter().product::<usize>() * info.dtype.size();
+        if end - begin != size {
+            return Err(Invalid(format!(
+                "tensor \"{name}\" has {} bytes, but {:?} {:?} needs {size}",
+                end - begin,
+                info.dtype,
+                info.shape,
+            )));
+        }
+    }
+    Ok(header)
+}
+
+#[test]
+fn test_checksum() {
+    // FNV-1a 的标准测试向量
+    assert_eq!(checksum(b""), "cbf29ce484222325");
+    assert_eq!(checksum(b"a"), "af63dc4c8601ec8c");
+}
+
+#[test]
+fn test_from_bytes() {
+    let header = br#"{"x":{"dtype":"F32","shape":[2],"data_offsets":[0,8]}}"#;
+    let mut file = (header.len() as u64).to_le_bytes().to_vec();
+    file.extend_from_slice(header);
+    file.extend([1f32, 2.].iter().flat_map(|x| x.to_le_bytes()));
+
+    let safetensors = SafeTensors::from_bytes([file.clone()]).unwrap();
+    let x = safetensors.get("x").unwrap();
+    assert_eq!(x.shape, [2]);
+    assert_eq!(x.data.len(), 8);
+    assert_eq!(x.format, "pt");
+
+    assert!(SafeTensors::from_bytes([file.clone(), file]).is_err());
 }
 
 #[test]
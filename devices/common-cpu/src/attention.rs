@@ -0,0 +1,142 @@
+use crate::simd::{dot, scale_add};
+use common::f16;
+use common_devices::AttentionArgs;
+use digit_layout::types::F16;
+use std::ops::{Deref, DerefMut};
+use tensor::{idim, udim, Tensor};
+
+/// 因果掩码的融合注意力，逐行在线 softmax，不构造注意力矩阵。
+///
+/// `q`/`o` 形状为 `[nh, seq, dh]`，`k`/`v` 为 `[nkvh, att, dh]`，要求最后一维连续。
+pub fn attention<T, U, V, W>(
+    o: &mut Tensor<T>,
+    q: &Tensor<U>,
+    k: &Tensor<V>,
+    v: &Tensor<W>,
+    args: AttentionArgs,
+) where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+    V: Deref<Target = [u8]>,
+    W: Deref<Target = [u8]>,
+{
+    let &[nh, seq, dh] = q.shape() else { panic!() };
+    let &[nkvh, att, _] = k.shape() else { panic!() };
+    assert_eq!(o.shape(), &[nh, seq, dh]);
+    assert_eq!(k.shape(), &[nkvh, att, dh]);
+    assert_eq!(v.shape(), &[nkvh, att, dh]);
+    assert!(nh % nkvh == 0 && seq <= att);
+    for dt in [
+        o.data_layout(),
+        q.data_layout(),
+        k.data_layout(),
+        v.data_layout(),
+    ] {
+        assert_eq!(dt, F16);
+    }
+    for s in [o.strides(), q.strides(), k.strides(), v.strides()] {
+        assert_eq!(s[2], 1);
+    }
+
+    let row = |t: *const f16, s: &[idim], h: udim, i: udim| unsafe {
+        let offset = h as isize * s[0] as isize + i as isize * s[1] as isize;
+        std::slice::from_raw_parts(t.offset(offset), dh as _)
+    };
+    let po = o.locate_start_mut().cast::<f16>();
+    let (so, sq, sk, sv) = (o.strides(), q.strides(), k.strides(), v.strides());
+    let (pq, pk, pv) = (
+        q.locate_start().cast::<f16>(),
+        k.locate_start().cast::<f16>(),
+        v.locate_start().cast::<f16>(),
+    );
+
+    let AttentionArgs {
+        scale,
+        softcap,
+        window,
+    } = args;
+    let mut acc = vec![0f32; dh as _];
+    for h in 0..nh {
+        let kv = h / (nh / nkvh);
+        for i in 0..seq {
+            let q = row(pq, sq, h, i);
+            let mut max = f32::NEG_INFINITY;
+            let mut sum = 0.;
+            acc.fill(0.);
+            // 查询位于注意力序列末尾，只能看到自身及之前的位置
+            let len = att - seq + i + 1;
+            let start = window.map_or(0, |w| len.saturating_sub(w));
+            for j in start..len {
+                let k = row(pk, sk, kv, j);
+                let mut s = dot(q, k) * scale;
+                if let Some(cap) = softcap {
+                    s = cap * (s / cap).tanh();
+                }
+                let max_ = max.max(s);
+                let (c, p) = ((max - max_).exp(), (s - max_).exp());
+                sum = sum * c + p;
+                scale_add(&mut acc, c, p, row(pv, sv, kv, j));
+                max = max_;
+            }
+            let o = unsafe {
+                let offset = h as isize * so[0] as isize + i as isize * so[1] as isize;
+                std::slice::from_raw_parts_mut(po.offset(offset), dh as _)
+            };
+            for (o, a) in o.iter_mut().zip(&acc) {
+                *o = f16::from_f32(a / sum);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_attention() {
+    use tensor::{reslice, reslice_mut};
+
+    // 2 个查询头共享 1 个键值头，注意力长度 3，最后 2 个位置是查询
+    let q = [1., 0., 0., 1., 1., 1., 0., 0.].map(f16::from_f32);
+    let k = [1., 0., 0., 1., 0., 0.].map(f16::from_f32);
+    let v = [1., 2., 3., 4., 5., 6.].map(f16::from_f32);
+    let mut o = [f16::ZERO; 8];
+    let q = Tensor::new(F16, &[2, 2, 2], reslice::<f16, u8>(&q));
+    let k = Tensor::new(F16, &[1, 3, 2], reslice::<f16, u8>(&k));
+    let v = Tensor::new(F16, &[1, 3, 2], reslice::<f16, u8>(&v));
+    let mut o_ = Tensor::new(F16, &[2, 2, 2], reslice_mut::<f16, u8>(&mut o));
+    attention(&mut o_, &q, &k, &v, AttentionArgs::scale(1.));
+
+    let e = std::f32::consts::E;
+    // 头 0 第 0 行只看到前 2 个位置，分数为 [1, 0]
+    let expect = [(e * 1. + 3.) / (e + 1.), (e * 2. + 4.) / (e + 1.)];
+    assert!((o[0].to_f32() - expect[0]).abs() < 1e-2);
+    assert!((o[1].to_f32() - expect[1]).abs() < 1e-2);
+    // 头 1 第 1 行全为零，均匀看到 3 个位置
+    assert!((o[6].to_f32() - 3.).abs() < 1e-2);
+    assert!((o[7].to_f32() - 4.).abs() < 1e-2);
+}
+
+#[test]
+fn test_attention_window() {
+    use tensor::{reslice, reslice_mut};
+
+    // 1 个头，注意力长度 3，最后 1 个位置是查询，窗口 2 只看到后 2 个位置
+    let q = [1., 0.].map(f16::from_f32);
+    let k = [4., 0., 0., 0., 2., 0.].map(f16::from_f32);
+    let v = [9., 9., 1., 2., 3., 4.].map(f16::from_f32);
+    let mut o = [f16::ZERO; 2];
+    let q = Tensor::new(F16, &[1, 1, 2], reslice::<f16, u8>(&q));
+    let k = Tensor::new(F16, &[1, 3, 2], reslice::<f16, u8>(&k));
+    let v = Tensor::new(F16, &[1, 3, 2], reslice::<f16, u8>(&v));
+    let mut o_ = Tensor::new(F16, &[1, 1, 2], reslice_mut::<f16, u8>(&mut o));
+    let args = AttentionArgs {
+        scale: 1.,
+        softcap: Some(1.),
+        window: Some(2),
+    };
+    attention(&mut o_, &q, &k, &v, args);
+
+    // 分数 [0, 2] 软截断为 [0, tanh 2]
+    let p = 2f32.tanh().exp();
+    let expect = [(1. + 3. * p) / (1. + p), (2. + 4. * p) / (1. + p)];
+    assert!((o[0].to_f32() - expect[0]).abs() < 1e-2);
+    assert!((o[1].to_f32() - expect[1]).abs() < 1e-2);
+}
@@ -0,0 +1,85 @@
+use common::f16;
+use digit_layout::types::F16;
+use std::ops::{Deref, DerefMut};
+use tensor::Tensor;
+
+/// `gate = gelu(gate) * up`。
+pub fn geglu<T, U>(gate: &mut Tensor<T>, up: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    binary(gate, up, |g, u| gelu(g) * u);
+}
+
+/// `c += a`。
+pub fn add<T, U>(c: &mut Tensor<T>, a: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    binary(c, a, |c, a| c + a);
+}
+
+/// `x = cap * tanh(x / cap)`。
+pub fn softcap<T>(x: &mut Tensor<T>, cap: f32)
+where
+    T: DerefMut<Target = [u8]>,
+{
+    let &[n, d] = x.shape() else { panic!() };
+    assert_eq!(x.data_layout(), F16);
+    assert_eq!(x.strides()[1], 1);
+
+    let sx = x.strides()[0] as isize;
+    let px = x.locate_start_mut().cast::<f16>();
+    for i in 0..n as isize {
+        let x = unsafe { std::slice::from_raw_parts_mut(px.offset(i * sx), d as _) };
+        for x in x {
+            *x = f16::from_f32(cap * (x.to_f32() / cap).tanh());
+        }
+    }
+}
+
+#[inline]
+fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1. + (SQRT_2_OVER_PI * (x + 0.044715 * x * x * x)).tanh())
+}
+
+/// 逐行处理二维张量，要求最后一维连续。
+fn binary<T, U>(y: &mut Tensor<T>, x: &Tensor<U>, f: impl Fn(f32, f32) -> f32)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let &[n, d] = y.shape() else { panic!() };
+    assert_eq!(x.shape(), &[n, d]);
+    assert_eq!(y.data_layout(), F16);
+    assert_eq!(x.data_layout(), F16);
+    assert_eq!(y.strides()[1], 1);
+    assert_eq!(x.strides()[1], 1);
+
+    let sy = y.strides()[0] as isize;
+    let sx = x.strides()[0] as isize;
+    let py = y.locate_start_mut().cast::<f16>();
+    let px = x.locate_start().cast::<f16>();
+    for i in 0..n as isize {
+        let y = unsafe { std::slice::from_raw_parts_mut(py.offset(i * sy), d as _) };
+        let x = unsafe { std::slice::from_raw_parts(px.offset(i * sx), d as _) };
+        for (y, x) in y.iter_mut().zip(x) {
+            *y = f16::from_f32(f(y.to_f32(), x.to_f32()));
+        }
+    }
+}
+
+#[test]
+fn test_add() {
+    use tensor::reslice;
+
+    let a = [1., 2., 3., 4.].map(f16::from_f32);
+    let mut c = [10., 20., 30., 40.].map(f16::from_f32);
+    let a = Tensor::new(F16, &[2, 2], reslice::<f16, u8>(&a));
+    let mut c_ = Tensor::new(F16, &[2, 2], tensor::reslice_mut::<f16, u8>(&mut c));
+    add(&mut c_, &a);
+    assert_eq!(c.map(f16::to_f32), [11., 22., 33., 44.]);
+}
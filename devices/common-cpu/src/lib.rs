@@ -5,7 +5,11 @@ macro_rules! slice {
     };
 }
 
+mod attention;
+mod elementwise;
 mod gather;
+mod rotary;
+mod simd;
 
 use common::utok;
 use common_devices::{mat_mul, rms_norm, rope, softmax, swiglu, SliceOn};
@@ -23,7 +27,7 @@ use tensor::Tensor;
 
 pub extern crate tensor;
 
-pub use common_devices::Kernels;
+pub use common_devices::{AttentionArgs, Kernels};
 pub use operators::common_cpu::{Device as Cpu, ThisThread};
 
 pub struct CpuKernels {
@@ -106,6 +110,14 @@ impl Kernels for CpuKernels {
         );
     }
 
+    fn rotary<T, U>(&self, t: &mut Tensor<T>, sin_cos: &Tensor<U>, _queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        rotary::rotary(t, sin_cos);
+    }
+
     fn mat_mul<T, U, V>(
         &self,
         c: &mut Tensor<T>,
@@ -146,6 +158,23 @@ impl Kernels for CpuKernels {
         softmax(PhantomData::<softmax::Scheme>, &self.softmax, att, queue);
     }
 
+    fn attention<T, U, V, W>(
+        &self,
+        o: &mut Tensor<T>,
+        q: &Tensor<U>,
+        k: &Tensor<V>,
+        v: &Tensor<W>,
+        args: AttentionArgs,
+        _queue: &QueueOf<Self::Device>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>>,
+    {
+        attention::attention(o, q, k, v, args);
+    }
+
     fn swiglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Device>)
     where
         T: DerefMut<Target = SliceOn<Self::Device>>,
@@ -153,4 +182,27 @@ impl Kernels for CpuKernels {
     {
         swiglu(PhantomData::<swiglu::Scheme>, &self.swiglu, gate, up, queue);
     }
+
+    fn geglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, _queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        elementwise::geglu(gate, up);
+    }
+
+    fn add<T, U>(&self, c: &mut Tensor<T>, a: &Tensor<U>, _queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        elementwise::add(c, a);
+    }
+
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, _queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+    {
+        elementwise::softcap(x, cap);
+    }
 }
@@ -0,0 +1,49 @@
+use common::f16;
+use digit_layout::types::{F16, F32};
+use std::ops::{Deref, DerefMut};
+use tensor::Tensor;
+
+/// 使用 `[nt, dr / 2, 2]` 的正余弦表旋转 `[nt, nh, dr]` 的张量，要求最后一维连续。
+pub fn rotary<T, U>(t: &mut Tensor<T>, sin_cos: &Tensor<U>)
+where
+    T: DerefMut<Target = [u8]>,
+    U: Deref<Target = [u8]>,
+{
+    let &[nt, nh, dr] = t.shape() else { panic!() };
+    assert_eq!(sin_cos.shape(), &[nt, dr / 2, 2]);
+    assert_eq!(t.data_layout(), F16);
+    assert_eq!(sin_cos.data_layout(), F32);
+    assert_eq!(t.strides()[2], 1);
+    assert!(sin_cos.is_contiguous());
+
+    let &[st, sh, _] = t.strides() else { panic!() };
+    let pt = t.locate_start_mut().cast::<f16>();
+    let table = sin_cos.locate_start().cast::<[f32; 2]>();
+    for i in 0..nt as isize {
+        let table = unsafe {
+            std::slice::from_raw_parts(table.offset(i * (dr / 2) as isize), (dr / 2) as _)
+        };
+        for j in 0..nh as isize {
+            let t = unsafe { pt.offset(i * st as isize + j * sh as isize) };
+            let t = unsafe { std::slice::from_raw_parts_mut(t.cast::<[f16; 2]>(), (dr / 2) as _) };
+            for ([a, b], [cos, sin]) in t.iter_mut().zip(table) {
+                let (x, y) = (a.to_f32(), b.to_f32());
+                *a = f16::from_f32(x * cos - y * sin);
+                *b = f16::from_f32(x * sin + y * cos);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rotary() {
+    use std::f32::consts::FRAC_PI_2;
+    use tensor::{reslice, reslice_mut};
+
+    let mut t = [1., 2., 3., 4.].map(f16::from_f32);
+    let table = [[1., 0.], [FRAC_PI_2.cos(), FRAC_PI_2.sin()]];
+    let table = Tensor::new(F32, &[1, 2, 2], reslice::<[f32; 2], u8>(&table));
+    let mut t_ = Tensor::new(F16, &[1, 1, 4], reslice_mut::<f16, u8>(&mut t));
+    rotary(&mut t_, &table);
+    assert_eq!(t.map(f16::to_f32), [1., 2., -4., 3.]);
+}
@@ -0,0 +1,76 @@
+//! 计算核中的向量运算，wasm32 开启 `simd128` 时使用 WASM SIMD。
+
+use common::f16;
+
+/// `a` 与 `b` 的点积，以 `f32` 累加。
+#[cfg(not(all(target_arch = "wasm32", target_feature = "simd128")))]
+pub(crate) fn dot(a: &[f16], b: &[f16]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a.to_f32() * b.to_f32()).sum()
+}
+
+/// `acc = acc * c + p * v`。
+#[cfg(not(all(target_arch = "wasm32", target_feature = "simd128")))]
+pub(crate) fn scale_add(acc: &mut [f32], c: f32, p: f32, v: &[f16]) {
+    for (a, v) in acc.iter_mut().zip(v) {
+        *a = *a * c + p * v.to_f32();
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+#[inline]
+fn load(x: &[f16]) -> std::arch::wasm32::v128 {
+    std::arch::wasm32::f32x4(x[0].to_f32(), x[1].to_f32(), x[2].to_f32(), x[3].to_f32())
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+pub(crate) fn dot(a: &[f16], b: &[f16]) -> f32 {
+    use std::arch::wasm32::*;
+
+    let a = a.chunks_exact(4);
+    let b = b.chunks_exact(4);
+    let tail = a
+        .remainder()
+        .iter()
+        .zip(b.remainder())
+        .map(|(a, b)| a.to_f32() * b.to_f32())
+        .sum::<f32>();
+    let sum = a.zip(b).fold(f32x4_splat(0.), |sum, (a, b)| {
+        f32x4_add(sum, f32x4_mul(load(a), load(b)))
+    });
+    f32x4_extract_lane::<0>(sum)
+        + f32x4_extract_lane::<1>(sum)
+        + f32x4_extract_lane::<2>(sum)
+        + f32x4_extract_lane::<3>(sum)
+        + tail
+}
+
+#[cfg(all(target_arch = "wasm32", target_feature = "simd128"))]
+pub(crate) fn scale_add(acc: &mut [f32], c: f32, p: f32, v: &[f16]) {
+    use std::arch::wasm32::*;
+
+    let (c4, p4) = (f32x4_splat(c), f32x4_splat(p));
+    let mut acc = acc.chunks_exact_mut(4);
+    let mut v = v.chunks_exact(4);
+    for (a, v) in (&mut acc).zip(&mut v) {
+        // 块内的 4 个 f32 连续，可以非对齐地整体读写
+        let ptr = a.as_mut_ptr().cast::<v128>();
+        unsafe {
+            let x = f32x4_add(f32x4_mul(v128_load(ptr), c4), f32x4_mul(load(v), p4));
+            v128_store(ptr, x);
+        }
+    }
+    for (a, v) in acc.into_remainder().iter_mut().zip(v.remainder()) {
+        *a = *a * c + p * v.to_f32();
+    }
+}
+
+#[test]
+fn test_simd() {
+    let a = [1., 2., 3., 4., 5.].map(f16::from_f32);
+    let b = [5., 4., 3., 2., 1.].map(f16::from_f32);
+    assert_eq!(dot(&a, &b), 35.);
+
+    let mut acc = [1., 1., 1., 1., 1.];
+    scale_add(&mut acc, 2., 0.5, &a);
+    assert_eq!(acc, [2.5, 3., 3.5, 4., 4.5]);
+}
@@ -13,7 +13,7 @@ use std::{
     marker::PhantomData,
     ops::{Deref, DerefMut},
 };
-use tensor::Tensor;
+use tensor::{slice, udim, Tensor};
 
 fn layout<T>(t: &Tensor<T>) -> TensorLayout {
     let dt = t.data_layout();
@@ -28,6 +28,40 @@ fn layout<T>(t: &Tensor<T>) -> TensorLayout {
 
 pub type SliceOn<D> = [<D as Device>::Byte];
 
+/// 融合旋转位置编码与写入缓存的计算核读写的张量。
+///
+/// `q` 形状为 `[nh, seq, dh]`，`k`/`v`/`k_cache`/`v_cache` 为 `[nkvh, seq, dh]`。
+pub struct QkvCache<'a, Q, K, V, KC, VC> {
+    pub q: &'a mut Tensor<Q>,
+    pub k: &'a mut Tensor<K>,
+    pub v: &'a Tensor<V>,
+    pub k_cache: &'a mut Tensor<KC>,
+    pub v_cache: &'a mut Tensor<VC>,
+}
+
+/// 融合注意力的参数。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AttentionArgs {
+    /// 注意力分数的缩放系数。
+    pub scale: f32,
+    /// 非空时将缩放后的分数 `s` 软截断为 `softcap * tanh(s / softcap)`。
+    pub softcap: Option<f32>,
+    /// 非空时每个查询只看到包括自身在内的最近 `window` 个位置。
+    pub window: Option<udim>,
+}
+
+impl AttentionArgs {
+    /// 只缩放分数的注意力。
+    #[inline]
+    pub const fn scale(scale: f32) -> Self {
+        Self {
+            scale,
+            softcap: None,
+            window: None,
+        }
+    }
+}
+
 pub trait Kernels {
     type Device: Device;
 
@@ -64,6 +98,48 @@ pub trait Kernels {
         T: DerefMut<Target = SliceOn<Self::Device>>,
         U: Deref<Target = SliceOn<Self::Device>>;
 
+    /// 融合旋转位置编码与写入缓存：按 `pos` 原地编码 `q`，编码 `k` 后与 `v` 一起写入缓存。
+    ///
+    /// 只编码每个头的前 `dr` 维。默认实现先原地编码 `q`、`k`，再复制到缓存。
+    fn rope_qkv<Q, K, V, KC, VC, P>(
+        &self,
+        qkv: QkvCache<Q, K, V, KC, VC>,
+        pos: &Tensor<P>,
+        dr: udim,
+        theta: f32,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        Q: DerefMut<Target = SliceOn<Self::Device>>,
+        K: DerefMut<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        KC: DerefMut<Target = SliceOn<Self::Device>>,
+        VC: DerefMut<Target = SliceOn<Self::Device>>,
+        P: Deref<Target = SliceOn<Self::Device>>,
+    {
+        let QkvCache {
+            q,
+            k,
+            v,
+            k_cache,
+            v_cache,
+        } = qkv;
+        let rotary = &[slice![=>], slice![=>], slice![=> dr]];
+        let q = q.as_mut().slice(rotary).map_physical(|u| &mut **u);
+        self.rope(&mut q.transpose(&[1, 0, 2]), pos, theta, queue);
+        let k_ = k.as_mut().slice(rotary).map_physical(|u| &mut **u);
+        self.rope(&mut k_.transpose(&[1, 0, 2]), pos, theta, queue);
+        self.reform(k_cache, k, queue);
+        self.reform(v_cache, v, queue);
+    }
+
+    /// 使用预先计算的正余弦表施加旋转位置编码。
+    ///
+    /// `t` 形状为 `[nt, nh, dr]`，`sin_cos` 为 `[nt, dr / 2, 2]` 的 f32 表，每对为 `(cos, sin)`。
+    fn rotary<T, U>(&self, t: &mut Tensor<T>, sin_cos: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>;
+
     fn mat_mul<T, U, V>(
         &self,
         c: &mut Tensor<T>,
@@ -86,10 +162,63 @@ pub trait Kernels {
     where
         T: DerefMut<Target = SliceOn<Self::Device>>;
 
+    /// 因果掩码的融合注意力 `o = softmax(q k^T * scale) v`，不构造注意力矩阵。
+    ///
+    /// `q`/`o` 形状为 `[nh, seq, dh]`，`k`/`v` 为 `[nkvh, att, dh]`，查询是注意力序列的最后 `seq` 个位置。
+    fn attention<T, U, V, W>(
+        &self,
+        o: &mut Tensor<T>,
+        q: &Tensor<U>,
+        k: &Tensor<V>,
+        v: &Tensor<W>,
+        args: AttentionArgs,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>>;
+
     fn swiglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Device>)
     where
         T: DerefMut<Target = SliceOn<Self::Device>>,
         U: Deref<Target = SliceOn<Self::Device>>;
+
+    /// `gate = gelu(gate) * up`，gelu 使用 tanh 近似。
+    fn geglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>;
+
+    /// `c += a`。
+    fn add<T, U>(&self, c: &mut Tensor<T>, a: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>;
+
+    /// `x = cap * tanh(x / cap)`。
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>;
+
+    /// `x += a` 后 `y = rms_norm(x) * w`，融合残差连接与归一化。
+    fn add_rms_norm<T, U, V, W>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &mut Tensor<U>,
+        a: &Tensor<V>,
+        w: &Tensor<W>,
+        epsilon: f32,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: DerefMut<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.add(x, a, queue);
+        self.rms_norm(y, x, w, epsilon, queue);
+    }
 }
 
 pub fn rms_norm<S, D, Y, X, W>(
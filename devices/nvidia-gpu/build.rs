@@ -5,13 +5,22 @@
     let cuda = Cfg::new("detected_cuda");
     if find_cuda_root().is_some() {
         cuda.define();
+        println!("cargo:rerun-if-changed=src/dtype.cuh");
         println!("cargo:rerun-if-changed=src/sample.cu");
+        println!("cargo:rerun-if-changed=src/elementwise.cu");
+        println!("cargo:rerun-if-changed=src/rotary.cu");
+        println!("cargo:rerun-if-changed=src/attention.cu");
+        println!("cargo:rerun-if-changed=src/profile.cu");
         cc::Build::new()
             .cuda(true)
             .flag("-gencode")
             .flag("arch=compute_80,code=sm_80")
             .flag("-allow-unsupported-compiler")
             .file("src/sample.cu")
+            .file("src/elementwise.cu")
+            .file("src/rotary.cu")
+            .file("src/attention.cu")
+            .file("src/profile.cu")
             .compile("sample");
     }
 }
@@ -0,0 +1,238 @@
+#include "dtype.cuh"
+
+constexpr int WARP = 32;
+// 每个线程负责的头维度分量数的上限，支持 dh <= 256
+constexpr int MAX_ITEMS = 8;
+constexpr int NUM_WARPS = 8;
+// 预填充时每次载入共享内存的键值数
+constexpr int TILE = 32;
+
+static __device__ float warp_sum(float x) {
+    for (int mask = WARP / 2; mask > 0; mask /= 2) {
+        x += __shfl_xor_sync(0xffffffff, x, mask);
+    }
+    return x;
+}
+
+// 在线 softmax 的状态，每个线程保存自己负责的分量
+struct State {
+    float max, sum, acc[MAX_ITEMS];
+
+    __device__ State() : max(-INFINITY), sum(0) {
+        for (int i = 0; i < MAX_ITEMS; ++i) {
+            acc[i] = 0;
+        }
+    }
+
+    template<class T>
+    __device__ void update(float score, T const *v, int dh, int lane) {
+        auto max_ = fmaxf(max, score);
+        auto c = __expf(max - max_), p = __expf(score - max_);
+        sum = sum * c + p;
+        for (int i = 0; i < MAX_ITEMS; ++i) {
+            auto d = lane + i * WARP;
+            if (d < dh) {
+                acc[i] = acc[i] * c + p * to_float(v[d]);
+            }
+        }
+        max = max_;
+    }
+};
+
+// 线程束内计算 q 与一个键的点积
+template<class T>
+static __device__ float dot(float const *q, T const *k, int dh, int lane) {
+    float ans = 0;
+    for (int i = 0; i < MAX_ITEMS; ++i) {
+        auto d = lane + i * WARP;
+        if (d < dh) {
+            ans += q[i] * to_float(k[d]);
+        }
+    }
+    return warp_sum(ans);
+}
+
+// 软截断注意力分数，softcap 为 0 时不截断
+static __device__ float cap(float score, float softcap) {
+    return softcap > 0 ? softcap * tanhf(score / softcap) : score;
+}
+
+template<class T>
+static __device__ void load_q(float *q_, T const *q, int dh, int lane, float scale) {
+    for (int i = 0; i < MAX_ITEMS; ++i) {
+        auto d = lane + i * WARP;
+        q_[i] = d < dh ? to_float(q[d]) * scale : 0;
+    }
+}
+
+// 解码：每个线程块计算一行，各线程束分摊键值，最后合并
+template<class T>
+static __global__ void attention_decode_kernel(
+    T *__restrict__ o, int so_h, int so_r,
+    T const *__restrict__ q, int sq_h, int sq_r,
+    T const *__restrict__ k, int sk_h, int sk_r,
+    T const *__restrict__ v, int sv_h, int sv_r,
+    int group, int seq, int att, int dh,
+    float scale, float softcap, int window) {
+    extern __shared__ char shared[];
+    __shared__ float max_[NUM_WARPS], sum_[NUM_WARPS];
+    auto acc_ = reinterpret_cast<float *>(shared);
+
+    int h = blockIdx.x, i = blockIdx.y, kv = h / group;
+    int warp = threadIdx.x / WARP, lane = threadIdx.x % WARP;
+    k += kv * sk_h;
+    v += kv * sv_h;
+
+    float q_[MAX_ITEMS];
+    load_q(q_, q + h * sq_h + i * sq_r, dh, lane, scale);
+
+    State s;
+    // window 为 0 时不限制窗口
+    auto len = att - seq + i + 1;
+    auto start = window > 0 ? max(0, len - window) : 0;
+    for (int j = start + warp; j < len; j += NUM_WARPS) {
+        s.update(cap(dot(q_, k + j * sk_r, dh, lane), softcap), v + j * sv_r, dh, lane);
+    }
+
+    if (lane == 0) {
+        max_[warp] = s.max;
+        sum_[warp] = s.sum;
+    }
+    for (int n = 0; n < MAX_ITEMS; ++n) {
+        auto d = lane + n * WARP;
+        if (d < dh) {
+            acc_[warp * dh + d] = s.acc[n];
+        }
+    }
+    __syncthreads();
+
+    // 没有分到键值的线程束 max 为负无穷，权重为 0
+    auto max = -INFINITY;
+    for (int w = 0; w < NUM_WARPS; ++w) {
+        max = fmaxf(max, max_[w]);
+    }
+    float sum = 0;
+    for (int w = 0; w < NUM_WARPS; ++w) {
+        sum += sum_[w] * __expf(max_[w] - max);
+    }
+    o += h * so_h + i * so_r;
+    for (int d = threadIdx.x; d < dh; d += blockDim.x) {
+        float x = 0;
+        for (int w = 0; w < NUM_WARPS; ++w) {
+            x += acc_[w * dh + d] * __expf(max_[w] - max);
+        }
+        o[d] = from_float<T>(x / sum);
+    }
+}
+
+// 预填充：每个线程束计算一行，线程块共享载入的键值分块
+template<class T>
+static __global__ void attention_prefill_kernel(
+    T *__restrict__ o, int so_h, int so_r,
+    T const *__restrict__ q, int sq_h, int sq_r,
+    T const *__restrict__ k, int sk_h, int sk_r,
+    T const *__restrict__ v, int sv_h, int sv_r,
+    int group, int seq, int att, int dh,
+    float scale, float softcap, int window) {
+    extern __shared__ char shared[];
+    auto k_ = reinterpret_cast<T *>(shared), v_ = k_ + TILE * dh;
+
+    int h = blockIdx.x, kv = h / group;
+    int warp = threadIdx.x / WARP, lane = threadIdx.x % WARP;
+    int i = blockIdx.y * NUM_WARPS + warp;
+    bool active = i < seq;
+    k += kv * sk_h;
+    v += kv * sv_h;
+
+    float q_[MAX_ITEMS];
+    if (active) {
+        load_q(q_, q + h * sq_h + i * sq_r, dh, lane, scale);
+    }
+
+    State s;
+    // 查询位于注意力序列末尾，只能看到自身及之前的位置
+    auto len = active ? att - seq + i + 1 : 0;
+    auto start = window > 0 ? max(0, len - window) : 0;
+    // 线程块内第一行窗口之前的键值不需要载入
+    auto first = (int) blockIdx.y * NUM_WARPS;
+    auto block_start = window > 0 ? max(0, att - seq + first + 1 - window) : 0;
+    auto last = min(seq, (int) (blockIdx.y + 1) * NUM_WARPS) - 1;
+    auto block_len = att - seq + last + 1;
+    for (int j0 = block_start; j0 < block_len; j0 += TILE) {
+        auto n = min(TILE, block_len - j0);
+        __syncthreads();
+        for (int t = threadIdx.x; t < n * dh; t += blockDim.x) {
+            auto r = t / dh, d = t % dh;
+            k_[t] = k[(j0 + r) * sk_r + d];
+            v_[t] = v[(j0 + r) * sv_r + d];
+        }
+        __syncthreads();
+        auto end = min(n, len - j0);
+        for (int r = max(0, start - j0); r < end; ++r) {
+            s.update(cap(dot(q_, k_ + r * dh, dh, lane), softcap), v_ + r * dh, dh, lane);
+        }
+    }
+
+    if (active) {
+        o += h * so_h + i * so_r;
+        for (int n = 0; n < MAX_ITEMS; ++n) {
+            auto d = lane + n * WARP;
+            if (d < dh) {
+                o[d] = from_float<T>(s.acc[n] / s.sum);
+            }
+        }
+    }
+}
+
+template<class T>
+static cudaError attention(
+    T *o, int so_h, int so_r,
+    T const *q, int sq_h, int sq_r,
+    T const *k, int sk_h, int sk_r,
+    T const *v, int sv_h, int sv_r,
+    int nh, int nkvh, int seq, int att, int dh,
+    float scale, float softcap, int window,
+    cudaStream_t stream) {
+    if (dh > MAX_ITEMS * WARP) {
+        return cudaErrorInvalidValue;
+    }
+    auto group = nh / nkvh;
+    if (seq == 1) {
+        dim3 grid(nh, seq);
+        auto shared = NUM_WARPS * dh * sizeof(float);
+        attention_decode_kernel<<<grid, NUM_WARPS * WARP, shared, stream>>>(
+            o, so_h, so_r, q, sq_h, sq_r, k, sk_h, sk_r, v, sv_h, sv_r,
+            group, seq, att, dh, scale, softcap, window);
+    } else {
+        dim3 grid(nh, (seq + NUM_WARPS - 1) / NUM_WARPS);
+        auto shared = 2 * TILE * dh * sizeof(T);
+        attention_prefill_kernel<<<grid, NUM_WARPS * WARP, shared, stream>>>(
+            o, so_h, so_r, q, sq_h, sq_r, k, sk_h, sk_r, v, sv_h, sv_r,
+            group, seq, att, dh, scale, softcap, window);
+    }
+    return cudaGetLastError();
+}
+
+extern "C" cudaError attention_half(
+    half *o, int so_h, int so_r,
+    half const *q, int sq_h, int sq_r,
+    half const *k, int sk_h, int sk_r,
+    half const *v, int sv_h, int sv_r,
+    int nh, int nkvh, int seq, int att, int dh,
+    float scale, float softcap, int window,
+    cudaStream_t stream) {
+    return attention(o, so_h, so_r, q, sq_h, sq_r, k, sk_h, sk_r, v, sv_h, sv_r,
+                     nh, nkvh, seq, att, dh, scale, softcap, window, stream);
+}
+
+extern "C" cudaError attention_bf16(
+    nv_bfloat16 *o, int so_h, int so_r,
+    nv_bfloat16 const *q, int sq_h, int sq_r,
+    nv_bfloat16 const *k, int sk_h, int sk_r,
+    nv_bfloat16 const *v, int sv_h, int sv_r,
+    int nh, int nkvh, int seq, int att, int dh,
+    float scale, float softcap, int window,
+    cudaStream_t stream) {
+    return attention(o, so_h, so_r, q, sq_h, sq_r, k, sk_h, sk_r, v, sv_h, sv_r,
+                     nh, nkvh, seq, att, dh, scale, softcap, window, stream);
+}
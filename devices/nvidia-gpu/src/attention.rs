@@ -0,0 +1,145 @@
+use common::{bf16, f16};
+use common_devices::AttentionArgs;
+use operators::nvidia_gpu::cuda::{bindings::CUstream, AsRaw, DevByte, Stream};
+use std::{
+    ffi::c_int,
+    ops::{Deref, DerefMut},
+};
+use tensor::Tensor;
+
+extern "C" {
+    // extern "C" cudaError attention_half(
+    //     half *o, int so_h, int so_r,
+    //     half const *q, int sq_h, int sq_r,
+    //     half const *k, int sk_h, int sk_r,
+    //     half const *v, int sv_h, int sv_r,
+    //     int nh, int nkvh, int seq, int att, int dh,
+    //     float scale, float softcap, int window,
+    //     cudaStream_t stream)
+    fn attention_half(
+        o: *mut f16,
+        so_h: c_int,
+        so_r: c_int,
+        q: *const f16,
+        sq_h: c_int,
+        sq_r: c_int,
+        k: *const f16,
+        sk_h: c_int,
+        sk_r: c_int,
+        v: *const f16,
+        sv_h: c_int,
+        sv_r: c_int,
+        nh: c_int,
+        nkvh: c_int,
+        seq: c_int,
+        att: c_int,
+        dh: c_int,
+        scale: f32,
+        softcap: f32,
+        window: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError attention_bf16(
+    //     nv_bfloat16 *o, int so_h, int so_r,
+    //     nv_bfloat16 const *q, int sq_h, int sq_r,
+    //     nv_bfloat16 const *k, int sk_h, int sk_r,
+    //     nv_bfloat16 const *v, int sv_h, int sv_r,
+    //     int nh, int nkvh, int seq, int att, int dh,
+    //     float scale, float softcap, int window,
+    //     cudaStream_t stream)
+    fn attention_bf16(
+        o: *mut bf16,
+        so_h: c_int,
+        so_r: c_int,
+        q: *const bf16,
+        sq_h: c_int,
+        sq_r: c_int,
+        k: *const bf16,
+        sk_h: c_int,
+        sk_r: c_int,
+        v: *const bf16,
+        sv_h: c_int,
+        sv_r: c_int,
+        nh: c_int,
+        nkvh: c_int,
+        seq: c_int,
+        att: c_int,
+        dh: c_int,
+        scale: f32,
+        softcap: f32,
+        window: c_int,
+        stream: CUstream,
+    ) -> c_int;
+}
+
+pub fn attention<T, U, V, W>(
+    o: &mut Tensor<T>,
+    q: &Tensor<U>,
+    k: &Tensor<V>,
+    v: &Tensor<W>,
+    args: AttentionArgs,
+    stream: &Stream,
+) where
+    T: DerefMut<Target = [DevByte]>,
+    U: Deref<Target = [DevByte]>,
+    V: Deref<Target = [DevByte]>,
+    W: Deref<Target = [DevByte]>,
+{
+    let &[nh, seq, dh] = q.shape() else { panic!() };
+    let &[nkvh, att, _] = k.shape() else { panic!() };
+    assert_eq!(o.shape(), &[nh, seq, dh]);
+    assert_eq!(k.shape(), &[nkvh, att, dh]);
+    assert_eq!(v.shape(), &[nkvh, att, dh]);
+    assert!(nh % nkvh == 0 && seq <= att);
+    let dt = q.data_layout();
+    for t in [o.data_layout(), k.data_layout(), v.data_layout()] {
+        assert_eq!(t, dt);
+    }
+    for s in [o.strides(), q.strides(), k.strides(), v.strides()] {
+        assert_eq!(s[2], 1);
+    }
+
+    let &[so_h, so_r, _] = o.strides() else {
+        panic!()
+    };
+    let &[sq_h, sq_r, _] = q.strides() else {
+        panic!()
+    };
+    let &[sk_h, sk_r, _] = k.strides() else {
+        panic!()
+    };
+    let &[sv_h, sv_r, _] = v.strides() else {
+        panic!()
+    };
+    let po = unsafe { o.physical_mut().as_mut_ptr().offset(o.bytes_offset()) };
+    let pq = unsafe { q.physical().as_ptr().offset(q.bytes_offset()) };
+    let pk = unsafe { k.physical().as_ptr().offset(k.bytes_offset()) };
+    let pv = unsafe { v.physical().as_ptr().offset(v.bytes_offset()) };
+    // 计算核以 0 表示不软截断、不限制窗口
+    let softcap = args.softcap.unwrap_or(0.);
+    let window = args.window.unwrap_or(0);
+    launch!(dt; attention_half | attention_bf16(
+        po.cast(),
+        so_h as _,
+        so_r as _,
+        pq.cast(),
+        sq_h as _,
+        sq_r as _,
+        pk.cast(),
+        sk_h as _,
+        sk_r as _,
+        pv.cast(),
+        sv_h as _,
+        sv_r as _,
+        nh as _,
+        nkvh as _,
+        seq as _,
+        att as _,
+        dh as _,
+        args.scale,
+        softcap,
+        window as _,
+        stream.as_raw(),
+    ));
+}
@@ -0,0 +1,109 @@
+use operators::nvidia_gpu::cuda::Stream;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tensor::udim;
+
+/// 每块最大线程数的候选值。
+pub(crate) const CANDIDATES: [usize; 4] = [128, 256, 512, 1024];
+
+/// 自动调优时测试的形状，应取模型推理中的典型形状。
+#[derive(Clone, Copy, Debug)]
+pub struct TuneShapes {
+    /// rms_norm 的 `[token 数, 隐藏层宽度]`。
+    pub rms_norm: [udim; 2],
+    /// softmax 的 `[头数, 查询长度, 注意力长度]`。
+    pub softmax: [udim; 3],
+}
+
+/// 持久化的算子调优结果，每行一项，以制表符分隔键和选用的每块最大线程数。
+///
+/// 键包含显卡名字、算子、数据类型和形状，换卡或换模型后重新调优。
+pub struct TuneCache {
+    path: PathBuf,
+    entries: HashMap<String, usize>,
+    dirty: bool,
+}
+
+impl TuneCache {
+    /// 从 `path` 加载调优结果，文件不存在时为空，无法解析的行被忽略。
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read_to_string(&path) {
+            Ok(text) => text
+                .lines()
+                .filter_map(|line| {
+                    let (key, block) = line.rsplit_once('\t')?;
+                    Some((key.to_string(), block.parse().ok()?))
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            entries,
+            dirty: false,
+        })
+    }
+
+    /// 有新的调优结果时写回文件。
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut entries = self.entries.iter().collect::<Vec<_>>();
+        entries.sort();
+        let text = entries
+            .into_iter()
+            .map(|(key, block)| format!("{key}\t{block}\n"))
+            .collect::<String>();
+        fs::write(&self.path, text)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// 取出 `key` 的调优结果，没有则调用 `tune` 并记录。
+    pub(crate) fn get_or_tune(&mut self, key: String, tune: impl FnOnce() -> usize) -> usize {
+        if let Some(&block) = self.entries.get(&key) {
+            return block;
+        }
+        let block = tune();
+        self.entries.insert(key, block);
+        self.dirty = true;
+        block
+    }
+}
+
+/// 以 CUDA 事件测量 `f` 在 `stream` 上重复执行的平均耗时。
+pub(crate) fn bench(stream: &Stream, mut f: impl FnMut()) -> Duration {
+    const WARM_UP: u32 = 3;
+    const TIMES: u32 = 20;
+
+    for _ in 0..WARM_UP {
+        f();
+    }
+    let start = stream.record();
+    for _ in 0..TIMES {
+        f();
+    }
+    let end = stream.record();
+    end.synchronize();
+    end.elapse_from(&start) / TIMES
+}
+
+#[test]
+fn test_cache() {
+    let path = std::env::temp_dir().join(format!("tune-cache-{}", std::process::id()));
+    let mut cache = TuneCache::load(&path).unwrap();
+    assert_eq!(cache.get_or_tune("a".into(), || 256), 256);
+    cache.save().unwrap();
+
+    let mut cache = TuneCache::load(&path).unwrap();
+    assert_eq!(cache.get_or_tune("a".into(), || unreachable!()), 256);
+    assert!(!cache.dirty);
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,62 @@
+#pragma once
+
+#include <cuda_bf16.h>
+#include <cuda_fp16.h>
+
+// 计算核以 float 计算，按存储类型读写
+
+static __device__ __forceinline__ float to_float(half x) {
+    return __half2float(x);
+}
+
+static __device__ __forceinline__ float to_float(nv_bfloat16 x) {
+    return __bfloat162float(x);
+}
+
+static __device__ __forceinline__ float2 to_float2(half2 x) {
+    return __half22float2(x);
+}
+
+static __device__ __forceinline__ float2 to_float2(nv_bfloat162 x) {
+    return __bfloat1622float2(x);
+}
+
+template<class T>
+static __device__ __forceinline__ T from_float(float x);
+
+template<>
+__device__ __forceinline__ half from_float<half>(float x) {
+    return __float2half(x);
+}
+
+template<>
+__device__ __forceinline__ nv_bfloat16 from_float<nv_bfloat16>(float x) {
+    return __float2bfloat16(x);
+}
+
+template<class T2>
+static __device__ __forceinline__ T2 from_float2(float x, float y);
+
+template<>
+__device__ __forceinline__ half2 from_float2<half2>(float x, float y) {
+    return __floats2half2_rn(x, y);
+}
+
+template<>
+__device__ __forceinline__ nv_bfloat162 from_float2<nv_bfloat162>(float x, float y) {
+    return __floats2bfloat162_rn(x, y);
+}
+
+// 相邻两个分量组成的向量类型
+template<class T>
+struct Pair;
+
+template<>
+struct Pair<half> {
+    using Type = half2;
+};
+
+template<>
+struct Pair<nv_bfloat16> {
+    using Type = nv_bfloat162;
+};
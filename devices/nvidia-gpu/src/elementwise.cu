@@ -0,0 +1,195 @@
+#include "dtype.cuh"
+
+static __device__ float gelu(float x) {
+    constexpr float SQRT_2_OVER_PI = 0.7978845608f;
+    return 0.5f * x * (1.f + tanhf(SQRT_2_OVER_PI * (x + 0.044715f * x * x * x)));
+}
+
+template<class T>
+static __global__ void geglu_kernel(
+    T *__restrict__ gate, int stride_gate,
+    T const *__restrict__ up, int stride_up,
+    int d) {
+    auto i = blockIdx.x, j = blockIdx.y * blockDim.x + threadIdx.x;
+    if (j < d) {
+        auto g = gate + i * stride_gate + j;
+        *g = from_float<T>(gelu(to_float(*g)) * to_float(up[i * stride_up + j]));
+    }
+}
+
+template<class T>
+static __global__ void add_kernel(
+    T *__restrict__ c, int stride_c,
+    T const *__restrict__ a, int stride_a,
+    int d) {
+    auto i = blockIdx.x, j = blockIdx.y * blockDim.x + threadIdx.x;
+    if (j < d) {
+        auto p = c + i * stride_c + j;
+        *p = from_float<T>(to_float(*p) + to_float(a[i * stride_a + j]));
+    }
+}
+
+template<class T>
+static __global__ void softcap_kernel(
+    T *__restrict__ x, int stride_x,
+    int d, float cap) {
+    auto i = blockIdx.x, j = blockIdx.y * blockDim.x + threadIdx.x;
+    if (j < d) {
+        auto p = x + i * stride_x + j;
+        *p = from_float<T>(cap * tanhf(to_float(*p) / cap));
+    }
+}
+
+// 每个线程块处理一行，先累加残差并写回 x，再以归约得到的均方根归一化
+template<class T>
+static __global__ void add_rms_norm_kernel(
+    T *__restrict__ y, int stride_y,
+    T *__restrict__ x, int stride_x,
+    T const *__restrict__ a, int stride_a,
+    T const *__restrict__ w,
+    int d, float epsilon) {
+    __shared__ float partial[32];
+    y += blockIdx.x * stride_y;
+    x += blockIdx.x * stride_x;
+    a += blockIdx.x * stride_a;
+
+    float sum = 0;
+    for (int j = threadIdx.x; j < d; j += blockDim.x) {
+        auto v = from_float<T>(to_float(x[j]) + to_float(a[j]));
+        x[j] = v;
+        auto f = to_float(v);
+        sum += f * f;
+    }
+    for (int mask = 16; mask > 0; mask /= 2) {
+        sum += __shfl_xor_sync(0xffffffff, sum, mask);
+    }
+    if (threadIdx.x % 32 == 0) {
+        partial[threadIdx.x / 32] = sum;
+    }
+    __syncthreads();
+    sum = 0;
+    for (int i = 0; i < (blockDim.x + 31) / 32; ++i) {
+        sum += partial[i];
+    }
+
+    auto k = rsqrtf(sum / d + epsilon);
+    // 每个线程只读回自己写入的分量，不需要再同步
+    for (int j = threadIdx.x; j < d; j += blockDim.x) {
+        y[j] = from_float<T>(to_float(x[j]) * k * to_float(w[j]));
+    }
+}
+
+template<class T>
+static cudaError geglu(
+    T *gate, int stride_gate,
+    T const *up, int stride_up,
+    int n, int d,
+    cudaStream_t stream) {
+    auto block = min(1024, d);
+    dim3 grid(n, (d + block - 1) / block);
+    geglu_kernel<<<grid, block, 0, stream>>>(gate, stride_gate, up, stride_up, d);
+    return cudaGetLastError();
+}
+
+template<class T>
+static cudaError add(
+    T *c, int stride_c,
+    T const *a, int stride_a,
+    int n, int d,
+    cudaStream_t stream) {
+    auto block = min(1024, d);
+    dim3 grid(n, (d + block - 1) / block);
+    add_kernel<<<grid, block, 0, stream>>>(c, stride_c, a, stride_a, d);
+    return cudaGetLastError();
+}
+
+template<class T>
+static cudaError softcap(
+    T *x, int stride_x,
+    int n, int d, float cap,
+    cudaStream_t stream) {
+    auto block = min(1024, d);
+    dim3 grid(n, (d + block - 1) / block);
+    softcap_kernel<<<grid, block, 0, stream>>>(x, stride_x, d, cap);
+    return cudaGetLastError();
+}
+
+template<class T>
+static cudaError add_rms_norm(
+    T *y, int stride_y,
+    T *x, int stride_x,
+    T const *a, int stride_a,
+    T const *w,
+    int n, int d, float epsilon,
+    cudaStream_t stream) {
+    // 线程数取 32 的整数倍，保证线程束归约时所有线程都参与
+    auto block = min(1024, (d + 31) / 32 * 32);
+    add_rms_norm_kernel<<<n, block, 0, stream>>>(y, stride_y, x, stride_x, a, stride_a, w, d, epsilon);
+    return cudaGetLastError();
+}
+
+extern "C" cudaError geglu_half(
+    half *gate, int stride_gate,
+    half const *up, int stride_up,
+    int n, int d,
+    cudaStream_t stream) {
+    return geglu(gate, stride_gate, up, stride_up, n, d, stream);
+}
+
+extern "C" cudaError geglu_bf16(
+    nv_bfloat16 *gate, int stride_gate,
+    nv_bfloat16 const *up, int stride_up,
+    int n, int d,
+    cudaStream_t stream) {
+    return geglu(gate, stride_gate, up, stride_up, n, d, stream);
+}
+
+extern "C" cudaError add_half(
+    half *c, int stride_c,
+    half const *a, int stride_a,
+    int n, int d,
+    cudaStream_t stream) {
+    return add(c, stride_c, a, stride_a, n, d, stream);
+}
+
+extern "C" cudaError add_bf16(
+    nv_bfloat16 *c, int stride_c,
+    nv_bfloat16 const *a, int stride_a,
+    int n, int d,
+    cudaStream_t stream) {
+    return add(c, stride_c, a, stride_a, n, d, stream);
+}
+
+extern "C" cudaError softcap_half(
+    half *x, int stride_x,
+    int n, int d, float cap,
+    cudaStream_t stream) {
+    return softcap(x, stride_x, n, d, cap, stream);
+}
+
+extern "C" cudaError softcap_bf16(
+    nv_bfloat16 *x, int stride_x,
+    int n, int d, float cap,
+    cudaStream_t stream) {
+    return softcap(x, stride_x, n, d, cap, stream);
+}
+
+extern "C" cudaError add_rms_norm_half(
+    half *y, int stride_y,
+    half *x, int stride_x,
+    half const *a, int stride_a,
+    half const *w,
+    int n, int d, float epsilon,
+    cudaStream_t stream) {
+    return add_rms_norm(y, stride_y, x, stride_x, a, stride_a, w, n, d, epsilon, stream);
+}
+
+extern "C" cudaError add_rms_norm_bf16(
+    nv_bfloat16 *y, int stride_y,
+    nv_bfloat16 *x, int stride_x,
+    nv_bfloat16 const *a, int stride_a,
+    nv_bfloat16 const *w,
+    int n, int d, float epsilon,
+    cudaStream_t stream) {
+    return add_rms_norm(y, stride_y, x, stride_x, a, stride_a, w, n, d, epsilon, stream);
+}
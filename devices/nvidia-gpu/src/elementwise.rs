@@ -0,0 +1,254 @@
+use common::{bf16, f16};
+use operators::nvidia_gpu::cuda::{bindings::CUstream, AsRaw, DevByte, Stream};
+use std::{
+    ffi::c_int,
+    ops::{Deref, DerefMut},
+};
+use tensor::Tensor;
+
+extern "C" {
+    // extern "C" cudaError geglu_half(
+    //     half *gate, int stride_gate,
+    //     half const *up, int stride_up,
+    //     int n, int d,
+    //     cudaStream_t stream)
+    fn geglu_half(
+        gate: *mut f16,
+        stride_gate: c_int,
+        up: *const f16,
+        stride_up: c_int,
+        n: c_int,
+        d: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError geglu_bf16(
+    //     nv_bfloat16 *gate, int stride_gate,
+    //     nv_bfloat16 const *up, int stride_up,
+    //     int n, int d,
+    //     cudaStream_t stream)
+    fn geglu_bf16(
+        gate: *mut bf16,
+        stride_gate: c_int,
+        up: *const bf16,
+        stride_up: c_int,
+        n: c_int,
+        d: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError add_half(
+    //     half *c, int stride_c,
+    //     half const *a, int stride_a,
+    //     int n, int d,
+    //     cudaStream_t stream)
+    fn add_half(
+        c: *mut f16,
+        stride_c: c_int,
+        a: *const f16,
+        stride_a: c_int,
+        n: c_int,
+        d: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError add_bf16(
+    //     nv_bfloat16 *c, int stride_c,
+    //     nv_bfloat16 const *a, int stride_a,
+    //     int n, int d,
+    //     cudaStream_t stream)
+    fn add_bf16(
+        c: *mut bf16,
+        stride_c: c_int,
+        a: *const bf16,
+        stride_a: c_int,
+        n: c_int,
+        d: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError softcap_half(
+    //     half *x, int stride_x,
+    //     int n, int d, float cap,
+    //     cudaStream_t stream)
+    fn softcap_half(
+        x: *mut f16,
+        stride_x: c_int,
+        n: c_int,
+        d: c_int,
+        cap: f32,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError softcap_bf16(
+    //     nv_bfloat16 *x, int stride_x,
+    //     int n, int d, float cap,
+    //     cudaStream_t stream)
+    fn softcap_bf16(
+        x: *mut bf16,
+        stride_x: c_int,
+        n: c_int,
+        d: c_int,
+        cap: f32,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError add_rms_norm_half(
+    //     half *y, int stride_y,
+    //     half *x, int stride_x,
+    //     half const *a, int stride_a,
+    //     half const *w,
+    //     int n, int d, float epsilon,
+    //     cudaStream_t stream)
+    fn add_rms_norm_half(
+        y: *mut f16,
+        stride_y: c_int,
+        x: *mut f16,
+        stride_x: c_int,
+        a: *const f16,
+        stride_a: c_int,
+        w: *const f16,
+        n: c_int,
+        d: c_int,
+        epsilon: f32,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError add_rms_norm_bf16(
+    //     nv_bfloat16 *y, int stride_y,
+    //     nv_bfloat16 *x, int stride_x,
+    //     nv_bfloat16 const *a, int stride_a,
+    //     nv_bfloat16 const *w,
+    //     int n, int d, float epsilon,
+    //     cudaStream_t stream)
+    fn add_rms_norm_bf16(
+        y: *mut bf16,
+        stride_y: c_int,
+        x: *mut bf16,
+        stride_x: c_int,
+        a: *const bf16,
+        stride_a: c_int,
+        w: *const bf16,
+        n: c_int,
+        d: c_int,
+        epsilon: f32,
+        stream: CUstream,
+    ) -> c_int;
+}
+
+pub fn geglu<T, U>(gate: &mut Tensor<T>, up: &Tensor<U>, stream: &Stream)
+where
+    T: DerefMut<Target = [DevByte]>,
+    U: Deref<Target = [DevByte]>,
+{
+    binary(geglu_half, geglu_bf16, gate, up, stream);
+}
+
+pub fn add<T, U>(c: &mut Tensor<T>, a: &Tensor<U>, stream: &Stream)
+where
+    T: DerefMut<Target = [DevByte]>,
+    U: Deref<Target = [DevByte]>,
+{
+    binary(add_half, add_bf16, c, a, stream);
+}
+
+pub fn softcap<T>(x: &mut Tensor<T>, cap: f32, stream: &Stream)
+where
+    T: DerefMut<Target = [DevByte]>,
+{
+    let &[n, d] = x.shape() else { panic!() };
+    assert_eq!(x.strides()[1], 1);
+
+    let sx = x.strides()[0] as c_int;
+    let px = unsafe { x.physical_mut().as_mut_ptr().offset(x.bytes_offset()) };
+    launch!(x.data_layout(); softcap_half | softcap_bf16(
+        px.cast(),
+        sx,
+        n as _,
+        d as _,
+        cap,
+        stream.as_raw(),
+    ));
+}
+
+pub fn add_rms_norm<T, U, V, W>(
+    y: &mut Tensor<T>,
+    x: &mut Tensor<U>,
+    a: &Tensor<V>,
+    w: &Tensor<W>,
+    epsilon: f32,
+    stream: &Stream,
+) where
+    T: DerefMut<Target = [DevByte]>,
+    U: DerefMut<Target = [DevByte]>,
+    V: Deref<Target = [DevByte]>,
+    W: Deref<Target = [DevByte]>,
+{
+    let &[n, d] = x.shape() else { panic!() };
+    assert_eq!(y.shape(), &[n, d]);
+    assert_eq!(a.shape(), &[n, d]);
+    assert_eq!(w.shape(), &[d]);
+    let dt = x.data_layout();
+    for t in [y.data_layout(), a.data_layout(), w.data_layout()] {
+        assert_eq!(t, dt);
+    }
+    assert_eq!(y.strides()[1], 1);
+    assert_eq!(x.strides()[1], 1);
+    assert_eq!(a.strides()[1], 1);
+    assert!(w.is_contiguous());
+
+    let sy = y.strides()[0] as c_int;
+    let sx = x.strides()[0] as c_int;
+    let sa = a.strides()[0] as c_int;
+    let py = unsafe { y.physical_mut().as_mut_ptr().offset(y.bytes_offset()) };
+    let px = unsafe { x.physical_mut().as_mut_ptr().offset(x.bytes_offset()) };
+    let pa = unsafe { a.physical().as_ptr().offset(a.bytes_offset()) };
+    let pw = unsafe { w.physical().as_ptr().offset(w.bytes_offset()) };
+    launch!(dt; add_rms_norm_half | add_rms_norm_bf16(
+        py.cast(),
+        sy,
+        px.cast(),
+        sx,
+        pa.cast(),
+        sa,
+        pw.cast(),
+        n as _,
+        d as _,
+        epsilon,
+        stream.as_raw(),
+    ));
+}
+
+type Binary<E> =
+    unsafe extern "C" fn(*mut E, c_int, *const E, c_int, c_int, c_int, CUstream) -> c_int;
+
+fn binary<T, U>(
+    f_half: Binary<f16>,
+    f_bf16: Binary<bf16>,
+    y: &mut Tensor<T>,
+    x: &Tensor<U>,
+    stream: &Stream,
+) where
+    T: DerefMut<Target = [DevByte]>,
+    U: Deref<Target = [DevByte]>,
+{
+    let &[n, d] = y.shape() else { panic!() };
+    assert_eq!(x.shape(), &[n, d]);
+    assert_eq!(y.data_layout(), x.data_layout());
+    assert_eq!(y.strides()[1], 1);
+    assert_eq!(x.strides()[1], 1);
+
+    let sy = y.strides()[0] as c_int;
+    let sx = x.strides()[0] as c_int;
+    let py = unsafe { y.physical_mut().as_mut_ptr().offset(y.bytes_offset()) };
+    let px = unsafe { x.physical().as_ptr().offset(x.bytes_offset()) };
+    launch!(y.data_layout(); f_half | f_bf16(
+        py.cast(),
+        sy,
+        px.cast(),
+        sx,
+        n as _,
+        d as _,
+        stream.as_raw(),
+    ));
+}
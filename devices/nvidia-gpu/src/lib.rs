@@ -1,25 +1,51 @@
 ﻿#![cfg(detected_cuda)]
 
+/// 按数据类型调用 F16 或 BF16 版本的计算核，并检查返回值。
+macro_rules! launch {
+    ($dt:expr; $half:ident | $bf16:ident($($arg:expr),* $(,)?)) => {
+        assert_eq!(0, match $dt {
+            digit_layout::types::F16 => unsafe { $half($($arg),*) },
+            digit_layout::types::BF16 => unsafe { $bf16($($arg),*) },
+            dt => panic!("unsupported data layout: {dt:?}"),
+        })
+    };
+}
+
+mod attention;
+mod autotune;
+mod elementwise;
 mod gather;
+mod pinned;
+mod profile;
+mod rotary;
 mod sample;
 
-use common::utok;
-use common_devices::{mat_mul, reform, rms_norm, rope, softmax, swiglu, SliceOn};
+use autotune::{bench, CANDIDATES};
+use common::{utok, FileLoadError};
+use common_devices::{mat_mul, reform, rms_norm, rope, softmax, swiglu, QkvCache, SliceOn};
 use cuda::{ContextGuard, ContextSpore, Device};
-use digit_layout::types::F16;
+use digit_layout::{
+    types::{BF16, F16},
+    DigitLayout,
+};
 use operators::{
     fuesd_softmax::nvidia_gpu as softmax, mat_mul::nvidia_gpu as mat_mul,
     reform::nvidia_gpu as reform, rms_norm::nvidia_gpu as rms_norm, rope::nvidia_gpu as rope,
     swiglu::nvidia_gpu as swiglu, Operator, QueueOf,
 };
+use profile::Profiler;
 use std::{
     marker::PhantomData,
     ops::{Deref, DerefMut},
+    time::Duration,
 };
 
-pub use common_devices::Kernels;
+pub use autotune::{TuneCache, TuneShapes};
+pub use common_devices::{AttentionArgs, Kernels};
 pub use operators::nvidia_gpu::{cuda, Device as Gpu};
-pub use sample::{sample_cpu, sample_nv};
+pub use pinned::PinnedPool;
+pub use profile::OpTiming;
+pub use sample::{hidden_cpu, sample_cpu, sample_nv, top_logprobs_cpu};
 pub use tensor::{reslice, reslice_mut, slice, split, udim, LocalSplitable, Tensor};
 
 pub struct NvidiaKernels {
@@ -29,52 +55,236 @@ pub struct NvidiaKernels {
     reform: reform::Operator,
     softmax: softmax::Operator,
     swiglu: swiglu::Operator,
+    profiler: Profiler,
+}
+
+/// 覆盖算子默认的每块最大线程数，不超过设备的上限。
+#[derive(Clone, Copy, Default)]
+struct Blocks {
+    rms_norm: Option<usize>,
+    softmax: Option<usize>,
+}
+
+/// 创建 [`NvidiaKernels`] 可能产生的错误。
+#[derive(Debug)]
+pub enum KernelsError {
+    /// 没有给出设备。
+    NoDevice,
+    /// 计算核不支持的数据类型。
+    UnsupportedDataLayout(DigitLayout),
+    /// 算子不支持给定的设备或配置，如计算能力不足。
+    Operator { name: &'static str, message: String },
+}
+
+/// 在英伟达显卡上加载模型可能产生的错误。
+///
+/// 只覆盖加载阶段，推理过程中显存分配失败等错误仍然直接 panic。
+#[derive(Debug)]
+pub enum LoadError {
+    /// 读取模型文件失败。
+    File(FileLoadError),
+    /// 创建计算核失败。
+    Kernels(KernelsError),
+    /// 读取算子调优结果失败。
+    TuneCache(std::io::Error),
+    /// 模型的结构不受支持。
+    Unsupported(String),
+}
+
+impl From<FileLoadError> for LoadError {
+    #[inline]
+    fn from(e: FileLoadError) -> Self {
+        Self::File(e)
+    }
+}
+
+impl From<KernelsError> for LoadError {
+    #[inline]
+    fn from(e: KernelsError) -> Self {
+        Self::Kernels(e)
+    }
 }
 
 impl NvidiaKernels {
-    pub fn new(devices: &[Device], rms_norm_max_size: usize, softmax_max_size: usize) -> Self {
-        let max_num_threads_block = devices.iter().map(|d| d.max_block_dims().0).min().unwrap();
+    /// 为 `dt` 类型的模型创建计算核，支持 F16 和 BF16，BF16 需要 Ampere 及以上架构。
+    #[inline]
+    pub fn new(
+        devices: &[Device],
+        dt: DigitLayout,
+        rms_norm_max_size: usize,
+        softmax_max_size: usize,
+    ) -> Result<Self, KernelsError> {
+        Self::with_blocks(
+            devices,
+            dt,
+            rms_norm_max_size,
+            softmax_max_size,
+            Default::default(),
+        )
+    }
+
+    /// 与 [`new`](Self::new) 相同，但 rms_norm 和 softmax 的每块最大线程数按 `shapes` 在 `devices[0]` 上测试选出。
+    ///
+    /// 调优结果记录在 `cache` 中，之后以相同的显卡和形状创建时直接复用。
+    pub fn tuned(
+        devices: &[Device],
+        dt: DigitLayout,
+        rms_norm_max_size: usize,
+        softmax_max_size: usize,
+        shapes: TuneShapes,
+        cache: &mut TuneCache,
+    ) -> Result<Self, KernelsError> {
+        let device = devices.first().ok_or(KernelsError::NoDevice)?;
+        let gpu = device.name();
+        let max_num_threads_block = device.max_block_dims().0;
+        let candidates = CANDIDATES
+            .into_iter()
+            .filter(|&block| block <= max_num_threads_block);
+        let build =
+            |blocks| Self::with_blocks(devices, dt, rms_norm_max_size, softmax_max_size, blocks);
+
+        let mut blocks = Blocks::default();
+        device.retain_primary().apply(|ctx| {
+            let stream = ctx.stream();
+            let fastest = |bench_block: &mut dyn FnMut(usize) -> Option<Duration>| {
+                candidates
+                    .clone()
+                    .filter_map(|block| bench_block(block).map(|time| (block, time)))
+                    .min_by_key(|&(_, time)| time)
+                    .map_or(max_num_threads_block, |(block, _)| block)
+            };
+
+            let [nt, d] = shapes.rms_norm;
+            let key = format!("{gpu}/rms_norm/{dt:?}/{nt}x{d}");
+            blocks.rms_norm = Some(cache.get_or_tune(key, || {
+                let mut y = Tensor::alloc(dt, &[nt, d], |len| stream.malloc::<u8>(len));
+                let x = Tensor::alloc(dt, &[nt, d], |len| stream.malloc::<u8>(len));
+                let w = Tensor::alloc(dt, &[d], |len| stream.malloc::<u8>(len));
+                fastest(&mut |block| {
+                    let kernels = build(Blocks {
+                        rms_norm: Some(block),
+                        softmax: None,
+                    })
+                    .ok()?;
+                    Some(bench(&stream, || {
+                        kernels.rms_norm(&mut y, &x, &w, 1e-5, &stream)
+                    }))
+                })
+            }));
+
+            let [nh, seq, att] = shapes.softmax;
+            let key = format!("{gpu}/softmax/{dt:?}/{nh}x{seq}x{att}");
+            blocks.softmax = Some(cache.get_or_tune(key, || {
+                let mut a = Tensor::alloc(dt, &[nh, seq, att], |len| stream.malloc::<u8>(len));
+                fastest(&mut |block| {
+                    let kernels = build(Blocks {
+                        rms_norm: None,
+                        softmax: Some(block),
+                    })
+                    .ok()?;
+                    Some(bench(&stream, || kernels.softmax(&mut a, &stream)))
+                })
+            }));
+        });
+        build(blocks)
+    }
+
+    fn with_blocks(
+        devices: &[Device],
+        dt: DigitLayout,
+        rms_norm_max_size: usize,
+        softmax_max_size: usize,
+        blocks: Blocks,
+    ) -> Result<Self, KernelsError> {
+        if !matches!(dt, F16 | BF16) {
+            return Err(KernelsError::UnsupportedDataLayout(dt));
+        }
+        let max_num_threads_block = devices
+            .iter()
+            .map(|d| d.max_block_dims().0)
+            .min()
+            .ok_or(KernelsError::NoDevice)?;
         let compute_capability = devices
             .iter()
             .map(Device::compute_capability)
             .min()
-            .unwrap();
-        Self {
-            mat_mul: mat_mul::Operator::new(&F16).unwrap(),
-            rms_norm: rms_norm::Operator::new(&rms_norm::Config {
-                data_layout: F16,
-                num_items_reduce: rms_norm_max_size,
-                num_threads_warp: 32,
-                max_num_threads_block,
-                compute_capability,
-            })
-            .unwrap(),
-            rope: rope::Operator::new(&rope::Config {
-                data_layout: F16,
-                max_num_threads_block,
-                compute_capability,
-            })
-            .unwrap(),
-            reform: reform::Operator::new(&reform::Config {
-                num_threads_warp: 32,
-                max_num_threads_block,
-                compute_capability,
-            })
-            .unwrap(),
-            softmax: softmax::Operator::new(&softmax::Config {
-                data_layout: F16,
-                max_seq_len: softmax_max_size,
-                max_num_threads_block,
-                compute_capability,
-            })
-            .unwrap(),
-            swiglu: swiglu::Operator::new(&swiglu::Config {
-                data_layout: F16,
-                max_num_threads_block,
-                compute_capability,
-            })
-            .unwrap(),
+            .ok_or(KernelsError::NoDevice)?;
+        macro_rules! op {
+            ($name:ident, $config:expr) => {
+                $name::Operator::new(&$config).map_err(|e| KernelsError::Operator {
+                    name: stringify!($name),
+                    message: format!("{e:?}"),
+                })?
+            };
         }
+        Ok(Self {
+            mat_mul: op!(mat_mul, dt),
+            rms_norm: op!(
+                rms_norm,
+                rms_norm::Config {
+                    data_layout: dt,
+                    num_items_reduce: rms_norm_max_size,
+                    num_threads_warp: 32,
+                    max_num_threads_block: blocks
+                        .rms_norm
+                        .map_or(max_num_threads_block, |b| { b.min(max_num_threads_block) }),
+                    compute_capability,
+                }
+            ),
+            rope: op!(
+                rope,
+                rope::Config {
+                    data_layout: dt,
+                    max_num_threads_block,
+                    compute_capability,
+                }
+            ),
+            reform: op!(
+                reform,
+                reform::Config {
+                    num_threads_warp: 32,
+                    max_num_threads_block,
+                    compute_capability,
+                }
+            ),
+            softmax: op!(
+                softmax,
+                softmax::Config {
+                    data_layout: dt,
+                    max_seq_len: softmax_max_size,
+                    max_num_threads_block: blocks
+                        .softmax
+                        .map_or(max_num_threads_block, |b| { b.min(max_num_threads_block) }),
+                    compute_capability,
+                }
+            ),
+            swiglu: op!(
+                swiglu,
+                swiglu::Config {
+                    data_layout: dt,
+                    max_num_threads_block,
+                    compute_capability,
+                }
+            ),
+            profiler: Default::default(),
+        })
+    }
+
+    /// 开启或关闭算子性能采集。
+    ///
+    /// 开启后每个算子调用都包在同名的 NVTX 范围中，Nsight 中可以看到每个算子的名字，
+    /// 并以 CUDA 事件测量算子在设备上的耗时，通过 [`take_op_timings`](Self::take_op_timings) 取出。
+    #[inline]
+    pub fn set_profiling(&self, enabled: bool) {
+        self.profiler.set_enabled(enabled);
+    }
+
+    /// 取出并清空采集到的算子耗时，按算子汇总。
+    ///
+    /// 会等待所有被记录的算子完成，被记录的流必须属于 `ctx`。
+    #[inline]
+    pub fn take_op_timings(&self, ctx: &ContextGuard) -> Vec<OpTiming> {
+        self.profiler.take(ctx)
     }
 }
 
@@ -92,7 +302,8 @@ impl Kernels for NvidiaKernels {
         U: Deref<Target = [u8]>,
         I: IntoIterator<Item = utok>,
     {
-        gather::gather(x, table, tokens, queue);
+        self.profiler
+            .scope(c"gather", queue, || gather::gather(x, table, tokens, queue));
     }
 
     fn rms_norm<T, U, V>(
@@ -107,15 +318,17 @@ impl Kernels for NvidiaKernels {
         U: Deref<Target = SliceOn<Self::Device>>,
         V: Deref<Target = SliceOn<Self::Device>>,
     {
-        rms_norm(
-            PhantomData::<rms_norm::Scheme>,
-            &self.rms_norm,
-            y,
-            x,
-            w,
-            epsilon,
-            queue,
-        );
+        self.profiler.scope(c"rms_norm", queue, || {
+            rms_norm(
+                PhantomData::<rms_norm::Scheme>,
+                &self.rms_norm,
+                y,
+                x,
+                w,
+                epsilon,
+                queue,
+            )
+        });
     }
 
     fn rope<T, U>(
@@ -128,14 +341,45 @@ impl Kernels for NvidiaKernels {
         T: DerefMut<Target = SliceOn<Self::Device>>,
         U: Deref<Target = SliceOn<Self::Device>>,
     {
-        rope(
-            PhantomData::<rope::Scheme>,
-            &self.rope,
-            t,
-            pos,
-            theta,
-            queue,
-        );
+        self.profiler.scope(c"rope", queue, || {
+            rope(
+                PhantomData::<rope::Scheme>,
+                &self.rope,
+                t,
+                pos,
+                theta,
+                queue,
+            )
+        });
+    }
+
+    fn rotary<T, U>(&self, t: &mut Tensor<T>, sin_cos: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler
+            .scope(c"rotary", queue, || rotary::rotary(t, sin_cos, queue));
+    }
+
+    fn rope_qkv<Q, K, V, KC, VC, P>(
+        &self,
+        qkv: QkvCache<Q, K, V, KC, VC>,
+        pos: &Tensor<P>,
+        dr: udim,
+        theta: f32,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        Q: DerefMut<Target = SliceOn<Self::Device>>,
+        K: DerefMut<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        KC: DerefMut<Target = SliceOn<Self::Device>>,
+        VC: DerefMut<Target = SliceOn<Self::Device>>,
+        P: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler.scope(c"rope_qkv", queue, || {
+            rotary::rope_qkv(qkv, pos, dr, theta, queue)
+        });
     }
 
     fn mat_mul<T, U, V>(
@@ -151,16 +395,18 @@ impl Kernels for NvidiaKernels {
         U: Deref<Target = SliceOn<Self::Device>>,
         V: Deref<Target = SliceOn<Self::Device>>,
     {
-        mat_mul(
-            PhantomData::<mat_mul::Scheme>,
-            &self.mat_mul,
-            c,
-            beta,
-            a,
-            b,
-            alpha,
-            queue,
-        );
+        self.profiler.scope(c"mat_mul", queue, || {
+            mat_mul(
+                PhantomData::<mat_mul::Scheme>,
+                &self.mat_mul,
+                c,
+                beta,
+                a,
+                b,
+                alpha,
+                queue,
+            )
+        });
     }
 
     fn reform<T, U>(&self, dst: &mut Tensor<T>, src: &Tensor<U>, queue: &QueueOf<Self::Device>)
@@ -168,14 +414,37 @@ impl Kernels for NvidiaKernels {
         T: DerefMut<Target = SliceOn<Self::Device>>,
         U: Deref<Target = SliceOn<Self::Device>>,
     {
-        reform(PhantomData::<reform::Scheme>, &self.reform, dst, src, queue);
+        self.profiler.scope(c"reform", queue, || {
+            reform(PhantomData::<reform::Scheme>, &self.reform, dst, src, queue)
+        });
     }
 
     fn softmax<T>(&self, att: &mut Tensor<T>, queue: &QueueOf<Self::Device>)
     where
         T: DerefMut<Target = SliceOn<Self::Device>>,
     {
-        softmax(PhantomData::<softmax::Scheme>, &self.softmax, att, queue);
+        self.profiler.scope(c"softmax", queue, || {
+            softmax(PhantomData::<softmax::Scheme>, &self.softmax, att, queue)
+        });
+    }
+
+    fn attention<T, U, V, W>(
+        &self,
+        o: &mut Tensor<T>,
+        q: &Tensor<U>,
+        k: &Tensor<V>,
+        v: &Tensor<W>,
+        args: AttentionArgs,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler.scope(c"attention", queue, || {
+            attention::attention(o, q, k, v, args, queue)
+        });
     }
 
     fn swiglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Device>)
@@ -183,7 +452,54 @@ impl Kernels for NvidiaKernels {
         T: DerefMut<Target = SliceOn<Self::Device>>,
         U: Deref<Target = SliceOn<Self::Device>>,
     {
-        swiglu(PhantomData::<swiglu::Scheme>, &self.swiglu, gate, up, queue);
+        self.profiler.scope(c"swiglu", queue, || {
+            swiglu(PhantomData::<swiglu::Scheme>, &self.swiglu, gate, up, queue)
+        });
+    }
+
+    fn geglu<T, U>(&self, gate: &mut Tensor<T>, up: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler
+            .scope(c"geglu", queue, || elementwise::geglu(gate, up, queue));
+    }
+
+    fn add<T, U>(&self, c: &mut Tensor<T>, a: &Tensor<U>, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler
+            .scope(c"add", queue, || elementwise::add(c, a, queue));
+    }
+
+    fn softcap<T>(&self, x: &mut Tensor<T>, cap: f32, queue: &QueueOf<Self::Device>)
+    where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler
+            .scope(c"softcap", queue, || elementwise::softcap(x, cap, queue));
+    }
+
+    fn add_rms_norm<T, U, V, W>(
+        &self,
+        y: &mut Tensor<T>,
+        x: &mut Tensor<U>,
+        a: &Tensor<V>,
+        w: &Tensor<W>,
+        epsilon: f32,
+        queue: &QueueOf<Self::Device>,
+    ) where
+        T: DerefMut<Target = SliceOn<Self::Device>>,
+        U: DerefMut<Target = SliceOn<Self::Device>>,
+        V: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>>,
+    {
+        self.profiler.scope(c"add_rms_norm", queue, || {
+            elementwise::add_rms_norm(y, x, a, w, epsilon, queue)
+        });
     }
 }
 
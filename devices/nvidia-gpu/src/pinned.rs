@@ -0,0 +1,87 @@
+use operators::nvidia_gpu::cuda::{
+    memcpy_d2h, ContextGuard, ContextResource, ContextSpore, DevByte, DevMem, Event, EventSpore,
+    HostMem, HostMemSpore, Stream,
+};
+use std::sync::Mutex;
+use tensor::{reslice, reslice_mut};
+
+/// 池中最多保留的缓冲区数量。
+const CAPACITY: usize = 8;
+
+/// 锁页内存池，用于中转每步都要跨 PCIe 传输的少量数据，如位置和采样参数、采样结果。
+///
+/// 从可分页内存复制时驱动要先复制到内部的锁页缓冲区，并且无法与计算重叠。
+/// 上传在流上异步进行，缓冲区连同复制完成的事件放回池中，再次取出前等待事件，避免覆盖未传完的数据。
+#[derive(Default)]
+pub struct PinnedPool(Mutex<Vec<(HostMemSpore, Option<EventSpore>)>>);
+
+impl PinnedPool {
+    /// 经锁页内存把 `data` 异步上传到设备。
+    pub fn upload<'ctx, T: Copy>(&self, data: &[T], stream: &Stream<'ctx>) -> DevMem<'ctx> {
+        let src = reslice::<T, u8>(data);
+        let len = src.len();
+        let ctx = stream.ctx();
+
+        let mut host = self.take(len, ctx);
+        host[..len].copy_from_slice(src);
+        let mut dev = stream.malloc::<u8>(len);
+        stream.memcpy_h2d(&mut dev, &host[..len]);
+        self.put(host, Some(stream.record()));
+        dev
+    }
+
+    /// 经锁页内存把设备上的 `src` 下载到 `dst`。
+    pub fn download<T: Copy>(&self, dst: &mut [T], src: &[DevByte], stream: &Stream) {
+        let dst = reslice_mut::<T, u8>(dst);
+        let len = dst.len();
+
+        let mut host = self.take(len, stream.ctx());
+        memcpy_d2h(&mut host[..len], src);
+        dst.copy_from_slice(&host[..len]);
+        self.put(host, None);
+    }
+
+    /// 释放池中所有缓冲区，必须在池所属的上下文上调用。
+    pub fn clear(&self, ctx: &ContextGuard) {
+        for (mem, event) in std::mem::take(&mut *self.0.lock().unwrap()) {
+            if let Some(event) = event {
+                event.sprout(ctx).synchronize();
+            }
+            drop(mem.sprout(ctx));
+        }
+    }
+
+    /// 取出能容纳 `len` 字节的最小缓冲区，没有则新分配。
+    fn take<'ctx>(&self, len: usize, ctx: &'ctx ContextGuard) -> HostMem<'ctx> {
+        let mut pool = self.0.lock().unwrap();
+        let best = pool
+            .iter()
+            .enumerate()
+            .filter(|(_, (mem, _))| mem.len() >= len)
+            .min_by_key(|(_, (mem, _))| mem.len())
+            .map(|(i, _)| i);
+        match best {
+            Some(i) => {
+                let (mem, event) = pool.swap_remove(i);
+                if let Some(event) = event {
+                    event.sprout(ctx).synchronize();
+                }
+                mem.sprout(ctx)
+            }
+            None => ctx.malloc_host::<u8>(len.next_power_of_two()),
+        }
+    }
+
+    fn put(&self, mem: HostMem, event: Option<Event>) {
+        let mut pool = self.0.lock().unwrap();
+        if pool.len() < CAPACITY {
+            pool.push((mem.sporulate(), event.map(|e| e.sporulate())));
+        } else {
+            // 释放前等待复制完成
+            if let Some(event) = event {
+                event.synchronize();
+            }
+            drop(mem);
+        }
+    }
+}
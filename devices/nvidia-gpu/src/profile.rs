@@ -0,0 +1,88 @@
+use operators::nvidia_gpu::cuda::{
+    ContextGuard, ContextResource, ContextSpore, EventSpore, Stream,
+};
+use std::{
+    ffi::{c_char, CStr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+extern "C" {
+    // extern "C" void nvtx_push(char const *name)
+    fn nvtx_push(name: *const c_char);
+
+    // extern "C" void nvtx_pop()
+    fn nvtx_pop();
+}
+
+/// 一种算子在采集期间的累计耗时。
+#[derive(Clone, Debug)]
+pub struct OpTiming {
+    /// 算子名字，与 NVTX 范围的名字相同。
+    pub name: &'static str,
+    /// 调用次数。
+    pub count: usize,
+    /// 以 CUDA 事件测量的设备上的总耗时。
+    pub total: Duration,
+}
+
+/// 算子性能采集，默认关闭。
+#[derive(Default)]
+pub(crate) struct Profiler {
+    enabled: AtomicBool,
+    records: Mutex<Vec<(&'static CStr, EventSpore, EventSpore)>>,
+}
+
+impl Profiler {
+    #[inline]
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// 在 `stream` 上以 `name` 为名调用 `f`。
+    pub fn scope<R>(&self, name: &'static CStr, stream: &Stream, f: impl FnOnce() -> R) -> R {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return f();
+        }
+        unsafe { nvtx_push(name.as_ptr()) };
+        let start = stream.record();
+        let ret = f();
+        let end = stream.record();
+        unsafe { nvtx_pop() };
+        self.records
+            .lock()
+            .unwrap()
+            .push((name, start.sporulate(), end.sporulate()));
+        ret
+    }
+
+    /// 取出已采集的记录，按算子汇总，顺序为算子首次调用的顺序。
+    ///
+    /// 记录的事件必须属于 `ctx`，会等待所有被记录的算子完成。
+    pub fn take(&self, ctx: &ContextGuard) -> Vec<OpTiming> {
+        let records = std::mem::take(&mut *self.records.lock().unwrap());
+        let mut timings = Vec::<OpTiming>::new();
+        for (name, start, end) in records {
+            let name = name.to_str().unwrap();
+            let start = start.sprout(ctx);
+            let end = end.sprout(ctx);
+            end.synchronize();
+            let time = end.elapse_from(&start);
+            match timings.iter_mut().find(|t| t.name == name) {
+                Some(t) => {
+                    t.count += 1;
+                    t.total += time;
+                }
+                None => timings.push(OpTiming {
+                    name,
+                    count: 1,
+                    total: time,
+                }),
+            }
+        }
+        timings
+    }
+}
@@ -0,0 +1,130 @@
+#include "dtype.cuh"
+
+template<class T2>
+static __global__ void rotary_kernel(
+    T2 *__restrict__ t, int stride_token, int stride_head,
+    float2 const *__restrict__ sin_cos,
+    int pairs) {
+    auto i = blockIdx.x, j = blockIdx.y, k = threadIdx.x;
+    auto cs = sin_cos[i * pairs + k];
+    auto p = t + i * stride_token + j * stride_head + k;
+    auto x = to_float2(*p);
+    *p = from_float2<T2>(x.x * cs.x - x.y * cs.y, x.x * cs.y + x.y * cs.x);
+}
+
+template<class T>
+static cudaError rotary(
+    T *t, int stride_token, int stride_head,
+    float const *sin_cos,
+    int nt, int nh, int dr,
+    cudaStream_t stream) {
+    using T2 = typename Pair<T>::Type;
+    // 每个线程处理一对相邻的维度
+    dim3 grid(nt, nh);
+    rotary_kernel<<<grid, dr / 2, 0, stream>>>(
+        reinterpret_cast<T2 *>(t), stride_token / 2, stride_head / 2,
+        reinterpret_cast<float2 const *>(sin_cos),
+        dr / 2);
+    return cudaGetLastError();
+}
+
+// 以 `pos` 和 `theta` 计算第 `k` 对维度的旋转，`k` 超出 `pairs` 的维度不旋转
+template<class T2>
+static __device__ T2 rope(T2 t, float pos, float theta, int k, int pairs) {
+    if (k >= pairs) {
+        return t;
+    }
+    float sin, cos;
+    sincosf(pos / powf(theta, float(k) / pairs), &sin, &cos);
+    auto x = to_float2(t);
+    return from_float2<T2>(x.x * cos - x.y * sin, x.x * sin + x.y * cos);
+}
+
+// 每个线程块处理一个 token 的一个头，前 nh 个块是 q，其余是 k、v
+template<class T2>
+static __global__ void rope_qkv_kernel(
+    T2 *__restrict__ q, int sq_h, int sq_t,
+    T2 const *__restrict__ k, int sk_h, int sk_t,
+    T2 const *__restrict__ v, int sv_h, int sv_t,
+    T2 *__restrict__ k_cache, int skc_h, int skc_t,
+    T2 *__restrict__ v_cache, int svc_h, int svc_t,
+    unsigned int const *__restrict__ pos,
+    int nh, int pairs, float theta) {
+    int i = blockIdx.x, h = blockIdx.y, k_ = threadIdx.x;
+    auto p = float(pos[i]);
+    if (h < nh) {
+        auto t = q + h * sq_h + i * sq_t + k_;
+        *t = rope(*t, p, theta, k_, pairs);
+    } else {
+        h -= nh;
+        k_cache[h * skc_h + i * skc_t + k_] = rope(k[h * sk_h + i * sk_t + k_], p, theta, k_, pairs);
+        v_cache[h * svc_h + i * svc_t + k_] = v[h * sv_h + i * sv_t + k_];
+    }
+}
+
+template<class T>
+static cudaError rope_qkv(
+    T *q, int sq_h, int sq_t,
+    T const *k, int sk_h, int sk_t,
+    T const *v, int sv_h, int sv_t,
+    T *k_cache, int skc_h, int skc_t,
+    T *v_cache, int svc_h, int svc_t,
+    unsigned int const *pos,
+    int nh, int nkvh, int seq, int dh, int dr, float theta,
+    cudaStream_t stream) {
+    using T2 = typename Pair<T>::Type;
+    // 每个线程处理一对相邻的维度，跨度以成对的分量为单位
+    dim3 grid(seq, nh + nkvh);
+    rope_qkv_kernel<<<grid, dh / 2, 0, stream>>>(
+        reinterpret_cast<T2 *>(q), sq_h / 2, sq_t / 2,
+        reinterpret_cast<T2 const *>(k), sk_h / 2, sk_t / 2,
+        reinterpret_cast<T2 const *>(v), sv_h / 2, sv_t / 2,
+        reinterpret_cast<T2 *>(k_cache), skc_h / 2, skc_t / 2,
+        reinterpret_cast<T2 *>(v_cache), svc_h / 2, svc_t / 2,
+        pos, nh, dr / 2, theta);
+    return cudaGetLastError();
+}
+
+extern "C" cudaError rotary_half(
+    half *t, int stride_token, int stride_head,
+    float const *sin_cos,
+    int nt, int nh, int dr,
+    cudaStream_t stream) {
+    return rotary(t, stride_token, stride_head, sin_cos, nt, nh, dr, stream);
+}
+
+extern "C" cudaError rotary_bf16(
+    nv_bfloat16 *t, int stride_token, int stride_head,
+    float const *sin_cos,
+    int nt, int nh, int dr,
+    cudaStream_t stream) {
+    return rotary(t, stride_token, stride_head, sin_cos, nt, nh, dr, stream);
+}
+
+extern "C" cudaError rope_qkv_half(
+    half *q, int sq_h, int sq_t,
+    half const *k, int sk_h, int sk_t,
+    half const *v, int sv_h, int sv_t,
+    half *k_cache, int skc_h, int skc_t,
+    half *v_cache, int svc_h, int svc_t,
+    unsigned int const *pos,
+    int nh, int nkvh, int seq, int dh, int dr, float theta,
+    cudaStream_t stream) {
+    return rope_qkv(q, sq_h, sq_t, k, sk_h, sk_t, v, sv_h, sv_t,
+                    k_cache, skc_h, skc_t, v_cache, svc_h, svc_t,
+                    pos, nh, nkvh, seq, dh, dr, theta, stream);
+}
+
+extern "C" cudaError rope_qkv_bf16(
+    nv_bfloat16 *q, int sq_h, int sq_t,
+    nv_bfloat16 const *k, int sk_h, int sk_t,
+    nv_bfloat16 const *v, int sv_h, int sv_t,
+    nv_bfloat16 *k_cache, int skc_h, int skc_t,
+    nv_bfloat16 *v_cache, int svc_h, int svc_t,
+    unsigned int const *pos,
+    int nh, int nkvh, int seq, int dh, int dr, float theta,
+    cudaStream_t stream) {
+    return rope_qkv(q, sq_h, sq_t, k, sk_h, sk_t, v, sv_h, sv_t,
+                    k_cache, skc_h, skc_t, v_cache, svc_h, svc_t,
+                    pos, nh, nkvh, seq, dh, dr, theta, stream);
+}
@@ -0,0 +1,233 @@
+use common::{bf16, f16};
+use common_devices::QkvCache;
+use digit_layout::types::{F32, U32};
+use operators::nvidia_gpu::cuda::{bindings::CUstream, AsRaw, DevByte, Stream};
+use std::{
+    ffi::c_int,
+    ops::{Deref, DerefMut},
+};
+use tensor::{idim, udim, Tensor};
+
+extern "C" {
+    // extern "C" cudaError rotary_half(
+    //     half *t, int stride_token, int stride_head,
+    //     float const *sin_cos,
+    //     int nt, int nh, int dr,
+    //     cudaStream_t stream)
+    fn rotary_half(
+        t: *mut f16,
+        stride_token: c_int,
+        stride_head: c_int,
+        sin_cos: *const f32,
+        nt: c_int,
+        nh: c_int,
+        dr: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError rotary_bf16(
+    //     nv_bfloat16 *t, int stride_token, int stride_head,
+    //     float const *sin_cos,
+    //     int nt, int nh, int dr,
+    //     cudaStream_t stream)
+    fn rotary_bf16(
+        t: *mut bf16,
+        stride_token: c_int,
+        stride_head: c_int,
+        sin_cos: *const f32,
+        nt: c_int,
+        nh: c_int,
+        dr: c_int,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError rope_qkv_half(
+    //     half *q, int sq_h, int sq_t,
+    //     half const *k, int sk_h, int sk_t,
+    //     half const *v, int sv_h, int sv_t,
+    //     half *k_cache, int skc_h, int skc_t,
+    //     half *v_cache, int svc_h, int svc_t,
+    //     unsigned int const *pos,
+    //     int nh, int nkvh, int seq, int dh, int dr, float theta,
+    //     cudaStream_t stream)
+    fn rope_qkv_half(
+        q: *mut f16,
+        sq_h: c_int,
+        sq_t: c_int,
+        k: *const f16,
+        sk_h: c_int,
+        sk_t: c_int,
+        v: *const f16,
+        sv_h: c_int,
+        sv_t: c_int,
+        k_cache: *mut f16,
+        skc_h: c_int,
+        skc_t: c_int,
+        v_cache: *mut f16,
+        svc_h: c_int,
+        svc_t: c_int,
+        pos: *const u32,
+        nh: c_int,
+        nkvh: c_int,
+        seq: c_int,
+        dh: c_int,
+        dr: c_int,
+        theta: f32,
+        stream: CUstream,
+    ) -> c_int;
+
+    // extern "C" cudaError rope_qkv_bf16(
+    //     nv_bfloat16 *q, int sq_h, int sq_t,
+    //     nv_bfloat16 const *k, int sk_h, int sk_t,
+    //     nv_bfloat16 const *v, int sv_h, int sv_t,
+    //     nv_bfloat16 *k_cache, int skc_h, int skc_t,
+    //     nv_bfloat16 *v_cache, int svc_h, int svc_t,
+    //     unsigned int const *pos,
+    //     int nh, int nkvh, int seq, int dh, int dr, float theta,
+    //     cudaStream_t stream)
+    fn rope_qkv_bf16(
+        q: *mut bf16,
+        sq_h: c_int,
+        sq_t: c_int,
+        k: *const bf16,
+        sk_h: c_int,
+        sk_t: c_int,
+        v: *const bf16,
+        sv_h: c_int,
+        sv_t: c_int,
+        k_cache: *mut bf16,
+        skc_h: c_int,
+        skc_t: c_int,
+        v_cache: *mut bf16,
+        svc_h: c_int,
+        svc_t: c_int,
+        pos: *const u32,
+        nh: c_int,
+        nkvh: c_int,
+        seq: c_int,
+        dh: c_int,
+        dr: c_int,
+        theta: f32,
+        stream: CUstream,
+    ) -> c_int;
+}
+
+pub fn rotary<T, U>(t: &mut Tensor<T>, sin_cos: &Tensor<U>, stream: &Stream)
+where
+    T: DerefMut<Target = [DevByte]>,
+    U: Deref<Target = [DevByte]>,
+{
+    let &[nt, nh, dr] = t.shape() else { panic!() };
+    assert_eq!(sin_cos.shape(), &[nt, dr / 2, 2]);
+    assert_eq!(sin_cos.data_layout(), F32);
+    assert_eq!(t.strides()[2], 1);
+    assert!(sin_cos.is_contiguous());
+    // 成对访问，行跨度必须是偶数
+    let &[st, sh, _] = t.strides() else { panic!() };
+    assert!(st % 2 == 0 && sh % 2 == 0 && dr % 2 == 0);
+
+    let pt = unsafe { t.physical_mut().as_mut_ptr().offset(t.bytes_offset()) };
+    let ps = unsafe { sin_cos.physical().as_ptr().offset(sin_cos.bytes_offset()) };
+    launch!(t.data_layout(); rotary_half | rotary_bf16(
+        pt.cast(),
+        st as _,
+        sh as _,
+        ps.cast(),
+        nt as _,
+        nh as _,
+        dr as _,
+        stream.as_raw(),
+    ));
+}
+
+pub fn rope_qkv<Q, K, V, KC, VC, P>(
+    qkv: QkvCache<Q, K, V, KC, VC>,
+    pos: &Tensor<P>,
+    dr: udim,
+    theta: f32,
+    stream: &Stream,
+) where
+    Q: DerefMut<Target = [DevByte]>,
+    K: DerefMut<Target = [DevByte]>,
+    V: Deref<Target = [DevByte]>,
+    KC: DerefMut<Target = [DevByte]>,
+    VC: DerefMut<Target = [DevByte]>,
+    P: Deref<Target = [DevByte]>,
+{
+    let QkvCache {
+        q,
+        k,
+        v,
+        k_cache,
+        v_cache,
+    } = qkv;
+    let &[nh, seq, dh] = q.shape() else { panic!() };
+    let &[nkvh, _, _] = k.shape() else { panic!() };
+    for t in [k.shape(), v.shape(), k_cache.shape(), v_cache.shape()] {
+        assert_eq!(t, &[nkvh, seq, dh]);
+    }
+    assert_eq!(pos.shape(), &[seq]);
+    assert_eq!(pos.data_layout(), U32);
+    assert!(dr <= dh && dr % 2 == 0 && dh % 2 == 0);
+    // 成对访问，跨度必须是偶数
+    let strides = |t: &[idim]| {
+        assert!(t[0] % 2 == 0 && t[1] % 2 == 0 && t[2] == 1);
+        (t[0] as c_int, t[1] as c_int)
+    };
+    let (sq_h, sq_t) = strides(q.strides());
+    let (sk_h, sk_t) = strides(k.strides());
+    let (sv_h, sv_t) = strides(v.strides());
+    let (skc_h, skc_t) = strides(k_cache.strides());
+    let (svc_h, svc_t) = strides(v_cache.strides());
+    let dt = q.data_layout();
+    for t in [
+        k.data_layout(),
+        v.data_layout(),
+        k_cache.data_layout(),
+        v_cache.data_layout(),
+    ] {
+        assert_eq!(t, dt);
+    }
+
+    let pq = unsafe { q.physical_mut().as_mut_ptr().offset(q.bytes_offset()) };
+    let pk = unsafe { k.physical().as_ptr().offset(k.bytes_offset()) };
+    let pv = unsafe { v.physical().as_ptr().offset(v.bytes_offset()) };
+    let pkc = unsafe {
+        k_cache
+            .physical_mut()
+            .as_mut_ptr()
+            .offset(k_cache.bytes_offset())
+    };
+    let pvc = unsafe {
+        v_cache
+            .physical_mut()
+            .as_mut_ptr()
+            .offset(v_cache.bytes_offset())
+    };
+    let pp = unsafe { pos.physical().as_ptr().offset(pos.bytes_offset()) };
+    launch!(dt; rope_qkv_half | rope_qkv_bf16(
+        pq.cast(),
+        sq_h,
+        sq_t,
+        pk.cast(),
+        sk_h,
+        sk_t,
+        pv.cast(),
+        sv_h,
+        sv_t,
+        pkc.cast(),
+        skc_h,
+        skc_t,
+        pvc.cast(),
+        svc_h,
+        svc_t,
+        pp.cast(),
+        nh as _,
+        nkvh as _,
+        seq as _,
+        dh as _,
+        dr as _,
+        theta,
+        stream.as_raw(),
+    ));
+}
@@ -1,119 +1,193 @@
-﻿#include <cub/device/device_radix_sort.cuh>
-#include <cub/device/device_reduce.cuh>
-#include <cub/device/device_scan.cuh>
-#include <cuda_fp16.h>
-
-extern "C" cudaError argmax_half(
-    void *temp_storage, size_t *temp_storage_bytes,
-    half const *input, int num_items,
-    cub::KeyValuePair<int, half> *output,
-    cudaStream_t stream) {
-    return cub::DeviceReduce::ArgMax(
-        temp_storage, *temp_storage_bytes,
-        input,
-        output,
-        num_items,
-        stream);
-}
+﻿#include "dtype.cuh"
+#include <cub/block/block_scan.cuh>
 
-extern "C" cudaError radix_sort_half(
-    void *temp_storage, size_t *temp_storage_bytes,
-    half const *key_in, half *key_out,
-    unsigned int const *value_in, unsigned int *value_out,
-    int num_items,
-    cudaStream_t stream) {
-    return cub::DeviceRadixSort::SortPairsDescending(
-        temp_storage, *temp_storage_bytes,
-        key_in,
-        key_out,
-        value_in,
-        value_out,
-        num_items,
-        0,
-        sizeof(half) * 8,
-        stream);
-}
+constexpr int BLOCK = 1024;
 
-extern "C" cudaError inclusive_sum_half(
-    void *temp_storage, size_t *temp_storage_bytes,
-    half *data, int num_items,
-    cudaStream_t stream) {
-    return cub::DeviceScan::InclusiveSum(
-        temp_storage, *temp_storage_bytes,
-        data,
-        data,
-        num_items,
-        stream);
-}
+// 每行的采样参数，与 Rust 侧的定义一致
+struct Params {
+    int row;
+    float temperature;
+    int topk;
+    float topp;
+    float random;
+};
 
-#define RUNTIME(statement)                                                                      \
-    {                                                                                           \
-        auto error = statement;                                                                 \
-        if (error != cudaSuccess) {                                                             \
-            printf("Error: %s (%d) at \"%s\"\n", cudaGetErrorString(error), error, #statement); \
-            return error;                                                                       \
-        }                                                                                       \
-    }
+struct Sum {
+    template<class T>
+    __device__ T operator()(T a, T b) const { return a + b; }
+};
+
+struct Max {
+    template<class T>
+    __device__ T operator()(T a, T b) const { return a > b ? a : b; }
+};
 
-static __global__ void partial_softmax_half_kernel(
-    half2 *__restrict__ data,
-    float temperature,
-    int n) {
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
-    if (0 < i && i < n) {
-        auto max = __ldg((half *) data);
-        data[i] = h2exp((data[i] - half2(max, max)) / half2(temperature, temperature));
+struct Min {
+    template<class T>
+    __device__ T operator()(T a, T b) const { return a < b ? a : b; }
+};
+
+// 线程块归约，所有线程都得到结果
+template<class T, class Op>
+static __device__ T block_reduce(T x, Op op) {
+    __shared__ T shared[BLOCK / 32];
+    for (int mask = 16; mask > 0; mask /= 2) {
+        x = op(x, __shfl_xor_sync(0xffffffff, x, mask));
+    }
+    // 等待上一次归约的结果读完再覆盖
+    __syncthreads();
+    if (threadIdx.x % 32 == 0) {
+        shared[threadIdx.x / 32] = x;
     }
+    __syncthreads();
+    x = shared[0];
+    for (int i = 1; i < BLOCK / 32; ++i) {
+        x = op(x, shared[i]);
+    }
+    return x;
 }
 
-static __global__ void set_softmax_max_kernel(
-    half *__restrict__ data, float temperature) {
-    data[1] = hexp((data[1] - data[0]) / (half) temperature);
-    data[0] = 1;
-}
+// 融合的随机采样：每个线程块采样一行，温度缩放、top-k、top-p 和按概率抽取都在线程块内完成。
+// top-k 和 top-p 都通过二分查找 logit 阈值实现，不需要对词表排序；
+// 抽取按词表顺序进行，只有被选中的下标写回。
+template<class T>
+static __global__ void sample_kernel(
+    T const *__restrict__ logits, int voc,
+    Params const *__restrict__ params,
+    unsigned int *__restrict__ indices) {
+    auto const param = params[blockIdx.x];
+    auto temperature = param.temperature, topp = param.topp, random = param.random;
+    auto topk = param.topk;
+    logits += (size_t) param.row * voc;
+    auto index = indices + blockIdx.x;
+    // 每个线程负责连续的一段词，抽取时按段求前缀和
+    int chunk = (voc + BLOCK - 1) / BLOCK;
+    int begin = min(voc, (int) threadIdx.x * chunk), end = min(voc, begin + chunk);
+    auto x = [&](int i) { return to_float(logits[i]); };
 
-extern "C" cudaError partial_softmax_half(
-    half *data,
-    float temperature,
-    int voc,
-    cudaStream_t stream) {
+    auto x_max = -INFINITY, x_min = INFINITY;
+    for (int i = begin; i < end; ++i) {
+        x_max = fmaxf(x_max, x(i));
+        x_min = fminf(x_min, x(i));
+    }
+    x_max = block_reduce(x_max, Max{});
+    x_min = block_reduce(x_min, Min{});
+    // 最大值一定在候选集中，作为舍入误差导致抽取落空时的结果
+    int best = voc;
+    for (int i = begin; i < end; ++i) {
+        if (x(i) == x_max) {
+            best = i;
+            break;
+        }
+    }
+    best = block_reduce(best, Min{});
+    // 贪心采样只需要最大值
+    if (topk <= 1) {
+        if (threadIdx.x == 0) {
+            *index = best;
+        }
+        return;
+    }
 
-    voc /= 2;
-    auto block = min(1024, voc);
-    auto grid = (voc + block - 1) / block;
-    partial_softmax_half_kernel<<<grid, block, 0, stream>>>((half2 *) data, temperature, voc);
-    set_softmax_max_kernel<<<1, 1, 0, stream>>>(data, temperature);
+    // 概率低于 e^-80 的词对抽取没有影响，截断下界避免负无穷参与二分
+    auto lo = fmaxf(x_min, x_max - 80 * temperature);
+    auto p = [&](int i) {
+        auto v = x(i);
+        return v >= lo ? __expf((v - x_max) / temperature) : 0.f;
+    };
 
-    return cudaGetLastError();
-}
+    // top-k：查找使不小于阈值的词数不少于 topk 的最大阈值
+    if (topk < voc) {
+        auto lo_ = lo, hi = x_max;
+        for (int iter = 0; iter < 32; ++iter) {
+            auto mid = (lo_ + hi) / 2;
+            int count = 0;
+            for (int i = begin; i < end; ++i) {
+                count += x(i) >= mid;
+            }
+            if (block_reduce(count, Sum{}) >= topk) {
+                lo_ = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo = lo_;
+    }
+
+    // top-p：在 top-k 的候选中查找使不小于阈值的概率之和不少于 topp 的最大阈值
+    if (topp < 1) {
+        float total = 0;
+        for (int i = begin; i < end; ++i) {
+            total += p(i);
+        }
+        total = block_reduce(total, Sum{});
+
+        auto lo_ = lo, hi = x_max;
+        for (int iter = 0; iter < 32; ++iter) {
+            auto mid = (lo_ + hi) / 2;
+            float mass = 0;
+            for (int i = begin; i < end; ++i) {
+                if (x(i) >= mid) {
+                    mass += p(i);
+                }
+            }
+            if (block_reduce(mass, Sum{}) >= topp * total) {
+                lo_ = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo = lo_;
+    }
+
+    // 按概率抽取
+    float part = 0;
+    for (int i = begin; i < end; ++i) {
+        part += p(i);
+    }
+    using Scan = cub::BlockScan<float, BLOCK>;
+    __shared__ typename Scan::TempStorage temp;
+    float prefix, total;
+    Scan(temp).ExclusiveSum(part, prefix, total);
 
-static __global__ void random_sample_kernel(
-    half const *__restrict__ data,
-    unsigned int const *__restrict__ indices,
-    unsigned int *__restrict__ index_,
-    float random, float topp, int topk, int voc) {
-    half p = random * min(topp * (float) data[voc - 1], (float) data[topk - 1]);
-    for (int i = 0;; ++i) {
-        if (data[i] >= p) {
-            *index_ = indices[i];
-            return;
+    if (threadIdx.x == 0) {
+        *index = best;
+    }
+    __syncthreads();
+    auto target = random * total;
+    if (prefix <= target && target < prefix + part) {
+        for (int i = begin; i < end; ++i) {
+            prefix += p(i);
+            if (prefix > target) {
+                *index = i;
+                break;
+            }
         }
     }
 }
 
-extern "C" cudaError random_sample_half(
-    half const *data,
-    unsigned int const *indices,
-    unsigned int *index,
-    float random, float topp, int topk, int voc,
+template<class T>
+static cudaError sample(
+    T const *logits, int voc,
+    Params const *params,
+    unsigned int *indices, int n,
     cudaStream_t stream) {
-    unsigned int *index_ = nullptr;
-    cudaMallocAsync(&index_, sizeof(unsigned int), stream);
-
-    random_sample_kernel<<<1, 1, 0, stream>>>(data, indices, index_, random, topp, topk, voc);
+    sample_kernel<<<n, BLOCK, 0, stream>>>(logits, voc, params, indices);
+    return cudaGetLastError();
+}
 
-    cudaMemcpy(index, index_, sizeof(unsigned int), cudaMemcpyDeviceToHost);
-    cudaFree(index_);
+extern "C" cudaError sample_half(
+    half const *logits, int voc,
+    Params const *params,
+    unsigned int *indices, int n,
+    cudaStream_t stream) {
+    return sample(logits, voc, params, indices, n, stream);
+}
 
-    return cudaGetLastError();
+extern "C" cudaError sample_bf16(
+    nv_bfloat16 const *logits, int voc,
+    Params const *params,
+    unsigned int *indices, int n,
+    cudaStream_t stream) {
+    return sample(logits, voc, params, indices, n, stream);
 }
@@ -1,283 +1,179 @@
-﻿use common::{f16, utok, Blob};
-use operators::nvidia_gpu::cuda::{bindings::CUstream, memcpy_d2h, AsRaw, DevByte, DevMem, Stream};
-use sample::SampleArgs;
-use std::{
-    collections::HashMap,
-    ffi::{c_int, c_void},
-    ptr::{null, null_mut},
-    sync::{Mutex, OnceLock},
+﻿use crate::PinnedPool;
+use common::{bf16, f16, utok, BetweenF32, Blob};
+use digit_layout::{
+    types::{BF16, F16},
+    DigitLayout,
 };
-use tensor::{reslice, reslice_mut};
+use operators::nvidia_gpu::cuda::{bindings::CUstream, memcpy_d2h, AsRaw, DevByte, Stream};
+use sample::{top_logprobs, History, SampleArgs};
+use std::ffi::c_int;
+use tensor::reslice;
 
+/// 把 logits 下载到主机上逐行采样，每行的采样参数和历史由 `rows` 依次给出。
 pub fn sample_cpu(
-    args: impl IntoIterator<Item = (usize, SampleArgs)>,
+    rows: impl IntoIterator<Item = (SampleArgs, History)>,
     logits: &[DevByte],
+    dt: DigitLayout,
     voc: usize,
     _stream: &Stream,
 ) -> Vec<utok> {
     let mut host = Blob::new(logits.len());
     memcpy_d2h(&mut host, logits);
 
-    let logits: &[f16] = reslice(&host);
-    args.into_iter()
-        .map(|(i, arg)| arg.random(&logits[voc * i..][..voc]))
-        .collect()
+    fn sample<T: BetweenF32 + PartialOrd>(
+        rows: impl IntoIterator<Item = (SampleArgs, History)>,
+        logits: &[T],
+        voc: usize,
+    ) -> Vec<utok> {
+        rows.into_iter()
+            .enumerate()
+            .map(|(i, (args, history))| args.sample(&logits[voc * i..][..voc], &history))
+            .collect()
+    }
+    match dt {
+        F16 => sample::<f16>(rows, reslice(&host), voc),
+        BF16 => sample::<bf16>(rows, reslice(&host), voc),
+        dt => panic!("unsupported data layout: {dt:?}"),
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
-#[repr(C)]
-struct CubKeyValuePair<K, V> {
-    k: K,
-    v: V,
-}
+/// 把 logits 下载到主机上，计算每行最大的 `k` 个对数概率。
+pub fn top_logprobs_cpu(
+    logits: &[DevByte],
+    dt: DigitLayout,
+    voc: usize,
+    k: usize,
+) -> Vec<Vec<(utok, f32)>> {
+    let mut host = Blob::new(logits.len());
+    memcpy_d2h(&mut host, logits);
 
-extern "C" {
-    // extern "C" cudaError argmax_half(
-    //     void *temp_storage, size_t *temp_storage_bytes,
-    //     half const *input, int num_items,
-    //     cub::KeyValuePair<int, half> *output,
-    //     cudaStream_t stream)
-    fn argmax_half(
-        temp_storage: *mut c_void,
-        temp_storage_bytes: *mut usize,
-        input: *const f16,
-        num_items: c_int,
-        output: *mut CubKeyValuePair<c_int, f16>,
-        stream: CUstream,
-    ) -> c_int;
+    fn top<T: BetweenF32>(logits: &[T], voc: usize, k: usize) -> Vec<Vec<(utok, f32)>> {
+        logits
+            .chunks_exact(voc)
+            .map(|row| top_logprobs(row, k))
+            .collect()
+    }
+    match dt {
+        F16 => top::<f16>(reslice(&host), voc, k),
+        BF16 => top::<bf16>(reslice(&host), voc, k),
+        dt => panic!("unsupported data layout: {dt:?}"),
+    }
+}
 
-    // extern "C" cudaError radix_sort_half(
-    //     void *temp_storage, size_t *temp_storage_bytes,
-    //     half const *key_in, half *key_out,
-    //     unsigned int const *value_in, unsigned int *value_out,
-    //     int num_items,
-    //     cudaStream_t stream)
-    fn radix_sort_half(
-        temp_storage: *mut c_void,
-        temp_storage_bytes: *mut usize,
-        key_in: *const f16,
-        key_out: *mut f16,
-        value_in: *const u32,
-        value_out: *mut u32,
-        num_items: c_int,
-        stream: CUstream,
-    ) -> c_int;
+/// 把隐藏状态下载到主机上，转换为 `f32`。
+pub fn hidden_cpu(hidden: &[DevByte], dt: DigitLayout) -> Vec<f32> {
+    let mut host = Blob::new(hidden.len());
+    memcpy_d2h(&mut host, hidden);
+
+    fn to_f32<T: BetweenF32>(hidden: &[T]) -> Vec<f32> {
+        hidden.iter().map(T::get).collect()
+    }
+    match dt {
+        F16 => to_f32::<f16>(reslice(&host)),
+        BF16 => to_f32::<bf16>(reslice(&host)),
+        dt => panic!("unsupported data layout: {dt:?}"),
+    }
+}
 
-    // extern "C" cudaError inclusive_sum_half(
-    //     void *temp_storage, size_t *temp_storage_bytes,
-    //     half *data, int num_items,
-    //     cudaStream_t stream)
-    fn inclusive_sum_half(
-        temp_storage: *mut c_void,
-        temp_storage_bytes: *mut usize,
-        data: *mut f16,
-        num_items: c_int,
-        stream: CUstream,
-    ) -> c_int;
+/// 每行的采样参数，与 `sample.cu` 中的定义一致。
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[repr(C)]
+struct Params {
+    row: c_int,
+    temperature: f32,
+    topk: c_int,
+    topp: f32,
+    random: f32,
+}
 
-    // extern "C" cudaError partial_softmax_half(
-    //     half *data,
-    //     float temperature,
-    //     unsigned int topk,
+extern "C" {
+    // extern "C" cudaError sample_half(
+    //     half const *logits, int voc,
+    //     Params const *params,
+    //     unsigned int *indices, int n,
     //     cudaStream_t stream)
-    fn partial_softmax_half(
-        data: *mut f16,
-        temperature: f32,
-        topk: c_int,
+    fn sample_half(
+        logits: *const f16,
+        voc: c_int,
+        params: *const Params,
+        indices: *mut u32,
+        n: c_int,
         stream: CUstream,
     ) -> c_int;
 
-    // extern "C" cudaError random_sample_half(
-    //     half const *data,
-    //     unsigned int const *indices,
-    //     unsigned int *index,
-    //     float probability,
-    //     int topk,
+    // extern "C" cudaError sample_bf16(
+    //     nv_bfloat16 const *logits, int voc,
+    //     Params const *params,
+    //     unsigned int *indices, int n,
     //     cudaStream_t stream)
-    fn random_sample_half(
-        data: *const f16,
-        indices: *const u32,
-        index: *mut u32,
-        random: f32,
-        topp: f32,
-        topk: c_int,
+    fn sample_bf16(
+        logits: *const bf16,
         voc: c_int,
+        params: *const Params,
+        indices: *mut u32,
+        n: c_int,
         stream: CUstream,
     ) -> c_int;
 }
 
-fn prealloc_argmax<'ctx>(stream: &Stream<'ctx>, len: usize) -> DevMem<'ctx> {
-    static MAP: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
-    let len = *MAP
-        .get_or_init(Default::default)
-        .lock()
-        .unwrap()
-        .entry(len)
-        .or_insert_with(|| {
-            let mut temp_storage_bytes = 0;
-            assert_eq!(0, unsafe {
-                argmax_half(
-                    null_mut(),
-                    &mut temp_storage_bytes,
-                    null(),
-                    len as _,
-                    null_mut(),
-                    stream.as_raw(),
-                )
-            });
-            temp_storage_bytes
-        });
-    stream.malloc::<u8>(len)
-}
-
-fn prealloc_radix_sort<'ctx>(stream: &Stream<'ctx>, len: usize) -> DevMem<'ctx> {
-    static MAP: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
-    let len = *MAP
-        .get_or_init(Default::default)
-        .lock()
-        .unwrap()
-        .entry(len)
-        .or_insert_with(|| {
-            let mut temp_storage_bytes = 0;
-            assert_eq!(0, unsafe {
-                radix_sort_half(
-                    null_mut(),
-                    &mut temp_storage_bytes,
-                    null(),
-                    null_mut(),
-                    null(),
-                    null_mut(),
-                    len as _,
-                    stream.as_raw(),
-                )
-            });
-            temp_storage_bytes
-        });
-    stream.malloc::<u8>(len)
-}
-
-fn prealloc_inclusive_sum<'ctx>(stream: &Stream<'ctx>, len: usize) -> DevMem<'ctx> {
-    static MAP: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
-    let len = *MAP
-        .get_or_init(Default::default)
-        .lock()
-        .unwrap()
-        .entry(len)
-        .or_insert_with(|| {
-            let mut temp_storage_bytes = 0;
-            assert_eq!(0, unsafe {
-                inclusive_sum_half(
-                    null_mut(),
-                    &mut temp_storage_bytes,
-                    null_mut(),
-                    len as _,
-                    stream.as_raw(),
-                )
-            });
-            temp_storage_bytes
-        });
-    stream.malloc::<u8>(len)
-}
-
+/// 批量采样，`logits` 形状为 `[batch, voc]`，`rows` 依次给出每行的采样参数和历史。
+///
+/// 所有行在一次启动中完成，每行由一个线程块采样，只有选中的词序号复制回主机。
+/// 采样参数和结果经 `pinned` 中转。
 pub fn sample_nv(
-    args: impl IntoIterator<Item = (usize, SampleArgs)>,
+    rows: impl IntoIterator<Item = (SampleArgs, History)>,
     logits: &[DevByte],
+    dt: DigitLayout,
     voc: usize,
+    pinned: &PinnedPool,
     stream: &Stream,
 ) -> Vec<utok> {
-    let mut temp_argmax = prealloc_argmax(stream, voc);
-    let mut argmax_host = CubKeyValuePair::<c_int, f16>::default();
-    let mut argmax_out = stream.malloc::<CubKeyValuePair<c_int, f16>>(1);
-
-    let mut temp_sort = prealloc_radix_sort(stream, voc);
-    let mut sort_out = stream.malloc::<f16>(voc);
-    let mut indices_host = stream.ctx().malloc_host::<u32>(voc);
-    reslice_mut::<u8, u32>(&mut indices_host)
-        .iter_mut()
-        .enumerate()
-        .for_each(|(i, idx)| *idx = i as u32);
-    let indices_in = stream.from_host(&indices_host);
-    let mut indices_out = stream.malloc::<u32>(voc);
-
-    let mut temp_sum = prealloc_inclusive_sum(stream, voc);
-
-    let logits = logits.as_ptr().cast::<f16>();
-    let ans = args
+    // 计算核只支持温度、top-k、top-p，有其他需要或需要处理 logits 的行时全部在主机上采样
+    let rows = rows.into_iter().collect::<Vec<_>>();
+    if !rows
+        .iter()
+        .all(|(args, history)| args.device_samplable() && !history.needs_processing())
+    {
+        return sample_cpu(rows, logits, dt, voc, stream);
+    }
+
+    let params = rows
         .into_iter()
-        .map(|(i, args)| {
-            let logits = unsafe { logits.add(i * voc) };
-
-            if args.is_argmax() {
-                assert_eq!(0, unsafe {
-                    argmax_half(
-                        temp_argmax.as_mut_ptr().cast(),
-                        &mut temp_argmax.len(),
-                        logits,
-                        voc as _,
-                        argmax_out.as_mut_ptr().cast(),
-                        stream.as_raw(),
-                    )
-                });
-                memcpy_d2h(std::slice::from_mut(&mut argmax_host), &argmax_out);
-                argmax_host.k as utok
+        .enumerate()
+        .map(|(i, (args, history))| {
+            // 贪心采样以 top-k = 1 表示
+            let topk = if args.is_argmax() {
+                1
             } else {
-                let topk = args.top_k.min(voc) as c_int;
-                assert_eq!(0, unsafe {
-                    radix_sort_half(
-                        temp_sort.as_mut_ptr().cast(),
-                        &mut temp_sort.len(),
-                        logits,
-                        sort_out.as_mut_ptr().cast(),
-                        indices_in.as_ptr().cast(),
-                        indices_out.as_mut_ptr().cast(),
-                        voc as _,
-                        stream.as_raw(),
-                    )
-                });
-                assert_eq!(0, unsafe {
-                    partial_softmax_half(
-                        sort_out.as_mut_ptr().cast(),
-                        args.temperature,
-                        voc as _,
-                        stream.as_raw(),
-                    )
-                });
-                assert_eq!(0, unsafe {
-                    inclusive_sum_half(
-                        temp_sum.as_mut_ptr().cast(),
-                        &mut temp_sum.len(),
-                        sort_out.as_mut_ptr().cast(),
-                        voc as _,
-                        stream.as_raw(),
-                    )
-                });
-                let mut index = 0;
-                assert_eq!(0, unsafe {
-                    random_sample_half(
-                        sort_out.as_ptr().cast(),
-                        indices_out.as_ptr().cast(),
-                        &mut index,
-                        rand::random::<f32>(),
-                        args.top_p,
-                        topk,
-                        voc as _,
-                        stream.as_raw(),
-                    )
-                });
-                index as utok
+                args.top_k.min(voc)
+            };
+            Params {
+                row: i as _,
+                temperature: args.temperature,
+                topk: topk as _,
+                topp: args.top_p,
+                random: args.uniform(history.generated),
             }
         })
-        .collect();
-
-    temp_argmax.drop_on(stream);
-    argmax_out.drop_on(stream);
-
-    temp_sort.drop_on(stream);
-    sort_out.drop_on(stream);
-    indices_in.drop_on(stream);
-    indices_out.drop_on(stream);
-
-    temp_sum.drop_on(stream);
-
-    ans
+        .collect::<Vec<_>>();
+    if params.is_empty() {
+        return vec![];
+    }
+
+    let params_dev = pinned.upload(&params, stream);
+    let mut indices_dev = stream.malloc::<u32>(params.len());
+    launch!(dt; sample_half | sample_bf16(
+        logits.as_ptr().cast(),
+        voc as _,
+        params_dev.as_ptr().cast(),
+        indices_dev.as_mut_ptr().cast(),
+        params.len() as _,
+        stream.as_raw(),
+    ));
+    let mut indices = vec![0u32; params.len()];
+    pinned.download(&mut indices, &indices_dev, stream);
+
+    params_dev.drop_on(stream);
+    indices_dev.drop_on(stream);
+    indices
 }
@@ -32,6 +32,14 @@ impl CausalLM for Transformer {
         todo!()
     }
 
+    fn vocab_size(&self) -> usize {
+        todo!()
+    }
+
+    fn hidden_size(&self) -> usize {
+        todo!()
+    }
+
     fn new_cache(&self) -> Tensor<Self::Storage> {
         todo!()
     }
@@ -70,4 +78,8 @@ impl CausalLM for Transformer {
     ) -> Vec<utok> {
         todo!()
     }
+
+    fn top_logprobs(&self, _logits: &Tensor<Self::Storage>, _k: usize) -> Vec<Vec<(utok, f32)>> {
+        todo!()
+    }
 }
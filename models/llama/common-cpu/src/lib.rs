@@ -1,15 +1,18 @@
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
+use causal_lm::{top_logprobs, CausalLM, DecodingMeta, Model, ModelInfo, QueryContext, SampleMeta};
 use common::{f16, upos, utok, Blob, FileLoadError};
 use common_cpu::{
     tensor::{reslice, slice, udim, Tensor},
     CpuKernels, Kernels, ThisThread,
 };
-use llama::{ComputeConst, ComputeStream, Device, LayerStorage, QueueOf, SliceOn, Storage, Weight};
-use std::{iter::repeat, path::Path, slice::from_raw_parts};
+use llama::{
+    ComputeConst, ComputeStream, Device, LayerStorage, LoraLayer, QueueOf, SliceOn, Storage, Weight,
+};
+use std::{collections::HashMap, iter::repeat, path::Path, slice::from_raw_parts};
 
 pub struct Transformer {
     s: Storage,
     kernels: CpuKernels,
+    info: ModelInfo,
 }
 
 impl Model for Transformer {
@@ -18,8 +21,12 @@ impl Model for Transformer {
 
     #[inline]
     fn load(model_dir: impl AsRef<Path>, _meta: Self::Meta) -> Result<Self, Self::Error> {
+        let s = llama::Storage::load_safetensors(model_dir)?;
+        let stored = s.config.quantization.unwrap_or(s.config.dt);
+        let s = s.dequantize();
         Ok(Self {
-            s: llama::Storage::load_safetensors(model_dir)?,
+            info: ModelInfo::new(s.config.dt, stored, "cpu"),
+            s,
             kernels: Default::default(),
         })
     }
@@ -59,9 +66,18 @@ impl ComputeStream for Transformer {
         ComputeConst {
             nh: self.s.config.nh,
             nkvh: self.s.config.nkvh,
+            dh: self.s.config.dh,
+            dr: self.s.config.dr,
             di: self.s.config.di,
             epsilon: self.s.config.epsilon,
             theta: self.s.config.theta,
+            long_rope: self.s.config.long_rope.clone(),
+            att_scale: self.s.config.att_scale,
+            att_softcap: self.s.config.att_softcap,
+            sliding_window: self.s.config.sliding_window,
+            window_layers: self.s.config.arch.attention().sliding_window,
+            mlp: self.s.config.arch.mlp(),
+            norm: self.s.config.arch.norm(),
         }
     }
 
@@ -69,11 +85,19 @@ impl ComputeStream for Transformer {
     fn layers(
         &self,
     ) -> impl Iterator<Item = impl llama::LLamaLayer<Byte = <Self::Device as Device>::Byte>> {
-        self.s.layers.iter().map(LlamaLayer)
+        self.s
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| LlamaLayer(l, i, &self.s.adapters))
     }
 }
 
-struct LlamaLayer<'a>(&'a LayerStorage<Weight>);
+struct LlamaLayer<'a>(
+    &'a LayerStorage<Weight>,
+    usize,
+    &'a HashMap<String, Vec<LoraLayer<Weight>>>,
+);
 
 impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     type Byte = u8;
@@ -88,10 +112,18 @@ impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
         self.0.att_qkv.clone()
     }
     #[inline]
+    fn att_qkv_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_qkv_bias.clone()
+    }
+    #[inline]
     fn att_o(&self) -> Tensor<Self::Storage<'_>> {
         self.0.att_o.clone()
     }
     #[inline]
+    fn att_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.att_post_layernorm.clone()
+    }
+    #[inline]
     fn mlp_layernorm(&self) -> Tensor<Self::Storage<'_>> {
         self.0.mlp_layernorm.clone()
     }
@@ -103,6 +135,14 @@ impl<'a> llama::LLamaLayer for LlamaLayer<'a> {
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>> {
         self.0.mlp_down.clone()
     }
+    #[inline]
+    fn mlp_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        self.0.mlp_post_layernorm.clone()
+    }
+    #[inline]
+    fn lora(&self, name: &str) -> Option<LoraLayer<Self::Storage<'_>>> {
+        self.2.get(name).map(|layers| layers[self.1].clone())
+    }
 }
 
 impl CausalLM for Transformer {
@@ -117,6 +157,22 @@ impl CausalLM for Transformer {
         self.s.config.eos_token
     }
     #[inline]
+    fn vocab_size(&self) -> usize {
+        self.s.config.voc as _
+    }
+    #[inline]
+    fn hidden_size(&self) -> usize {
+        self.s.config.d as _
+    }
+    #[inline]
+    fn has_adapter(&self, name: &str) -> bool {
+        self.s.adapters.contains_key(name)
+    }
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
+    #[inline]
     fn new_cache(&self) -> Tensor<Self::Storage> {
         self.s.config.new_cache(Blob::new)
     }
@@ -180,6 +236,9 @@ impl CausalLM for Transformer {
             .rms_norm(&mut x, &x_, lm_layernorm, epsilon, self.queue());
         self.kernels()
             .mat_mul(&mut logits, 0., &x, lm_head, 1., self.queue());
+        if let Some(cap) = self.s.config.final_softcap {
+            self.kernels().softcap(&mut logits, cap, self.queue());
+        }
 
         logits
     }
@@ -192,11 +251,39 @@ impl CausalLM for Transformer {
         let &[_, voc] = logits.shape() else { panic!() };
         let logits: &[f16] = reslice(logits.as_slice());
         args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
+            .flat_map(|meta| repeat((meta.args, meta.history)).take(meta.num_decode))
             .enumerate()
-            .map(|(i, args)| args.random(&common_cpu::slice!(logits; voc; [i])))
+            .map(|(i, (args, history))| {
+                args.sample(&common_cpu::slice!(logits; voc; [i]), &history)
+            })
             .collect()
     }
+
+    fn top_logprobs(&self, logits: &Tensor<Self::Storage>, k: usize) -> Vec<Vec<(utok, f32)>> {
+        let &[_, voc] = logits.shape() else { panic!() };
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits
+            .chunks_exact(voc as usize)
+            .map(|row| top_logprobs(row, k))
+            .collect()
+    }
+
+    fn final_hidden(&self, hidden_state: Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        let mut x = hidden_state;
+        // 复制一个 x 以实现原地归一化
+        let x_ = x
+            .as_ref()
+            .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+        self.kernels().rms_norm(
+            &mut x,
+            &x_,
+            &self.s.lm_layernorm,
+            self.s.config.epsilon,
+            self.queue(),
+        );
+        let x: &[f16] = reslice(x.as_slice());
+        Some(x.iter().map(|x| x.to_f32()).collect())
+    }
 }
 
 #[test]
@@ -0,0 +1,390 @@
+﻿//! 不同模型家族在 llama 骨架上的差异。
+
+use crate::json::ConfigJson;
+use tensor::udim;
+
+/// 模型结构。
+///
+/// 描述一个模型家族的权重命名和各个部件的变种，加载和计算都只依赖这个特性，
+/// 新的模型家族只需要在这里实现，不需要修改各个后端。
+pub trait Architecture: Sync + Send {
+    /// 结构名字，与 config.json 中的 `model_type` 一致。
+    fn name(&self) -> &'static str;
+
+    /// 权重在 safetensors 文件中的名字。
+    fn weight_name(&self, weight: WeightName) -> String {
+        use WeightName::*;
+        let layer = |l: usize, name: &str| format!("model.layers.{l}.{name}");
+        match weight {
+            EmbedTokens => "model.embed_tokens.weight".into(),
+            AttLayernorm(l) => layer(l, "input_layernorm.weight"),
+            AttQKV(l) => layer(l, "self_attn.qkv_proj.weight"),
+            AttQ(l) => layer(l, "self_attn.q_proj.weight"),
+            AttK(l) => layer(l, "self_attn.k_proj.weight"),
+            AttV(l) => layer(l, "self_attn.v_proj.weight"),
+            AttQKVBias(l) => layer(l, "self_attn.qkv_proj.bias"),
+            AttQBias(l) => layer(l, "self_attn.q_proj.bias"),
+            AttKBias(l) => layer(l, "self_attn.k_proj.bias"),
+            AttVBias(l) => layer(l, "self_attn.v_proj.bias"),
+            AttO(l) => layer(l, "self_attn.o_proj.weight"),
+            AttPostLayernorm(l) => layer(l, "post_attention_layernorm.weight"),
+            MlpLayernorm(l) => layer(l, "post_attention_layernorm.weight"),
+            MlpGateUp(l) => layer(l, "mlp.gate_up_proj.weight"),
+            MlpGate(l) => layer(l, "mlp.gate_proj.weight"),
+            MlpUp(l) => layer(l, "mlp.up_proj.weight"),
+            MlpDown(l) => layer(l, "mlp.down_proj.weight"),
+            MlpPostLayernorm(l) => layer(l, "post_feedforward_layernorm.weight"),
+            LmLayernorm => "model.norm.weight".into(),
+            LmHead => "lm_head.weight".into(),
+        }
+    }
+
+    /// 注意力的变种。
+    #[inline]
+    fn attention(&self) -> AttentionVariant {
+        AttentionVariant {
+            qkv_bias: false,
+            sliding_window: SlidingWindow::None,
+        }
+    }
+
+    /// 旋转位置编码的变种。
+    #[inline]
+    fn rope(&self) -> RopeVariant {
+        RopeVariant {
+            interleaved: false,
+            partial_factor: None,
+        }
+    }
+
+    /// 前馈网络的变种。
+    #[inline]
+    fn mlp(&self) -> MlpVariant {
+        MlpVariant::SwiGLU
+    }
+
+    /// 归一化的位置。
+    #[inline]
+    fn norm(&self) -> NormPlacement {
+        NormPlacement::PreNorm
+    }
+
+    /// 文件中 RMSNorm 权重相对实际缩放系数的偏移，加载时加回权重。
+    #[inline]
+    fn norm_offset(&self) -> f32 {
+        0.
+    }
+
+    /// 词嵌入是否要乘以 `sqrt(hidden_size)`，加载时乘进词表。
+    #[inline]
+    fn scale_embedding(&self) -> bool {
+        false
+    }
+
+    /// config.json 没有指定 `tie_word_embeddings` 时，输出层是否与词表共享权重。
+    #[inline]
+    fn tie_word_embeddings(&self) -> bool {
+        false
+    }
+}
+
+/// 模型中所有权重的名字。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum WeightName {
+    EmbedTokens,
+    AttLayernorm(usize),
+    AttQKV(usize),
+    AttQ(usize),
+    AttK(usize),
+    AttV(usize),
+    AttQKVBias(usize),
+    AttQBias(usize),
+    AttKBias(usize),
+    AttVBias(usize),
+    AttO(usize),
+    AttPostLayernorm(usize),
+    MlpLayernorm(usize),
+    MlpGateUp(usize),
+    MlpGate(usize),
+    MlpUp(usize),
+    MlpDown(usize),
+    MlpPostLayernorm(usize),
+    LmLayernorm,
+    LmHead,
+}
+
+/// 注意力的变种。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AttentionVariant {
+    /// QKV 投影是否带偏置。
+    pub qkv_bias: bool,
+    /// 使用滑动窗口注意力的层。
+    pub sliding_window: SlidingWindow,
+}
+
+/// 使用 config.json 中 `sliding_window` 指定的滑动窗口注意力的层。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum SlidingWindow {
+    /// 所有层都使用全局注意力。
+    None,
+    /// 偶数层使用滑动窗口，与使用全局注意力的奇数层交替。
+    Alternating,
+}
+
+impl SlidingWindow {
+    /// 第 `layer` 层的窗口大小，空表示全局注意力。
+    #[inline]
+    pub fn window(self, size: Option<udim>, layer: usize) -> Option<udim> {
+        match self {
+            Self::None => None,
+            Self::Alternating => size.filter(|_| layer.is_multiple_of(2)),
+        }
+    }
+}
+
+/// 旋转位置编码的变种。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RopeVariant {
+    /// 每对旋转的维度在权重中是否已经相邻，否则是头内前后两半的对应维度，加载时重排。
+    pub interleaved: bool,
+    /// 施加旋转编码的维度占头维度的比例，config.json 中的 `partial_rotary_factor` 优先。
+    pub partial_factor: Option<f32>,
+}
+
+/// 前馈网络的变种。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MlpVariant {
+    /// `silu(gate) * up`。
+    SwiGLU,
+    /// `gelu(gate) * up`，gelu 使用 tanh 近似。
+    GeGLU,
+}
+
+/// 归一化的位置。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum NormPlacement {
+    /// 在注意力和前馈网络之前归一化。
+    PreNorm,
+    /// 在注意力和前馈网络前后都归一化，输出归一化后再加到残差上。
+    Sandwich,
+}
+
+/// Llama 及其变种（TinyLlama、MiniCPM、Mistral 等）。
+pub struct Llama;
+
+impl Architecture for Llama {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "llama"
+    }
+}
+
+/// Qwen2，QKV 投影带偏置。
+pub struct Qwen2;
+
+impl Architecture for Qwen2 {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "qwen2"
+    }
+
+    #[inline]
+    fn attention(&self) -> AttentionVariant {
+        AttentionVariant {
+            qkv_bias: true,
+            sliding_window: SlidingWindow::None,
+        }
+    }
+}
+
+/// Gemma，GeGLU 激活，词嵌入缩放，RMSNorm 权重偏移 1。
+pub struct Gemma;
+
+impl Architecture for Gemma {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "gemma"
+    }
+
+    #[inline]
+    fn mlp(&self) -> MlpVariant {
+        MlpVariant::GeGLU
+    }
+
+    #[inline]
+    fn norm_offset(&self) -> f32 {
+        1.
+    }
+
+    #[inline]
+    fn scale_embedding(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn tie_word_embeddings(&self) -> bool {
+        true
+    }
+}
+
+/// Gemma 2，在 Gemma 的基础上增加注意力和前馈网络之后的归一化，
+/// 隔层使用滑动窗口注意力，注意力分数和输出的软截断由 config.json 指定。
+pub struct Gemma2;
+
+impl Architecture for Gemma2 {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "gemma2"
+    }
+
+    fn weight_name(&self, weight: WeightName) -> String {
+        use WeightName::*;
+        let layer = |l: usize, name: &str| format!("model.layers.{l}.{name}");
+        match weight {
+            MlpLayernorm(l) => layer(l, "pre_feedforward_layernorm.weight"),
+            _ => Gemma.weight_name(weight),
+        }
+    }
+
+    #[inline]
+    fn attention(&self) -> AttentionVariant {
+        AttentionVariant {
+            qkv_bias: false,
+            sliding_window: SlidingWindow::Alternating,
+        }
+    }
+
+    #[inline]
+    fn mlp(&self) -> MlpVariant {
+        MlpVariant::GeGLU
+    }
+
+    #[inline]
+    fn norm(&self) -> NormPlacement {
+        NormPlacement::Sandwich
+    }
+
+    #[inline]
+    fn norm_offset(&self) -> f32 {
+        1.
+    }
+
+    #[inline]
+    fn scale_embedding(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn tie_word_embeddings(&self) -> bool {
+        true
+    }
+}
+
+/// Phi-3，QKV 和 gate/up 投影都是融合的权重，可能使用 LongRoPE 和部分旋转。
+pub struct Phi3;
+
+impl Architecture for Phi3 {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "phi3"
+    }
+}
+
+/// ChatGLM2、ChatGLM3 和 GLM-4，多查询注意力，QKV 投影带偏置，
+/// 只在每个头的前一半维度上施加相邻维度成对的旋转编码。
+pub struct ChatGLM;
+
+impl Architecture for ChatGLM {
+    #[inline]
+    fn name(&self) -> &'static str {
+        "chatglm"
+    }
+
+    fn weight_name(&self, weight: WeightName) -> String {
+        use WeightName::*;
+        let layer = |l: usize, name: &str| format!("transformer.encoder.layers.{l}.{name}");
+        match weight {
+            EmbedTokens => "transformer.embedding.word_embeddings.weight".into(),
+            AttLayernorm(l) => layer(l, "input_layernorm.weight"),
+            AttQKV(l) => layer(l, "self_attention.query_key_value.weight"),
+            AttQKVBias(l) => layer(l, "self_attention.query_key_value.bias"),
+            AttO(l) => layer(l, "self_attention.dense.weight"),
+            AttPostLayernorm(l) | MlpLayernorm(l) => layer(l, "post_attention_layernorm.weight"),
+            MlpGateUp(l) => layer(l, "mlp.dense_h_to_4h.weight"),
+            MlpDown(l) => layer(l, "mlp.dense_4h_to_h.weight"),
+            LmLayernorm => "transformer.encoder.final_layernorm.weight".into(),
+            LmHead => "transformer.output_layer.weight".into(),
+            AttQ(_) | AttK(_) | AttV(_) | AttQBias(_) | AttKBias(_) | AttVBias(_) | MlpGate(_)
+            | MlpUp(_) | MlpPostLayernorm(_) => unreachable!("ChatGLM has no {weight:?}"),
+        }
+    }
+
+    #[inline]
+    fn attention(&self) -> AttentionVariant {
+        AttentionVariant {
+            qkv_bias: true,
+            sliding_window: SlidingWindow::None,
+        }
+    }
+
+    #[inline]
+    fn rope(&self) -> RopeVariant {
+        RopeVariant {
+            interleaved: true,
+            partial_factor: Some(0.5),
+        }
+    }
+}
+
+/// 根据 config.json 选择模型结构，无法识别的结构按 llama 处理。
+pub(crate) fn from_config(config: &ConfigJson) -> &'static dyn Architecture {
+    match config.model_type.as_deref() {
+        Some("qwen2") => &Qwen2,
+        Some("gemma") => &Gemma,
+        Some("gemma2") => &Gemma2,
+        Some("phi3") => &Phi3,
+        Some("chatglm") => &ChatGLM,
+        _ => &Llama,
+    }
+}
+
+#[test]
+fn test_weight_name() {
+    assert_eq!(
+        Llama.weight_name(WeightName::AttQKV(3)),
+        "model.layers.3.self_attn.qkv_proj.weight"
+    );
+    assert_eq!(
+        Qwen2.weight_name(WeightName::AttKBias(0)),
+        "model.layers.0.self_attn.k_proj.bias"
+    );
+    assert!(Qwen2.attention().qkv_bias);
+    assert!(!Llama.attention().qkv_bias);
+    assert_eq!(
+        Gemma2.weight_name(WeightName::MlpLayernorm(1)),
+        "model.layers.1.pre_feedforward_layernorm.weight"
+    );
+    assert_eq!(
+        Gemma2.weight_name(WeightName::AttPostLayernorm(1)),
+        "model.layers.1.post_attention_layernorm.weight"
+    );
+    assert_eq!(
+        Phi3.weight_name(WeightName::MlpGateUp(2)),
+        "model.layers.2.mlp.gate_up_proj.weight"
+    );
+    assert_eq!(
+        ChatGLM.weight_name(WeightName::AttLayernorm(5)),
+        "transformer.encoder.layers.5.input_layernorm.weight"
+    );
+    assert_eq!(
+        ChatGLM.weight_name(WeightName::AttQKVBias(0)),
+        "transformer.encoder.layers.0.self_attention.query_key_value.bias"
+    );
+    assert!(ChatGLM.rope().interleaved);
+    assert!(Gemma2.tie_word_embeddings());
+    let window = Gemma2.attention().sliding_window;
+    assert_eq!(window.window(Some(4096), 0), Some(4096));
+    assert_eq!(window.window(Some(4096), 1), None);
+    assert_eq!(Llama.attention().sliding_window.window(Some(4096), 0), None);
+    assert!(!Qwen2.tie_word_embeddings());
+}
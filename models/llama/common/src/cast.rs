@@ -1,12 +1,33 @@
-﻿use crate::{InferenceConfig, LayerStorage, Storage, Weight};
-use common::{bf16, f16, Blob};
+﻿use crate::{InferenceConfig, LayerStorage, LoraLayer, LoraPair, Storage, Weight};
+use common::{bf16, f16, f8e4m3, f8e5m2, BetweenF32, Blob, F8_E4M3, F8_E5M2};
 use digit_layout::{
     types::{BF16, F16, F32},
     AsDigit, DigitLayout,
 };
+use std::{
+    ops::Deref,
+    sync::{Arc, OnceLock},
+};
 use tensor::Tensor;
 
 impl Storage {
+    /// 计算核不支持 FP8，以 FP8 保存的权重乘以缩放系数反量化为 F16 进行计算，每个权重在首次使用时反量化。
+    ///
+    /// FP8 只是存储格式，反量化后的权重一直常驻，推理时占用的内存与 F16 模型相同。
+    pub fn dequantize(self) -> Self {
+        match self.config.dt {
+            F8_E4M3 | F8_E5M2 => self.cast(F16),
+            _ => self,
+        }
+    }
+
+    /// 转换权重的数据类型。
+    ///
+    /// 转换为 FP8 时每个张量按绝对值的最大值缩放到 FP8 的范围内，缩放系数随权重保存。
+    ///
+    /// 转换是延迟的，每个权重在首次访问数据时才转换，避免加载时一次性转换整个模型造成的内存峰值。
+    /// 转换的结果一直缓存到权重被释放，推理访问过所有层之后仍然占用整个模型的内存；
+    /// 只有 [`Storage::save`] 逐层写入并释放，可以处理转换后超过内存的模型。
     pub fn cast(self, dt: DigitLayout) -> Self {
         if self.config.dt == dt {
             return self;
@@ -20,44 +41,222 @@ impl Storage {
                 .map(|l| LayerStorage {
                     att_layernorm: cast(l.att_layernorm, dt),
                     att_qkv: cast(l.att_qkv, dt),
+                    att_qkv_bias: l.att_qkv_bias.map(|t| cast(t, dt)),
                     att_o: cast(l.att_o, dt),
+                    att_post_layernorm: l.att_post_layernorm.map(|t| cast(t, dt)),
                     mlp_layernorm: cast(l.mlp_layernorm, dt),
                     mlp_gate_up: cast(l.mlp_gate_up, dt),
                     mlp_down: cast(l.mlp_down, dt),
+                    mlp_post_layernorm: l.mlp_post_layernorm.map(|t| cast(t, dt)),
                 })
                 .collect(),
             lm_layernorm: cast(self.lm_layernorm, dt),
             lm_head: cast(self.lm_head, dt),
+            adapters: self
+                .adapters
+                .into_iter()
+                .map(|(name, layers)| {
+                    let pair = |p: Option<LoraPair<Weight>>| {
+                        p.map(|p| LoraPair {
+                            a: cast(p.a, dt),
+                            b: cast(p.b, dt),
+                        })
+                    };
+                    let layers = layers
+                        .into_iter()
+                        .map(|l| LoraLayer {
+                            att_qkv: pair(l.att_qkv),
+                            att_o: pair(l.att_o),
+                            mlp_gate_up: pair(l.mlp_gate_up),
+                            mlp_down: pair(l.mlp_down),
+                        })
+                        .collect();
+                    (name, layers)
+                })
+                .collect(),
         }
     }
 }
 
-fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
-    match (src.data_layout(), dt) {
-        (F16, BF16) => typed(src, |x: &f16| bf16::from_f32(x.to_f32())),
-        (F16, F32) => typed(src, |x: &f16| x.to_f32()),
-        (BF16, F16) => typed(src, |x: &bf16| f16::from_f32(x.to_f32())),
-        (BF16, F32) => typed(src, |x: &bf16| x.to_f32()),
-        (F32, F16) => typed(src, |x: &f32| f16::from_f32(*x)),
-        (F32, BF16) => typed(src, |x: &f32| bf16::from_f32(*x)),
-        _ => todo!(),
+/// 延迟的数据类型转换，首次访问时转换并缓存结果，结果在释放这个对象之前不会释放。
+pub struct LazyCast {
+    src: Tensor<Weight>,
+    /// 源数据分块的缩放系数，为空时使用源数据的 [`Weight::scale`]。
+    block_scale: Option<BlockScale>,
+    dt: DigitLayout,
+    /// 转换的结果和转换为 FP8 时的缩放系数。
+    data: OnceLock<(Blob, f32)>,
+}
+
+impl LazyCast {
+    /// 转换为 FP8 时的缩放系数，其他类型为 1。
+    pub fn scale(&self) -> f32 {
+        match self.dt {
+            F8_E4M3 | F8_E5M2 => self.get().1,
+            _ => 1.,
+        }
+    }
+
+    fn get(&self) -> &(Blob, f32) {
+        self.data
+            .get_or_init(|| convert(&self.src, self.block_scale.as_ref(), self.dt))
+    }
+}
+
+impl Deref for LazyCast {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.get().0
     }
 }
 
-fn typed<T: AsDigit + Sync, U: AsDigit + Send>(
+/// FP8 权重分块的缩放系数，实际值是存储的值乘以所在块的系数。
+#[derive(Clone, Debug)]
+pub(crate) struct BlockScale {
+    /// 按行排列的各块的系数。
+    pub values: Vec<f32>,
+    /// 每行的块数。
+    pub cols: usize,
+    /// 每块的行数和列数。
+    pub block: [usize; 2],
+}
+
+impl BlockScale {
+    #[inline]
+    fn get(&self, row: usize, col: usize) -> f32 {
+        self.values[row / self.block[0] * self.cols + col / self.block[1]]
+    }
+}
+
+/// 转换后的张量保持原来的形状和步长。
+#[inline]
+pub(crate) fn cast(src: Tensor<Weight>, dt: DigitLayout) -> Tensor<Weight> {
+    lazy(src, None, dt)
+}
+
+/// 以分块的缩放系数反量化连续的 FP8 矩阵，再转换为 `dt`。
+#[inline]
+pub(crate) fn cast_scaled(
     src: Tensor<Weight>,
-    cast: impl Fn(&T) -> U + Sync,
+    scale: BlockScale,
+    dt: DigitLayout,
 ) -> Tensor<Weight> {
+    assert!(src.is_contiguous());
+    lazy(src, Some(scale), dt)
+}
+
+fn lazy(src: Tensor<Weight>, block_scale: Option<BlockScale>, dt: DigitLayout) -> Tensor<Weight> {
+    let shape = src.shape().to_vec();
+    let pattern = src.pattern().to_vec();
+    let lazy = LazyCast {
+        src,
+        block_scale,
+        dt,
+        data: OnceLock::new(),
+    };
+    unsafe { Tensor::from_raw_parts(dt, &shape, &pattern, Weight::Lazy(Arc::new(lazy))) }
+}
+
+/// 转换整个存储区，源数据先乘以缩放系数，转换为 FP8 时再除以新的缩放系数。
+fn convert(src: &Tensor<Weight>, block_scale: Option<&BlockScale>, dt: DigitLayout) -> (Blob, f32) {
+    use rayon::iter::*;
+
+    let data = src.physical();
+    let get = match src.data_layout() {
+        F16 => get::<f16>,
+        BF16 => get::<bf16>,
+        F32 => get::<f32>,
+        F8_E4M3 => get::<f8e4m3>,
+        F8_E5M2 => get::<f8e5m2>,
+        _ => todo!(),
+    };
+    let n = data.len() / src.data_layout().nbytes();
+    let cols = src.shape().last().map_or(1, |&d| d as usize);
+    let scale = data.scale();
+    let value = |i: usize| {
+        get(data, i)
+            * match block_scale {
+                Some(s) => s.get(i / cols, i % cols),
+                None => scale,
+            }
+    };
+
+    let max = match dt {
+        F8_E4M3 => f8e4m3::MAX,
+        F8_E5M2 => f8e5m2::MAX,
+        _ => 0.,
+    };
+    let scale = if max > 0. {
+        let absmax = (0..n)
+            .into_par_iter()
+            .map(|i| value(i).abs())
+            .reduce(|| 0., f32::max);
+        if absmax > 0. {
+            absmax / max
+        } else {
+            1.
+        }
+    } else {
+        1.
+    };
+    let value = |i| value(i) / scale;
+    let ans = match dt {
+        F16 => typed::<f16>(n, value),
+        BF16 => typed::<bf16>(n, value),
+        F32 => typed::<f32>(n, value),
+        F8_E4M3 => typed::<f8e4m3>(n, value),
+        F8_E5M2 => typed::<f8e5m2>(n, value),
+        _ => todo!(),
+    };
+    (ans, scale)
+}
+
+#[inline]
+fn get<T: BetweenF32>(data: &[u8], i: usize) -> f32 {
+    tensor::reslice::<u8, T>(data)[i].get()
+}
+
+/// 逐元素生成存储区。
+fn typed<T: AsDigit + BetweenF32 + Send>(n: usize, value: impl Fn(usize) -> f32 + Sync) -> Blob {
     use rayon::iter::*;
-    use tensor::{reslice, reslice_mut};
 
-    assert_eq!(src.data_layout(), T::LAYOUT);
-    let mut ans = Tensor::alloc(U::LAYOUT, src.shape(), Blob::new);
+    let mut ans = Blob::new(n * T::LAYOUT.nbytes());
+    tensor::reslice_mut::<u8, T>(&mut ans)
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, dst)| *dst = T::cast(value(i)));
+    ans
+}
+
+#[test]
+fn test_scale() {
+    use std::iter::zip;
+    use tensor::reslice;
+
+    let weight = |bytes: &[u8]| {
+        let mut blob = Blob::new(bytes.len());
+        blob.copy_from_slice(bytes);
+        Weight::from(blob)
+    };
 
-    reslice(src.physical())
-        .par_iter()
-        .zip(reslice_mut(ans.physical_mut()))
-        .for_each(|(src, dst)| *dst = cast(src));
+    // 转换为 FP8 时按绝对值的最大值缩放，最大值编码为 448
+    let x = [1., -2., 4., 0.5].map(f16::from_f32);
+    let x = Tensor::new(F16, &[2, 2], weight(reslice(&x)));
+    let y = cast(x, F8_E4M3);
+    assert_eq!(y.physical().scale(), 4. / f8e4m3::MAX);
+    let z = cast(y, F32);
+    let z = reslice::<u8, f32>(z.physical());
+    assert!(zip(z, [1., -2., 4., 0.5]).all(|(z, x)| (z - x).abs() < 1e-6));
 
-    ans.map_physical(|b| b.into())
+    // 分块反量化，每块 1 行 2 列
+    let x = [1., 2., 3., 4.].map(f8e4m3::from_f32);
+    let x = Tensor::new(F8_E4M3, &[2, 2], weight(reslice(&x)));
+    let scale = BlockScale {
+        values: vec![0.5, 2.],
+        cols: 1,
+        block: [1, 2],
+    };
+    let y = cast_scaled(x, scale, F32);
+    assert_eq!(reslice::<u8, f32>(y.physical()), [0.5, 1., 6., 8.]);
 }
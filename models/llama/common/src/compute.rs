@@ -1,9 +1,15 @@
-﻿use causal_lm::QueryContext;
-use common_devices::{Kernels, SliceOn};
+﻿use crate::{LongRope, LoraLayer, LoraPair, MlpVariant, NormPlacement, SlidingWindow};
+use causal_lm::QueryContext;
+use common_devices::{AttentionArgs, Kernels, QkvCache, SliceOn};
+use digit_layout::types::F32;
 use itertools::izip;
 use operators::{Device, QueueOf};
-use std::ops::{Deref, DerefMut};
-use tensor::{slice, split, udim, LocalSplitable, Tensor};
+use std::{
+    iter::zip,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tensor::{reslice, slice, split, udim, LocalSplitable, Tensor};
 
 pub trait ComputeStream {
     type Device: Device;
@@ -27,6 +33,35 @@ pub trait ComputeStream {
         &self,
     ) -> impl Iterator<Item = impl LLamaLayer<Byte = <Self::Device as Device>::Byte>>;
 
+    /// 对使用适配器的查询，在 `y` 的对应行上累加低秩增量 `x A B`。
+    fn lora<'w, Y, X, W>(
+        &self,
+        y: &mut Tensor<Y>,
+        x: &Tensor<X>,
+        seq_len: &[udim],
+        pairs: impl IntoIterator<Item = Option<&'w LoraPair<W>>>,
+    ) where
+        Y: DerefMut<Target = SliceOn<Self::Device>>,
+        X: Deref<Target = SliceOn<Self::Device>>,
+        W: Deref<Target = SliceOn<Self::Device>> + 'w,
+    {
+        let queue = self.queue();
+        let mut start = 0;
+        for (&len, pair) in zip(seq_len, pairs) {
+            if let Some(LoraPair { a, b }) = pair {
+                let rows = &[slice![start =>=> len], slice![=>]];
+                let x = x.as_ref().slice(rows).map_physical(|u| &**u);
+                let mut y = y.as_mut().slice(rows).map_physical(|u| &mut **u);
+                let r = a.shape()[1];
+                let mut t = Tensor::alloc(x.data_layout(), &[len, r], |len| self.malloc(len));
+                self.kernels().mat_mul(&mut t, 0., &x, a, 1., queue);
+                self.kernels().mat_mul(&mut y, 1., &t, b, 1., queue);
+                self.free(t.take_physical());
+            }
+            start += len;
+        }
+    }
+
     fn forward<'q>(
         &self,
         queries: impl IntoIterator<Item = QueryContext<'q, Self::Storage>>,
@@ -36,71 +71,116 @@ pub trait ComputeStream {
         Self::Storage: 'q,
     {
         let mut queries = queries.into_iter().collect::<Vec<_>>();
-        let mut nt = 0;
-        let mut max_seq_len = 0;
-        let mut max_att_len = 0;
-        let seq_len = queries
-            .iter()
-            .map(|q| {
-                let seq = q.seq_len();
-                let att = q.att_len();
-                nt += seq;
-                max_seq_len = max_seq_len.max(seq);
-                max_att_len = max_att_len.max(att);
-                seq
-            })
-            .collect::<Vec<_>>();
+        let seq_len = queries.iter().map(|q| q.seq_len()).collect::<Vec<_>>();
+        let nt = seq_len.iter().sum::<udim>();
 
         let ComputeConst {
             nh,
             nkvh,
+            dh,
+            dr,
             di,
             epsilon,
             theta,
+            long_rope,
+            att_scale,
+            att_softcap,
+            sliding_window,
+            window_layers,
+            mlp,
+            norm,
         } = self.constant();
         let dt = token_embedded.data_layout();
         let d = token_embedded.shape()[1];
-        let dh = d / nh;
+        let dq = nh * dh;
         let dkv = nkvh * dh;
-        let head_group = nh / nkvh;
-        let head_div = (dh as f32).sqrt().recip();
         let queue = self.queue();
 
         let mut x = token_embedded
             .as_mut()
             .map_physical(|u| self.map_storage(u));
-        let reusing = (d + dkv + dkv).max(di + di);
-        let mut state_buf = Tensor::alloc(dt, &[nt, d + reusing], |len| self.malloc(len));
+        // 头维度与隐藏层解耦时，注意力输出的宽度可能大于隐藏层
+        let dx = d.max(dq);
+        let reusing = (dq + dkv + dkv).max(di + di).max(d + d);
+        let mut state_buf = Tensor::alloc(dt, &[nt, dx + reusing], |len| self.malloc(len));
 
-        let mut q_buf = self.malloc((nh * max_seq_len * dh) as usize * dt.nbytes());
-        let mut att_buf = self.malloc((nh * max_seq_len * max_att_len) as usize * dt.nbytes());
         let pos = causal_lm::pos(&queries, nt);
         let pos = pos.as_ref().map_physical(|u| self.map_pos(u));
+        // 频率逐维缩放时，在主机上计算每个 token 的正余弦表，按位置数组的方式传给设备
+        let sin_cos = long_rope.map(|rope| {
+            queries
+                .iter()
+                .flat_map(|q| {
+                    let rope = &rope;
+                    let att_len = q.att_len();
+                    q.range
+                        .clone()
+                        .flat_map(move |p| rope.sin_cos(p, att_len, theta, dr))
+                })
+                .collect::<Vec<_>>()
+        });
+        let sin_cos = sin_cos
+            .as_ref()
+            .map(|t| Tensor::new(F32, &[nt, dr / 2, 2], self.map_pos(reslice(t))));
 
         for (layer, params) in self.layers().enumerate() {
-            let (mut x1, qkv) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
-            let mut qkv = qkv.slice(&[slice![=>], slice![=> d + dkv + dkv]]);
+            let (x1, qkv) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
+            let (o,) = split!(x1; [1]: dq);
+            let (mut x1,) = split!(x1; [1]: d);
+            let mut qkv = qkv.slice(&[slice![=>], slice![=> dq + dkv + dkv]]);
 
             self.kernels()
                 .rms_norm(&mut x1, &x, &params.att_layernorm(), epsilon, queue);
+            // 有偏置时先将偏置广播到输出，再累加矩阵乘的结果
+            let beta = if let Some(bias) = params.att_qkv_bias() {
+                let bias = bias.broadcast(&[nt, dq + dkv + dkv]);
+                self.kernels().reform(&mut qkv, &bias, queue);
+                1.
+            } else {
+                0.
+            };
             self.kernels()
-                .mat_mul(&mut qkv, 0., &x1, &params.att_qkv(), 1., queue);
+                .mat_mul(&mut qkv, beta, &x1, &params.att_qkv(), 1., queue);
+            // 每个查询选用的适配器在这一层的增量
+            let lora = queries
+                .iter()
+                .map(|q| q.adapter.and_then(|name| params.lora(name)))
+                .collect::<Vec<_>>();
+            let lora = |f: fn(&LoraLayer<_>) -> Option<&LoraPair<_>>| {
+                lora.iter().map(move |l| l.as_ref().and_then(f))
+            };
+            self.lora(&mut qkv, &x1, &seq_len, lora(|l| l.att_qkv.as_ref()));
 
-            let (q, k, v) = split!(qkv; [1]: d, dkv, dkv);
+            let (q, k, v) = split!(qkv; [1]: dq, dkv, dkv);
             let mut q = q.reshape(&[nt, nh, dh]);
             let mut k = k.reshape(&[nt, nkvh, dh]);
             let v = v.reshape(&[nt, nkvh, dh]);
-            let o = x1.reshape(&[nt, nh, dh]);
+            let o = o.reshape(&[nt, nh, dh]);
 
-            self.kernels().rope(&mut q, &pos, theta, queue);
-            self.kernels().rope(&mut k, &pos, theta, queue);
+            // 频率逐维缩放时按正余弦表编码所有 token，否则在写入缓存时逐个查询融合编码
+            if let Some(sin_cos) = &sin_cos {
+                for t in [&mut q, &mut k] {
+                    // 部分旋转时只编码每个头的前 dr 维
+                    let mut t = t
+                        .as_mut()
+                        .slice(&[slice![=>], slice![=>], slice![=> dr]])
+                        .map_physical(|u| &mut **u);
+                    self.kernels().rotary(&mut t, sin_cos, queue);
+                }
+            }
 
             let q = q.transpose(&[1, 0, 2]).split(1, &seq_len);
             let k = k.transpose(&[1, 0, 2]).split(1, &seq_len);
             let v = v.transpose(&[1, 0, 2]).split(1, &seq_len);
             let o = o.transpose(&[1, 0, 2]).split(1, &seq_len);
+            let pos_q = pos.as_ref().map_physical(|u| &**u).split(0, &seq_len);
+            let att_args = AttentionArgs {
+                scale: att_scale,
+                softcap: att_softcap,
+                window: window_layers.window(sliding_window, layer),
+            };
 
-            for (query, q, k, v, mut o) in izip!(&mut queries, q, k, v, o) {
+            for (query, mut q, mut k, v, mut o, pos_q) in izip!(&mut queries, q, k, v, o, pos_q) {
                 let pos = query.pos();
                 let seq_len = query.seq_len();
                 let att_len = query.att_len();
@@ -111,6 +191,7 @@ pub trait ComputeStream {
                 let mut query = QueryContext {
                     cache: cache.as_mut(),
                     range: query.range.clone(),
+                    adapter: query.adapter,
                 };
                 let Some((mut k_cache, mut v_cache)) = query.cache(layer as _) else {
                     continue;
@@ -118,52 +199,90 @@ pub trait ComputeStream {
 
                 let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
                 let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
-                let shape_q0 = &[nkvh * head_group, seq_len, dh];
-                let shape_q1 = &[nkvh, head_group * seq_len, dh];
-                let shape_att0 = &[nkvh, head_group * seq_len, att_len];
-                let shape_att1 = &[nkvh * head_group, seq_len, att_len];
 
-                let mut q_att = Tensor::new(dt, shape_q0, &mut q_buf[..]);
                 let mut k_cat = k_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
                 let mut v_cat = v_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
-                self.kernels().reform(&mut q_att, &q, queue);
-                self.kernels().reform(&mut k_cat, &k, queue);
-                self.kernels().reform(&mut v_cat, &v, queue);
+                if sin_cos.is_some() {
+                    self.kernels().reform(&mut k_cat, &k, queue);
+                    self.kernels().reform(&mut v_cat, &v, queue);
+                } else {
+                    let qkv = QkvCache {
+                        q: &mut q,
+                        k: &mut k,
+                        v: &v,
+                        k_cache: &mut k_cat,
+                        v_cache: &mut v_cat,
+                    };
+                    self.kernels().rope_qkv(qkv, &pos_q, dr, theta, queue);
+                }
 
-                let q_att = q_att.reshape(shape_q1);
-                let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
+                let k_att = k_cache.slice(slice_att);
                 let v_att = v_cache.slice(slice_att);
-
-                let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
-                self.kernels()
-                    .mat_mul(&mut att, 0., &q_att, &k_att, head_div, queue);
-                let mut att = att.reshape(shape_att1);
-                self.kernels().softmax(&mut att, queue);
-                let mut x2 = q_att;
                 self.kernels()
-                    .mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1., queue);
-
-                self.kernels().reform(&mut o, &x2.reshape(shape_q0), queue);
+                    .attention(&mut o, &q, &k_att, &v_att, att_args, queue);
             }
 
-            let (mut x1, gate_up) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing);
-            let mut gate_up = gate_up.slice(&[slice![=>], slice![=> di + di]]);
+            let (x1, buf) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: dx, reusing);
+            let (o,) = split!(x1; [1]: dq);
+            let (mut x1,) = split!(x1; [1]: d);
 
-            self.kernels()
-                .mat_mul(&mut x, 1., &x1, &params.att_o(), 1., queue);
-            self.kernels()
-                .rms_norm(&mut x1, &x, &params.mlp_layernorm(), epsilon, queue);
+            match norm {
+                NormPlacement::PreNorm => {
+                    self.kernels()
+                        .mat_mul(&mut x, 1., &o, &params.att_o(), 1., queue);
+                    self.lora(&mut x, &o, &seq_len, lora(|l| l.att_o.as_ref()));
+                    self.kernels()
+                        .rms_norm(&mut x1, &x, &params.mlp_layernorm(), epsilon, queue);
+                }
+                NormPlacement::Sandwich => {
+                    let (mut y, mut z) = split!(buf; [1]: d, d);
+                    self.kernels()
+                        .mat_mul(&mut y, 0., &o, &params.att_o(), 1., queue);
+                    self.lora(&mut y, &o, &seq_len, lora(|l| l.att_o.as_ref()));
+                    let w = params.att_post_layernorm().unwrap();
+                    self.kernels().rms_norm(&mut z, &y, &w, epsilon, queue);
+                    // 残差连接与前馈层之前的归一化融合
+                    let w = params.mlp_layernorm();
+                    self.kernels()
+                        .add_rms_norm(&mut x1, &mut x, &z, &w, epsilon, queue);
+                }
+            }
+            let mut gate_up = buf.slice(&[slice![=>], slice![=> di + di]]);
             self.kernels()
                 .mat_mul(&mut gate_up, 0., &x1, &params.mlp_gate_up(), 1., queue);
+            self.lora(
+                &mut gate_up,
+                &x1,
+                &seq_len,
+                lora(|l| l.mlp_gate_up.as_ref()),
+            );
             let (mut gate, up) = split!(gate_up; [1]: di, di);
-            self.kernels().swiglu(&mut gate, &up, queue);
-            self.kernels()
-                .mat_mul(&mut x, 1., &gate, &params.mlp_down(), 1., queue);
+            match mlp {
+                MlpVariant::SwiGLU => self.kernels().swiglu(&mut gate, &up, queue),
+                MlpVariant::GeGLU => self.kernels().geglu(&mut gate, &up, queue),
+            }
+            match norm {
+                NormPlacement::PreNorm => {
+                    self.kernels()
+                        .mat_mul(&mut x, 1., &gate, &params.mlp_down(), 1., queue);
+                    self.lora(&mut x, &gate, &seq_len, lora(|l| l.mlp_down.as_ref()));
+                }
+                NormPlacement::Sandwich => {
+                    self.kernels()
+                        .mat_mul(&mut x1, 0., &gate, &params.mlp_down(), 1., queue);
+                    self.lora(&mut x1, &gate, &seq_len, lora(|l| l.mlp_down.as_ref()));
+                    let (mut y,) = split!(gate_up; [1]: d);
+                    let w = params.mlp_post_layernorm().unwrap();
+                    self.kernels().rms_norm(&mut y, &x1, &w, epsilon, queue);
+                    self.kernels().add(&mut x, &y, queue);
+                }
+            }
         }
         self.free_pos(pos.take_physical());
+        if let Some(sin_cos) = sin_cos {
+            self.free_pos(sin_cos.take_physical());
+        }
         self.free(state_buf.take_physical());
-        self.free(q_buf);
-        self.free(att_buf);
         drop(x);
         token_embedded
     }
@@ -172,9 +291,18 @@ pub trait ComputeStream {
 pub struct ComputeConst {
     pub nh: udim,
     pub nkvh: udim,
+    pub dh: udim,
+    pub dr: udim,
     pub di: udim,
     pub epsilon: f32,
     pub theta: f32,
+    pub long_rope: Option<Arc<LongRope>>,
+    pub att_scale: f32,
+    pub att_softcap: Option<f32>,
+    pub sliding_window: Option<udim>,
+    pub window_layers: SlidingWindow,
+    pub mlp: MlpVariant,
+    pub norm: NormPlacement,
 }
 
 pub trait LLamaLayer {
@@ -185,8 +313,16 @@ pub trait LLamaLayer {
 
     fn att_layernorm(&self) -> Tensor<Self::Storage<'_>>;
     fn att_qkv(&self) -> Tensor<Self::Storage<'_>>;
+    fn att_qkv_bias(&self) -> Option<Tensor<Self::Storage<'_>>>;
     fn att_o(&self) -> Tensor<Self::Storage<'_>>;
+    fn att_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>>;
     fn mlp_layernorm(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_gate_up(&self) -> Tensor<Self::Storage<'_>>;
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>>;
+    fn mlp_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>>;
+    /// 名为 `name` 的常驻适配器在这一层的增量，不支持适配器的实现返回空。
+    #[inline]
+    fn lora(&self, _name: &str) -> Option<LoraLayer<Self::Storage<'_>>> {
+        None
+    }
 }
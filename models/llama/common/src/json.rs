@@ -1,25 +1,123 @@
-﻿use common::utok;
+﻿use common::{utok, F8_E4M3, F8_E5M2};
 use digit_layout::{
     types::{BF16, F16, F32},
     DigitLayout,
 };
 
+/// config.json 的内容。
+///
+/// 别名是 ChatGLM 系列配置中的字段名。
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub(crate) struct ConfigJson {
-    pub bos_token_id: utok,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bos_token_id: Option<utok>,
+    #[serde(deserialize_with = "first_token")]
     pub eos_token_id: utok,
     pub hidden_size: usize,
+    #[serde(alias = "ffn_hidden_size")]
     pub intermediate_size: usize,
+    #[serde(alias = "seq_length")]
     pub max_position_embeddings: usize,
     pub num_attention_heads: usize,
+    #[serde(alias = "num_layers")]
     pub num_hidden_layers: usize,
+    #[serde(alias = "multi_query_group_num")]
     pub num_key_value_heads: usize,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        alias = "kv_channels"
+    )]
+    pub head_dim: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_pre_attn_scalar: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attn_logit_softcapping: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub final_logit_softcapping: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sliding_window: Option<usize>,
+    #[serde(alias = "padded_vocab_size")]
     pub vocab_size: usize,
-    #[serde(default = "default_rms_norm_eps")]
+    #[serde(default = "default_rms_norm_eps", alias = "layernorm_epsilon")]
     pub rms_norm_eps: f32,
     #[serde(default = "default_rope_theta")]
     pub rope_theta: f32,
+    /// ChatGLM 以 `rope_theta` 的倍数表示旋转编码的底数。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rope_ratio: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partial_rotary_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_max_position_embeddings: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rope_scaling: Option<RopeScalingJson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tie_word_embeddings: Option<bool>,
     pub torch_dtype: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantization_config: Option<QuantizationConfigJson>,
+}
+
+/// config.json 中的 `quantization_config`，只支持 FP8 权重。
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct QuantizationConfigJson {
+    pub quant_method: String,
+    /// 分块缩放时每块的行数和列数，没有指定时由缩放系数的形状推断。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight_block_size: Option<[usize; 2]>,
+}
+
+impl QuantizationConfigJson {
+    /// 权重以 FP8 保存、带有缩放系数的量化方法。
+    pub fn is_fp8(&self) -> bool {
+        matches!(
+            self.quant_method.as_str(),
+            "fp8" | "fbgemm_fp8" | "compressed-tensors"
+        )
+    }
+}
+
+/// config.json 中的 `rope_scaling`，目前只使用 LongRoPE 的参数。
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct RopeScalingJson {
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rope_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub short_factor: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub long_factor: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attention_factor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_max_position_embeddings: Option<usize>,
+}
+
+impl RopeScalingJson {
+    /// 是否 LongRoPE，早期的 Phi-3 配置称为 `su`。
+    pub fn is_long_rope(&self) -> bool {
+        matches!(
+            self.rope_type.as_deref().or(self.ty.as_deref()),
+            Some("longrope" | "su")
+        )
+    }
+}
+
+/// PEFT 保存的 adapter_config.json，只使用 LoRA 需要的字段。
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct LoraConfigJson {
+    pub r: usize,
+    pub lora_alpha: f32,
+    #[serde(default)]
+    pub use_rslora: bool,
+    #[serde(default)]
+    pub fan_in_fan_out: bool,
+    #[serde(default)]
+    pub use_dora: bool,
 }
 
 impl ConfigJson {
@@ -28,6 +126,8 @@ impl ConfigJson {
             "float16" => F16,
             "float32" => F32,
             "bfloat16" => BF16,
+            "float8_e4m3fn" => F8_E4M3,
+            "float8_e5m2" => F8_E5M2,
             _ => todo!(),
         }
     }
@@ -38,10 +138,29 @@ pub(crate) fn data_layout_name(layout: DigitLayout) -> &'static str {
         F16 => "float16",
         F32 => "float32",
         BF16 => "bfloat16",
+        F8_E4M3 => "float8_e4m3fn",
+        F8_E5M2 => "float8_e5m2",
         _ => todo!(),
     }
 }
 
+/// 有多个结束符时只使用第一个。
+fn first_token<'de, D: serde::Deserializer<'de>>(d: D) -> Result<utok, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(utok),
+        Many(Vec<utok>),
+    }
+    match serde::Deserialize::deserialize(d)? {
+        OneOrMany::One(t) => Ok(t),
+        OneOrMany::Many(t) => t
+            .first()
+            .copied()
+            .ok_or_else(|| serde::de::Error::custom("empty eos_token_id")),
+    }
+}
+
 #[inline(always)]
 const fn default_rms_norm_eps() -> f32 {
     1e-5
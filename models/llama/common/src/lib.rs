@@ -1,17 +1,26 @@
+mod architecture;
 mod cast;
 mod compute;
 mod json;
 mod load;
+mod lora;
+mod rope;
 mod save;
 
 use common::{safe_tensors::SharedTensor, upos, utok, Blob};
 use digit_layout::DigitLayout;
-use std::{ops::Deref, sync::Arc};
+use std::{collections::HashMap, fmt, ops::Deref, sync::Arc};
 use tensor::{slice, udim, Tensor};
 
+pub use architecture::{
+    Architecture, AttentionVariant, ChatGLM, Gemma, Gemma2, Llama, MlpVariant, NormPlacement, Phi3,
+    Qwen2, RopeVariant, SlidingWindow, WeightName,
+};
+pub use cast::LazyCast;
 pub use common_devices::SliceOn;
 pub use compute::{ComputeConst, ComputeStream, LLamaLayer};
 pub use operators::{Device, QueueOf};
+pub use rope::LongRope;
 
 pub struct Storage {
     pub config: InferenceConfig,
@@ -20,24 +29,31 @@ pub struct Storage {
     pub layers: Vec<LayerStorage<Weight>>,
     pub lm_layernorm: Tensor<Weight>,
     pub lm_head: Tensor<Weight>,
+    /// 常驻的 LoRA 适配器，每个适配器逐层保存。
+    pub adapters: HashMap<String, Vec<LoraLayer<Weight>>>,
 }
 
 pub struct LayerStorage<T> {
     pub att_layernorm: Tensor<T>,
     pub att_qkv: Tensor<T>,
+    pub att_qkv_bias: Option<Tensor<T>>,
     pub att_o: Tensor<T>,
+    pub att_post_layernorm: Option<Tensor<T>>,
     pub mlp_layernorm: Tensor<T>,
     pub mlp_gate_up: Tensor<T>,
     pub mlp_down: Tensor<T>,
+    pub mlp_post_layernorm: Option<Tensor<T>>,
 }
 
 impl<T> LayerStorage<T> {
     pub fn map<U>(&self, mut f: impl FnMut(&T) -> U) -> LayerStorage<U> {
         macro_rules! map {
-            ($($ident:ident)+) => {
+            ($($ident:ident)+; $($option:ident)*) => {
                 LayerStorage {$(
                     $ident: self.$ident.as_ref().map_physical(&mut f),
-                )+}
+                )+$(
+                    $option: self.$option.as_ref().map(|t| t.as_ref().map_physical(&mut f)),
+                )*}
             };
         }
         map! {
@@ -47,38 +63,68 @@ impl<T> LayerStorage<T> {
             mlp_layernorm
             mlp_gate_up
             mlp_down
+            ;
+            att_qkv_bias
+            att_post_layernorm
+            mlp_post_layernorm
         }
     }
 }
 
+/// 一个适配器在一层中的低秩增量，不适配的投影为空。
+#[derive(Clone)]
+pub struct LoraLayer<T> {
+    pub att_qkv: Option<LoraPair<T>>,
+    pub att_o: Option<LoraPair<T>>,
+    pub mlp_gate_up: Option<LoraPair<T>>,
+    pub mlp_down: Option<LoraPair<T>>,
+}
+
+/// 投影 `y = x W` 的低秩增量 `x A B`。
+#[derive(Clone)]
+pub struct LoraPair<T> {
+    /// `in x r`。
+    pub a: Tensor<T>,
+    /// `r x out`，已乘缩放系数。
+    pub b: Tensor<T>,
+}
+
 #[derive(Clone, Debug)]
 pub struct InferenceConfig {
+    pub arch: &'static dyn Architecture,
     pub dt: DigitLayout,
+    /// 权重文件中 FP8 权重的类型，这些权重加载时反量化为 `dt`。
+    pub quantization: Option<DigitLayout>,
     pub voc: udim,
     pub nlayers: udim,
     pub nh: udim,
     pub nkvh: udim,
     pub d: udim,
+    pub dh: udim,
+    /// 每个头中施加旋转位置编码的维度数。
+    pub dr: udim,
     pub dkv: udim,
     pub di: udim,
     pub max_seq_len: udim,
-    pub bos_token: utok,
+    pub bos_token: Option<utok>,
     pub eos_token: utok,
     pub epsilon: f32,
     pub theta: f32,
+    pub long_rope: Option<Arc<LongRope>>,
+    pub att_scale: f32,
+    /// 非空时软截断注意力分数。
+    pub att_softcap: Option<f32>,
+    /// 非空时软截断输出的 logits。
+    pub final_softcap: Option<f32>,
+    /// 滑动窗口注意力的窗口大小，哪些层使用由模型结构决定。
+    pub sliding_window: Option<udim>,
 }
 
 impl InferenceConfig {
     pub fn new_cache<S>(&self, f: impl FnOnce(usize) -> S) -> Tensor<S> {
         Tensor::alloc(
             self.dt,
-            &[
-                self.nlayers,
-                2,
-                self.nkvh,
-                self.max_seq_len,
-                self.d / self.nh,
-            ],
+            &[self.nlayers, 2, self.nkvh, self.max_seq_len, self.dh],
             f,
         )
     }
@@ -109,10 +155,32 @@ impl InferenceConfig {
     }
 }
 
+impl fmt::Debug for dyn Architecture {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Clone)]
 pub enum Weight {
     SafeTensor(SharedTensor),
     Blob(Arc<Blob>),
+    /// 首次访问时才转换数据类型的权重。
+    Lazy(Arc<LazyCast>),
+    /// 按张量缩放的 FP8 权重，实际值是存储的值乘以缩放系数。
+    Scaled(Box<Weight>, f32),
+}
+
+impl Weight {
+    /// FP8 权重的缩放系数，其他权重为 1。
+    pub fn scale(&self) -> f32 {
+        match self {
+            Self::SafeTensor(_) | Self::Blob(_) => 1.,
+            Self::Lazy(lazy) => lazy.scale(),
+            Self::Scaled(_, scale) => *scale,
+        }
+    }
 }
 
 impl From<SharedTensor> for Weight {
@@ -136,6 +204,8 @@ impl Deref for Weight {
         match self {
             Self::SafeTensor(tensor) => tensor,
             Self::Blob(blob) => blob,
+            Self::Lazy(lazy) => lazy,
+            Self::Scaled(weight, _) => weight,
         }
     }
 }
@@ -1,113 +1,450 @@
-﻿use crate::{json::ConfigJson, InferenceConfig, LayerStorage, Storage, Weight};
+﻿use crate::{
+    architecture,
+    cast::{cast, cast_scaled, BlockScale},
+    json::ConfigJson,
+    lora::Lora,
+    InferenceConfig, LayerStorage, LongRope, LoraLayer, LoraPair, NormPlacement, RopeVariant,
+    Storage, Weight,
+    WeightName::{self, *},
+};
 use common::{
-    safe_tensors::{Dtype, SafeTensors},
-    Blob,
+    bf16, f16, f8e4m3, f8e5m2,
+    safe_tensors::{Dtype, SafeTensors, SharedTensor},
+    BetweenF32, Blob,
     FileLoadError::{self, Io, Json},
+    F8_E4M3, F8_E5M2,
 };
 use digit_layout::DigitLayout;
 use std::{fs::File, path::Path, pin::Pin, sync::Arc};
 use tensor::{udim, Shape, Tensor};
 
 impl Storage {
+    /// 从目录加载模型，目录中有 PEFT 格式的 LoRA 适配器时合并到权重中。
+    ///
+    /// `adapters` 子目录中的每个适配器不合并，以子目录名常驻，推理时按查询选用。
     pub fn load_safetensors(model_dir: impl AsRef<Path>) -> Result<Self, FileLoadError> {
         let config = File::open(model_dir.as_ref().join("config.json")).map_err(Io)?;
         let config: ConfigJson = serde_json::from_reader(&config).map_err(Json)?;
+        let lora = Lora::load(&model_dir)?;
+        let adapters = Lora::load_all(model_dir.as_ref().join("adapters"))?;
         let model = SafeTensors::load_from_dir(model_dir)?.share();
+        Self::from_safetensors(config, lora, adapters, model)
+    }
+
+    /// 从内存中的 `config.json` 和 safetensors 文件内容加载模型，用于没有文件系统的环境（如 wasm32）。
+    pub fn load_safetensors_from_bytes(
+        config: &[u8],
+        files: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Result<Self, FileLoadError> {
+        let config: ConfigJson = serde_json::from_slice(config).map_err(Json)?;
+        let model = SafeTensors::from_bytes(files)?.share();
+        Self::from_safetensors(config, None, vec![], model)
+    }
 
-        let dt = config.data_layout();
+    fn from_safetensors(
+        config: ConfigJson,
+        lora: Option<Lora>,
+        adapters: Vec<(String, Lora)>,
+        model: Pin<Arc<SafeTensors>>,
+    ) -> Result<Self, FileLoadError> {
+        let arch = architecture::from_config(&config);
+        let block = match &config.quantization_config {
+            Some(q) if !q.is_fp8() => {
+                return Err(FileLoadError::Invalid(format!(
+                    "unsupported quantization method: {}",
+                    q.quant_method
+                )))
+            }
+            Some(q) => q.weight_block_size,
+            None => None,
+        };
+        // 计算核不支持 FP8，其他来源的 FP8 权重加载时反量化，只有本项目保存的 FP8 模型以 FP8 常驻
+        let rs = model
+            .share_tensor(&arch.weight_name(EmbedTokens))
+            .is_some_and(|t| t.format() == "rs");
+        let dt = match config.data_layout() {
+            F8_E4M3 | F8_E5M2 if !rs => digit_layout::types::F16,
+            dt => dt,
+        };
+        let quantization = model
+            .iter()
+            .find(|(_, t)| matches!(t.dtype, Dtype::F8_E4M3 | Dtype::F8_E5M2))
+            .map(|(_, t)| convert(t.dtype))
+            .filter(|&t| t != dt);
         let voc = config.vocab_size as udim;
         let d = config.hidden_size as udim;
         let nh = config.num_attention_heads as udim;
         let nkvh = config.num_key_value_heads as udim;
-        let dh = d / nh;
+        let dh = config.head_dim.map_or(d / nh, |dh| dh as udim);
+        let rope = arch.rope();
+        let dr = config
+            .partial_rotary_factor
+            .or(rope.partial_factor)
+            .map_or(dh, |f| (dh as f32 * f) as udim);
+        assert_eq!(dr % 2, 0);
+        let dq = dh * nh;
         let dkv = dh * nkvh;
         let di = config.intermediate_size as udim;
 
+        let name = |w: WeightName| arch.weight_name(w);
+        let norm = |w: WeightName| {
+            let t = tensor(&model, &name(w), dt, [d], block);
+            match arch.norm_offset() {
+                0. => t,
+                offset => transform(t, |x| x + offset),
+            }
+        };
+        // 投影矩阵，模型目录中有 LoRA 适配器时合并到权重中
+        let matrix = |name: &str, shape: [udim; 2]| {
+            let t = tensor(&model, name, dt, shape, block);
+            match &lora {
+                Some(lora) if !is_transformed(&t) => lora.merge(name, t),
+                _ => t,
+            }
+        };
+        let sandwich = |w: WeightName| match arch.norm() {
+            NormPlacement::PreNorm => None,
+            NormPlacement::Sandwich => Some(norm(w)),
+        };
+
+        // 常驻适配器的低秩增量，与权重一样转置为 `x A B` 的形式
+        let pair = |lora: &Lora, modules: &[(WeightName, udim)], cols: udim, rope_heads| {
+            let modules = modules
+                .iter()
+                .map(|&(w, n)| (name(w), n))
+                .collect::<Vec<_>>();
+            lora.pair(&modules, cols, dt).map(|(a, b)| LoraPair {
+                a: a.transpose(&[1, 0]),
+                b: match rope_heads {
+                    Some(heads) => rope_rows(b, rope, heads, dh, dr),
+                    None => b,
+                }
+                .transpose(&[1, 0]),
+            })
+        };
+        let adapter_layer = |lora: &Lora, l| LoraLayer {
+            att_qkv: if model.contains(&name(AttQKV(l))) {
+                pair(lora, &[(AttQKV(l), dq + dkv + dkv)], d, Some(nh + nkvh))
+            } else {
+                let qkv = [(AttQ(l), dq), (AttK(l), dkv), (AttV(l), dkv)];
+                pair(lora, &qkv, d, Some(nh + nkvh))
+            },
+            att_o: pair(lora, &[(AttO(l), d)], dq, None),
+            mlp_gate_up: if model.contains(&name(MlpGateUp(l))) {
+                pair(lora, &[(MlpGateUp(l), di + di)], d, None)
+            } else {
+                pair(lora, &[(MlpGate(l), di), (MlpUp(l), di)], d, None)
+            },
+            mlp_down: pair(lora, &[(MlpDown(l), d)], di, None),
+        };
+
+        let embed_tokens = tensor(&model, &name(EmbedTokens), dt, [voc, d], block);
+        // 共享权重时输出层直接使用词表，文件中即使有输出层也与词表相同；
+        // 文件中没有输出层时也只能与词表共享
+        let tied = config
+            .tie_word_embeddings
+            .unwrap_or_else(|| arch.tie_word_embeddings())
+            || !model.contains(&name(LmHead));
+        let lm_head = if tied {
+            embed_tokens.clone()
+        } else {
+            tensor(&model, &name(LmHead), dt, [voc, d], block)
+        }
+        .transpose(&[1, 0]);
+        let embed_tokens = if arch.scale_embedding() {
+            let scale = (d as f32).sqrt();
+            transform(embed_tokens, |x| x * scale)
+        } else {
+            embed_tokens
+        };
+
         Ok(Self {
             config: InferenceConfig {
+                arch,
                 dt,
+                quantization,
                 voc,
                 nlayers: config.num_hidden_layers as _,
                 nh,
                 nkvh,
                 d,
+                dh,
+                dr,
                 dkv,
                 di,
                 max_seq_len: config.max_position_embeddings as _,
                 bos_token: config.bos_token_id,
                 eos_token: config.eos_token_id,
                 epsilon: config.rms_norm_eps,
-                theta: config.rope_theta,
+                theta: config.rope_theta * config.rope_ratio.unwrap_or(1.),
+                long_rope: config
+                    .rope_scaling
+                    .as_ref()
+                    .filter(|r| r.is_long_rope())
+                    .map(|r| {
+                        let max = config.max_position_embeddings as f32;
+                        let original = r
+                            .original_max_position_embeddings
+                            .or(config.original_max_position_embeddings)
+                            .expect("longrope requires original_max_position_embeddings");
+                        let attention_factor = r.attention_factor.unwrap_or_else(|| {
+                            let scale = max / original as f32;
+                            if scale <= 1. {
+                                1.
+                            } else {
+                                (1. + scale.ln() / (original as f32).ln()).sqrt()
+                            }
+                        });
+                        Arc::new(LongRope {
+                            short_factor: r.short_factor.clone(),
+                            long_factor: r.long_factor.clone(),
+                            original_max_seq_len: original as _,
+                            attention_factor,
+                        })
+                    }),
+                att_scale: config
+                    .query_pre_attn_scalar
+                    .unwrap_or(dh as _)
+                    .sqrt()
+                    .recip(),
+                att_softcap: config.attn_logit_softcapping,
+                final_softcap: config.final_logit_softcapping,
+                sliding_window: config.sliding_window.map(|w| w as _),
             },
 
-            embed_tokens: tensor(&model, "model.embed_tokens.weight", dt, [voc, d]),
+            embed_tokens,
             layers: (0..config.num_hidden_layers)
-                .map(|l| {
-                    let name = |name: &str| format!("model.layers.{l}.{name}.weight");
-                    LayerStorage {
-                        att_layernorm: tensor(&model, &name("input_layernorm"), dt, [d]),
-                        att_qkv: {
-                            let qkv = name("self_attn.qkv_proj");
-                            if model.contains(&qkv) {
-                                tensor(&model, &qkv, dt, [d + dkv + dkv, d])
-                            } else {
-                                let sq = &[nh, 2, dh / 2, d];
-                                let skv = &[nkvh, 2, dh / 2, d];
-                                let perm = &[0, 2, 1, 3];
-
-                                let q = tensor(&model, &name("self_attn.q_proj"), dt, [d, d])
-                                    .reshape(sq)
-                                    .transpose(perm);
-                                let k = tensor(&model, &name("self_attn.k_proj"), dt, [dkv, d])
-                                    .reshape(skv)
-                                    .transpose(perm);
-                                let v = tensor(&model, &name("self_attn.v_proj"), dt, [dkv, d])
-                                    .reshape(skv);
-                                concat0(&[q, k, v]).reshape(&[d + dkv + dkv, d])
-                            }
-                        }
-                        .transpose(&[1, 0]),
-                        att_o: tensor(&model, &name("self_attn.o_proj"), dt, [d, d])
-                            .transpose(&[1, 0]),
-                        mlp_layernorm: tensor(&model, &name("post_attention_layernorm"), dt, [d]),
-                        mlp_gate_up: {
-                            let gate_up = name("mlp.gate_up_proj");
-                            if model.contains(&gate_up) {
-                                tensor(&model, &gate_up, dt, [di + di, d])
-                            } else {
-                                concat0(&[
-                                    tensor(&model, &name("mlp.gate_proj"), dt, [di, d]),
-                                    tensor(&model, &name("mlp.up_proj"), dt, [di, d]),
-                                ])
-                            }
+                .map(|l| LayerStorage {
+                    att_layernorm: norm(AttLayernorm(l)),
+                    att_qkv: {
+                        let qkv = name(AttQKV(l));
+                        let qkv = if model.contains(&qkv) {
+                            matrix(&qkv, [dq + dkv + dkv, d])
+                        } else {
+                            concat0(&[
+                                matrix(&name(AttQ(l)), [dq, d]),
+                                matrix(&name(AttK(l)), [dkv, d]),
+                                matrix(&name(AttV(l)), [dkv, d]),
+                            ])
+                        };
+                        rope_rows(qkv, rope, nh + nkvh, dh, dr)
+                    }
+                    .transpose(&[1, 0]),
+                    att_qkv_bias: if arch.attention().qkv_bias {
+                        let qkv = name(AttQKVBias(l));
+                        let qkv = if model.contains(&qkv) {
+                            tensor(&model, &qkv, dt, [dq + dkv + dkv], block)
+                        } else {
+                            concat0(&[
+                                tensor(&model, &name(AttQBias(l)), dt, [dq], block),
+                                tensor(&model, &name(AttKBias(l)), dt, [dkv], block),
+                                tensor(&model, &name(AttVBias(l)), dt, [dkv], block),
+                            ])
+                        };
+                        Some(rope_rows(qkv, rope, nh + nkvh, dh, dr))
+                    } else {
+                        None
+                    },
+                    att_o: matrix(&name(AttO(l)), [d, dq]).transpose(&[1, 0]),
+                    att_post_layernorm: sandwich(AttPostLayernorm(l)),
+                    mlp_layernorm: norm(MlpLayernorm(l)),
+                    mlp_gate_up: {
+                        let gate_up = name(MlpGateUp(l));
+                        if model.contains(&gate_up) {
+                            matrix(&gate_up, [di + di, d])
+                        } else {
+                            concat0(&[
+                                matrix(&name(MlpGate(l)), [di, d]),
+                                matrix(&name(MlpUp(l)), [di, d]),
+                            ])
                         }
-                        .transpose(&[1, 0]),
-                        mlp_down: tensor(&model, &name("mlp.down_proj"), dt, [d, di])
-                            .transpose(&[1, 0]),
                     }
+                    .transpose(&[1, 0]),
+                    mlp_down: matrix(&name(MlpDown(l)), [d, di]).transpose(&[1, 0]),
+                    mlp_post_layernorm: sandwich(MlpPostLayernorm(l)),
+                })
+                .collect(),
+            lm_layernorm: norm(LmLayernorm),
+            lm_head,
+            adapters: adapters
+                .into_iter()
+                .map(|(name, lora)| {
+                    let layers = (0..config.num_hidden_layers)
+                        .map(|l| adapter_layer(&lora, l))
+                        .collect();
+                    (name, layers)
                 })
                 .collect(),
-            lm_layernorm: tensor(&model, "model.norm.weight", dt, [d]),
-            lm_head: tensor(&model, "lm_head.weight", dt, [voc, d]).transpose(&[1, 0]),
         })
     }
 }
 
+/// 加载张量，类型与 `dt` 不同时延迟转换为 `dt`。
+///
+/// 有 `{name}_scale` 或 `{name}_scale_inv` 的张量是带缩放系数的 FP8 权重，系数可以按张量、按行或按块，
+/// `block` 是 `quantization_config` 指定的块大小。
 fn tensor<const N: usize>(
     model: &Pin<Arc<SafeTensors>>,
     name: &str,
     dt: DigitLayout,
     shape: [udim; N],
+    block: Option<[usize; 2]>,
 ) -> Tensor<Weight> {
     let shared = model
         .share_tensor(name)
         .unwrap_or_else(|| panic!("missing tensor: {name}"));
-    assert_eq!(convert(shared.dtype()), dt);
     assert_eq!(
         &*shared.shape().iter().map(|&d| d as udim).collect::<Shape>(),
-        shape
+        shape,
+        "tensor {name} has shape {:?}, but the config requires {shape:?}",
+        shared.shape(),
+    );
+    let stored = convert(shared.dtype());
+    let t = Tensor::new(stored, &shape, Weight::SafeTensor(shared));
+    let scale = [format!("{name}_scale"), format!("{name}_scale_inv")]
+        .iter()
+        .find_map(|name| model.share_tensor(name));
+    match scale {
+        Some(scale) => {
+            let scale = block_scale(name, &scale, &shape, block);
+            if stored == dt && scale.values.len() == 1 {
+                let scale = scale.values[0];
+                t.map_physical(|w| Weight::Scaled(Box::new(w), scale))
+            } else {
+                cast_scaled(t, scale, dt)
+            }
+        }
+        None if stored == dt => t,
+        None => cast(t, dt),
+    }
+}
+
+/// 读取 `name` 的缩放系数。
+fn block_scale(
+    name: &str,
+    scale: &SharedTensor,
+    shape: &[udim],
+    block: Option<[usize; 2]>,
+) -> BlockScale {
+    use digit_layout::types::{BF16, F16, F32};
+
+    // 保存的缩放系数紧跟在 FP8 权重之后，不一定对齐
+    let values = match convert(scale.dtype()) {
+        F32 => scale
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+        F16 => scale
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        BF16 => scale
+            .chunks_exact(2)
+            .map(|b| bf16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(),
+        dt => panic!("scale of {name} has unsupported dtype {dt:?}"),
+    };
+    let [rows, cols] = match *shape {
+        [rows] => [rows as usize, 1],
+        [rows, cols] => [rows as usize, cols as usize],
+        _ => unreachable!(),
+    };
+    let grid = match *scale.shape() {
+        [] | [1] => [1, 1],
+        [r] => [r, 1],
+        [r, c] => [r, c],
+        _ => panic!("scale of {name} has shape {:?}", scale.shape()),
+    };
+    let block = block
+        .filter(|_| grid != [1, 1])
+        .unwrap_or([rows.div_ceil(grid[0]), cols.div_ceil(grid[1])]);
+    assert_eq!(
+        [rows.div_ceil(block[0]), cols.div_ceil(block[1])],
+        grid,
+        "scale of {name} has shape {grid:?}, but the weight is {shape:?} in blocks of {block:?}",
     );
-    Tensor::new(dt, &shape, Weight::SafeTensor(shared))
+    BlockScale {
+        values,
+        cols: grid[1],
+        block,
+    }
+}
+
+/// 权重是否来自本项目保存的文件，这样的文件已经变换过，直接使用。
+fn is_transformed(t: &Tensor<Weight>) -> bool {
+    fn rs(w: &Weight) -> bool {
+        match w {
+            Weight::SafeTensor(shared) => shared.format() == "rs",
+            Weight::Scaled(w, _) => rs(w),
+            Weight::Blob(_) | Weight::Lazy(_) => false,
+        }
+    }
+    rs(t.physical())
+}
+
+/// 重排 q、k 各头中施加旋转编码的行，使 rope 的每对维度相邻。
+///
+/// 原始模型中第 `i` 对维度是头内的第 `i` 和第 `i + dr / 2` 行，重排到第 `2i` 和 `2i + 1` 行；
+/// `heads` 个头之后的行（v）保持不变。
+fn rope_rows(
+    t: Tensor<Weight>,
+    rope: RopeVariant,
+    heads: udim,
+    dh: udim,
+    dr: udim,
+) -> Tensor<Weight> {
+    if rope.interleaved || is_transformed(&t) {
+        return t;
+    }
+    assert!(t.is_contiguous());
+    let line = t.bytes_size() / t.shape()[0] as usize;
+    let src = t.as_slice();
+    let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+    let dst = ans.physical_mut();
+    dst.copy_from_slice(src);
+    for h in 0..heads {
+        for i in 0..dr / 2 {
+            for k in 0..2 {
+                let from = (h * dh + k * dr / 2 + i) as usize * line;
+                let to = (h * dh + 2 * i + k) as usize * line;
+                dst[to..][..line].copy_from_slice(&src[from..][..line]);
+            }
+        }
+    }
+    ans.map_physical(|b| b.into())
+}
+
+/// 逐元素变换从原始模型文件加载的权重。
+fn transform(t: Tensor<Weight>, f: impl Fn(f32) -> f32 + Sync) -> Tensor<Weight> {
+    use digit_layout::types::{BF16, F16, F32};
+    use rayon::iter::*;
+    use tensor::{reslice, reslice_mut};
+
+    fn typed<T: BetweenF32 + Sync + Send>(
+        t: &Tensor<Weight>,
+        dst: &mut [u8],
+        f: impl Fn(f32) -> f32 + Sync,
+    ) {
+        reslice::<u8, T>(t.as_slice())
+            .par_iter()
+            .zip(reslice_mut::<u8, T>(dst))
+            .for_each(|(src, dst)| *dst = T::cast(f(src.get())));
+    }
+
+    if is_transformed(&t) {
+        return t;
+    }
+    assert!(t.is_contiguous());
+    let mut ans = Tensor::alloc(t.data_layout(), t.shape(), Blob::new);
+    match t.data_layout() {
+        F16 => typed::<f16>(&t, ans.physical_mut(), f),
+        BF16 => typed::<bf16>(&t, ans.physical_mut(), f),
+        F32 => typed::<f32>(&t, ans.physical_mut(), f),
+        F8_E4M3 => typed::<f8e4m3>(&t, ans.physical_mut(), f),
+        F8_E5M2 => typed::<f8e5m2>(&t, ans.physical_mut(), f),
+        _ => todo!(),
+    }
+    ans.map_physical(|b| b.into())
 }
 
 fn concat0(tensors: &[Tensor<Weight>]) -> Tensor<Weight> {
@@ -136,8 +473,8 @@ fn convert(dtype: Dtype) -> DigitLayout {
         Dtype::BOOL => BOOL,
         Dtype::U8 => U8,
         Dtype::I8 => I8,
-        Dtype::F8_E5M2 => DigitLayout::new(1, true, 5, 2),
-        Dtype::F8_E4M3 => DigitLayout::new(1, true, 4, 3),
+        Dtype::F8_E5M2 => F8_E5M2,
+        Dtype::F8_E4M3 => F8_E4M3,
         Dtype::I16 => I16,
         Dtype::U16 => U16,
         Dtype::F16 => F16,
@@ -0,0 +1,215 @@
+//! PEFT 格式的 LoRA 适配器。
+
+use crate::{cast::cast, json::LoraConfigJson, Weight};
+use common::{
+    bf16, f16, f8e4m3, f8e5m2,
+    safe_tensors::{Dtype, SafeTensor, SafeTensors},
+    BetweenF32, Blob,
+    FileLoadError::{self, Io, Json},
+    F8_E4M3, F8_E5M2,
+};
+use digit_layout::{
+    types::{BF16, F16, F32},
+    DigitLayout,
+};
+use rayon::{iter::*, slice::*};
+use std::{fs, fs::File, io::ErrorKind::NotFound, path::Path};
+use tensor::{reslice, reslice_mut, udim, Tensor};
+
+/// LoRA 适配器，加载时合并到基础模型的权重中，或作为常驻适配器按查询选用。
+pub(crate) struct Lora {
+    tensors: SafeTensors,
+    scale: f32,
+}
+
+impl Lora {
+    /// 加载目录中的 `adapter_config.json` 和 `adapter_model.safetensors`，目录中没有适配器时返回空。
+    pub fn load(dir: impl AsRef<Path>) -> Result<Option<Self>, FileLoadError> {
+        let config = match File::open(dir.as_ref().join("adapter_config.json")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == NotFound => return Ok(None),
+            Err(e) => return Err(Io(e)),
+        };
+        let config: LoraConfigJson = serde_json::from_reader(&config).map_err(Json)?;
+        assert!(
+            !config.fan_in_fan_out,
+            "fan_in_fan_out lora is not supported"
+        );
+        assert!(!config.use_dora, "DoRA is not supported");
+
+        let r = config.r as f32;
+        let scale = if config.use_rslora {
+            config.lora_alpha / r.sqrt()
+        } else {
+            config.lora_alpha / r
+        };
+        let tensors = SafeTensors::single_file(dir.as_ref().join("adapter_model.safetensors"))?;
+        Ok(Some(Self { tensors, scale }))
+    }
+
+    /// 加载 `dir` 下每个子目录中的适配器，以子目录名为适配器名，`dir` 不存在时返回空。
+    pub fn load_all(dir: impl AsRef<Path>) -> Result<Vec<(String, Self)>, FileLoadError> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == NotFound => return Ok(vec![]),
+            Err(e) => return Err(Io(e)),
+        };
+        let mut ans = vec![];
+        for entry in entries {
+            let entry = entry.map_err(Io)?;
+            if !entry.file_type().map_err(Io)?.is_dir() {
+                continue;
+            }
+            if let Some(lora) = Self::load(entry.path())? {
+                ans.push((entry.file_name().to_string_lossy().into_owned(), lora));
+            }
+        }
+        Ok(ans)
+    }
+
+    /// 提取输出拼接在一起的若干投影矩阵的低秩分解，`modules` 是各投影的名字和输出维度。
+    ///
+    /// 返回 `A`（`r x cols`）和缩放过的 `B`（`rows x r`），`r` 是各投影的秩之和，
+    /// `B` 中每个投影只占用自己的行和列；适配器不包含其中任何投影时返回空。
+    pub fn pair(
+        &self,
+        modules: &[(String, udim)],
+        cols: udim,
+        dt: DigitLayout,
+    ) -> Option<(Tensor<Weight>, Tensor<Weight>)> {
+        let cols = cols as usize;
+        let rows = modules.iter().map(|&(_, n)| n as usize).sum::<usize>();
+        let mut parts = vec![];
+        let mut row = 0;
+        for (name, n) in modules {
+            let n = *n;
+            let module = name.strip_suffix(".weight").unwrap_or(name);
+            let get = |ab: char| {
+                self.tensors
+                    .get(&format!("base_model.model.{module}.lora_{ab}.weight"))
+            };
+            if let (Some(a), Some(b)) = (get('A'), get('B')) {
+                let &[r, cols_] = a.shape else { panic!() };
+                assert_eq!(cols_, cols);
+                assert_eq!(b.shape, [n as usize, r]);
+                parts.push((row, n as usize, r, to_f32(&a), to_f32(&b)));
+            }
+            row += n as usize;
+        }
+        if parts.is_empty() {
+            return None;
+        }
+
+        let r = parts.iter().map(|&(_, _, r, _, _)| r).sum::<usize>();
+        let mut a_ = Vec::with_capacity(r * cols);
+        let mut b_ = vec![0.; rows * r];
+        let mut col = 0;
+        for (row, n, r_, a, b) in parts {
+            a_.extend_from_slice(&a);
+            for i in 0..n {
+                for k in 0..r_ {
+                    b_[(row + i) * r + col + k] = self.scale * b[i * r_ + k];
+                }
+            }
+            col += r_;
+        }
+        Some((
+            from_f32(&a_, dt, [r as _, cols as _]),
+            from_f32(&b_, dt, [rows as _, r as _]),
+        ))
+    }
+
+    /// 将适配器合并到名为 `name` 的权重（`W += scale * B A`），适配器不包含这个权重时原样返回。
+    pub fn merge(&self, name: &str, w: Tensor<Weight>) -> Tensor<Weight> {
+        let module = name.strip_suffix(".weight").unwrap_or(name);
+        let get = |ab: char| {
+            self.tensors
+                .get(&format!("base_model.model.{module}.lora_{ab}.weight"))
+        };
+        let (Some(a), Some(b)) = (get('A'), get('B')) else {
+            return w;
+        };
+
+        let &[rows, cols] = w.shape() else {
+            panic!("lora applies to matrices only: {name}")
+        };
+        let &[r, cols_] = a.shape else { panic!() };
+        assert_eq!(cols_, cols as usize);
+        assert_eq!(b.shape, [rows as usize, r]);
+        assert!(w.is_contiguous());
+
+        let a = to_f32(&a);
+        let b = to_f32(&b);
+        let mut ans = Tensor::alloc(w.data_layout(), w.shape(), Blob::new);
+        let (src, dst, cols) = (w.as_slice(), ans.physical_mut(), cols as usize);
+        match w.data_layout() {
+            F16 => typed::<f16>(src, dst, &a, &b, cols, self.scale),
+            BF16 => typed::<bf16>(src, dst, &a, &b, cols, self.scale),
+            F32 => typed::<f32>(src, dst, &a, &b, cols, self.scale),
+            F8_E4M3 => typed::<f8e4m3>(src, dst, &a, &b, cols, self.scale),
+            F8_E5M2 => typed::<f8e5m2>(src, dst, &a, &b, cols, self.scale),
+            _ => todo!(),
+        }
+        ans.map_physical(|b| b.into())
+    }
+}
+
+/// 逐行计算 `dst = w + scale * B A`，`a` 是 `r x cols`，`b` 是 `rows x r`。
+fn typed<T: BetweenF32 + Sync + Send>(
+    w: &[u8],
+    dst: &mut [u8],
+    a: &[f32],
+    b: &[f32],
+    cols: usize,
+    scale: f32,
+) {
+    let w = reslice::<u8, T>(w);
+    let dst = reslice_mut::<u8, T>(dst);
+    let r = a.len() / cols;
+    dst.par_chunks_mut(cols)
+        .zip(w.par_chunks(cols))
+        .zip(b.par_chunks(r))
+        .for_each(|((dst, w), b)| {
+            for (i, (dst, w)) in dst.iter_mut().zip(w).enumerate() {
+                let delta = b
+                    .iter()
+                    .enumerate()
+                    .map(|(k, b)| b * a[k * cols + i])
+                    .sum::<f32>();
+                *dst = T::cast(w.get() + scale * delta);
+            }
+        });
+}
+
+fn from_f32(data: &[f32], dt: DigitLayout, shape: [udim; 2]) -> Tensor<Weight> {
+    fn typed<T: BetweenF32 + Send>(data: &[f32], dst: &mut [u8]) {
+        reslice_mut::<u8, T>(dst)
+            .par_iter_mut()
+            .zip(data)
+            .for_each(|(dst, &x)| *dst = T::cast(x));
+    }
+    // FP8 需要按张量缩放
+    if matches!(dt, F8_E4M3 | F8_E5M2) {
+        return cast(from_f32(data, F32, shape), dt);
+    }
+    let mut ans = Tensor::alloc(dt, &shape, Blob::new);
+    match dt {
+        F16 => typed::<f16>(data, ans.physical_mut()),
+        BF16 => typed::<bf16>(data, ans.physical_mut()),
+        F32 => typed::<f32>(data, ans.physical_mut()),
+        _ => todo!(),
+    }
+    ans.map_physical(|b| b.into())
+}
+
+fn to_f32(t: &SafeTensor) -> Vec<f32> {
+    fn typed<T: BetweenF32 + Sync>(data: &[u8]) -> Vec<f32> {
+        reslice::<u8, T>(data).par_iter().map(T::get).collect()
+    }
+    match t.dtype {
+        Dtype::F16 => typed::<f16>(t.data),
+        Dtype::BF16 => typed::<bf16>(t.data),
+        Dtype::F32 => typed::<f32>(t.data),
+        dtype => panic!("unsupported lora dtype: {dtype:?}"),
+    }
+}
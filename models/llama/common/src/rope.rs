@@ -0,0 +1,57 @@
+//! 旋转位置编码的频率缩放。
+
+use common::upos;
+use tensor::udim;
+
+/// LongRoPE 逐维频率缩放（Phi-3 等模型使用）。
+///
+/// 第 `i` 对维度的频率为 `1 / (factor[i] * theta^(2i / dr))`，正余弦再乘以 `attention_factor`。
+#[derive(Clone, Debug)]
+pub struct LongRope {
+    /// 注意力长度不超过原始上下文长度时使用的缩放系数。
+    pub short_factor: Vec<f32>,
+    /// 注意力长度超过原始上下文长度时使用的缩放系数。
+    pub long_factor: Vec<f32>,
+    /// 模型预训练的上下文长度。
+    pub original_max_seq_len: udim,
+    /// 正余弦的缩放系数。
+    pub attention_factor: f32,
+}
+
+impl LongRope {
+    /// 生成位置 `pos` 处每对维度的 `(cos, sin)`，缩放系数由所在请求的注意力长度 `att_len` 决定。
+    pub fn sin_cos(
+        &self,
+        pos: upos,
+        att_len: udim,
+        theta: f32,
+        dr: udim,
+    ) -> impl Iterator<Item = [f32; 2]> + '_ {
+        let factor = if att_len > self.original_max_seq_len {
+            &self.long_factor
+        } else {
+            &self.short_factor
+        };
+        assert_eq!(factor.len(), dr as usize / 2);
+        factor.iter().enumerate().map(move |(i, f)| {
+            let freq = (f * theta.powf(2. * i as f32 / dr as f32)).recip();
+            let (sin, cos) = (pos as f32 * freq).sin_cos();
+            [cos * self.attention_factor, sin * self.attention_factor]
+        })
+    }
+}
+
+#[test]
+fn test_sin_cos() {
+    let rope = LongRope {
+        short_factor: vec![1., 1.],
+        long_factor: vec![2., 4.],
+        original_max_seq_len: 8,
+        attention_factor: 1.,
+    };
+    let short = rope.sin_cos(3, 8, 1e4, 4).collect::<Vec<_>>();
+    let long = rope.sin_cos(3, 9, 1e4, 4).collect::<Vec<_>>();
+    assert_eq!(short[0], [3f32.cos(), 3f32.sin()]);
+    assert_eq!(long[0], [1.5f32.cos(), 1.5f32.sin()]);
+    assert!(long[1][1] < short[1][1]);
+}
@@ -1,8 +1,15 @@
 ﻿use crate::{
-    json::{data_layout_name, ConfigJson},
+    json::{data_layout_name, ConfigJson, RopeScalingJson},
     Storage, Weight,
+    WeightName::{self, *},
+};
+use common::{
+    safe_tensors::{
+        Dtype, SafeTensors, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo,
+        CHECKSUMS_FILE,
+    },
+    FileLoadError, F8_E4M3, F8_E5M2,
 };
-use common::safe_tensors::{Dtype, SafeTensorsHeader, SafeTensorsHeaderMetadata, TensorInfo};
 use digit_layout::DigitLayout;
 use std::{
     collections::HashMap,
@@ -13,10 +20,15 @@ use std::{
 use tensor::Tensor;
 
 impl Storage {
-    pub fn save(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+    /// 保存模型到目录。
+    ///
+    /// 权重逐层写入，写入后即释放，延迟转换的权重不会同时占用整个模型的内存。
+    /// 写入完成后记录每个张量的校验和，加载时校验。
+    pub fn save(self, dir: impl AsRef<Path>) -> io::Result<()> {
         let dir = dir.as_ref();
         fs::create_dir_all(dir)?;
         let config = serde_json::to_string_pretty(&ConfigJson {
+            model_type: Some(self.config.arch.name().into()),
             bos_token_id: self.config.bos_token,
             eos_token_id: self.config.eos_token,
             hidden_size: self.config.d as _,
@@ -25,10 +37,34 @@ impl Storage {
             num_attention_heads: self.config.nh as _,
             num_hidden_layers: self.config.nlayers as _,
             num_key_value_heads: self.config.nkvh as _,
+            head_dim: (self.config.dh * self.config.nh != self.config.d)
+                .then_some(self.config.dh as _),
+            query_pre_attn_scalar: (self.config.att_scale
+                != (self.config.dh as f32).sqrt().recip())
+            .then(|| self.config.att_scale.powi(-2)),
+            attn_logit_softcapping: self.config.att_softcap,
+            final_logit_softcapping: self.config.final_softcap,
+            sliding_window: self.config.sliding_window.map(|w| w as _),
             vocab_size: self.config.voc as _,
             rms_norm_eps: self.config.epsilon,
             rope_theta: self.config.theta,
+            rope_ratio: None,
+            partial_rotary_factor: (self.config.dr != self.config.dh)
+                .then(|| self.config.dr as f32 / self.config.dh as f32),
+            original_max_position_embeddings: None,
+            rope_scaling: self.config.long_rope.as_ref().map(|r| RopeScalingJson {
+                ty: Some("longrope".into()),
+                rope_type: None,
+                short_factor: r.short_factor.clone(),
+                long_factor: r.long_factor.clone(),
+                attention_factor: Some(r.attention_factor),
+                original_max_position_embeddings: Some(r.original_max_seq_len as _),
+            }),
+            // 输出层总是单独保存，词表可能已经缩放过
+            tie_word_embeddings: Some(false),
             torch_dtype: data_layout_name(self.config.dt).to_string(),
+            // FP8 权重的缩放系数以 `{name}_scale` 保存，不需要额外的配置
+            quantization_config: None,
         })?;
         fs::write(dir.join("config.json"), config)?;
 
@@ -40,40 +76,59 @@ impl Storage {
             },
         };
 
-        let mut t = |tensor: &Tensor<Weight>| TensorInfo {
-            dtype: convert(tensor.data_layout()),
-            shape: tensor.shape().iter().map(|&d| d as _).collect(),
+        let mut info = |dtype, shape, size| TensorInfo {
+            dtype,
+            shape,
             data_offsets: {
                 let start = offset;
-                offset += tensor.bytes_size();
+                offset += size;
                 (start, offset)
             },
         };
+        // FP8 权重之后紧跟着它的缩放系数
+        let arch = self.config.arch;
+        let mut t = |name: WeightName, tensor: &Tensor<Weight>| {
+            let name = arch.weight_name(name);
+            let dtype = convert(tensor.data_layout());
+            let shape = tensor.shape().iter().map(|&d| d as _).collect();
+            let ans = info(dtype, shape, tensor.bytes_size());
+            let scale = is_f8(tensor).then(|| {
+                let info = info(Dtype::F32, vec![], size_of::<f32>());
+                (format!("{name}_scale"), info)
+            });
+            [Some((name, ans)), scale].into_iter().flatten()
+        };
 
-        header
-            .tensors
-            .insert("model.embed_tokens.weight".into(), t(&self.embed_tokens));
+        header.tensors.extend(t(EmbedTokens, &self.embed_tokens));
         for (i, l) in self.layers.iter().enumerate() {
             #[rustfmt::skip]
             let iter = [
-                ("input_layernorm"         , &l.att_layernorm),
-                ("self_attn.qkv_proj"      , &l.att_qkv    .clone().transpose(&[1, 0])),
-                ("self_attn.o_proj"        , &l.att_o      .clone().transpose(&[1, 0])),
-                ("post_attention_layernorm", &l.mlp_layernorm),
-                ("mlp.gate_up_proj"        , &l.mlp_gate_up.clone().transpose(&[1, 0])),
-                ("mlp.down_proj"           , &l.mlp_down   .clone().transpose(&[1, 0])),
+                (AttLayernorm(i), &l.att_layernorm),
+                (AttQKV      (i), &l.att_qkv    .clone().transpose(&[1, 0])),
+                (AttO        (i), &l.att_o      .clone().transpose(&[1, 0])),
+                (MlpLayernorm(i), &l.mlp_layernorm),
+                (MlpGateUp   (i), &l.mlp_gate_up.clone().transpose(&[1, 0])),
+                (MlpDown     (i), &l.mlp_down   .clone().transpose(&[1, 0])),
+            ];
+            header
+                .tensors
+                .extend(iter.into_iter().flat_map(|(name, tensor)| t(name, tensor)));
+            #[rustfmt::skip]
+            let iter = [
+                (AttQKVBias      (i), &l.att_qkv_bias      ),
+                (AttPostLayernorm(i), &l.att_post_layernorm),
+                (MlpPostLayernorm(i), &l.mlp_post_layernorm),
             ];
             header.tensors.extend(
-                iter.map(|(name, tensor)| (format!("model.layers.{i}.{name}.weight"), t(tensor))),
+                iter.into_iter()
+                    .filter_map(|(name, tensor)| Some((name, tensor.as_ref()?)))
+                    .flat_map(|(name, tensor)| t(name, tensor)),
             );
         }
-        header.tensors.extend([
-            ("model.norm.weight".into(), t(&self.lm_layernorm)),
-            (
-                "lm_head.weight".into(),
-                t(&self.lm_head.clone().transpose(&[1, 0])),
-            ),
-        ]);
+        header.tensors.extend(t(LmLayernorm, &self.lm_layernorm));
+        header
+            .tensors
+            .extend(t(LmHead, &self.lm_head.clone().transpose(&[1, 0])));
 
         let header = {
             let str = serde_json::to_string(&header)?;
@@ -92,23 +147,57 @@ impl Storage {
             buffer
         };
 
-        let mut file = fs::File::create(dir.join("model.safetensors"))?;
+        let path = dir.join("model.safetensors");
+        let mut file = fs::File::create(&path)?;
         file.write_all(&header)?;
-        file.write_all(self.embed_tokens.physical())?;
-        for l in self.layers.iter() {
-            file.write_all(l.att_layernorm.physical())?;
-            file.write_all(l.att_qkv.physical())?;
-            file.write_all(l.att_o.physical())?;
-            file.write_all(l.mlp_layernorm.physical())?;
-            file.write_all(l.mlp_gate_up.physical())?;
-            file.write_all(l.mlp_down.physical())?;
+        let mut write = |t: &Tensor<Weight>| {
+            file.write_all(t.physical())?;
+            if is_f8(t) {
+                file.write_all(&t.physical().scale().to_le_bytes())?;
+            }
+            io::Result::Ok(())
+        };
+        write(&self.embed_tokens)?;
+        drop(self.embed_tokens);
+        for l in self.layers {
+            write(&l.att_layernorm)?;
+            write(&l.att_qkv)?;
+            write(&l.att_o)?;
+            write(&l.mlp_layernorm)?;
+            write(&l.mlp_gate_up)?;
+            write(&l.mlp_down)?;
+            for t in [
+                &l.att_qkv_bias,
+                &l.att_post_layernorm,
+                &l.mlp_post_layernorm,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                write(t)?;
+            }
         }
-        file.write_all(self.lm_layernorm.physical())?;
-        file.write_all(self.lm_head.physical())?;
-        Ok(())
+        write(&self.lm_layernorm)?;
+        write(&self.lm_head)?;
+        drop(file);
+
+        let checksums = match SafeTensors::single_file(&path) {
+            Ok(model) => model.checksums(),
+            Err(FileLoadError::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{e:?}"))),
+        };
+        fs::write(
+            dir.join(CHECKSUMS_FILE),
+            serde_json::to_string_pretty(&checksums)?,
+        )
     }
 }
 
+#[inline]
+fn is_f8(t: &Tensor<Weight>) -> bool {
+    matches!(t.data_layout(), F8_E4M3 | F8_E5M2)
+}
+
 fn convert(dtype: DigitLayout) -> Dtype {
     use digit_layout::types::*;
     match dtype {
@@ -123,6 +212,8 @@ fn convert(dtype: DigitLayout) -> Dtype {
         U32 => Dtype::U32,
         F32 => Dtype::F32,
         F64 => Dtype::F64,
+        F8_E4M3 => Dtype::F8_E4M3,
+        F8_E5M2 => Dtype::F8_E5M2,
         I64 => Dtype::I64,
         U64 => Dtype::U64,
         _ => todo!(),
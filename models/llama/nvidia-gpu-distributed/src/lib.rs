@@ -6,18 +6,18 @@ mod parameters;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, FileLoadError};
+use causal_lm::{CausalLM, DecodingMeta, Model, ModelInfo, QueryContext, SampleMeta};
+use common::{upos, utok};
 use common_nv::{
     cuda::{
         AsRaw, Context, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device,
         HostMemSpore, Stream, StreamSpore,
     },
-    sample_nv, slice, split, udim, DropOption, Kernels, LocalSplitable, NvidiaKernels, Tensor,
+    sample_nv, slice, split, top_logprobs_cpu, udim, AttentionArgs, DropOption, Kernels, LoadError,
+    LocalSplitable, NvidiaKernels, PinnedPool, Tensor,
 };
-use digit_layout::types::F16;
 use itertools::izip;
-use llama::InferenceConfig;
+use llama::{InferenceConfig, MlpVariant, NormPlacement};
 use nccl::CommunicatorGroup;
 use parameters::{Layer, ParameterMatrix};
 use std::{
@@ -33,6 +33,7 @@ pub use common_nv::cuda;
 
 pub struct Transformer {
     config: InferenceConfig,
+    info: ModelInfo,
 
     comms: CommunicatorGroup,
     streams: Vec<StreamSpore>,
@@ -42,19 +43,46 @@ pub struct Transformer {
     matrix: ParameterMatrix,
     lm_layernorm: Tensor<DropOption<DevMemSpore>>,
     lm_head: Tensor<DropOption<DevMemSpore>>,
+    pinned: PinnedPool,
 }
 
 impl Model for Transformer {
     type Meta = Vec<Device>;
-    type Error = FileLoadError;
+    type Error = LoadError;
 
     #[inline]
     fn load(model_dir: impl AsRef<Path>, meta: Self::Meta) -> Result<Self, Self::Error> {
         let time = Instant::now();
         let host = llama::Storage::load_safetensors(model_dir)?;
+        let stored = host.config.quantization.unwrap_or(host.config.dt);
+        let host = host.dequantize();
+        let devices = meta
+            .iter()
+            .map(|dev| format!("cuda:{}", unsafe { dev.as_raw() }))
+            .collect::<Vec<_>>();
+        let info = ModelInfo::new(host.config.dt, stored, devices.join(","));
+        let arch = host.config.arch;
+        if arch.attention().qkv_bias
+            || arch.mlp() != MlpVariant::SwiGLU
+            || arch.norm() != NormPlacement::PreNorm
+            || host.config.dh * host.config.nh != host.config.d
+            || host.config.dr != host.config.dh
+            || host.config.long_rope.is_some()
+            || host.config.att_softcap.is_some()
+            || host.config.final_softcap.is_some()
+        {
+            return Err(LoadError::Unsupported(format!(
+                "{arch:?} is not supported by distributed inference yet"
+            )));
+        }
         info!("load host: {:?}", time.elapsed());
 
-        let kernels = NvidiaKernels::new(&meta, host.config.d as _, host.config.max_seq_len as _);
+        let kernels = NvidiaKernels::new(
+            &meta,
+            host.config.dt,
+            host.config.d as _,
+            host.config.max_seq_len as _,
+        )?;
 
         let contexts = meta
             .iter()
@@ -96,8 +124,10 @@ impl Model for Transformer {
             matrix,
             lm_layernorm,
             lm_head,
+            pinned: Default::default(),
 
             config: host.config,
+            info,
         })
     }
 }
@@ -113,6 +143,18 @@ impl CausalLM for Transformer {
     fn eos_token(&self) -> utok {
         self.config.eos_token
     }
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.config.voc as _
+    }
+    #[inline]
+    fn hidden_size(&self) -> usize {
+        self.config.d as _
+    }
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
 
     fn new_cache(&self) -> Tensor<Self::Storage> {
         let contexts = Arc::new(self.comms.contexts().collect::<Vec<_>>());
@@ -196,20 +238,8 @@ impl CausalLM for Transformer {
         Self: 'a,
     {
         let queries = queries.into_iter().collect::<Vec<_>>();
-        let mut nt = 0;
-        let mut max_seq_len = 0;
-        let mut max_att_len = 0;
-        let seq_len = queries
-            .iter()
-            .map(|q| {
-                let seq = q.seq_len();
-                let att = q.att_len();
-                nt += seq;
-                max_seq_len = max_seq_len.max(seq);
-                max_att_len = max_att_len.max(att);
-                seq
-            })
-            .collect::<Vec<_>>();
+        let seq_len = queries.iter().map(|q| q.seq_len()).collect::<Vec<_>>();
+        let nt = seq_len.iter().sum::<udim>();
         let seq_len = &seq_len;
 
         let dt = self.config.dt;
@@ -276,6 +306,7 @@ impl CausalLM for Transformer {
                                 .map(|(cache, range)| QueryContext {
                                     cache: cache.as_mut(),
                                     range: range.clone(),
+                                    adapter: None,
                                 })
                                 .collect::<Vec<_>>();
 
@@ -285,10 +316,6 @@ impl CausalLM for Transformer {
                             let mut state_buf = Tensor::alloc(dt, &[nt, d + reusing / n], |len| {
                                 stream.malloc::<u8>(len)
                             });
-                            let buf_len_common = (nh / n * max_seq_len) as usize * dt.nbytes();
-                            let mut q_buf = stream.malloc::<u8>(buf_len_common * dh as usize);
-                            let mut att_buf =
-                                stream.malloc::<u8>(buf_len_common * max_att_len as usize);
 
                             for layer in 0..self.config.nlayers as usize {
                                 let params = self.matrix.get(layer, i, ctx);
@@ -301,8 +328,6 @@ impl CausalLM for Transformer {
                                     &mut x,
                                     &mut state_buf,
                                     &pos,
-                                    &mut q_buf,
-                                    &mut att_buf,
                                     i,
                                     layer,
                                     nt,
@@ -327,8 +352,6 @@ impl CausalLM for Transformer {
                             }
 
                             pos.take_physical().drop_on(stream);
-                            att_buf.drop_on(stream);
-                            q_buf.drop_on(stream);
                             state_buf.take_physical().drop_on(stream);
                         })
                     })
@@ -412,7 +435,6 @@ impl CausalLM for Transformer {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
-        assert_eq!(logits.data_layout(), F16);
         let &[_nt, voc] = logits.shape() else {
             panic!()
         };
@@ -422,14 +444,26 @@ impl CausalLM for Transformer {
         contexts[0].apply(|ctx| {
             sample_nv(
                 args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
-                    .enumerate(),
+                    .flat_map(|meta| repeat((meta.args, meta.history)).take(meta.num_decode)),
                 mem[0].sprout_ref(ctx),
+                logits.data_layout(),
                 voc,
+                &self.pinned,
                 self.streams[0].sprout_ref(ctx),
             )
         })
     }
+
+    fn top_logprobs(&self, logits: &Tensor<Self::Storage>, k: usize) -> Vec<Vec<(utok, f32)>> {
+        let &[_nt, voc] = logits.shape() else {
+            panic!()
+        };
+        let Cache { contexts, mem } = logits.physical();
+
+        contexts[0].apply(|ctx| {
+            top_logprobs_cpu(mem[0].sprout_ref(ctx), logits.data_layout(), voc as _, k)
+        })
+    }
 }
 
 impl Transformer {
@@ -442,21 +476,17 @@ impl Transformer {
         x: &mut Tensor<&mut [DevByte]>,
         state_buf: &mut Tensor<DevMem>,
         pos: &Tensor<DevMem>,
-        q_buf: &mut DevMem,
-        att_buf: &mut DevMem,
         i: usize,
         layer: usize,
         nt: udim,
         stream: &Stream,
     ) {
-        let dt = self.config.dt;
         let d = self.config.d;
         let nh = self.config.nh;
         let nkvh = self.config.nkvh;
         let dh = d / nh;
         let dkv = nkvh * dh;
         let di = self.config.di;
-        let head_group = nh / nkvh;
         let head_div = (dh as f32).sqrt().recip();
         let theta = self.config.theta;
         let epsilon = self.config.epsilon;
@@ -495,30 +525,22 @@ impl Transformer {
 
             let slice_cat = &[slice![=>], slice![pos =>=> seq_len], slice![=>]];
             let slice_att = &[slice![=>], slice![      => att_len], slice![=>]];
-            let shape_q0 = &[nkvh / n * head_group, seq_len, dh];
-            let shape_q1 = &[nkvh / n, head_group * seq_len, dh];
-            let shape_att0 = &[nkvh / n, head_group * seq_len, att_len];
-            let shape_att1 = &[nkvh / n * head_group, seq_len, att_len];
 
-            let mut q_att = Tensor::new(dt, shape_q0, &mut q_buf[..]);
             let mut k_cat = k_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
             let mut v_cat = v_cache.as_mut().slice(slice_cat).map_physical(|u| &mut **u);
-            kernels.reform(&mut q_att, &q, stream);
             kernels.reform(&mut k_cat, &k, stream);
             kernels.reform(&mut v_cat, &v, stream);
 
-            let q_att = q_att.reshape(shape_q1);
-            let k_att = k_cache.slice(slice_att).transpose(&[0, 2, 1]);
+            let k_att = k_cache.slice(slice_att);
             let v_att = v_cache.slice(slice_att);
-
-            let mut att = Tensor::new(dt, shape_att0, &mut att_buf[..]);
-            kernels.mat_mul(&mut att, 0., &q_att, &k_att, head_div, stream);
-            let mut att = att.reshape(shape_att1);
-            kernels.softmax(&mut att, stream);
-            let mut x2 = q_att;
-            kernels.mat_mul(&mut x2, 0., &att.reshape(shape_att0), &v_att, 1., stream);
-
-            kernels.reform(&mut o, &x2.reshape(shape_q0), stream);
+            kernels.attention(
+                &mut o,
+                &q,
+                &k_att,
+                &v_att,
+                AttentionArgs::scale(head_div),
+                stream,
+            );
         }
 
         let (x1, _) = split!(state_buf.as_mut().map_physical(|u| LocalSplitable::from(&mut **u)); [1]: d, reusing / n);
@@ -581,6 +603,7 @@ impl Drop for Transformer {
         let contexts = self.comms.contexts().collect::<Vec<_>>();
         unsafe {
             contexts[0].apply(|ctx| {
+                self.pinned.clear(ctx);
                 self.embed_tokens.physical_mut().sprout(ctx);
                 self.lm_layernorm.physical_mut().sprout(ctx);
                 self.lm_head.physical_mut().sprout(ctx);
@@ -5,32 +5,35 @@ mod resource;
 #[macro_use]
 extern crate log;
 
-use causal_lm::{CausalLM, DecodingMeta, Model, QueryContext, SampleMeta};
-use common::{upos, utok, FileLoadError};
-use common_nv::{sample_nv, slice, udim, DropOption, Gpu, Kernels, NvidiaKernels, Tensor};
+use causal_lm::{CausalLM, DecodingMeta, Model, ModelInfo, QueryContext, SampleMeta};
+use common::{upos, utok};
+use common_nv::{
+    hidden_cpu, sample_nv, slice, top_logprobs_cpu, udim, DropOption, Gpu, Kernels, LoadError,
+    NvidiaKernels, PinnedPool, Tensor, TuneCache, TuneShapes,
+};
 use cuda::{
-    ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device, EventSpore, HostMemSpore,
-    Stream, StreamSpore,
+    AsRaw, ContextResource, ContextSpore, DevByte, DevMem, DevMemSpore, Device, EventSpore,
+    HostMemSpore, Stream, StreamSpore,
 };
-use digit_layout::types::F16;
 use llama::{ComputeConst, InferenceConfig, LayerStorage, SliceOn, Weight};
 use resource::Resource;
 use std::{
     cell::RefCell,
     collections::VecDeque,
     iter::repeat,
-    path::Path,
+    path::{Path, PathBuf},
     rc::Rc,
     slice::from_raw_parts,
     sync::{Arc, Mutex, MutexGuard},
     time::Instant,
 };
 
-pub use common_nv::{cuda, synchronize};
+pub use common_nv::{cuda, synchronize, OpTiming};
 pub use resource::Cache;
 
 pub struct Transformer {
     config: InferenceConfig,
+    info: ModelInfo,
 
     resource: Arc<Resource>,
     transfer: DropOption<StreamSpore>,
@@ -42,11 +45,14 @@ pub struct Transformer {
     lm_head: Tensor<DropOption<DevMemSpore>>,
 
     pool: Mutex<VecDeque<(LayerStorage<DevMemSpore>, EventSpore)>>,
+    pinned: PinnedPool,
 }
 
 pub struct ModelLoadMeta {
     pub device: Device,
     pub load_layers: usize,
+    /// 算子调优结果的缓存文件，指定时按模型的形状调优算子。
+    pub tune_cache: Option<PathBuf>,
 }
 
 impl ModelLoadMeta {
@@ -55,13 +61,14 @@ impl ModelLoadMeta {
         Self {
             device: Device::new(n),
             load_layers: usize::MAX,
+            tune_cache: None,
         }
     }
 }
 
 impl Model for Transformer {
     type Meta = ModelLoadMeta;
-    type Error = FileLoadError;
+    type Error = LoadError;
 
     #[inline]
     fn load(
@@ -69,12 +76,51 @@ impl Model for Transformer {
         Self::Meta {
             device,
             load_layers,
+            tune_cache,
         }: Self::Meta,
     ) -> Result<Self, Self::Error> {
         let time = Instant::now();
         let host = llama::Storage::load_safetensors(model_dir)?;
+        let stored = host.config.quantization.unwrap_or(host.config.dt);
+        let host = host.dequantize();
         info!("load host: {:?}", time.elapsed());
-        let load_layers = (load_layers as udim).min(host.config.nlayers);
+        let info = ModelInfo::new(
+            host.config.dt,
+            stored,
+            format!("cuda:{}", unsafe { device.as_raw() }),
+        );
+        // 至少常驻一层用于轮流复制其他层
+        let load_layers = (load_layers as udim).clamp(1, host.config.nlayers);
+        info!("{load_layers}/{} layers resident", host.config.nlayers);
+        let devices = std::slice::from_ref(&device);
+        let dt = host.config.dt;
+        let d = host.config.d;
+        let max_seq_len = host.config.max_seq_len;
+        let kernels = match tune_cache {
+            Some(path) => {
+                let time = Instant::now();
+                let mut cache = TuneCache::load(&path).map_err(LoadError::TuneCache)?;
+                // 以解码时最长的注意力为典型形状
+                let shapes = TuneShapes {
+                    rms_norm: [1, d],
+                    softmax: [host.config.nh, 1, max_seq_len],
+                };
+                let kernels = NvidiaKernels::tuned(
+                    devices,
+                    dt,
+                    d as _,
+                    max_seq_len as _,
+                    shapes,
+                    &mut cache,
+                )?;
+                if let Err(e) = cache.save() {
+                    warn!("Failed to save tune cache to {}: {e}", path.display());
+                }
+                info!("tune kernels: {:?}", time.elapsed());
+                kernels
+            }
+            None => NvidiaKernels::new(devices, dt, d as _, max_seq_len as _)?,
+        };
 
         device.set_mempool_threshold(u64::MAX);
         let resource = Arc::new(Resource::new(&device));
@@ -101,11 +147,7 @@ impl Model for Transformer {
                 .collect();
 
             Ok(Self {
-                kernels: NvidiaKernels::new(
-                    &[device],
-                    host.config.d as _,
-                    host.config.max_seq_len as _,
-                ),
+                kernels,
                 embed_tokens: host
                     .embed_tokens
                     .as_ref()
@@ -119,8 +161,10 @@ impl Model for Transformer {
                     .lm_head
                     .map_physical(|u| transfer.from_host(&u).sporulate().into()),
                 pool: Mutex::new(pool),
+                pinned: Default::default(),
 
                 config: host.config,
+                info,
                 resource: resource.clone(),
                 transfer: transfer.sporulate().into(),
             })
@@ -129,6 +173,19 @@ impl Model for Transformer {
 }
 
 impl Transformer {
+    /// 开启或关闭算子性能采集，参见 [`NvidiaKernels::set_profiling`]。
+    #[inline]
+    pub fn set_profiling(&self, enabled: bool) {
+        self.kernels.set_profiling(enabled);
+    }
+
+    /// 取出并清空采集到的算子耗时。
+    #[inline]
+    pub fn take_op_timings(&self) -> Vec<OpTiming> {
+        self.resource
+            .apply(|compute| self.kernels.take_op_timings(compute.ctx()))
+    }
+
     #[inline]
     fn cache(&self, len: usize) -> Cache {
         Cache::new(&self.resource, len)
@@ -151,6 +208,18 @@ impl CausalLM for Transformer {
     fn eos_token(&self) -> utok {
         self.config.eos_token
     }
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.config.voc as _
+    }
+    #[inline]
+    fn hidden_size(&self) -> usize {
+        self.config.d as _
+    }
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        self.info.clone()
+    }
 
     fn new_cache(&self) -> Tensor<Self::Storage> {
         self.config.new_cache(|len| self.cache(len))
@@ -205,16 +274,13 @@ impl CausalLM for Transformer {
             let ctx = compute.ctx();
             let transfer = self.transfer.as_ref().sprout_ref(ctx);
             let stream = ComputeStream {
-                nh: self.config.nh,
-                nkvh: self.config.nkvh,
-                di: self.config.di,
-                epsilon: self.config.epsilon,
-                theta: self.config.theta,
+                config: &self.config,
                 kernels: &self.kernels,
                 compute,
                 transfer,
                 host: &self.layers,
                 dev: Rc::new(RefCell::new(self.pool.lock().unwrap())),
+                pinned: &self.pinned,
             };
             <ComputeStream as llama::ComputeStream>::forward(&stream, queries, token_embedded)
         })
@@ -254,16 +320,14 @@ impl CausalLM for Transformer {
                 .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
             self.kernels
                 .rms_norm(&mut x, &x_, &lm_layernorm, self.config.epsilon, compute);
-            self.kernels.mat_mul(
-                &mut logits
-                    .as_mut()
-                    .map_physical(|u| &mut **u.mem.as_mut().sprout_mut(ctx)),
-                0.,
-                &x,
-                &lm_head,
-                1.,
-                compute,
-            );
+            let mut logits_ = logits
+                .as_mut()
+                .map_physical(|u| &mut **u.mem.as_mut().sprout_mut(ctx));
+            self.kernels
+                .mat_mul(&mut logits_, 0., &x, &lm_head, 1., compute);
+            if let Some(cap) = self.config.final_softcap {
+                self.kernels.softcap(&mut logits_, cap, compute);
+            }
 
             logits
         })
@@ -274,27 +338,66 @@ impl CausalLM for Transformer {
         args: impl IntoIterator<Item = SampleMeta>,
         logits: Tensor<Self::Storage>,
     ) -> Vec<utok> {
-        assert_eq!(logits.data_layout(), F16);
         let &[_nt, voc] = logits.shape() else {
             panic!()
         };
         let voc = voc as usize;
+        let dt = logits.data_layout();
 
         self.resource.apply(|compute| {
             sample_nv(
                 args.into_iter()
-                    .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
-                    .enumerate(),
+                    .flat_map(|meta| repeat((meta.args, meta.history)).take(meta.num_decode)),
                 logits
                     .take_physical()
                     .mem
                     .as_ref()
                     .sprout_ref(compute.ctx()),
+                dt,
                 voc,
+                &self.pinned,
                 compute,
             )
         })
     }
+
+    fn top_logprobs(&self, logits: &Tensor<Self::Storage>, k: usize) -> Vec<Vec<(utok, f32)>> {
+        let &[_nt, voc] = logits.shape() else {
+            panic!()
+        };
+        let dt = logits.data_layout();
+
+        self.resource.apply(|compute| {
+            top_logprobs_cpu(
+                logits.physical().mem.as_ref().sprout_ref(compute.ctx()),
+                dt,
+                voc as _,
+                k,
+            )
+        })
+    }
+
+    fn final_hidden(&self, mut hidden_state: Tensor<Self::Storage>) -> Option<Vec<f32>> {
+        let dt = hidden_state.data_layout();
+        Some(self.resource.apply(|compute| {
+            let ctx = compute.ctx();
+            let mut x = hidden_state
+                .as_mut()
+                .map_physical(|u| &mut **u.mem.as_mut().sprout_mut(ctx));
+            let lm_layernorm = self
+                .lm_layernorm
+                .as_ref()
+                .map_physical(|u| &**u.as_ref().sprout_ref(ctx));
+
+            // 复制一个 x 以实现原地归一化
+            let x_ = x
+                .as_ref()
+                .map_physical(|u| unsafe { from_raw_parts(u.as_ptr(), u.len()) });
+            self.kernels
+                .rms_norm(&mut x, &x_, &lm_layernorm, self.config.epsilon, compute);
+            hidden_cpu(x.physical(), dt)
+        }))
+    }
 }
 
 impl Drop for Transformer {
@@ -302,6 +405,8 @@ impl Drop for Transformer {
     fn drop(&mut self) {
         self.resource.apply(|compute| {
             let ctx = compute.ctx();
+            self.pinned.clear(ctx);
+            self.kernels.take_op_timings(ctx);
             self.transfer.sprout(ctx);
             self.embed_tokens.physical_mut().sprout(ctx);
             self.lm_layernorm.physical_mut().sprout(ctx);
@@ -313,6 +418,16 @@ impl Drop for Transformer {
                 layer.mlp_layernorm.take_physical().sprout(ctx);
                 layer.mlp_gate_up.take_physical().sprout(ctx);
                 layer.mlp_down.take_physical().sprout(ctx);
+                for t in [
+                    layer.att_qkv_bias,
+                    layer.att_post_layernorm,
+                    layer.mlp_post_layernorm,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    t.take_physical().sprout(ctx);
+                }
             }
             let mut pool = self.pool.lock().unwrap();
             while let Some((layer, event)) = pool.pop_front() {
@@ -322,6 +437,16 @@ impl Drop for Transformer {
                 layer.mlp_layernorm.take_physical().sprout(ctx);
                 layer.mlp_gate_up.take_physical().sprout(ctx);
                 layer.mlp_down.take_physical().sprout(ctx);
+                for t in [
+                    layer.att_qkv_bias,
+                    layer.att_post_layernorm,
+                    layer.mlp_post_layernorm,
+                ]
+                .into_iter()
+                .flatten()
+                {
+                    t.take_physical().sprout(ctx);
+                }
                 event.sprout(ctx);
             }
         });
@@ -329,16 +454,13 @@ impl Drop for Transformer {
 }
 
 struct ComputeStream<'a> {
-    nh: udim,
-    nkvh: udim,
-    di: udim,
-    epsilon: f32,
-    theta: f32,
+    config: &'a InferenceConfig,
     kernels: &'a NvidiaKernels,
     compute: &'a Stream<'a>,
     transfer: &'a Stream<'a>,
     host: &'a [LayerStorage<HostMemSpore>],
     dev: DevMemPool<'a>,
+    pinned: &'a PinnedPool,
 }
 
 type DevMemPool<'a> =
@@ -363,7 +485,7 @@ impl<'a> llama::ComputeStream for ComputeStream<'a> {
     where
         Self: 'b,
     {
-        self.compute.from_host(pos)
+        self.pinned.upload(pos, self.compute)
     }
     #[inline]
     fn free_pos(&self, mem: Self::Pos<'_>) {
@@ -384,11 +506,20 @@ impl<'a> llama::ComputeStream for ComputeStream<'a> {
     #[inline]
     fn constant(&self) -> ComputeConst {
         ComputeConst {
-            nh: self.nh,
-            nkvh: self.nkvh,
-            di: self.di,
-            epsilon: self.epsilon,
-            theta: self.theta,
+            nh: self.config.nh,
+            nkvh: self.config.nkvh,
+            dh: self.config.dh,
+            dr: self.config.dr,
+            di: self.config.di,
+            epsilon: self.config.epsilon,
+            theta: self.config.theta,
+            long_rope: self.config.long_rope.clone(),
+            att_scale: self.config.att_scale,
+            att_softcap: self.config.att_softcap,
+            sliding_window: self.config.sliding_window,
+            window_layers: self.config.arch.attention().sliding_window,
+            mlp: self.config.arch.mlp(),
+            norm: self.config.arch.norm(),
         }
     }
 
@@ -473,6 +604,12 @@ macro_rules! access {
             .as_ref()
             .map_physical(|u| &**u.sprout_ref($self.transfer.ctx()))
     };
+    ($self:expr, ?$name:ident) => {
+        $self.storage.as_ref().unwrap().$name.as_ref().map(|t| {
+            t.as_ref()
+                .map_physical(|u| &**u.sprout_ref($self.transfer.ctx()))
+        })
+    };
 }
 impl<'a> llama::LLamaLayer for LayerLoader<'a> {
     type Byte = DevByte;
@@ -484,9 +621,15 @@ impl<'a> llama::LLamaLayer for LayerLoader<'a> {
     fn att_qkv(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, att_qkv)
     }
+    fn att_qkv_bias(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        access!(self, ?att_qkv_bias)
+    }
     fn att_o(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, att_o)
     }
+    fn att_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        access!(self, ?att_post_layernorm)
+    }
     fn mlp_layernorm(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, mlp_layernorm)
     }
@@ -496,6 +639,9 @@ impl<'a> llama::LLamaLayer for LayerLoader<'a> {
     fn mlp_down(&self) -> Tensor<Self::Storage<'_>> {
         access!(self, mlp_down)
     }
+    fn mlp_post_layernorm(&self) -> Option<Tensor<Self::Storage<'_>>> {
+        access!(self, ?mlp_post_layernorm)
+    }
 }
 
 impl Drop for LayerLoader<'_> {
@@ -503,12 +649,18 @@ impl Drop for LayerLoader<'_> {
         let mut lll = self.storage.take().unwrap();
         if let Some(load) = self.load {
             macro_rules! exchange {
-                ($($name:ident)+) => {
+                ($($name:ident)+; $($option:ident)*) => {
                     $(
                         let host = self.host[load].$name.physical();
                         let mut dev = lll.$name.physical_mut().sprout_mut(self.transfer.ctx());
                         self.transfer.memcpy_h2d(&mut dev, host);
                     )+
+                    $(
+                        if let (Some(host), Some(dev)) = (&self.host[load].$option, &mut lll.$option) {
+                            let mut dev = dev.physical_mut().sprout_mut(self.transfer.ctx());
+                            self.transfer.memcpy_h2d(&mut dev, host.physical());
+                        }
+                    )*
                 };
             }
             exchange! {
@@ -518,6 +670,10 @@ impl Drop for LayerLoader<'_> {
                 mlp_layernorm
                 mlp_gate_up
                 mlp_down
+                ;
+                att_qkv_bias
+                att_post_layernorm
+                mlp_post_layernorm
             }
         }
         self.pool
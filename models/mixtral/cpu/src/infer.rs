@@ -1,5 +1,5 @@
 use super::MixtralCPU;
-use causal_lm::{CausalLM, DecodingMeta, QueryContext, SampleMeta};
+use causal_lm::{top_logprobs, CausalLM, DecodingMeta, ModelInfo, QueryContext, SampleMeta};
 use common::{f16, upos, utok, Blob};
 use common_cpu::{Kernels, ThisThread};
 use digit_layout::{types::U32, DigitLayout};
@@ -18,6 +18,18 @@ impl CausalLM for MixtralCPU {
     fn max_seq_len(&self) -> upos {
         self.max_seq_len
     }
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.voc as _
+    }
+    #[inline]
+    fn hidden_size(&self) -> usize {
+        self.d as _
+    }
+    #[inline]
+    fn info(&self) -> ModelInfo {
+        ModelInfo::new(self.data_type, self.data_type, "cpu")
+    }
 
     fn new_cache(&self) -> Tensor<Self::Storage> {
         let dt = self.data_type;
@@ -289,9 +301,20 @@ impl CausalLM for MixtralCPU {
         let &[_, voc] = logits.shape() else { panic!() };
         let logits: &[f16] = reslice(logits.as_slice());
         args.into_iter()
-            .flat_map(|meta| repeat(meta.args).take(meta.num_decode))
+            .flat_map(|meta| repeat((meta.args, meta.history)).take(meta.num_decode))
             .enumerate()
-            .map(|(i, args)| args.random(&common_cpu::slice!(logits; voc; [i])))
+            .map(|(i, (args, history))| {
+                args.sample(&common_cpu::slice!(logits; voc; [i]), &history)
+            })
+            .collect()
+    }
+
+    fn top_logprobs(&self, logits: &Tensor<Self::Storage>, k: usize) -> Vec<Vec<(utok, f32)>> {
+        let &[_, voc] = logits.shape() else { panic!() };
+        let logits: &[f16] = reslice(logits.as_slice());
+        logits
+            .chunks_exact(voc as usize)
+            .map(|row| top_logprobs(row, k))
             .collect()
     }
 }
@@ -11,6 +11,7 @@ use tensor::udim;
 pub struct MixtralCPU {
     eos_token: utok,
     data_type: DigitLayout,
+    voc: udim,
     nlayers: udim,
     nh: udim,
     nkvh: udim,
@@ -35,6 +36,7 @@ impl Model for MixtralCPU {
         Ok(Self {
             eos_token: config.eos_token_id,
             data_type: config.data_layout(),
+            voc: config.vocab_size as _,
             nlayers: config.num_hidden_layers as _,
             nh: config.num_attention_heads as _,
             nkvh: config.num_key_value_heads as _,
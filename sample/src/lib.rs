@@ -1,7 +1,17 @@
 #![deny(warnings)]
 
+mod processor;
 mod sample;
 
+pub use processor::{LogitsProcessor, ProcessorChain};
+pub use sample::top_logprobs;
+
+use common::utok;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 /// 采样参数。
 #[derive(Clone, PartialEq, Debug)]
 pub struct SampleArgs {
@@ -11,6 +21,22 @@ pub struct SampleArgs {
     pub top_k: usize,
     /// 软阈值，(0, 1] 区间有效，不大于 0 使用贪心采样。
     pub top_p: f32,
+    /// 相对阈值，丢弃概率低于最大概率 `min_p` 倍的 token，0 不过滤。
+    pub min_p: f32,
+    /// 局部典型采样阈值，(0, 1) 区间有效，只保留信息量最接近熵、累计概率达到阈值的 token，1 不过滤。
+    pub typical_p: f32,
+    /// 重复惩罚，大于 1 时压低最近出现过的 token 的概率，1 不惩罚。
+    pub repetition_penalty: f32,
+    /// 重复惩罚考虑的最近 token 数。
+    pub repetition_window: usize,
+    /// 频率惩罚，按 token 在本次生成中出现的次数从 logit 中减去，0 不惩罚。
+    pub frequency_penalty: f32,
+    /// 存在惩罚，从本次生成中出现过的 token 的 logit 中减去，0 不惩罚。
+    pub presence_penalty: f32,
+    /// 随机数种子，指定时相同的输入和参数生成相同的结果。
+    pub seed: Option<u64>,
+    /// 采样前加到指定 token 的 logit 上的偏置，负无穷禁止生成该 token。
+    pub logit_bias: HashMap<utok, f32>,
 }
 
 impl Default for SampleArgs {
@@ -21,6 +47,76 @@ impl Default for SampleArgs {
             temperature: 0.,
             top_k: usize::MAX,
             top_p: 1.,
+            min_p: 0.,
+            typical_p: 1.,
+            repetition_penalty: 1.,
+            repetition_window: 64,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            seed: None,
+            logit_bias: HashMap::new(),
         }
     }
 }
+
+/// 采样时参考的历史 token。
+#[derive(Clone, Default, Debug)]
+pub struct History {
+    /// 对话中最近的 token，按出现顺序排列，长度至少为 [`SampleArgs::history_len`]（对话足够长时）。
+    pub tokens: Vec<utok>,
+    /// 本次推理已经生成的 token 数，其中不超过 `tokens` 长度的部分位于 `tokens` 末尾。
+    pub generated: usize,
+    /// 允许生成的 token，`mask[t]` 为假的 token 不会被采样，为空时不限制。
+    pub mask: Option<Arc<[bool]>>,
+    /// 在内置的惩罚和偏置之后、掩码之前执行的处理器链，`tokens` 为上下文窗口中的全部 token。
+    pub processors: Option<Arc<Mutex<ProcessorChain>>>,
+    /// 无分类器引导，在其他处理之前合并无条件上下文的分布。
+    pub guidance: Option<Guidance>,
+}
+
+/// 无分类器引导，按 `scale` 把分布从无条件上下文推向条件上下文：
+/// `log p = log p_u + scale * (log p_c - log p_u)`。
+#[derive(Clone, Debug)]
+pub struct Guidance {
+    /// 引导系数，1 等于不引导，越大越远离无条件上下文。
+    pub scale: f32,
+    /// 无条件上下文（如负面提示词）中下一个 token 的对数概率，按 token 序号排列。
+    pub unconditioned: Arc<[f32]>,
+}
+
+impl History {
+    /// 采样前是否需要在主机上处理 logits。
+    #[inline]
+    pub fn needs_processing(&self) -> bool {
+        self.mask.is_some() || self.processors.is_some() || self.guidance.is_some()
+    }
+}
+
+/// 内置采样预设的名字。
+pub const PRESETS: [&str; 3] = ["precise", "balanced", "creative"];
+
+impl SampleArgs {
+    /// 按名字获取内置的采样预设，名字见 [`PRESETS`]。
+    pub fn preset(name: &str) -> Option<Self> {
+        let (temperature, top_k, top_p) = match name {
+            "precise" => (0.2, 10, 0.5),
+            "balanced" => (0.7, 40, 0.9),
+            "creative" => (1.0, 100, 0.95),
+            _ => return None,
+        };
+        Some(Self {
+            temperature,
+            top_k,
+            top_p,
+            ..Default::default()
+        })
+    }
+}
+
+#[test]
+fn test_preset() {
+    for name in PRESETS {
+        assert!(!SampleArgs::preset(name).unwrap().is_argmax());
+    }
+    assert!(SampleArgs::preset("unknown").is_none());
+}
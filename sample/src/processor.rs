@@ -0,0 +1,83 @@
+use common::utok;
+use std::fmt;
+
+/// 采样前处理 logits 的处理器，实现它可以定制解码而不必修改采样器。
+pub trait LogitsProcessor: Send {
+    /// 处理下一个 token 的 `logits`，`tokens` 是上下文中的 token，按出现顺序排列。
+    fn process(&mut self, tokens: &[utok], logits: &mut [f32]);
+}
+
+impl<F> LogitsProcessor for F
+where
+    F: FnMut(&[utok], &mut [f32]) + Send,
+{
+    #[inline]
+    fn process(&mut self, tokens: &[utok], logits: &mut [f32]) {
+        self(tokens, logits)
+    }
+}
+
+/// 按加入顺序执行的 logits 处理器链。
+#[derive(Default)]
+pub struct ProcessorChain(Vec<Box<dyn LogitsProcessor>>);
+
+impl ProcessorChain {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 在链尾加入处理器。
+    #[inline]
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'static) -> &mut Self {
+        self.0.push(Box::new(processor));
+        self
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// 依次执行所有处理器。
+    pub fn process(&mut self, tokens: &[utok], logits: &mut [f32]) {
+        for processor in &mut self.0 {
+            processor.process(tokens, logits)
+        }
+    }
+}
+
+impl FromIterator<Box<dyn LogitsProcessor>> for ProcessorChain {
+    #[inline]
+    fn from_iter<T: IntoIterator<Item = Box<dyn LogitsProcessor>>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl fmt::Debug for ProcessorChain {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProcessorChain({})", self.0.len())
+    }
+}
+
+#[test]
+fn test_chain() {
+    let mut chain = ProcessorChain::new();
+    chain
+        .push(|_: &[utok], logits: &mut [f32]| logits[0] += 1.)
+        .push(|tokens: &[utok], logits: &mut [f32]| {
+            for &t in tokens {
+                logits[t as usize] *= 2.
+            }
+        });
+    let mut logits = [1., 2., 3.];
+    chain.process(&[0, 2], &mut logits);
+    // 按加入顺序执行
+    assert_eq!(logits, [4., 2., 6.]);
+}
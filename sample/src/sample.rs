@@ -1,5 +1,10 @@
-﻿use common::{utok, BetweenF32};
-use std::{cmp::Ordering, mem::replace};
+﻿use crate::History;
+use common::{utok, BetweenF32};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
 
 impl crate::SampleArgs {
     #[inline]
@@ -7,7 +12,112 @@ impl crate::SampleArgs {
         self.temperature <= 0. || self.top_k < 2 || self.top_p <= 0.
     }
 
-    pub fn random<T>(&self, logits: &[T]) -> utok
+    /// 采样前是否需要参考历史处理 logits。
+    #[inline]
+    pub fn needs_processing(&self) -> bool {
+        self.repetition_penalty != 1. || self.penalizes_generated() || !self.logit_bias.is_empty()
+    }
+
+    /// 是否只用到温度、top-k、top-p，可以直接由设备上的采样核完成。
+    #[inline]
+    pub fn device_samplable(&self) -> bool {
+        !self.needs_processing() && self.min_p <= 0. && self.typical_p >= 1.
+    }
+
+    /// 已经生成了 `generated` 个 token 时，采样需要参考的最近 token 数。
+    pub fn history_len(&self, generated: usize) -> usize {
+        let mut len = 0;
+        if self.repetition_penalty != 1. {
+            len = self.repetition_window;
+        }
+        if self.penalizes_generated() {
+            len = len.max(generated);
+        }
+        len
+    }
+
+    #[inline]
+    fn penalizes_generated(&self) -> bool {
+        self.frequency_penalty != 0. || self.presence_penalty != 0.
+    }
+
+    /// 参考 `history` 处理 logits 后采样。
+    pub fn sample<T>(&self, logits: &[T], history: &History) -> utok
+    where
+        T: BetweenF32 + PartialOrd,
+    {
+        let step = history.generated;
+        if !self.needs_processing() && !history.needs_processing() {
+            return self.random(logits, step);
+        }
+        let mut logits = logits.iter().map(BetweenF32::get).collect::<Vec<_>>();
+        self.process(&mut logits, history);
+        self.random(&logits, step)
+    }
+
+    /// 生成第 `step` 个 token 时使用的 [0, 1) 区间随机数，指定了种子时由种子和 `step` 唯一确定。
+    pub fn uniform(&self, step: usize) -> f32 {
+        match self.seed {
+            Some(seed) => {
+                let step = (step as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                StdRng::seed_from_u64(seed ^ step).gen()
+            }
+            None => rand::random(),
+        }
+    }
+
+    /// 在 logits 上依次施加引导、惩罚、偏置、处理器链和掩码。
+    fn process(&self, logits: &mut [f32], history: &History) {
+        if let Some(guidance) = &history.guidance {
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let lse = max + logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+            for (x, &u) in logits.iter_mut().zip(&*guidance.unconditioned) {
+                *x = u + guidance.scale * (*x - lse - u);
+            }
+        }
+        if self.repetition_penalty != 1. {
+            let p = self.repetition_penalty;
+            let start = history.tokens.len().saturating_sub(self.repetition_window);
+            let seen = history.tokens[start..].iter().collect::<HashSet<_>>();
+            for &tok in seen {
+                // 正的 logit 缩小，负的 logit 放大，都使概率降低
+                if let Some(x) = logits.get_mut(tok as usize) {
+                    *x = if *x > 0. { *x / p } else { *x * p };
+                }
+            }
+        }
+        if self.penalizes_generated() {
+            let len = history.tokens.len();
+            let generated = &history.tokens[len - history.generated.min(len)..];
+            let mut counts = HashMap::<utok, usize>::new();
+            for &tok in generated {
+                *counts.entry(tok).or_default() += 1;
+            }
+            for (tok, n) in counts {
+                if let Some(x) = logits.get_mut(tok as usize) {
+                    *x -= n as f32 * self.frequency_penalty + self.presence_penalty;
+                }
+            }
+        }
+        for (&tok, &bias) in &self.logit_bias {
+            if let Some(x) = logits.get_mut(tok as usize) {
+                *x += bias;
+            }
+        }
+        if let Some(processors) = &history.processors {
+            processors.lock().unwrap().process(&history.tokens, logits);
+        }
+        if let Some(mask) = &history.mask {
+            for (i, x) in logits.iter_mut().enumerate() {
+                if mask.get(i) != Some(&true) {
+                    *x = f32::NEG_INFINITY;
+                }
+            }
+        }
+    }
+
+    /// 不处理 logits，直接采样生成第 `step` 个 token。
+    pub fn random<T>(&self, logits: &[T], step: usize) -> utok
     where
         T: BetweenF32 + PartialOrd,
     {
@@ -58,16 +168,234 @@ impl crate::SampleArgs {
             .map(Probability::from)
             .collect::<Vec<_>>();
         logits.sort_unstable();
-        let max = replace(&mut logits[0].val, 1.);
-        // softmax & sum
+        // softmax，不归一化，最大的概率为 1
+        let max = logits[0].val;
+        for p in &mut logits {
+            p.val = ((p.val - max) / self.temperature).exp();
+        }
+        // minp
+        let len = logits.partition_point(|p| p.val >= self.min_p).max(1);
+        logits.truncate(len);
+        // typical：保留负对数概率最接近熵的 token，直到累计概率达到 typical_p
+        if self.typical_p < 1. {
+            let sum = logits.iter().map(|p| p.val).sum::<f32>();
+            let surprise = |p: &Probability| -(p.val / sum).ln();
+            let entropy = logits
+                .iter()
+                .filter(|p| p.val > 0.)
+                .map(|p| p.val / sum * surprise(p))
+                .sum::<f32>();
+            let mut order = (0..logits.len()).collect::<Vec<_>>();
+            order.sort_by(|&i, &j| {
+                let di = (surprise(&logits[i]) - entropy).abs();
+                let dj = (surprise(&logits[j]) - entropy).abs();
+                di.total_cmp(&dj)
+            });
+            let mut keep = vec![false; logits.len()];
+            let mut mass = 0.;
+            for i in order {
+                keep[i] = true;
+                mass += logits[i].val;
+                if mass >= sum * self.typical_p {
+                    break;
+                }
+            }
+            let mut keep = keep.into_iter();
+            logits.retain(|_| keep.next().unwrap());
+        }
+        // sum
         for i in 1..logits.len() {
-            logits[i].val = logits[i - 1].val + ((logits[i].val - max) / self.temperature).exp();
+            logits[i].val += logits[i - 1].val;
         }
         // topk & topp & random
         let pk = logits[self.top_k.min(logits.len()) - 1].val;
         let pp = logits[logits.len() - 1].val * self.top_p;
-        let plimit = rand::random::<f32>() * f32::min(pk, pp);
+        let plimit = self.uniform(step) * f32::min(pk, pp);
         // sample
         logits.iter().find(|p| p.val >= plimit).unwrap().tok
     }
 }
+
+/// 计算一行 logits 的对数概率，返回最大的 `k` 个 token 及其对数概率，从大到小排列。
+pub fn top_logprobs<T: BetweenF32>(logits: &[T], k: usize) -> Vec<(utok, f32)> {
+    let logits = logits.iter().map(BetweenF32::get).collect::<Vec<_>>();
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let lse = max + logits.iter().map(|x| (x - max).exp()).sum::<f32>().ln();
+
+    let mut ans = logits
+        .into_iter()
+        .enumerate()
+        .map(|(i, x)| (i as utok, x - lse))
+        .collect::<Vec<_>>();
+    let k = k.min(ans.len());
+    let cmp = |a: &(utok, f32), b: &(utok, f32)| b.1.total_cmp(&a.1);
+    if k < ans.len() {
+        ans.select_nth_unstable_by(k, cmp);
+        ans.truncate(k);
+    }
+    ans.sort_unstable_by(cmp);
+    ans
+}
+
+#[test]
+fn test_repetition_penalty() {
+    let args = crate::SampleArgs {
+        repetition_penalty: 2.,
+        repetition_window: 2,
+        ..Default::default()
+    };
+    let history = History {
+        tokens: vec![0, 1, 1],
+        generated: 0,
+        ..Default::default()
+    };
+    let mut logits = [4., 3., -1.];
+    args.process(&mut logits, &history);
+    // token 0 在窗口外，token 1 只惩罚一次
+    assert_eq!(logits, [4., 1.5, -1.]);
+
+    // 超出词表的 token 被忽略
+    let history = History {
+        tokens: vec![7],
+        generated: 0,
+        ..Default::default()
+    };
+    let mut logits = [4., 3., -1.];
+    args.process(&mut logits, &history);
+    assert_eq!(logits, [4., 3., -1.]);
+
+    // 贪心采样也先施加惩罚
+    let history = History {
+        tokens: vec![0],
+        generated: 0,
+        ..Default::default()
+    };
+    assert_eq!(args.sample(&[4f32, 3., -1.], &history), 1);
+}
+
+#[test]
+fn test_frequency_presence_penalty() {
+    let args = crate::SampleArgs {
+        frequency_penalty: 0.5,
+        presence_penalty: 1.,
+        ..Default::default()
+    };
+    // 只有末尾 4 个是生成的 token，超出词表的 token 被忽略
+    let history = History {
+        tokens: vec![2, 0, 1, 1, 9],
+        generated: 4,
+        ..Default::default()
+    };
+    let mut logits = [4., 3., -1.];
+    args.process(&mut logits, &history);
+    assert_eq!(logits, [2.5, 1., -1.]);
+}
+
+#[test]
+fn test_min_p() {
+    let args = crate::SampleArgs {
+        temperature: 1.,
+        top_k: usize::MAX,
+        top_p: 1.,
+        min_p: 0.5,
+        ..Default::default()
+    };
+    // 除最大者外的概率都低于最大概率的一半
+    let logits = [0., 5., 1., 2.];
+    for _ in 0..16 {
+        assert_eq!(args.random(&logits, 0), 1);
+    }
+}
+
+#[test]
+fn test_typical_p() {
+    let args = crate::SampleArgs {
+        temperature: 1.,
+        top_k: usize::MAX,
+        top_p: 1.,
+        typical_p: 0.5,
+        ..Default::default()
+    };
+    // 最大者的信息量远低于熵，不是典型的 token
+    let mut logits = vec![1.; 21];
+    logits[0] = 3.;
+    for _ in 0..16 {
+        assert_ne!(args.random(&logits, 0), 0);
+    }
+}
+
+#[test]
+fn test_seed() {
+    let args = crate::SampleArgs {
+        temperature: 1.,
+        top_k: usize::MAX,
+        top_p: 1.,
+        seed: Some(42),
+        ..Default::default()
+    };
+    let logits = [1.; 1024];
+    let a = (0..16).map(|i| args.random(&logits, i)).collect::<Vec<_>>();
+    let b = (0..16).map(|i| args.random(&logits, i)).collect::<Vec<_>>();
+    assert_eq!(a, b);
+    // 不同步的随机数不同
+    assert!(a.windows(2).any(|w| w[0] != w[1]));
+}
+
+#[test]
+fn test_logit_bias() {
+    let args = crate::SampleArgs {
+        logit_bias: [(0, f32::NEG_INFINITY), (2, 3.), (9, 1.)].into(),
+        ..Default::default()
+    };
+    let mut logits = [4., 3., 1.];
+    args.process(&mut logits, &History::default());
+    assert_eq!(logits, [f32::NEG_INFINITY, 3., 4.]);
+    assert_eq!(args.sample(&[4., 3., 1.], &History::default()), 2);
+}
+
+#[test]
+fn test_mask() {
+    let args = crate::SampleArgs::default();
+    let history = History {
+        mask: Some([false, true].into()),
+        ..Default::default()
+    };
+    // 超出掩码长度的 token 也不允许
+    assert_eq!(args.sample(&[4f32, 3., 5.], &history), 1);
+
+    // 处理器链在掩码之前执行
+    let mut chain = crate::ProcessorChain::new();
+    chain.push(|_: &[utok], logits: &mut [f32]| logits.reverse());
+    let history = History {
+        mask: Some([true, true, false].into()),
+        processors: Some(std::sync::Arc::new(chain.into())),
+        ..Default::default()
+    };
+    assert_eq!(args.sample(&[5f32, 4., 3.], &history), 1);
+}
+
+#[test]
+fn test_guidance() {
+    let args = crate::SampleArgs::default();
+    let unconditioned = [0.8f32, 0.1, 0.1].map(f32::ln);
+    let guided = |scale| History {
+        guidance: Some(crate::Guidance {
+            scale,
+            unconditioned: unconditioned.into(),
+        }),
+        ..Default::default()
+    };
+    // 条件上下文略偏向 0，无条件上下文强烈偏向 0，引导后偏向 1
+    let logits = [2f32, 1.9, 0.];
+    assert_eq!(args.sample(&logits, &guided(1.)), 0);
+    assert_eq!(args.sample(&logits, &guided(3.)), 1);
+}
+
+#[test]
+fn test_top_logprobs() {
+    let logits = [0.25f32, 0.5, 0.125, 0.125].map(f32::ln);
+    let ans = top_logprobs(&logits, 2);
+    assert_eq!(ans.iter().map(|(t, _)| *t).collect::<Vec<_>>(), [1, 0]);
+    assert!((ans[0].1 - 0.5f32.ln()).abs() < 1e-6);
+    assert!((ans[1].1 - 0.25f32.ln()).abs() < 1e-6);
+}
@@ -0,0 +1,725 @@
+use super::{code_range, split_utf8, CharSet, Constraint, Vocab};
+use common::utok;
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt,
+    sync::Arc,
+};
+
+/// GBNF 文法。
+///
+/// 支持字符串、字符类（`[a-z]`、`[^"]`）、任意字符（`.`）、规则引用、分组、选择（`|`）
+/// 和重复（`*`、`+`、`?`、`{m,n}`），以 `root` 规则为起点，不支持左递归和叠加的重复。
+#[derive(Clone, Debug)]
+pub struct Grammar {
+    /// 每个规则的所有选择，每个选择是一个元素序列。
+    rules: Vec<Vec<Vec<Element>>>,
+    root: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Element {
+    /// 匹配一个字符。
+    Chars(CharSet),
+    Rule(usize),
+}
+
+/// 文法解析错误。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum GrammarError {
+    /// 语法错误，附带出错的字节位置。
+    Syntax(usize, &'static str),
+    UndefinedRule(String),
+    DuplicateRule(String),
+    MissingRoot,
+    /// 规则在不消耗字符时引用自身（如 `a ::= a "x"`）。
+    LeftRecursion(String),
+    /// 不支持或非法的 JSON Schema。
+    InvalidSchema(String),
+}
+
+impl error::Error for GrammarError {}
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax(pos, msg) => write!(f, "{msg} at {pos}"),
+            Self::UndefinedRule(name) => write!(f, "undefined rule \"{name}\""),
+            Self::DuplicateRule(name) => write!(f, "duplicate rule \"{name}\""),
+            Self::MissingRoot => write!(f, "missing root rule"),
+            Self::LeftRecursion(name) => write!(f, "left recursion in rule \"{name}\""),
+            Self::InvalidSchema(msg) => write!(f, "invalid json schema: {msg}"),
+        }
+    }
+}
+
+impl Grammar {
+    /// 解析 GBNF 文法。
+    pub fn parse(src: &str) -> Result<Self, GrammarError> {
+        let mut p = Parser {
+            src,
+            pos: 0,
+            rules: vec![],
+            names: vec![],
+            ids: HashMap::new(),
+            defined: vec![],
+            current: "",
+        };
+        p.skip_space();
+        while p.pos < src.len() {
+            let name = p
+                .ident()
+                .ok_or(GrammarError::Syntax(p.pos, "expect rule name"))?;
+            p.skip_space();
+            if !p.eat("::=") {
+                return Err(GrammarError::Syntax(p.pos, "expect \"::=\""));
+            }
+            let id = p.symbol(name);
+            if p.defined[id] {
+                return Err(GrammarError::DuplicateRule(name.into()));
+            }
+            p.defined[id] = true;
+            p.current = name;
+            p.rules[id] = p.alternatives()?;
+        }
+        if let Some(i) = p.defined.iter().position(|d| !d) {
+            return Err(GrammarError::UndefinedRule(p.names[i].clone()));
+        }
+        let root = *p.ids.get("root").ok_or(GrammarError::MissingRoot)?;
+        let ans = Self {
+            rules: p.rules,
+            root,
+        };
+        if let Some(rule) = ans.left_recursion() {
+            return Err(GrammarError::LeftRecursion(p.names[rule].clone()));
+        }
+        Ok(ans)
+    }
+
+    /// 找到左递归的规则。
+    ///
+    /// 规则在不消耗字符时可以引用的规则构成一个图，引用是选择的最后一个元素时是尾调用，展开时不增加栈深，
+    /// 由展开时记录的已访问状态截断；图中经过非尾调用的环会使栈无限增长，视为左递归。
+    fn left_recursion(&self) -> Option<usize> {
+        // 可以匹配空串的规则
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, alts) in self.rules.iter().enumerate() {
+                if !nullable[i]
+                    && alts.iter().any(|alt| {
+                        alt.iter()
+                            .all(|e| matches!(e, &Element::Rule(r) if nullable[r]))
+                    })
+                {
+                    nullable[i] = true;
+                    changed = true;
+                }
+            }
+        }
+        // 不消耗字符时的引用，附带是否是尾调用
+        let calls = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut calls = Vec::new();
+                for alt in alts {
+                    for (i, e) in alt.iter().enumerate() {
+                        let &Element::Rule(r) = e else { break };
+                        calls.push((r, i + 1 == alt.len()));
+                        if !nullable[r] {
+                            break;
+                        }
+                    }
+                }
+                calls
+            })
+            .collect::<Vec<_>>();
+        // 非尾调用 a -> b 且 b 可以回到 a 时存在左递归
+        let reaches = |from: usize, to: usize| {
+            let mut visited = vec![false; self.rules.len()];
+            let mut queue = vec![from];
+            while let Some(r) = queue.pop() {
+                if r == to {
+                    return true;
+                }
+                if !std::mem::replace(&mut visited[r], true) {
+                    queue.extend(calls[r].iter().map(|&(r, _)| r));
+                }
+            }
+            false
+        };
+        (0..self.rules.len()).find(|&a| calls[a].iter().any(|&(b, tail)| !tail && reaches(b, a)))
+    }
+
+    /// 初始状态，每个栈的栈顶都指向一个字符元素，空栈表示文法已经完整匹配。
+    fn init(&self) -> Vec<Stack> {
+        let mut ans = Vec::new();
+        let mut seen = HashSet::new();
+        for alt in 0..self.rules[self.root].len() {
+            self.expand(vec![Pos::new(self.root, alt, 0)], &mut ans, &mut seen);
+        }
+        dedup(ans)
+    }
+
+    /// 展开栈顶的规则引用，直到栈顶是字符元素或栈为空。
+    ///
+    /// 尾调用先弹出调用者，`seen` 记录已经展开过的栈，使重复的空匹配（如 `("a"?)*`）不会无限展开。
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>, seen: &mut HashSet<Stack>) {
+        if !seen.insert(stack.clone()) {
+            return;
+        }
+        let Some(&top) = stack.last() else {
+            out.push(stack);
+            return;
+        };
+        match self.element(top) {
+            None => {
+                stack.pop();
+                self.expand(stack, out, seen)
+            }
+            Some(Element::Chars(_)) => out.push(stack),
+            Some(&Element::Rule(rule)) => {
+                if self.element(top.next()).is_some() {
+                    *stack.last_mut().unwrap() = top.next();
+                } else {
+                    stack.pop();
+                }
+                for alt in 0..self.rules[rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(Pos::new(rule, alt, 0));
+                    self.expand(stack, out, seen)
+                }
+            }
+        }
+    }
+
+    /// 所有栈接受字符 `c` 之后的状态。
+    fn accept(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut ans = Vec::new();
+        let mut seen = HashSet::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if matches!(self.element(top), Some(Element::Chars(set)) if set.matches(c)) {
+                let mut stack = stack.clone();
+                *stack.last_mut().unwrap() = top.next();
+                self.expand(stack, &mut ans, &mut seen)
+            }
+        }
+        dedup(ans)
+    }
+
+    /// 文本是否完整匹配文法。
+    #[cfg(test)]
+    pub(super) fn matches(&self, s: &str) -> bool {
+        let mut stacks = self.init();
+        for c in s.chars() {
+            stacks = self.accept(&stacks, c);
+        }
+        stacks.iter().any(Vec::is_empty)
+    }
+
+    #[inline]
+    fn element(&self, pos: Pos) -> Option<&Element> {
+        self.rules[pos.rule as usize][pos.alt as usize].get(pos.elem as usize)
+    }
+}
+
+/// 文法中的位置：第 `rule` 个规则的第 `alt` 个选择中的第 `elem` 个元素。
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+struct Pos {
+    rule: u32,
+    alt: u32,
+    elem: u32,
+}
+
+impl Pos {
+    #[inline]
+    fn new(rule: usize, alt: usize, elem: usize) -> Self {
+        Self {
+            rule: rule as _,
+            alt: alt as _,
+            elem: elem as _,
+        }
+    }
+
+    #[inline]
+    fn next(self) -> Self {
+        Self {
+            elem: self.elem + 1,
+            ..self
+        }
+    }
+}
+
+type Stack = Vec<Pos>;
+
+#[inline]
+fn dedup(mut stacks: Vec<Stack>) -> Vec<Stack> {
+    stacks.sort_unstable();
+    stacks.dedup();
+    stacks
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    defined: Vec<bool>,
+    /// 正在解析的规则名，用于命名分组和重复生成的规则。
+    current: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    #[inline]
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        if self.rest().starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 跳过空白和注释。
+    fn skip_space(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.pos += rest.len() - trimmed.len();
+            if trimmed.starts_with('#') {
+                self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Option<&'a str> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(rest.len());
+        self.pos += len;
+        Some(&rest[..len]).filter(|s| !s.is_empty())
+    }
+
+    /// 当前位置是否是下一个规则定义的开头。
+    fn at_rule_head(&mut self) -> bool {
+        let pos = self.pos;
+        let ans = self.ident().is_some() && {
+            self.skip_space();
+            self.rest().starts_with("::=")
+        };
+        self.pos = pos;
+        ans
+    }
+
+    fn symbol(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        // 具名规则在定义之前可能先被引用
+        let id = self.new_rule();
+        self.names[id] = name.into();
+        self.defined[id] = false;
+        self.ids.insert(name.into(), id);
+        id
+    }
+
+    fn new_rule(&mut self) -> usize {
+        self.rules.push(vec![]);
+        self.names
+            .push(format!("{}-{}", self.current, self.rules.len() - 1));
+        self.defined.push(true);
+        self.rules.len() - 1
+    }
+
+    fn alternatives(&mut self) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alts = vec![self.sequence()?];
+        while self.eat("|") {
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Element>, GrammarError> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space();
+            match self.peek() {
+                None | Some('|' | ')') => break,
+                _ if self.at_rule_head() => break,
+                _ => {}
+            }
+            let atom = self.atom()?;
+            let op = self.peek();
+            if matches!(op, Some('*' | '+' | '?' | '{')) {
+                self.pos += 1;
+            }
+            let (min, max) = match op {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => self.bounds()?,
+                _ => {
+                    seq.extend(atom);
+                    continue;
+                }
+            };
+            if matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+                return Err(GrammarError::Syntax(self.pos, "repeated quantifier"));
+            }
+            seq.extend(self.repeat(atom, min, max));
+        }
+        Ok(seq)
+    }
+
+    /// 解析 `{m}`、`{m,}`、`{m,n}` 中左括号之后的部分。
+    fn bounds(&mut self) -> Result<(usize, Option<usize>), GrammarError> {
+        let number = |p: &mut Self| {
+            let rest = p.rest();
+            let len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            p.pos += len;
+            rest[..len].parse::<usize>().ok()
+        };
+        self.skip_space();
+        let min = number(self).ok_or(GrammarError::Syntax(self.pos, "expect number"))?;
+        self.skip_space();
+        let max = if self.eat(",") {
+            self.skip_space();
+            number(self)
+        } else {
+            Some(min)
+        };
+        self.skip_space();
+        if !self.eat("}") || max.is_some_and(|max| max < min) {
+            return Err(GrammarError::Syntax(self.pos, "invalid repetition"));
+        }
+        Ok((min, max))
+    }
+
+    /// 把 `atom` 重复 `min` 到 `max` 次，`max` 为空表示不限。
+    fn repeat(&mut self, atom: Vec<Element>, min: usize, max: Option<usize>) -> Vec<Element> {
+        let mut seq = [&*atom].repeat(min).concat();
+        match max {
+            None => {
+                // r ::= atom r | ε
+                let r = self.new_rule();
+                let mut alt = atom;
+                alt.push(Element::Rule(r));
+                self.rules[r] = vec![alt, vec![]];
+                seq.push(Element::Rule(r));
+            }
+            Some(max) => {
+                // r_k ::= atom r_{k-1} | ε
+                let mut tail = None;
+                for _ in min..max {
+                    let r = self.new_rule();
+                    let mut alt = atom.clone();
+                    alt.extend(tail.map(Element::Rule));
+                    self.rules[r] = vec![alt, vec![]];
+                    tail = Some(r);
+                }
+                seq.extend(tail.map(Element::Rule));
+            }
+        }
+        seq
+    }
+
+    fn atom(&mut self) -> Result<Vec<Element>, GrammarError> {
+        let start = self.pos;
+        match self.peek() {
+            Some('"') => {
+                self.pos += 1;
+                let mut seq = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => return Err(GrammarError::Syntax(start, "unterminated string")),
+                        Some('"') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => {
+                            let c = self.char()?;
+                            seq.push(Element::Chars(CharSet::single(c)))
+                        }
+                    }
+                }
+                Ok(seq)
+            }
+            Some('[') => {
+                self.pos += 1;
+                let negated = self.eat("^");
+                let mut ranges = Vec::new();
+                loop {
+                    match self.peek() {
+                        None => return Err(GrammarError::Syntax(start, "unterminated class")),
+                        Some(']') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => {
+                            let lo = self.char()?;
+                            let hi =
+                                if self.rest().starts_with('-') && !self.rest().starts_with("-]") {
+                                    self.pos += 1;
+                                    self.char()?
+                                } else {
+                                    lo
+                                };
+                            ranges.push((lo, hi))
+                        }
+                    }
+                }
+                Ok(vec![Element::Chars(CharSet { ranges, negated })])
+            }
+            Some('.') => {
+                self.pos += 1;
+                Ok(vec![Element::Chars(CharSet::ANY)])
+            }
+            Some('(') => {
+                self.pos += 1;
+                let alts = self.alternatives()?;
+                if !self.eat(")") {
+                    return Err(GrammarError::Syntax(self.pos, "expect \")\""));
+                }
+                let r = self.new_rule();
+                self.rules[r] = alts;
+                Ok(vec![Element::Rule(r)])
+            }
+            _ => {
+                let name = self
+                    .ident()
+                    .ok_or(GrammarError::Syntax(start, "unexpected character"))?;
+                Ok(vec![Element::Rule(self.symbol(name))])
+            }
+        }
+    }
+
+    /// 解析一个可能转义的字符。
+    fn char(&mut self) -> Result<char, GrammarError> {
+        let start = self.pos;
+        let c = self.peek().unwrap();
+        self.pos += c.len_utf8();
+        if c != '\\' {
+            return Ok(c);
+        }
+        let c = self
+            .peek()
+            .ok_or(GrammarError::Syntax(start, "invalid escape"))?;
+        self.pos += c.len_utf8();
+        let hex = |p: &mut Self, len: usize| {
+            let code = p
+                .rest()
+                .get(..len)
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .and_then(char::from_u32)
+                .ok_or(GrammarError::Syntax(start, "invalid escape"))?;
+            p.pos += len;
+            Ok(code)
+        };
+        match c {
+            'n' => Ok('\n'),
+            'r' => Ok('\r'),
+            't' => Ok('\t'),
+            'x' => hex(self, 2),
+            'u' => hex(self, 4),
+            'U' => hex(self, 8),
+            c => Ok(c),
+        }
+    }
+}
+
+/// 按文法约束生成，跟踪文法的匹配状态。
+pub(crate) struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    vocab: Arc<Vocab>,
+    stacks: Vec<Stack>,
+    /// 不完整的 UTF-8 字符，等待后续 token 补全。
+    partial: Vec<u8>,
+}
+
+impl GrammarMatcher {
+    pub fn new(grammar: Arc<Grammar>, vocab: Arc<Vocab>) -> Self {
+        Self {
+            stacks: grammar.init(),
+            grammar,
+            vocab,
+            partial: vec![],
+        }
+    }
+
+    /// 尝试接受一段字节，返回接受后的状态。
+    fn try_accept(&self, bytes: &[u8]) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let mut buf = self.partial.clone();
+        buf.extend_from_slice(bytes);
+        let (text, rest) = split_utf8(&buf)?;
+
+        let mut chars = text.chars();
+        let mut stacks = match chars.next() {
+            Some(c) => self.grammar.accept(&self.stacks, c),
+            None => self.stacks.clone(),
+        };
+        for c in chars {
+            if stacks.is_empty() {
+                break;
+            }
+            stacks = self.grammar.accept(&stacks, c);
+        }
+        // 不完整的字符要求可能被某个栈接受
+        let alive = if rest.is_empty() {
+            !stacks.is_empty()
+        } else {
+            let (lo, hi) = code_range(rest);
+            stacks.iter().any(|s| {
+                s.last()
+                    .and_then(|&top| self.grammar.element(top))
+                    .is_some_and(|e| matches!(e, Element::Chars(set) if set.may_match(lo, hi)))
+            })
+        };
+        alive.then(|| (stacks, rest.to_vec()))
+    }
+}
+
+impl Constraint for GrammarMatcher {
+    fn mask(&self) -> Arc<[bool]> {
+        let eos = self.vocab.eos as usize;
+        let complete = self.partial.is_empty() && self.stacks.iter().any(Vec::is_empty);
+        let dead = self.stacks.iter().all(Vec::is_empty);
+        self.vocab
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(t, bytes)| {
+                if t == eos {
+                    complete || dead
+                } else {
+                    !dead && !bytes.is_empty() && self.try_accept(bytes).is_some()
+                }
+            })
+            .collect()
+    }
+
+    fn advance(&mut self, token: utok) {
+        if token == self.vocab.eos {
+            return;
+        }
+        let bytes = self.vocab.tokens.get(token as usize).map_or(&[][..], |b| b);
+        (self.stacks, self.partial) = self.try_accept(bytes).unwrap_or_default();
+    }
+}
+
+#[test]
+fn test_grammar() {
+    let grammar = Grammar::parse(
+        r#"
+        # 简单的列表
+        root ::= "[" ws ( item ( "," ws item )* )? "]"
+        item ::= [0-9]+ | "\"" [^"]{1,3} "\""
+        ws   ::= " "?
+        "#,
+    )
+    .unwrap();
+    let matches = |s: &str| grammar.matches(s);
+    assert!(matches("[]"));
+    assert!(matches("[ 12, \"abc\",34]"));
+    assert!(!matches("[12,]"));
+    assert!(!matches("[\"abcd\"]"));
+
+    assert_eq!(
+        Grammar::parse("a ::= b").unwrap_err(),
+        GrammarError::UndefinedRule("b".into())
+    );
+    assert_eq!(
+        Grammar::parse("a ::= \"x\"").unwrap_err(),
+        GrammarError::MissingRoot
+    );
+    assert!(matches!(
+        Grammar::parse("root ::= (\"x\""),
+        Err(GrammarError::Syntax(..))
+    ));
+    assert!(matches!(
+        Grammar::parse("root ::= \"a\"{2}*"),
+        Err(GrammarError::Syntax(..))
+    ));
+    let grammar = Grammar::parse("root ::= \"a\"{2} \"*\"").unwrap();
+    assert!(grammar.matches("aa*"));
+}
+
+#[test]
+fn test_grammar_recursion() {
+    // 左递归在解析时报错
+    assert_eq!(
+        Grammar::parse(r#"root ::= a  a ::= a "x" | "y""#).unwrap_err(),
+        GrammarError::LeftRecursion("a".into())
+    );
+    assert_eq!(
+        Grammar::parse(r#"root ::= a "x"  a ::= b?  b ::= root | "y""#).unwrap_err(),
+        GrammarError::LeftRecursion("root".into())
+    );
+    // 右递归和嵌套的可空重复不受栈深限制
+    let grammar = Grammar::parse(r#"root ::= ( ( "a"? )* )* "b""#).unwrap();
+    assert!(grammar.init().len() <= 2);
+    assert!(grammar.matches(&("a".repeat(1000) + "b")));
+    let grammar = Grammar::parse(r#"root ::= "(" root ")" | [0-9]*"#).unwrap();
+    assert!(grammar.matches(&("(".repeat(300) + "42" + &")".repeat(300))));
+}
+
+#[test]
+fn test_grammar_matcher() {
+    let vocab = ["<eos>", "ye", "s", "no", "yes!", "\u{e4}"]
+        .map(|s| s.as_bytes().into())
+        .to_vec();
+    let mut vocab = Vocab {
+        tokens: vocab,
+        eos: 0,
+    };
+    // 把 "ä" 拆成两个字节 token
+    vocab.tokens[5] = Box::new([0xc3]);
+    vocab.tokens.push(Box::new([0xa4]));
+    let grammar = Arc::new(Grammar::parse(r#"root ::= "yes" | "no" | "ä""#).unwrap());
+    let mut matcher = GrammarMatcher::new(grammar, Arc::new(vocab));
+
+    assert_eq!(
+        &*matcher.mask(),
+        [false, true, false, true, false, true, false]
+    );
+    matcher.advance(1);
+    assert_eq!(
+        &*matcher.mask(),
+        [false, false, true, false, false, false, false]
+    );
+    matcher.advance(2);
+    assert_eq!(
+        &*matcher.mask(),
+        [true, false, false, false, false, false, false]
+    );
+
+    let mut matcher = GrammarMatcher::new(matcher.grammar.clone(), matcher.vocab.clone());
+    matcher.advance(5);
+    assert_eq!(
+        &*matcher.mask(),
+        [false, false, false, false, false, false, true]
+    );
+    matcher.advance(6);
+    assert_eq!(
+        &*matcher.mask(),
+        [true, false, false, false, false, false, false]
+    );
+}
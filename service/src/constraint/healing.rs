@@ -0,0 +1,51 @@
+use super::{Constraint, Vocab};
+use common::utok;
+use std::sync::Arc;
+
+/// 修复提示词末尾的 token：回退提示词的最后一个 token 后，
+/// 第一个生成的 token 必须以回退的文本开头，之后不再限制。
+pub(crate) struct HealingMatcher {
+    mask: Arc<[bool]>,
+    healed: bool,
+}
+
+impl HealingMatcher {
+    /// 回退的 token 解码为 `prefix`。
+    pub fn new(vocab: &Vocab, prefix: &[u8]) -> Self {
+        Self {
+            mask: vocab.tokens.iter().map(|t| t.starts_with(prefix)).collect(),
+            healed: false,
+        }
+    }
+}
+
+impl Constraint for HealingMatcher {
+    #[inline]
+    fn mask(&self) -> Arc<[bool]> {
+        self.mask.clone()
+    }
+    #[inline]
+    fn advance(&mut self, _token: utok) {
+        self.healed = true;
+    }
+    #[inline]
+    fn finished(&self) -> bool {
+        self.healed
+    }
+}
+
+#[test]
+fn test_healing_matcher() {
+    let vocab = Vocab {
+        tokens: ["", "a", "ab", "b", "abc", " a"]
+            .iter()
+            .map(|s| s.as_bytes().into())
+            .collect(),
+        eos: 0,
+    };
+    let mut matcher = HealingMatcher::new(&vocab, b"a");
+    assert_eq!(&*matcher.mask(), [false, true, true, false, true, false]);
+    assert!(!matcher.finished());
+    matcher.advance(2);
+    assert!(matcher.finished());
+}
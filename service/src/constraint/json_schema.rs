@@ -0,0 +1,301 @@
+use super::{Grammar, GrammarError};
+use serde_json::{Map, Value};
+use std::{collections::HashMap, fmt::Write};
+
+impl Grammar {
+    /// 把 JSON Schema 编译为文法，匹配文法的文本都是满足模式的 JSON。
+    ///
+    /// 支持 `type`、`properties`、`required`、`additionalProperties`、`items`、
+    /// `minItems`、`maxItems`、`minLength`、`maxLength`、`enum`、`const`、`anyOf`、`oneOf`
+    /// 和指向模式内部的 `$ref`；对象先生成必要的属性，再生成可选的属性，不生成额外的属性。
+    pub fn from_json_schema(schema: &Value) -> Result<Self, GrammarError> {
+        let mut converter = SchemaConverter {
+            root: schema,
+            rules: String::new(),
+            refs: HashMap::new(),
+            count: 0,
+        };
+        let root = converter.visit(schema)?;
+        let mut src = format!("root ::= {root}\n");
+        src.push_str(&converter.rules);
+        src.push_str(PRIMITIVES);
+        Self::parse(&src)
+    }
+}
+
+/// 基本的 JSON 值。
+const PRIMITIVES: &str = r#"
+space   ::= | " " | "\n" [ \t]{0,20}
+char    ::= [^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4})
+string  ::= "\"" char* "\"" space
+integer ::= "-"? ([0-9] | [1-9] [0-9]{0,15}) space
+number  ::= "-"? ([0-9] | [1-9] [0-9]{0,15}) ("." [0-9]+)? ([eE] [-+]? [0-9]{1,15})? space
+boolean ::= ("true" | "false") space
+null    ::= "null" space
+value   ::= object | array | string | number | boolean | null
+object  ::= "{" space (string ":" space value ("," space string ":" space value)*)? "}" space
+array   ::= "[" space (value ("," space value)*)? "]" space
+"#;
+
+struct SchemaConverter<'a> {
+    root: &'a Value,
+    rules: String,
+    /// 已经生成的引用，支持递归的模式。
+    refs: HashMap<&'a str, String>,
+    count: usize,
+}
+
+impl<'a> SchemaConverter<'a> {
+    /// 添加一个新规则，返回规则名。
+    fn rule(&mut self, body: &str) -> String {
+        self.count += 1;
+        let name = format!("s{}", self.count);
+        writeln!(self.rules, "{name} ::= {body}").unwrap();
+        name
+    }
+
+    /// 返回匹配 `schema` 的表达式。
+    fn visit(&mut self, schema: &'a Value) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Object(map) => map,
+            _ => return Err(invalid("schema must be an object or true")),
+        };
+        for key in ["allOf", "not", "if", "pattern", "patternProperties"] {
+            if schema.contains_key(key) {
+                return Err(invalid(&format!("\"{key}\" is not supported")));
+            }
+        }
+
+        if let Some(Value::String(path)) = schema.get("$ref") {
+            return self.reference(path);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(format!("{} space", literal(&value.to_string())));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Value::Array(values) = values else {
+                return Err(invalid("\"enum\" must be an array"));
+            };
+            let alts = values
+                .iter()
+                .map(|v| literal(&v.to_string()))
+                .collect::<Vec<_>>();
+            return Ok(format!("({}) space", alts.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let Value::Array(schemas) = schemas else {
+                return Err(invalid("\"anyOf\" must be an array"));
+            };
+            let alts = schemas
+                .iter()
+                .map(|s| self.visit(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(format!("({})", alts.join(" | ")));
+        }
+
+        match schema.get("type") {
+            None => Ok("value".into()),
+            Some(Value::String(ty)) => self.typed(ty, schema),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .map(|ty| match ty {
+                        Value::String(ty) => self.typed(ty, schema),
+                        _ => Err(invalid("\"type\" must be a string")),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(format!("({})", alts.join(" | ")))
+            }
+            Some(_) => Err(invalid("\"type\" must be a string or an array")),
+        }
+    }
+
+    fn typed(&mut self, ty: &str, schema: &'a Map<String, Value>) -> Result<String, GrammarError> {
+        match ty {
+            "object" => self.object(schema),
+            "array" => self.array(schema),
+            "string" => {
+                let min = usize_of(schema, "minLength")?.unwrap_or(0);
+                match usize_of(schema, "maxLength")? {
+                    None if min == 0 => Ok("string".into()),
+                    None => Ok(format!(r#""\"" char{{{min},}} "\"" space"#)),
+                    Some(max) => Ok(format!(r#""\"" char{{{min},{max}}} "\"" space"#)),
+                }
+            }
+            "integer" | "number" | "boolean" | "null" => Ok(ty.into()),
+            _ => Err(invalid(&format!("unknown type \"{ty}\""))),
+        }
+    }
+
+    fn object(&mut self, schema: &'a Map<String, Value>) -> Result<String, GrammarError> {
+        let properties = match schema.get("properties") {
+            None => None,
+            Some(Value::Object(properties)) => Some(properties),
+            Some(_) => return Err(invalid("\"properties\" must be an object")),
+        };
+        let additional = schema.get("additionalProperties");
+        let Some(properties) = properties.filter(|p| !p.is_empty()) else {
+            // 没有属性时按键值对的映射生成
+            let value = match additional {
+                None | Some(Value::Bool(true)) => "value".into(),
+                Some(Value::Bool(false)) => return Ok(r#""{" space "}" space"#.into()),
+                Some(s) => self.visit(s)?,
+            };
+            let kv = self.rule(&format!(r#"string ":" space {value}"#));
+            return Ok(format!(
+                r#""{{" space ({kv} ("," space {kv})*)? "}}" space"#
+            ));
+        };
+        if additional.is_some_and(|a| a != &Value::Bool(false)) {
+            return Err(invalid(
+                "\"additionalProperties\" with \"properties\" is not supported",
+            ));
+        }
+
+        let required = match schema.get("required") {
+            None => vec![],
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+            Some(_) => return Err(invalid("\"required\" must be an array")),
+        };
+        let mut kvs = Vec::with_capacity(properties.len());
+        for (name, value) in properties {
+            let value = self.visit(value)?;
+            let key = literal(&Value::String(name.clone()).to_string());
+            let kv = self.rule(&format!(r#"{key} space ":" space {value}"#));
+            kvs.push((kv, required.contains(&name.as_str())));
+        }
+        // 必要的属性都生成，可选的属性可以省略，逗号只出现在属性之间
+        let (required, optional): (Vec<_>, Vec<_>) = kvs.into_iter().partition(|(_, r)| *r);
+        let required = required.into_iter().map(|(kv, _)| kv).collect::<Vec<_>>();
+        let optional = optional.into_iter().map(|(kv, _)| kv).collect::<Vec<_>>();
+        let body = if required.is_empty() {
+            // 第一个出现的可选属性之前没有逗号
+            let mut tail = String::new();
+            let mut alts = Vec::with_capacity(optional.len());
+            for kv in optional.iter().rev() {
+                alts.push(format!("{kv}{tail}"));
+                tail = format!(r#" ("," space {kv})?{tail}"#);
+            }
+            alts.reverse();
+            format!("({})?", alts.join(" | "))
+        } else {
+            let mut body = required.join(r#" "," space "#);
+            for kv in optional {
+                write!(body, r#" ("," space {kv})?"#).unwrap();
+            }
+            body
+        };
+        Ok(format!(r#""{{" space {body} "}}" space"#))
+    }
+
+    fn array(&mut self, schema: &'a Map<String, Value>) -> Result<String, GrammarError> {
+        let item = match schema.get("items") {
+            None => "value".into(),
+            Some(items) => self.visit(items)?,
+        };
+        let item = self.rule(&item);
+        let min = usize_of(schema, "minItems")?.unwrap_or(0);
+        let max = usize_of(schema, "maxItems")?;
+        if max.is_some_and(|max| max < min) {
+            return Err(invalid("\"maxItems\" is less than \"minItems\""));
+        }
+        let body = match (min, max) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!(r#"({item} ("," space {item})*)?"#),
+            (0, Some(max)) => format!(r#"({item} ("," space {item}){{0,{}}})?"#, max - 1),
+            (min, None) => format!(r#"{item} ("," space {item}){{{},}}"#, min - 1),
+            (min, Some(max)) => {
+                format!(r#"{item} ("," space {item}){{{},{}}}"#, min - 1, max - 1)
+            }
+        };
+        Ok(format!(r#""[" space {body} "]" space"#))
+    }
+
+    fn reference(&mut self, path: &'a str) -> Result<String, GrammarError> {
+        if let Some(name) = self.refs.get(path) {
+            return Ok(name.clone());
+        }
+        let target = path
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| invalid(&format!("unresolved reference \"{path}\"")))?;
+        // 先占用规则名，目标中的递归引用指向这个规则
+        self.count += 1;
+        let name = format!("s{}", self.count);
+        self.refs.insert(path, name.clone());
+        let body = self.visit(target)?;
+        writeln!(self.rules, "{name} ::= {body}").unwrap();
+        Ok(name)
+    }
+}
+
+#[inline]
+fn invalid(msg: &str) -> GrammarError {
+    GrammarError::InvalidSchema(msg.into())
+}
+
+fn usize_of(schema: &Map<String, Value>, key: &str) -> Result<Option<usize>, GrammarError> {
+    match schema.get(key) {
+        None => Ok(None),
+        Some(v) => v
+            .as_u64()
+            .map(|n| Some(n as _))
+            .ok_or_else(|| invalid(&format!("\"{key}\" must be a non-negative integer"))),
+    }
+}
+
+/// 把文本转换为 GBNF 字符串。
+fn literal(s: &str) -> String {
+    let mut ans = String::with_capacity(s.len() + 2);
+    ans.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ans.push_str(r#"\""#),
+            '\\' => ans.push_str(r"\\"),
+            '\n' => ans.push_str(r"\n"),
+            '\r' => ans.push_str(r"\r"),
+            '\t' => ans.push_str(r"\t"),
+            c => ans.push(c),
+        }
+    }
+    ans.push('"');
+    ans
+}
+
+#[test]
+fn test_json_schema() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "maxLength": 8 },
+            "age": { "type": "integer" },
+            "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "maxItems": 2 },
+            "next": { "$ref": "#" }
+        },
+        "required": ["name"]
+    });
+    let grammar = Grammar::from_json_schema(&schema).unwrap();
+    let matches = |s: &str| grammar.matches(s);
+    assert!(matches(r#"{"name": "tom"}"#));
+    assert!(matches(r#"{"name":"tom","age":3,"tags":["a","b"]}"#));
+    assert!(matches(
+        r#"{"name": "a", "next": {"name": "b", "age": -1}}"#
+    ));
+    assert!(!matches(r#"{"age": 3}"#));
+    assert!(!matches(r#"{"name": "tom", "tags": ["c"]}"#));
+    assert!(!matches(r#"{"name": "tom", "tags": ["a", "b", "a"]}"#));
+    assert!(!matches(r#"{"name": "tom", "extra": 1}"#));
+
+    let grammar = Grammar::from_json_schema(&serde_json::json!({
+        "type": "object",
+        "properties": { "a": { "type": "null" }, "b": { "type": "boolean" } }
+    }))
+    .unwrap();
+    assert!(grammar.matches("{}"));
+    assert!(grammar.matches(r#"{"b": true}"#));
+    assert!(grammar.matches(r#"{"a": null, "b": false}"#));
+    assert!(!grammar.matches(r#"{, "b": true}"#));
+
+    assert!(Grammar::from_json_schema(&serde_json::json!({ "allOf": [] })).is_err());
+}
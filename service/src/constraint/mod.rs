@@ -0,0 +1,118 @@
+mod grammar;
+mod healing;
+mod json_schema;
+mod regex;
+
+use crate::ServiceComponent;
+use causal_lm::CausalLM;
+use common::utok;
+use std::{str, sync::Arc};
+
+pub use grammar::{Grammar, GrammarError};
+pub use regex::{Regex, RegexError};
+
+pub(crate) use grammar::GrammarMatcher;
+pub(crate) use healing::HealingMatcher;
+pub(crate) use regex::RegexMatcher;
+
+/// 约束生成的文本，每步采样前给出允许生成的 token。
+pub(crate) trait Constraint: Send {
+    /// 当前状态下允许生成的 token，`mask[t]` 为真表示允许生成 `t`。
+    fn mask(&self) -> Arc<[bool]>;
+    /// 接受新生成的 token，推进状态。
+    fn advance(&mut self, token: utok);
+    /// 约束不再限制之后的生成，任务随即丢弃约束。
+    #[inline]
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+/// 字符集合，`negated` 为真时表示不在范围内的字符。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct CharSet {
+    pub ranges: Vec<(char, char)>,
+    pub negated: bool,
+}
+
+impl CharSet {
+    /// 任意字符。
+    pub const ANY: Self = Self {
+        ranges: vec![],
+        negated: true,
+    };
+
+    #[inline]
+    pub fn single(c: char) -> Self {
+        Self {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    #[inline]
+    pub fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+
+    /// 是否可能匹配码点在 `lo..=hi` 之间的某个字符。
+    pub fn may_match(&self, lo: u32, hi: u32) -> bool {
+        let mut ranges = self.ranges.iter().map(|&(a, b)| (a as u32, b as u32));
+        if self.negated {
+            !ranges.any(|(a, b)| a <= lo && hi <= b)
+        } else {
+            ranges.any(|(a, b)| a <= hi && lo <= b)
+        }
+    }
+}
+
+/// 把字节分为完整的 UTF-8 文本和末尾不完整的字符，字节非法时返回空。
+pub(crate) fn split_utf8(bytes: &[u8]) -> Option<(&str, &[u8])> {
+    let valid = match str::from_utf8(bytes) {
+        Ok(s) => s.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => return None,
+    };
+    let (text, rest) = bytes.split_at(valid);
+    Some((str::from_utf8(text).unwrap(), rest))
+}
+
+/// 以不完整的 UTF-8 序列 `prefix` 开头的字符的码点范围。
+pub(crate) fn code_range(prefix: &[u8]) -> (u32, u32) {
+    let lead = prefix[0];
+    let (len, mask) = match lead.leading_ones() {
+        2 => (2, 0x1f),
+        3 => (3, 0x0f),
+        _ => (4, 0x07),
+    };
+    let code = |fill: u8| {
+        (1..len).fold((lead & mask) as u32, |code, i| {
+            code << 6 | (prefix.get(i).copied().unwrap_or(fill) & 0x3f) as u32
+        })
+    };
+    (code(0x80), code(0xbf))
+}
+
+/// 词表中每个 token 解码得到的字节，用于检查 token 是否满足约束。
+pub(crate) struct Vocab {
+    pub tokens: Vec<Box<[u8]>>,
+    pub eos: utok,
+}
+
+impl<M: CausalLM> ServiceComponent<M> {
+    /// 约束解码使用的词表，与输出文本的解码方式一致。
+    pub(crate) fn vocab(&self) -> Arc<Vocab> {
+        self.vocab
+            .get_or_init(|| {
+                let tokens = (0..self.tokenizer.vocab_size() as utok)
+                    .map(|t| {
+                        let s = self.normalizer.decode(self.tokenizer.decode(t));
+                        s.as_bytes().into()
+                    })
+                    .collect();
+                let eos = self.handle.model.eos_token();
+                Arc::new(Vocab { tokens, eos })
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,520 @@
+use super::{code_range, split_utf8, CharSet, Constraint, Vocab};
+use common::utok;
+use std::{cell::RefCell, collections::HashMap, error, fmt, sync::Arc};
+
+/// 正则表达式，生成的文本必须完整匹配。
+///
+/// 支持字符、任意字符（`.`）、字符类（`[a-z]`、`[^0-9]`）、`\d`、`\w`、`\s` 及其取反、
+/// 分组（`(...)`、`(?:...)`）、选择（`|`）和重复（`*`、`+`、`?`、`{m,n}`），
+/// 不支持反向引用和环视，开头的 `^` 和结尾的 `$` 被忽略。
+#[derive(Clone, Debug)]
+pub struct Regex {
+    nodes: Vec<Node>,
+    start: usize,
+}
+
+/// 非确定有限自动机的节点。
+#[derive(Clone, Debug)]
+enum Node {
+    /// 匹配一个字符后转移到下一个节点。
+    Chars(CharSet, usize),
+    /// 不消耗字符转移到任一节点。
+    Split(Vec<usize>),
+    Match,
+}
+
+/// 正则表达式解析错误，附带出错的字节位置。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct RegexError(pub usize, pub &'static str);
+
+impl error::Error for RegexError {}
+impl fmt::Display for RegexError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.1, self.0)
+    }
+}
+
+impl Regex {
+    /// 解析正则表达式。
+    pub fn new(pattern: &str) -> Result<Self, RegexError> {
+        let pattern = pattern.strip_prefix('^').unwrap_or(pattern);
+        let pattern = match pattern.strip_suffix('$') {
+            Some(p) if !p.ends_with('\\') => p,
+            _ => pattern,
+        };
+        let mut p = Parser {
+            src: pattern,
+            pos: 0,
+        };
+        let ast = p.alternatives()?;
+        if p.pos < pattern.len() {
+            return Err(RegexError(p.pos, "unmatched \")\""));
+        }
+        let mut nodes = vec![Node::Match];
+        let start = compile(&ast, 0, &mut nodes);
+        Ok(Self { nodes, start })
+    }
+
+    /// 从 `nodes` 出发不消耗字符能到达的匹配字符的节点和终点。
+    fn closure(&self, nodes: impl IntoIterator<Item = usize>) -> Vec<usize> {
+        let mut visited = vec![false; self.nodes.len()];
+        let mut stack = nodes.into_iter().collect::<Vec<_>>();
+        let mut ans = Vec::new();
+        while let Some(i) = stack.pop() {
+            if std::mem::replace(&mut visited[i], true) {
+                continue;
+            }
+            match &self.nodes[i] {
+                Node::Split(next) => stack.extend(next),
+                Node::Chars(..) | Node::Match => ans.push(i),
+            }
+        }
+        ans.sort_unstable();
+        ans
+    }
+
+    /// 文本是否完整匹配正则表达式。
+    #[cfg(test)]
+    fn matches(&self, s: &str) -> bool {
+        let mut set = self.closure([self.start]);
+        for c in s.chars() {
+            set = self.closure(set.iter().filter_map(|&i| match &self.nodes[i] {
+                Node::Chars(chars, next) if chars.matches(c) => Some(*next),
+                _ => None,
+            }));
+        }
+        set.iter().any(|&i| matches!(self.nodes[i], Node::Match))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Ast {
+    Chars(CharSet),
+    Concat(Vec<Ast>),
+    Alt(Vec<Ast>),
+    Repeat(Box<Ast>, usize, Option<usize>),
+}
+
+/// 编译 `ast`，匹配后转移到 `next`，返回入口节点。
+fn compile(ast: &Ast, next: usize, nodes: &mut Vec<Node>) -> usize {
+    let push = |nodes: &mut Vec<Node>, node| {
+        nodes.push(node);
+        nodes.len() - 1
+    };
+    match ast {
+        Ast::Chars(set) => push(nodes, Node::Chars(set.clone(), next)),
+        Ast::Concat(seq) => seq
+            .iter()
+            .rev()
+            .fold(next, |next, ast| compile(ast, next, nodes)),
+        Ast::Alt(alts) => {
+            let entries = alts.iter().map(|ast| compile(ast, next, nodes)).collect();
+            push(nodes, Node::Split(entries))
+        }
+        Ast::Repeat(ast, min, max) => {
+            let mut entry = match max {
+                None => {
+                    let head = push(nodes, Node::Split(vec![]));
+                    let body = compile(ast, head, nodes);
+                    nodes[head] = Node::Split(vec![body, next]);
+                    head
+                }
+                Some(max) => (*min..*max).fold(next, |tail, _| {
+                    let body = compile(ast, tail, nodes);
+                    push(nodes, Node::Split(vec![body, next]))
+                }),
+            };
+            for _ in 0..*min {
+                entry = compile(ast, entry, nodes);
+            }
+            entry
+        }
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    #[inline]
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    #[inline]
+    fn eat(&mut self, s: &str) -> bool {
+        if self.src[self.pos..].starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn alternatives(&mut self) -> Result<Ast, RegexError> {
+        let mut alts = vec![self.sequence()?];
+        while self.eat("|") {
+            alts.push(self.sequence()?);
+        }
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Ast::Alt(alts)
+        })
+    }
+
+    fn sequence(&mut self) -> Result<Ast, RegexError> {
+        let mut seq = Vec::new();
+        while !matches!(self.peek(), None | Some('|' | ')')) {
+            let mut atom = self.atom()?;
+            loop {
+                let (min, max) = match self.peek() {
+                    Some('*') => (0, None),
+                    Some('+') => (1, None),
+                    Some('?') => (0, Some(1)),
+                    Some('{') => self.bounds()?,
+                    _ => break,
+                };
+                if !matches!(self.peek(), Some('{')) {
+                    self.pos += 1;
+                } else {
+                    self.pos += self.src[self.pos..].find('}').unwrap() + 1;
+                }
+                // 惰性匹配不影响能匹配的文本
+                self.eat("?");
+                atom = Ast::Repeat(Box::new(atom), min, max);
+            }
+            seq.push(atom);
+        }
+        Ok(Ast::Concat(seq))
+    }
+
+    /// 解析 `{m}`、`{m,}`、`{m,n}`，不移动位置。
+    fn bounds(&self) -> Result<(usize, Option<usize>), RegexError> {
+        let err = RegexError(self.pos, "invalid repetition");
+        let rest = &self.src[self.pos + 1..];
+        let body = &rest[..rest.find('}').ok_or(err)?];
+        let number = |s: &str| s.trim().parse::<usize>().map_err(|_| err);
+        let (min, max) = match body.split_once(',') {
+            None => (number(body)?, Some(number(body)?)),
+            Some((min, max)) if max.trim().is_empty() => (number(min)?, None),
+            Some((min, max)) => (number(min)?, Some(number(max)?)),
+        };
+        if max.is_some_and(|max| max < min) {
+            return Err(err);
+        }
+        Ok((min, max))
+    }
+
+    fn atom(&mut self) -> Result<Ast, RegexError> {
+        let start = self.pos;
+        let c = self.peek().unwrap();
+        self.pos += c.len_utf8();
+        match c {
+            '(' => {
+                self.eat("?:");
+                let ast = self.alternatives()?;
+                if !self.eat(")") {
+                    return Err(RegexError(start, "unmatched \"(\""));
+                }
+                Ok(ast)
+            }
+            '[' => {
+                let negated = self.eat("^");
+                let mut ranges = Vec::new();
+                // 紧跟左括号的右括号是普通字符
+                if self.eat("]") {
+                    ranges.push((']', ']'));
+                }
+                loop {
+                    match self.peek() {
+                        None => return Err(RegexError(start, "unterminated class")),
+                        Some(']') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => {}
+                    }
+                    let lo = match self.class_char()? {
+                        Ok(c) => c,
+                        Err(set) if !set.negated => {
+                            ranges.extend(set.ranges);
+                            continue;
+                        }
+                        Err(_) => {
+                            return Err(RegexError(self.pos, "negated class in class"));
+                        }
+                    };
+                    let hi = if self.src[self.pos..].starts_with('-')
+                        && !self.src[self.pos..].starts_with("-]")
+                    {
+                        self.pos += 1;
+                        self.class_char()?
+                            .map_err(|_| RegexError(self.pos, "invalid range"))?
+                    } else {
+                        lo
+                    };
+                    if hi < lo {
+                        return Err(RegexError(self.pos, "invalid range"));
+                    }
+                    ranges.push((lo, hi))
+                }
+                Ok(Ast::Chars(CharSet { ranges, negated }))
+            }
+            '.' => Ok(Ast::Chars(CharSet::ANY)),
+            '\\' => Ok(Ast::Chars(match self.escape(start)? {
+                Ok(c) => CharSet::single(c),
+                Err(set) => set,
+            })),
+            '*' | '+' | '?' | '{' => Err(RegexError(start, "nothing to repeat")),
+            c => Ok(Ast::Chars(CharSet::single(c))),
+        }
+    }
+
+    /// 字符类中的一个字符或转义的字符集合。
+    fn class_char(&mut self) -> Result<Result<char, CharSet>, RegexError> {
+        let start = self.pos;
+        let c = self.peek().unwrap();
+        self.pos += c.len_utf8();
+        if c == '\\' {
+            self.escape(start)
+        } else {
+            Ok(Ok(c))
+        }
+    }
+
+    /// 解析反斜杠之后的转义，返回字符或字符集合。
+    fn escape(&mut self, start: usize) -> Result<Result<char, CharSet>, RegexError> {
+        let err = RegexError(start, "invalid escape");
+        let c = self.peek().ok_or(err)?;
+        self.pos += c.len_utf8();
+        let set = |ranges: &[(char, char)], negated| {
+            Ok(Err(CharSet {
+                ranges: ranges.to_vec(),
+                negated,
+            }))
+        };
+        const DIGIT: &[(char, char)] = &[('0', '9')];
+        const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+        const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+        let mut hex = |len: usize| {
+            let code = self.src[self.pos..]
+                .get(..len)
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .and_then(char::from_u32)
+                .ok_or(err)?;
+            self.pos += len;
+            Ok(Ok(code))
+        };
+        match c {
+            'd' => set(DIGIT, false),
+            'D' => set(DIGIT, true),
+            'w' => set(WORD, false),
+            'W' => set(WORD, true),
+            's' => set(SPACE, false),
+            'S' => set(SPACE, true),
+            'n' => Ok(Ok('\n')),
+            'r' => Ok(Ok('\r')),
+            't' => Ok(Ok('\t')),
+            'x' => hex(2),
+            'u' => hex(4),
+            c if c.is_ascii_alphanumeric() => Err(err),
+            c => Ok(Ok(c)),
+        }
+    }
+}
+
+/// 按正则表达式约束生成，在词表上惰性构造确定有限自动机。
+pub(crate) struct RegexMatcher {
+    vocab: Arc<Vocab>,
+    state: usize,
+    /// 不完整的 UTF-8 字符，等待后续 token 补全。
+    partial: Vec<u8>,
+    dfa: RefCell<Dfa>,
+}
+
+/// 惰性构造的确定有限自动机，状态 0 是不能再匹配任何字符的死状态。
+struct Dfa {
+    regex: Arc<Regex>,
+    /// 每个状态对应的非确定有限自动机节点集合。
+    states: Vec<Vec<usize>>,
+    ids: HashMap<Vec<usize>, usize>,
+    next: HashMap<(usize, char), usize>,
+    /// 每个状态允许生成的 token。
+    masks: HashMap<usize, Arc<[bool]>>,
+}
+
+const DEAD: usize = 0;
+
+impl Dfa {
+    fn state(&mut self, set: Vec<usize>) -> usize {
+        if let Some(&id) = self.ids.get(&set) {
+            return id;
+        }
+        let id = self.states.len();
+        self.states.push(set.clone());
+        self.ids.insert(set, id);
+        id
+    }
+
+    fn step(&mut self, state: usize, c: char) -> usize {
+        if let Some(&next) = self.next.get(&(state, c)) {
+            return next;
+        }
+        let regex = &self.regex;
+        let set = regex.closure(
+            self.states[state]
+                .iter()
+                .filter_map(|&i| match &regex.nodes[i] {
+                    Node::Chars(chars, next) if chars.matches(c) => Some(*next),
+                    _ => None,
+                }),
+        );
+        let next = self.state(set);
+        self.next.insert((state, c), next);
+        next
+    }
+
+    fn is_match(&self, state: usize) -> bool {
+        self.states[state]
+            .iter()
+            .any(|&i| matches!(self.regex.nodes[i], Node::Match))
+    }
+
+    /// 接受一段字节，返回接受后的状态和末尾不完整的字符。
+    fn accept<'a>(&mut self, mut state: usize, bytes: &'a [u8]) -> Option<(usize, &'a [u8])> {
+        let (text, rest) = split_utf8(bytes)?;
+        for c in text.chars() {
+            state = self.step(state, c);
+            if state == DEAD {
+                return None;
+            }
+        }
+        // 不完整的字符要求可能被某个节点接受
+        if !rest.is_empty() {
+            let (lo, hi) = code_range(rest);
+            let alive = self.states[state].iter().any(|&i| {
+                matches!(&self.regex.nodes[i], Node::Chars(chars, _) if chars.may_match(lo, hi))
+            });
+            if !alive {
+                return None;
+            }
+        }
+        Some((state, rest))
+    }
+}
+
+impl RegexMatcher {
+    pub fn new(regex: Arc<Regex>, vocab: Arc<Vocab>) -> Self {
+        let mut dfa = Dfa {
+            states: vec![],
+            ids: HashMap::new(),
+            next: HashMap::new(),
+            masks: HashMap::new(),
+            regex,
+        };
+        assert_eq!(dfa.state(vec![]), DEAD);
+        let start = dfa.regex.closure([dfa.regex.start]);
+        let state = dfa.state(start);
+        Self {
+            vocab,
+            state,
+            partial: vec![],
+            dfa: RefCell::new(dfa),
+        }
+    }
+}
+
+impl Constraint for RegexMatcher {
+    fn mask(&self) -> Arc<[bool]> {
+        let mut dfa = self.dfa.borrow_mut();
+        if self.partial.is_empty() {
+            if let Some(mask) = dfa.masks.get(&self.state) {
+                return mask.clone();
+            }
+        }
+        let eos = self.vocab.eos as usize;
+        let dead = self.state == DEAD;
+        let complete = self.partial.is_empty() && dfa.is_match(self.state);
+        let mut buf = self.partial.clone();
+        let mask = self
+            .vocab
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(t, bytes)| {
+                if t == eos {
+                    complete || dead
+                } else if dead || bytes.is_empty() {
+                    false
+                } else {
+                    buf.truncate(self.partial.len());
+                    buf.extend_from_slice(bytes);
+                    dfa.accept(self.state, &buf).is_some()
+                }
+            })
+            .collect::<Arc<[bool]>>();
+        if self.partial.is_empty() {
+            dfa.masks.insert(self.state, mask.clone());
+        }
+        mask
+    }
+
+    fn advance(&mut self, token: utok) {
+        if token == self.vocab.eos {
+            return;
+        }
+        let mut buf = std::mem::take(&mut self.partial);
+        buf.extend_from_slice(self.vocab.tokens.get(token as usize).map_or(&[][..], |b| b));
+        (self.state, self.partial) = match self.dfa.get_mut().accept(self.state, &buf) {
+            Some((state, rest)) => (state, rest.to_vec()),
+            None => (DEAD, vec![]),
+        };
+    }
+}
+
+#[test]
+fn test_regex() {
+    let regex = Regex::new(r"^\d{3}-[a-c]+(?:\.x|y)?$").unwrap();
+    assert!(regex.matches("123-abc"));
+    assert!(regex.matches("000-a.x"));
+    assert!(regex.matches("999-cy"));
+    assert!(!regex.matches("12-a"));
+    assert!(!regex.matches("123-d"));
+    assert!(!regex.matches("123-a.y"));
+
+    let regex = Regex::new(r"(a|b)*c{2,}|[^\w\s]").unwrap();
+    assert!(regex.matches("abacc"));
+    assert!(regex.matches("cccc"));
+    assert!(regex.matches("!"));
+    assert!(!regex.matches("abc"));
+    assert!(!regex.matches("_"));
+
+    assert!(Regex::new("(a").is_err());
+    assert!(Regex::new("a)").is_err());
+    assert!(Regex::new("*a").is_err());
+    assert!(Regex::new("a{3,1}").is_err());
+}
+
+#[test]
+fn test_regex_matcher() {
+    let vocab = ["<eos>", "1", "12", "a", "2a", ""]
+        .map(|s| s.as_bytes().into())
+        .to_vec();
+    let vocab = Arc::new(Vocab {
+        tokens: vocab,
+        eos: 0,
+    });
+    let regex = Arc::new(Regex::new(r"\d{2}a?").unwrap());
+    let mut matcher = RegexMatcher::new(regex, vocab);
+
+    assert_eq!(&*matcher.mask(), [false, true, true, false, false, false]);
+    matcher.advance(1);
+    assert_eq!(&*matcher.mask(), [false, true, false, false, true, false]);
+    matcher.advance(4);
+    // 完整匹配且不能继续时只能结束
+    assert_eq!(&*matcher.mask(), [true, false, false, false, false, false]);
+}
@@ -0,0 +1,179 @@
+use crate::{ContextOverflow, Priority, Service};
+use causal_lm::CausalLM;
+use common::utok;
+use std::{error, fmt};
+
+/// 计算文本嵌入的参数。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct EmbedArgs {
+    /// 把每个 token 的隐藏状态合成一个向量的方式。
+    pub pooling: Pooling,
+    /// 是否把向量归一化为单位长度，便于直接以点积计算余弦相似度。
+    pub normalize: bool,
+    /// 只保留向量的前若干维，在归一化之前截断；为空时保留全部维度。
+    pub dimensions: Option<usize>,
+}
+
+impl Default for EmbedArgs {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            pooling: Pooling::Mean,
+            normalize: true,
+            dimensions: None,
+        }
+    }
+}
+
+/// 隐藏状态的池化方式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum Pooling {
+    /// 所有 token 的平均。
+    #[default]
+    Mean,
+    /// 最后一个 token，适用于以因果语言模型训练的嵌入模型。
+    Last,
+    /// 第一个 token，适用于以开头的特殊 token 汇总全文的嵌入模型。
+    First,
+}
+
+/// 计算文本嵌入的错误。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum EmbedError {
+    /// 模型不支持取出隐藏状态。
+    Unsupported,
+    /// 第若干个输入为空。
+    EmptyInput(usize),
+    /// 第若干个输入超过了模型的最大序列长度。
+    ContextOverflow(usize, ContextOverflow),
+    /// 要求的维度为零或超过了隐藏状态的维度，附带隐藏状态的维度。
+    Dimensions(usize),
+    /// 推理线程已退出。
+    Stopped,
+}
+
+impl error::Error for EmbedError {}
+impl fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "the model does not support embeddings"),
+            Self::EmptyInput(i) => write!(f, "input {i} is empty"),
+            Self::ContextOverflow(i, e) => write!(f, "input {i}: {e}"),
+            Self::Dimensions(d) => write!(f, "dimensions must be between 1 and {d}"),
+            Self::Stopped => write!(f, "the inference thread has stopped"),
+        }
+    }
+}
+
+impl<M: CausalLM> Service<M> {
+    /// 计算每个输入的文本嵌入：前向计算输入的全部 token，取最终归一化的隐藏状态，按 `args` 池化。
+    ///
+    /// 每个输入作为一个推理任务，与其他求嵌入的任务同批计算，不使用也不留下会话的缓存。
+    pub async fn embed(
+        &self,
+        inputs: Vec<Vec<utok>>,
+        args: EmbedArgs,
+        priority: Priority,
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        // 入队之前检查维度，避免计算注定被丢弃的嵌入
+        let d = self.component.handle.model.hidden_size();
+        if args.dimensions.is_some_and(|n| n == 0 || n > d) {
+            return Err(EmbedError::Dimensions(d));
+        }
+        let capacity = self.max_seq_len();
+        for (i, tokens) in inputs.iter().enumerate() {
+            if tokens.is_empty() {
+                return Err(EmbedError::EmptyInput(i));
+            }
+            if tokens.len() > capacity {
+                let required = tokens.len();
+                return Err(EmbedError::ContextOverflow(
+                    i,
+                    ContextOverflow { required, capacity },
+                ));
+            }
+        }
+        // 先全部入队，使它们能合并到同一次前向计算
+        let receivers = inputs
+            .into_iter()
+            .map(|tokens| (tokens.len(), self.component.hidden(tokens, priority)))
+            .collect::<Vec<_>>();
+        let mut ans = Vec::with_capacity(receivers.len());
+        for (len, receiver) in receivers {
+            let hidden = receiver.await.map_err(|_| EmbedError::Stopped)?;
+            let hidden = hidden.ok_or(EmbedError::Unsupported)?;
+            ans.push(pool(&hidden, hidden.len() / len, args)?);
+        }
+        Ok(ans)
+    }
+}
+
+/// 把 `hidden` 中每个 token 的隐藏状态按 `args` 合成一个向量，`hidden` 的每行有 `d` 个元素。
+fn pool(hidden: &[f32], d: usize, args: EmbedArgs) -> Result<Vec<f32>, EmbedError> {
+    // 模型没有给出完整的一行时无法池化
+    if d == 0 || hidden.len() < d {
+        return Err(EmbedError::Unsupported);
+    }
+    let dims = args.dimensions.unwrap_or(d);
+    if dims == 0 || dims > d {
+        return Err(EmbedError::Dimensions(d));
+    }
+    let mut rows = hidden.chunks_exact(d);
+    let mut ans = match args.pooling {
+        Pooling::Mean => {
+            let n = rows.len() as f32;
+            let mut sum = vec![0.; d];
+            for row in rows {
+                for (s, x) in sum.iter_mut().zip(row) {
+                    *s += x;
+                }
+            }
+            sum.iter_mut().for_each(|s| *s /= n);
+            sum
+        }
+        Pooling::Last => rows.next_back().unwrap().to_vec(),
+        Pooling::First => rows.next().unwrap().to_vec(),
+    };
+    ans.truncate(dims);
+    if args.normalize {
+        let norm = ans.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0. {
+            ans.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    Ok(ans)
+}
+
+#[test]
+fn test_pool() {
+    let hidden = [1., 2., 3., 4., 5., 6.];
+    let args = |pooling, normalize, dimensions| EmbedArgs {
+        pooling,
+        normalize,
+        dimensions,
+    };
+    assert_eq!(
+        pool(&hidden, 2, args(Pooling::Mean, false, None)),
+        Ok(vec![3., 4.])
+    );
+    assert_eq!(
+        pool(&hidden, 2, args(Pooling::Last, false, None)),
+        Ok(vec![5., 6.])
+    );
+    assert_eq!(
+        pool(&hidden, 2, args(Pooling::First, false, Some(1))),
+        Ok(vec![1.])
+    );
+    assert_eq!(
+        pool(&hidden, 2, args(Pooling::Mean, true, None)),
+        Ok(vec![0.6, 0.8])
+    );
+    assert_eq!(
+        pool(&hidden, 2, args(Pooling::Mean, true, Some(3))),
+        Err(EmbedError::Dimensions(2))
+    );
+    assert_eq!(
+        pool(&[], 0, args(Pooling::Last, true, None)),
+        Err(EmbedError::Unsupported)
+    );
+}
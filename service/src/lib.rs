@@ -1,21 +1,41 @@
 #![deny(warnings)]
 
+mod constraint;
+mod embed;
 mod session;
 mod template;
 
-use causal_lm::{CausalLM, SampleArgs};
-use session::{Dispatcher, Generator};
-use std::{fmt::Debug, path::Path, sync::Arc};
+use causal_lm::{CausalLM, ModelInfo, SampleArgs};
+use common::utok;
+use constraint::Vocab;
+use log::{info, warn};
+use session::{Dispatcher, Draft, Generator, Warm};
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+};
 use template::Template;
-use tokenizer::{BPECommonNormalizer, Normalizer, Tokenizer, VocabTxt, BPE};
+use tokenizer::{
+    AddedTokens, ByteLevel, Normalizer, SentencePiece, Tokenizer, TokenizerJson, VocabTxt,
+};
 use tokio::task::JoinHandle;
 
-pub use session::{BusySession, ChatError, Session};
+pub use constraint::{Grammar, GrammarError, Regex, RegexError};
+pub use embed::{EmbedArgs, EmbedError, Pooling};
+pub use session::{
+    BeamArgs, BusySession, ChatError, ContextOverflow, FinishReason, Overflow, Priority, Role,
+    Session, SessionStats, TokenLogprob, Turn,
+};
+pub use template::{ChatTemplateError, Custom as ChatTemplate};
 
 /// 对话服务。
 pub struct Service<M: CausalLM> {
     component: Arc<ServiceComponent<M>>,
     pub default_sample: SampleArgs,
+    /// 文本生成是否默认修复提示词末尾的 token，见 [`LoadOptions::token_healing`]。
+    pub token_healing: bool,
 }
 
 /// 服务中不变的组件，将在所有会话之间共享。
@@ -26,6 +46,9 @@ struct ServiceComponent<M: CausalLM> {
     tokenizer: Box<dyn Tokenizer + Send + Sync>,
     normalizer: Box<dyn Normalizer + Send + Sync>,
     template: Box<dyn Template + Send + Sync>,
+    warm: Mutex<Vec<Warm<M::Storage>>>,
+    /// 约束解码使用的词表，第一次使用时构造。
+    vocab: OnceLock<Arc<Vocab>>,
 }
 
 impl<M: CausalLM> Drop for ServiceComponent<M> {
@@ -33,6 +56,12 @@ impl<M: CausalLM> Drop for ServiceComponent<M> {
     fn drop(&mut self) {
         // 停止推理任务
         self.handle.stop();
+        // 所有会话都已释放，释放预填充的模板后检查缓存泄漏
+        self.warm.get_mut().unwrap().clear();
+        self.handle.check_leak();
+        if let Some(Draft::Model { dispatcher, .. }) = self.handle.draft.get().map(|d| &**d) {
+            dispatcher.check_leak();
+        }
     }
 }
 
@@ -42,21 +71,121 @@ where
     M::Storage: Send,
     M::Error: Debug,
 {
+    #[inline]
     pub fn load(model_dir: impl AsRef<Path>, meta: M::Meta) -> (Self, JoinHandle<()>) {
-        let handle = Arc::new(Dispatcher::from(M::load(&model_dir, meta).unwrap()));
+        Self::load_with_options(model_dir, meta, Default::default())
+    }
+
+    /// 按照 `options` 加载模型。
+    pub fn load_with_options(
+        model_dir: impl AsRef<Path>,
+        meta: M::Meta,
+        options: LoadOptions,
+    ) -> (Self, JoinHandle<()>) {
+        let mut dispatcher = Dispatcher::from(M::load(&model_dir, meta).unwrap());
+        if let Some(max_batch) = options.max_batch_size {
+            assert!(max_batch > 0, "max_batch_size must be positive");
+            dispatcher.max_batch = max_batch;
+        }
+        if let Some(chunk) = options.prefill_chunk {
+            assert!(chunk > 0, "prefill_chunk must be positive");
+            dispatcher.prefill_chunk = chunk;
+        }
+        if let Some(limit) = options.speculation_limit.or(options.max_batch_size) {
+            dispatcher.speculation_limit = limit;
+        }
+        let handle = Arc::new(dispatcher);
+        if let Some(num_draft) = options.prompt_lookup.filter(|&n| n > 0) {
+            let _ = handle.draft.set(Box::new(Draft::Lookup { num_draft }));
+        }
+        let template = template(&model_dir, options.chat_template);
+        let (tokenizer, normalizer) = if options.byte_tokenizer {
+            byte_tokenizer()
+        } else {
+            let embd_rows = handle.model.vocab_size();
+            let (tokenizer, normalizer) = match tokenizer(&model_dir) {
+                Some((tokenizer, normalizer)) => {
+                    // 分词器文件的词表（包括 tokenizer.json 的 added_tokens）必须都在词嵌入矩阵中
+                    let vocab_size = tokenizer.vocab_size();
+                    assert!(
+                        vocab_size <= embd_rows,
+                        "the tokenizer has {vocab_size} tokens, \
+                        but the embedding matrix only has {embd_rows} rows"
+                    );
+                    (tokenizer, normalizer)
+                }
+                None => {
+                    warn!("Tokenizer file not found, fall back to byte-level tokenizer");
+                    byte_tokenizer()
+                }
+            };
+            let tokenizer = added_tokens(&model_dir, tokenizer, embd_rows);
+            (tokenizer, normalizer)
+        };
         (
             Self {
                 component: Arc::new(ServiceComponent {
                     handle: handle.clone(),
-                    tokenizer: tokenizer(&model_dir),
-                    normalizer: normalizer(&model_dir),
-                    template: template(model_dir),
+                    tokenizer,
+                    normalizer,
+                    template,
+                    warm: Default::default(),
+                    vocab: Default::default(),
                 }),
                 default_sample: Default::default(),
+                token_healing: options.token_healing,
             },
             tokio::task::spawn_blocking(move || handle.run()),
         )
     }
+
+    /// 加载草稿模型，之后的推理用草稿模型每步推测至多 `num_draft` 个 token，再由模型一次验证。
+    ///
+    /// 草稿模型必须与模型使用相同的词表。只有采样与历史无关且不指定种子、
+    /// 没有约束和处理器链的推理会推测，推测不改变输出的分布。
+    pub fn load_draft(&self, model_dir: impl AsRef<Path>, meta: M::Meta, num_draft: usize) {
+        assert!(num_draft > 0, "num_draft must be positive");
+        let dispatcher = Dispatcher::from(M::load(model_dir, meta).unwrap());
+        assert_eq!(
+            dispatcher.model.eos_token(),
+            self.component.handle.model.eos_token(),
+            "draft model must share the vocabulary"
+        );
+        let draft = Draft::Model {
+            dispatcher,
+            num_draft,
+        };
+        if self.component.handle.draft.set(Box::new(draft)).is_err() {
+            panic!("speculative decoding already enabled");
+        }
+    }
+}
+
+/// 加载服务的选项。
+#[derive(Clone, Default, Debug)]
+pub struct LoadOptions {
+    /// 覆盖模型自带的对话模板。
+    pub chat_template: Option<ChatTemplate>,
+    /// 不使用分词器文件，文本的每个字节对应一个 token。
+    pub byte_tokenizer: bool,
+    /// 在上下文中查找与末尾相同的 n-gram 推测之后的 token，每步至多推测的 token 数。
+    ///
+    /// 推测由模型一次验证，不需要草稿模型，适合输出大量复述上下文的任务（如摘要和代码修改）。
+    pub prompt_lookup: Option<usize>,
+    /// 文本生成时回退提示词的最后一个 token，约束第一个生成的 token 以回退的文本开头，
+    /// 改善在 token 中间结束的提示词（如末尾的空格或半个单词）的续写质量。
+    pub token_healing: bool,
+    /// 每次前向计算至多合并的推理任务数，不指定时合并所有等待的任务。
+    ///
+    /// 所有会话的推理任务连续批处理，新的请求在两次前向计算之间加入，超出的任务等待下一次前向计算。
+    pub max_batch_size: Option<usize>,
+    /// 分块预填充时每块的 token 数，不指定时一次预填充整个提示词。
+    ///
+    /// 长提示词分块预填充，每次前向计算只计算一块，与其他会话的解码交替进行，避免其他会话长时间停顿。
+    pub prefill_chunk: Option<usize>,
+    /// 推测解码的负载上限，一次前向计算的任务数与等待的任务数之和达到上限时不再推测，
+    /// 负载低于上限时按负载的比例减少每步的草稿数；不指定时为 `max_batch_size`。
+    pub speculation_limit: Option<usize>,
 }
 
 impl<M: CausalLM> Service<M> {
@@ -68,11 +197,86 @@ impl<M: CausalLM> Service<M> {
         session
     }
 
+    /// 模型是否加载了名为 `name` 的 LoRA 适配器。
+    #[inline]
+    pub fn has_adapter(&self, name: &str) -> bool {
+        self.component.handle.model.has_adapter(name)
+    }
+
+    /// 词表大小，合法的 token 序号小于这个值。
+    #[inline]
+    pub fn vocab_size(&self) -> usize {
+        self.component.tokenizer.vocab_size()
+    }
+
+    /// 模型的上下文长度，即会话至多容纳的 token 数。
+    #[inline]
+    pub fn max_seq_len(&self) -> usize {
+        self.component.handle.model.max_seq_len() as _
+    }
+
+    /// 模型加载的方式。
+    #[inline]
+    pub fn model_info(&self) -> ModelInfo {
+        self.component.handle.model.info()
+    }
+
+    /// 推理线程是否仍在运行，推理线程崩溃后服务不再能推理。
+    #[inline]
+    pub fn is_ready(&self) -> bool {
+        self.component.handle.is_running()
+    }
+
+    /// 等待前向计算的推理任务数，反映服务的负载。
+    #[inline]
+    pub fn queue_len(&self) -> usize {
+        self.component.handle.queue_len()
+    }
+
+    /// 将文本编码为 token 序列，不套用对话模板，文本中的特殊词汇编码为特殊 token。
+    #[inline]
+    pub fn tokenize(&self, text: &str) -> Vec<utok> {
+        let text = self.component.normalizer.encode(text);
+        self.component.tokenizer.encode(&text)
+    }
+
+    /// 将 token 序列解码为文本，`skip_special` 为真时跳过特殊词汇。
+    ///
+    /// token 序号必须小于 [`Service::vocab_size`]。
+    #[inline]
+    pub fn detokenize(&self, tokens: &[utok], skip_special: bool) -> String {
+        self.component.detokenize(tokens, skip_special)
+    }
+
+    /// 预填充对话模板（如系统提示词和示例对话），之后系统提示词相同、以模板开头的会话复用模板的缓存。
+    ///
+    /// 模板由完整的问答轮次组成，相同的模板只保留最新的一份。
+    pub async fn warm_up(&self, system: Option<String>, template: Vec<String>) {
+        assert!(
+            !template.is_empty() && template.len().is_multiple_of(2),
+            "template must consist of complete turns"
+        );
+        let mut session = self.launch();
+        session.system = system;
+        session.warm_up(template).await;
+    }
+
     /// 从对话服务启动一个文本生成器。
     #[inline]
     pub fn generate(&self, prompt: impl AsRef<str>, sample: Option<SampleArgs>) -> Generator<M> {
+        self.generate_with(prompt, sample, None)
+    }
+
+    /// 从对话服务启动一个文本生成器，`token_healing` 为空时按服务的设置修复提示词末尾的 token。
+    pub fn generate_with(
+        &self,
+        prompt: impl AsRef<str>,
+        sample: Option<SampleArgs>,
+        token_healing: Option<bool>,
+    ) -> Generator<M> {
         let sample = sample.unwrap_or_else(|| self.default_sample.clone());
-        Generator::new(self.component.clone(), prompt, sample)
+        let token_healing = token_healing.unwrap_or(self.token_healing);
+        Generator::new(self.component.clone(), prompt, sample, token_healing)
     }
 }
 
@@ -116,42 +320,154 @@ fn test() {
     runtime.shutdown_background();
 }
 
-fn template(model_dir: impl AsRef<Path>) -> Box<dyn Template + Send + Sync> {
+/// 优先使用 `custom` 和 tokenizer_config.json 中的对话模板，都没有时按模型路径和特殊词汇选择内置的模板。
+///
+/// jinja 模板使用 tokenizer_config.json 中的 bos、eos 词汇。
+fn template(
+    model_dir: impl AsRef<Path>,
+    custom: Option<ChatTemplate>,
+) -> Box<dyn Template + Send + Sync> {
+    use serde_json::Value;
+
+    let config = std::fs::read_to_string(model_dir.as_ref().join("tokenizer_config.json"))
+        .ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .unwrap_or_default();
+    // 模板可能是多个命名模板的列表
+    let custom = custom.map(|t| t.0);
+    let source = custom.as_deref().or(match &config["chat_template"] {
+        Value::String(s) => Some(s.as_str()),
+        Value::Array(list) => list
+            .iter()
+            .find(|t| t["name"] == "default")
+            .or(list.first())
+            .and_then(|t| t["template"].as_str()),
+        _ => None,
+    });
+    if let Some(source) = source {
+        // 特殊词汇可能是字符串或带有 content 的对象
+        let token = |key: &str| match &config[key] {
+            Value::String(s) => s.clone(),
+            v => v["content"].as_str().unwrap_or_default().into(),
+        };
+        match template::Jinja::new(source.into(), token("bos_token"), token("eos_token")) {
+            Ok(t) => return Box::new(t),
+            Err(e) => warn!("Failed to compile chat template: {e}, fall back to built-in template"),
+        }
+    }
+
     let path: String = model_dir.as_ref().display().to_string();
     let path = path.to_ascii_lowercase();
+    let config = config.to_string();
     if path.contains("tinyllama") {
         Box::new(template::ChatTinyLlama)
+    } else if path.contains("llama-2") || path.contains("llama2") {
+        Box::new(template::ChatLlama2)
+    } else if path.contains("gemma") || config.contains("<start_of_turn>") {
+        Box::new(template::ChatGemma)
+    } else if path.contains("qwen") || config.contains("<|im_start|>") {
+        Box::new(template::ChatML)
     } else {
         Box::new(template::ChatCPM)
     }
 }
 
-fn normalizer(model_dir: impl AsRef<Path>) -> Box<dyn Normalizer + Send + Sync> {
+type BoxTokenizer = Box<dyn Tokenizer + Send + Sync>;
+type BoxNormalizer = Box<dyn Normalizer + Send + Sync>;
+
+/// 从模型目录中的分词器文件加载分词器和对应的规范化器，没有分词器文件时返回空。
+fn tokenizer(model_dir: impl AsRef<Path>) -> Option<(BoxTokenizer, BoxNormalizer)> {
     use std::io::ErrorKind::NotFound;
-    match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
-        Ok(_) => return Box::new(BPECommonNormalizer {}),
+    match SentencePiece::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
+        Ok(sp) => return Some((Box::new(sp), Box::new(()))),
+        Err(e) if e.kind() == NotFound => {}
+        Err(e) => panic!("{e:?}"),
+    }
+    match TokenizerJson::from_json_file(model_dir.as_ref().join("tokenizer.json")) {
+        Ok(json) => return Some((Box::new(json), Box::new(()))),
         Err(e) if e.kind() == NotFound => {}
         Err(e) => panic!("{e:?}"),
     }
     match VocabTxt::from_txt_file(model_dir.as_ref().join("vocabs.txt")) {
-        Ok(_) => return Box::new(()),
+        Ok(voc) => return Some((Box::new(voc), Box::new(()))),
         Err(e) if e.kind() == NotFound => {}
         Err(e) => panic!("{e:?}"),
     }
-    panic!("Tokenizer file not found");
+    None
 }
 
-fn tokenizer(model_dir: impl AsRef<Path>) -> Box<dyn Tokenizer + Send + Sync> {
-    use std::io::ErrorKind::NotFound;
-    match BPE::from_model_file(model_dir.as_ref().join("tokenizer.model")) {
-        Ok(bpe) => return Box::new(bpe),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+/// 加载模型目录中 added_tokens.json 和 tokenizer_config.json 的 `added_tokens_decoder` 增加的词汇。
+///
+/// 分词器中已有的词汇不重复增加，增加的词汇的序号必须小于词嵌入矩阵的行数 `embd_rows`。
+fn added_tokens(
+    model_dir: impl AsRef<Path>,
+    tokenizer: BoxTokenizer,
+    embd_rows: usize,
+) -> BoxTokenizer {
+    use serde_json::Value;
+
+    let read = |name: &str| {
+        std::fs::read_to_string(model_dir.as_ref().join(name))
+            .ok()
+            .map(|s| serde_json::from_str::<Value>(&s).unwrap_or_else(|e| panic!("{name}: {e}")))
+            .unwrap_or_default()
+    };
+    let config = read("tokenizer_config.json");
+    // 特殊词汇可能是字符串或带有 content 的对象
+    let content = |v: &Value| match v {
+        Value::String(s) => Some(s.clone()),
+        v => v["content"].as_str().map(Into::into),
+    };
+    let special = ["bos_token", "eos_token", "unk_token", "pad_token"]
+        .iter()
+        .filter_map(|key| content(&config[key]))
+        .chain(
+            config["additional_special_tokens"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(content),
+        )
+        .collect::<HashSet<_>>();
+
+    let mut added = Vec::new();
+    if let Value::Object(map) = &config["added_tokens_decoder"] {
+        for (id, token) in map {
+            if let (Ok(id), Some(piece)) = (id.parse::<utok>(), content(token)) {
+                let is_special = token["special"]
+                    .as_bool()
+                    .unwrap_or(special.contains(&piece));
+                added.push((piece, id, is_special));
+            }
+        }
     }
-    match VocabTxt::from_txt_file(model_dir.as_ref().join("vocabs.txt")) {
-        Ok(voc) => return Box::new(voc),
-        Err(e) if e.kind() == NotFound => {}
-        Err(e) => panic!("{e:?}"),
+    if let Value::Object(map) = read("added_tokens.json") {
+        for (piece, id) in map {
+            if let Some(id) = id.as_u64() {
+                let is_special = special.contains(&piece);
+                added.push((piece, id as utok, is_special));
+            }
+        }
     }
-    panic!("Tokenizer file not found");
+    added.retain(|(piece, id, _)| {
+        (*id as usize) >= tokenizer.vocab_size() || tokenizer.decode(*id) != piece
+    });
+    if added.is_empty() {
+        return tokenizer;
+    }
+
+    if let Some((piece, id, _)) = added.iter().find(|(_, id, _)| *id as usize >= embd_rows) {
+        panic!(
+            "added token {piece:?} has id {id}, but the embedding matrix only has {embd_rows} rows, \
+            resize the token embeddings of the model after adding tokens"
+        );
+    }
+    let tokenizer = AddedTokens::new(tokenizer, added);
+    info!("{} added tokens loaded", tokenizer.len());
+    Box::new(tokenizer)
+}
+
+/// 字节级分词器，与 sentencepiece 词表一致，字节 token 从 3 开始。
+fn byte_tokenizer() -> (BoxTokenizer, BoxNormalizer) {
+    (Box::new(ByteLevel::new(3)), Box::new(()))
 }
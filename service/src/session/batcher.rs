@@ -1,7 +1,16 @@
-﻿use std::sync::{Condvar, Mutex};
+﻿use super::Priority;
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    sync::{Condvar, Mutex},
+};
 
+/// 任务等待多少批后提升一级优先级。
+const AGING: usize = 8;
+
+/// 任务队列，同一优先级的任务先进先出。
 pub struct Batcher<T> {
-    queue: Mutex<(Vec<T>, bool)>,
+    queue: Mutex<(VecDeque<Entry<T>>, bool)>,
     condvar: Condvar,
 }
 
@@ -9,30 +18,59 @@ impl<T> Batcher<T> {
     #[inline]
     pub fn new() -> Self {
         Self {
-            queue: Mutex::new((Vec::new(), true)),
+            queue: Mutex::new((VecDeque::new(), true)),
             condvar: Default::default(),
         }
     }
 
     #[inline]
-    pub fn enq(&self, val: T) {
+    pub fn enq(&self, val: T, priority: Priority) {
         let mut lock = self.queue.lock().unwrap();
         let (queue, alive) = &mut *lock;
         if *alive {
-            queue.push(val);
+            queue.push_back(Entry {
+                val,
+                priority,
+                waited: 0,
+            });
         }
         self.condvar.notify_one();
     }
 
+    /// 队列是否仍接受任务。
+    #[inline]
+    pub fn is_alive(&self) -> bool {
+        self.queue.lock().unwrap().1
+    }
+
+    /// 队列中等待的任务数。
     #[inline]
-    pub fn deq(&self) -> Vec<T> {
-        std::mem::take(
-            &mut self
-                .condvar
-                .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
-                .unwrap()
-                .0,
-        )
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().0.len()
+    }
+
+    /// 等待任务入队，取出至多 `max` 个任务，队列关闭后返回空。
+    ///
+    /// 任务超出 `max` 时优先取出优先级高的任务，同一优先级中先取出先入队的；
+    /// 没有取出的任务每等待 [`AGING`] 批提升一级优先级，低优先级的任务不会一直等待。
+    pub fn deq(&self, max: usize) -> Vec<T> {
+        let mut lock = self
+            .condvar
+            .wait_while(self.queue.lock().unwrap(), |(q, a)| q.is_empty() && *a)
+            .unwrap();
+        let queue = &mut lock.0;
+        if queue.len() > max {
+            // 稳定排序，同一优先级保持入队顺序
+            queue
+                .make_contiguous()
+                .sort_by_key(|e| Reverse(e.priority as usize + e.waited / AGING));
+        }
+        let n = queue.len().min(max);
+        let ans = queue.drain(..n).map(|e| e.val).collect();
+        for e in queue {
+            e.waited += 1;
+        }
+        ans
     }
 
     #[inline]
@@ -44,3 +82,43 @@ impl<T> Batcher<T> {
         self.condvar.notify_all();
     }
 }
+
+struct Entry<T> {
+    val: T,
+    priority: Priority,
+    /// 入队后没有被取出的批数。
+    waited: usize,
+}
+
+#[test]
+fn test_deq() {
+    let batcher = Batcher::new();
+    for i in 0..5 {
+        batcher.enq(i, Priority::Normal);
+    }
+    assert_eq!(batcher.deq(2), [0, 1]);
+    batcher.enq(5, Priority::Normal);
+    assert_eq!(batcher.deq(usize::MAX), [2, 3, 4, 5]);
+    assert!(batcher.is_alive());
+    batcher.shutdown();
+    assert!(!batcher.is_alive());
+    batcher.enq(6, Priority::Normal);
+    assert!(batcher.deq(usize::MAX).is_empty());
+}
+
+#[test]
+fn test_priority() {
+    let batcher = Batcher::new();
+    batcher.enq("batch", Priority::Low);
+    batcher.enq("chat", Priority::High);
+    batcher.enq("default", Priority::Normal);
+    assert_eq!(batcher.deq(1), ["chat"]);
+    // 高优先级的任务持续入队时，低优先级的任务等待一段时间后仍能被取出
+    let mut served = vec![];
+    for _ in 0..4 * AGING {
+        batcher.enq("chat", Priority::High);
+        served.extend(batcher.deq(1));
+    }
+    assert!(served.contains(&"default"));
+    assert!(served.contains(&"batch"));
+}
@@ -0,0 +1,149 @@
+use super::{cache::Cache, Priority};
+use crate::ServiceComponent;
+use causal_lm::CausalLM;
+use common::utok;
+
+/// 束搜索参数。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BeamArgs {
+    /// 束宽，每步保留的候选数。
+    pub width: usize,
+    /// 长度惩罚，候选的得分为对数概率之和除以长度的 `length_penalty` 次方，大于 0 鼓励更长的结果。
+    pub length_penalty: f32,
+    /// 提前停止，得到 `width` 个完整的候选即停止，否则直到不可能得到更好的候选。
+    pub early_stopping: bool,
+}
+
+impl Default for BeamArgs {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            width: 4,
+            length_penalty: 1.,
+            early_stopping: false,
+        }
+    }
+}
+
+impl BeamArgs {
+    #[inline]
+    fn score(&self, logprob: f32, len: usize) -> f32 {
+        logprob / (len.max(1) as f32).powf(self.length_penalty)
+    }
+}
+
+/// 搜索中的一个候选，每个候选持有自己的缓存，分叉的候选共享缓存直到写入。
+struct Beam<Storage> {
+    cache: Cache<Storage>,
+    tokens: Vec<utok>,
+    logprob: f32,
+}
+
+/// 束搜索的结果。
+pub(super) struct BeamOutput<Storage> {
+    pub cache: Cache<Storage>,
+    /// 生成的 token，不包括结束符。
+    pub tokens: Vec<utok>,
+    /// 是否生成了结束符。
+    pub finished: bool,
+}
+
+impl<M: CausalLM> ServiceComponent<M> {
+    /// 从 `cache` 开始束搜索，至多生成 `max_tokens` 个 token，返回得分最高的候选。
+    pub(super) async fn beam_search(
+        &self,
+        cache: Cache<M::Storage>,
+        args: BeamArgs,
+        max_tokens: usize,
+        priority: Priority,
+    ) -> BeamOutput<M::Storage> {
+        let eos = self.handle.model.eos_token();
+        let width = args.width.max(1);
+
+        let mut beams = vec![Beam {
+            cache,
+            tokens: vec![],
+            logprob: 0.,
+        }];
+        // 生成了结束符的候选，按得分从高到低排列
+        let mut finished = Vec::<(f32, Beam<M::Storage>)>::new();
+        for step in 1..=max_tokens {
+            // 所有候选同时入队，在同一批次中计算
+            let tasks = beams
+                .into_iter()
+                .map(|b| {
+                    (
+                        self.candidates(b.cache, width * 2, priority),
+                        b.tokens,
+                        b.logprob,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let mut parents = Vec::with_capacity(tasks.len());
+            let mut expanded = Vec::new();
+            for (i, (task, tokens, logprob)) in tasks.into_iter().enumerate() {
+                let (cache, candidates) = task.wait().await;
+                expanded.extend(candidates.into_iter().map(|(t, p)| (i, t, logprob + p)));
+                parents.push((cache, tokens));
+            }
+            expanded.sort_unstable_by(|a, b| b.2.total_cmp(&a.2));
+
+            beams = Vec::with_capacity(width);
+            for (i, token, logprob) in expanded {
+                if beams.len() == width {
+                    break;
+                }
+                let (cache, tokens) = &parents[i];
+                let mut beam = Beam {
+                    cache: cache.fork(),
+                    tokens: tokens.clone(),
+                    logprob,
+                };
+                if token == eos {
+                    finished.push((args.score(logprob, beam.tokens.len()), beam));
+                } else {
+                    beam.tokens.push(token);
+                    beam.cache.push(token);
+                    beams.push(beam);
+                }
+            }
+            finished.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+            finished.truncate(width);
+
+            if beams.is_empty() || step == max_tokens {
+                break;
+            }
+            if finished.len() == width {
+                if args.early_stopping {
+                    break;
+                }
+                // 最好的候选也不如最差的完整候选时停止
+                let worst = finished.last().unwrap().0;
+                let best = beams
+                    .iter()
+                    .map(|b| args.score(b.logprob, b.tokens.len()))
+                    .fold(f32::NEG_INFINITY, f32::max);
+                if best <= worst {
+                    break;
+                }
+            }
+        }
+
+        // 没有完成的候选也参与比较
+        let finished = finished
+            .into_iter()
+            .map(|(score, beam)| (score, beam, true));
+        let unfinished = beams
+            .into_iter()
+            .map(|b| (args.score(b.logprob, b.tokens.len()), b, false));
+        let (_, beam, finished) = finished
+            .chain(unfinished)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+        BeamOutput {
+            cache: beam.cache,
+            tokens: beam.tokens,
+            finished,
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering::Relaxed},
+    Arc, Mutex,
+};
+
+/// 计算缓存块。
+///
+/// 分叉的会话之间共享同一个缓存块，写入前若仍被共享则复制一份（写时复制），
+/// 因此任何持有者都不会看到其他持有者写入的内容。
+pub(super) struct KvBlock<T>(Arc<Shared<T>>);
+
+struct Shared<T> {
+    val: Mutex<T>,
+    live: Arc<AtomicUsize>,
+}
+
+impl<T> Drop for Shared<T> {
+    #[inline]
+    fn drop(&mut self) {
+        let prev = self.live.fetch_sub(1, Relaxed);
+        debug_assert!(prev > 0, "kv block double free");
+    }
+}
+
+/// 缓存块计数器，统计存活的缓存块以检查泄漏。
+#[derive(Clone, Default, Debug)]
+pub(super) struct BlockCounter(Arc<AtomicUsize>);
+
+impl BlockCounter {
+    /// 分配一个新的缓存块。
+    #[inline]
+    pub fn alloc<T>(&self, val: T) -> KvBlock<T> {
+        self.0.fetch_add(1, Relaxed);
+        KvBlock(Arc::new(Shared {
+            val: Mutex::new(val),
+            live: self.0.clone(),
+        }))
+    }
+
+    /// 存活的缓存块数量。
+    #[inline]
+    pub fn live(&self) -> usize {
+        self.0.load(Relaxed)
+    }
+}
+
+impl<T> KvBlock<T> {
+    /// 共享缓存块，引用计数加一。
+    #[inline]
+    pub fn share(&self) -> Self {
+        let ans = Self(self.0.clone());
+        debug_assert!(ans.ref_count() > 1);
+        ans
+    }
+
+    /// 缓存块当前的持有者数量。
+    #[inline]
+    pub fn ref_count(&self) -> usize {
+        Arc::strong_count(&self.0)
+    }
+
+    /// 读取缓存块。
+    #[inline]
+    pub fn read<U>(&self, f: impl FnOnce(&T) -> U) -> U {
+        f(&self.0.val.lock().unwrap())
+    }
+
+    /// 获取可写的缓存块。若缓存块仍被共享，使用 `dup` 复制一份独占的缓存块。
+    pub fn make_mut(&mut self, dup: impl FnOnce(&T) -> T) -> &mut T {
+        if Arc::get_mut(&mut self.0).is_none() {
+            let val = dup(&self.0.val.lock().unwrap());
+            let counter = BlockCounter(self.0.live.clone());
+            *self = counter.alloc(val);
+        }
+        let shared = Arc::get_mut(&mut self.0).unwrap();
+        debug_assert!(shared.live.load(Relaxed) > 0);
+        shared.val.get_mut().unwrap()
+    }
+}
+
+#[test]
+fn test_fork_drop() {
+    let counter = BlockCounter::default();
+    let mut a = counter.alloc(vec![1, 2, 3]);
+    assert_eq!(counter.live(), 1);
+
+    let mut b = a.share();
+    let c = b.share();
+    assert_eq!((a.ref_count(), counter.live()), (3, 1));
+
+    // 共享时写入，复制出独占的块
+    b.make_mut(Clone::clone).push(4);
+    assert_eq!((a.ref_count(), b.ref_count(), counter.live()), (2, 1, 2));
+    // 独占时写入，不再复制
+    b.make_mut(|_| unreachable!()).push(5);
+    assert_eq!(*b.make_mut(|_| unreachable!()), [1, 2, 3, 4, 5]);
+
+    drop(c);
+    assert_eq!(*a.make_mut(|_| unreachable!()), [1, 2, 3]);
+    drop(a);
+    drop(b);
+    assert_eq!(counter.live(), 0);
+}
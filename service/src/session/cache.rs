@@ -1,6 +1,7 @@
-﻿use causal_lm::{CausalLM, QueryContext};
+﻿use super::{block::KvBlock, Dispatcher};
+use causal_lm::{CausalLM, QueryContext};
 use common::{upos, utok};
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 use tensor::Tensor;
 
 pub(super) struct Cache<Storage> {
@@ -10,30 +11,37 @@ pub(super) struct Cache<Storage> {
     pos: usize,
     /// 缓存在 token 序列中的范围。
     cached: Range<usize>,
-    /// 计算缓存。
-    cache: Tensor<Storage>,
+    /// 计算缓存，可能与分叉的会话共享。
+    cache: KvBlock<Tensor<Storage>>,
+    /// 计算缓存使用的适配器。
+    adapter: Option<Arc<str>>,
 }
 
 impl<Storage> Cache<Storage> {
     /// 生成一个空白的缓存结构，准备填充 `tokens`。
     #[inline]
-    pub fn new(t: &impl CausalLM<Storage = Storage>, tokens: Vec<utok>) -> Self {
+    pub fn new<M>(d: &Dispatcher<M>, tokens: Vec<utok>) -> Self
+    where
+        M: CausalLM<Storage = Storage>,
+    {
         Self {
             tokens,
             pos: 0,
             cached: 0..0,
-            cache: t.new_cache(),
+            cache: d.blocks.alloc(d.model.new_cache()),
+            adapter: None,
         }
     }
-    /// 复制缓存结构。
+    /// 分叉缓存结构，计算缓存将在首次写入时复制。
     #[inline]
-    pub fn duplicate(&self, t: &impl CausalLM<Storage = Storage>) -> Self {
+    pub fn fork(&self) -> Self {
         assert_eq!(self.cached.start, 0);
         Self {
             tokens: self.tokens.clone(),
             pos: self.pos,
             cached: self.cached.clone(),
-            cache: t.duplicate_cache(&self.cache, self.cached.end as _),
+            cache: self.cache.share(),
+            adapter: self.adapter.clone(),
         }
     }
     /// 回滚缓存到 `pos`，并返回剩余的有效缓存长度。
@@ -51,6 +59,13 @@ impl<Storage> Cache<Storage> {
         // 返回当前的缓存长度
         self.cached.len()
     }
+    /// 丢弃对话中 `end` 之后的 token，用于丢弃没有被接受的推测 token。
+    pub fn truncate(&mut self, end: usize) {
+        let len = end.checked_sub(self.pos).unwrap();
+        assert!(self.cached.start <= len);
+        self.tokens.truncate(len);
+        self.cached.end = self.cached.end.min(len);
+    }
     /// 扩展待填充 token。
     #[inline]
     pub fn extend(&mut self, tokens: &[utok]) {
@@ -61,20 +76,36 @@ impl<Storage> Cache<Storage> {
     pub fn query(&self) -> &[utok] {
         &self.tokens[self.cached.end..]
     }
-    /// 生成对应的查询上下文。
+    /// 生成查询开头 `len` 个 token 的查询上下文。
+    ///
+    /// 查询将写入计算缓存，因此仍被共享的缓存先复制一份。
     #[inline]
-    pub fn as_ctx(&mut self) -> QueryContext<Storage> {
+    pub fn as_ctx(
+        &mut self,
+        t: &impl CausalLM<Storage = Storage>,
+        len: usize,
+    ) -> QueryContext<Storage> {
         let Cache {
             pos: _pos,
             cache,
             tokens,
             cached,
+            adapter,
         } = self;
+        assert!(cached.end + len <= tokens.len());
+        let start = cached.len() as upos;
         QueryContext {
-            cache: Some(cache),
-            range: cached.len() as upos..(tokens.len() - cached.start) as upos,
+            cache: Some(cache.make_mut(|c| t.duplicate_cache(c, start))),
+            range: start..start + len as upos,
+            adapter: adapter.as_deref(),
         }
     }
+    /// 查询开头的 `len` 个 token 已经计算，加入缓存，用于分块预填充。
+    #[inline]
+    pub fn advance(&mut self, len: usize) {
+        assert!(self.cached.end + len <= self.tokens.len());
+        self.cached.end += len;
+    }
 
     /// 将新采样的值加入缓存。
     #[inline]
@@ -82,11 +113,21 @@ impl<Storage> Cache<Storage> {
         self.cached.end = self.tokens.len();
         self.tokens.push(token);
     }
+    /// 将所有 token 标记为已缓存，用于只预填充不采样的推理。
+    #[inline]
+    pub fn commit(&mut self) {
+        self.cached.end = self.tokens.len();
+    }
     /// 已采样的最后一个词在对话中的位置。
     #[inline]
     pub fn end(&self) -> usize {
         self.pos + self.tokens.len()
     }
+    /// 最近的至多 `len` 个 token。
+    #[inline]
+    pub fn recent(&self, len: usize) -> &[utok] {
+        &self.tokens[self.tokens.len().saturating_sub(len)..]
+    }
     /// 提取尾部词序列。
     #[inline]
     pub fn slice_tail(&self, pos: usize) -> &[utok] {
@@ -94,13 +135,34 @@ impl<Storage> Cache<Storage> {
         &self.tokens[known..]
     }
 
+    /// 已计算缓存的 token 数。
+    #[inline]
+    pub fn cached_len(&self) -> usize {
+        self.cached.len()
+    }
+    /// 计算缓存占用的字节数，以及是否与分叉的会话共享。
+    #[inline]
+    pub fn memory(&self) -> (usize, bool) {
+        let bytes = self.cache.read(|t| t.bytes_size());
+        (bytes, self.cache.ref_count() > 1)
+    }
+    /// 缓存窗口中的 token 数，包括还没有计算的查询。
+    #[inline]
+    pub fn window_len(&self) -> usize {
+        self.tokens.len() - self.cached.start
+    }
     /// 重置缓存窗口。
     pub fn reset_within(&mut self, min: usize, max: usize) {
-        if self.tokens.len() - self.cached.start >= max {
+        if self.window_len() >= max {
             self.cached.start = self.tokens.len() - min;
             self.cached.end = self.cached.start;
         }
     }
+    /// 切换适配器，已有的计算缓存全部失效，之后需要重置缓存窗口。
+    #[inline]
+    pub fn set_adapter(&mut self, adapter: Option<Arc<str>>) {
+        self.adapter = adapter;
+    }
     /// 重置缓存窗口。
     pub fn reset_with(&mut self, tokens: Vec<utok>, pos: usize) {
         self.tokens = tokens;
@@ -1,8 +1,61 @@
 ﻿use common::utok;
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc, time::SystemTime};
 
 #[derive(Clone, Default, Debug)]
-pub(crate) struct Dialog(Vec<Arc<(Vec<utok>, usize)>>);
+pub(crate) struct Dialog(Vec<Arc<Sentence>>);
+
+#[derive(Debug)]
+struct Sentence {
+    tokens: Vec<utok>,
+    turn: Turn,
+}
+
+/// 对话中的一轮发言。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Turn {
+    pub role: Role,
+    /// 发言的原文，生成的发言是解码得到的文本。
+    pub content: String,
+    /// 发言在对话 token 序列中的范围，包括对话模板和结束符。
+    pub tokens: Range<usize>,
+    /// 发言开始的时间，生成的发言是开始推理的时间。
+    pub created: SystemTime,
+    /// 发言加入对话的时间。
+    pub completed: SystemTime,
+    /// 生成结束的原因，不是生成的发言没有。
+    pub finish_reason: Option<FinishReason>,
+}
+
+/// 发言的角色，对话中的用户与助手交替发言。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Role {
+    /// 系统提示词，由对话模板放在第一句用户发言之前，不是对话中的发言。
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    #[inline]
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+/// 生成结束的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum FinishReason {
+    /// 模型生成了结束符或停止序列。
+    Stop,
+    /// 生成的 token 数达到上限，或上下文达到模型的最大序列长度。
+    Length,
+    /// 生成结束符之前，忙会话被释放。
+    Abort,
+}
 
 impl Dialog {
     #[inline]
@@ -12,7 +65,7 @@ impl Dialog {
 
     #[inline]
     pub fn num_tokens(&self) -> usize {
-        self.0.last().map_or(0, |s| s.1)
+        self.0.last().map_or(0, |s| s.turn.tokens.end)
     }
 
     #[inline]
@@ -25,19 +78,44 @@ impl Dialog {
         self.0
             .last()
             .filter(|_| self.0.len() % 2 != 0)
-            .map(|s| &*s.0)
+            .map(|s| &*s.tokens)
+    }
+
+    /// 加入一个句子，`created` 为空表示句子现在才开始。
+    pub fn push(
+        &mut self,
+        tokens: Vec<utok>,
+        content: String,
+        created: Option<SystemTime>,
+        finish_reason: Option<FinishReason>,
+    ) {
+        let role = if self.0.len().is_multiple_of(2) {
+            Role::User
+        } else {
+            Role::Assistant
+        };
+        let start = self.num_tokens();
+        let completed = SystemTime::now();
+        let turn = Turn {
+            role,
+            content,
+            tokens: start..start + tokens.len(),
+            created: created.unwrap_or(completed),
+            completed,
+            finish_reason,
+        };
+        self.0.push(Arc::new(Sentence { tokens, turn }))
     }
 
     #[inline]
-    pub fn push(&mut self, tokens: Vec<utok>) {
-        let len = self.num_tokens() + tokens.len();
-        self.0.push(Arc::new((tokens, len)))
+    pub fn turns(&self) -> impl Iterator<Item = &Turn> {
+        self.0.iter().map(|s| &s.turn)
     }
 
     #[inline]
     pub fn window(&self, len: usize) -> (Vec<utok>, usize) {
         let start = self.num_tokens().saturating_sub(len);
-        let mut iter = self.0.iter().map(|s| &*s.0);
+        let mut iter = self.0.iter().map(|s| &*s.tokens);
         let mut pos = 0;
         for tokens in iter.by_ref() {
             if let Some(len) = start.checked_sub(pos) {
@@ -54,3 +132,22 @@ impl Dialog {
         unreachable!()
     }
 }
+
+#[test]
+fn test_turns() {
+    let mut dialog = Dialog::default();
+    dialog.push(vec![1, 2, 3], "Hi".into(), None, None);
+    dialog.push(vec![4, 5], "Hello".into(), None, Some(FinishReason::Stop));
+    dialog.push(vec![6], "Bye".into(), None, None);
+
+    let turns = dialog.turns().collect::<Vec<_>>();
+    assert_eq!(turns[1].role, Role::Assistant);
+    assert_eq!(turns[1].tokens, 3..5);
+    assert_eq!(turns[1].finish_reason, Some(FinishReason::Stop));
+    assert_eq!(turns[2].role, Role::User);
+    assert_eq!(dialog.last_prompt(), Some(&[6][..]));
+
+    dialog.revert(2);
+    assert_eq!(dialog.num_tokens(), 5);
+    assert_eq!(dialog.window(3), (vec![3, 4, 5], 2));
+}
@@ -1,19 +1,40 @@
-﻿use super::{batcher::Batcher, cache::Cache, task::Task};
-use crate::ServiceComponent;
-use causal_lm::{CausalLM, DecodingMeta, SampleArgs, SampleMeta};
+﻿use super::{
+    batcher::Batcher,
+    block::BlockCounter,
+    cache::Cache,
+    stop::StopMatcher,
+    task::{Logprob, Task},
+    Overflow, Priority, TokenLogprob,
+};
+use crate::{constraint::Constraint, ServiceComponent};
+use causal_lm::{CausalLM, DecodingMeta, Guidance, ProcessorChain, SampleArgs, SampleMeta};
 use common::utok;
+use log::error;
 use std::{
-    iter::zip,
-    mem::{replace, size_of},
-    str,
-    sync::{Arc, Mutex},
+    iter::{once, zip},
+    mem::take,
+    sync::{Arc, Mutex, OnceLock},
+    time::Instant,
+};
+use tokenizer::Detokenizer;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver},
+    oneshot,
 };
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
 
 pub(super) struct TaskHandle<M: CausalLM> {
     receiver: Option<UnboundedReceiver<utok>>,
+    /// 接收生成的 token 的对数概率，不需要时为空。
+    logprobs: Option<UnboundedReceiver<Logprob>>,
+    /// 已经解码但还没有取走的 token 的对数概率。
+    pending: Vec<TokenLogprob>,
     cache: Arc<Mutex<Option<Cache<M::Storage>>>>,
-    buffer: Utf8Buffer,
+    detokenizer: Detokenizer,
+    stop: StopMatcher,
+    /// 输出开头需要丢弃的字节数。
+    skip: usize,
+    /// 解码时跳过特殊词汇。
+    skip_special: bool,
 }
 
 impl<M: CausalLM> TaskHandle<M> {
@@ -24,37 +45,234 @@ impl<M: CausalLM> TaskHandle<M> {
         // 取走 cache
         self.cache.lock().unwrap().take().unwrap()
     }
+
+    /// 取走已经解码的 token 的对数概率。
+    #[inline]
+    pub fn take_logprobs(&mut self) -> Vec<TokenLogprob> {
+        take(&mut self.pending)
+    }
+
+    /// 丢弃输出开头的 `n` 个字节，即修复提示词末尾 token 时重新生成的文本。
+    #[inline]
+    pub fn skip(&mut self, n: usize) {
+        self.skip = n;
+    }
+
+    /// 设置解码时是否跳过特殊词汇。
+    #[inline]
+    pub fn skip_special(&mut self, skip: bool) {
+        self.skip_special = skip;
+    }
+
+    /// 因停止序列结束时，返回截断在停止序列之前的完整输出。
+    #[inline]
+    pub fn stopped_output(&self) -> Option<String> {
+        self.stop.stopped_output().map(String::from)
+    }
+}
+
+/// 推理任务的参数。
+pub(super) struct InferArgs<M: CausalLM> {
+    /// 采样参数，为空时只预填充缓存，完成后关闭响应管道。
+    pub sample: Option<SampleArgs>,
+    /// 输出中出现其中任一序列时，截断输出并停止推理。
+    pub stop: Vec<String>,
+    /// 至多生成的 token 数。
+    pub max_tokens: Option<usize>,
+    /// 上下文超长时的处理方式。
+    pub overflow: Overflow,
+    /// 非空时只生成满足约束的 token。
+    pub constraint: Option<Box<dyn Constraint>>,
+    /// 在采样前处理 logits。
+    pub processors: Option<Arc<Mutex<ProcessorChain>>>,
+    /// 非空时以其中的缓存为无条件上下文，按其中的系数做无分类器引导。
+    pub guidance: Option<(Cache<M::Storage>, f32)>,
+    /// 非空时记录每个生成的 token 的对数概率和概率最大的若干个候选。
+    pub logprobs: Option<usize>,
+    /// 每批的任务数有上限时按优先级调度。
+    pub priority: Priority,
+}
+
+impl<M: CausalLM> Default for InferArgs<M> {
+    fn default() -> Self {
+        Self {
+            sample: None,
+            stop: vec![],
+            max_tokens: None,
+            overflow: Default::default(),
+            constraint: None,
+            processors: None,
+            guidance: None,
+            logprobs: None,
+            priority: Default::default(),
+        }
+    }
 }
 
 impl<M: CausalLM> ServiceComponent<M> {
-    pub(super) fn infer(&self, sample: SampleArgs, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
+    /// 在 `cache` 上按 `args` 启动推理任务。
+    pub(super) fn infer(&self, args: InferArgs<M>, mut cache: Cache<M::Storage>) -> TaskHandle<M> {
+        let InferArgs {
+            sample,
+            stop,
+            max_tokens,
+            overflow,
+            constraint,
+            processors,
+            guidance,
+            logprobs,
+            priority,
+        } = args;
         let max = self.handle.model.max_seq_len() as usize;
-        cache.reset_within(max / 4, max / 4 * 3);
+        match overflow {
+            Overflow::Shift => cache.reset_within(max / 4, max / 4 * 3),
+            // 丢弃最早的 token，为生成留出空间
+            Overflow::TruncateLeft => {
+                let keep = max - max_tokens.unwrap_or(max / 4).clamp(1, max - 1);
+                cache.reset_within(keep, keep + 1)
+            }
+            // 会话已经检查过上下文长度
+            Overflow::Error => {}
+        }
         // 生成推理任务与会话的交互管道
         let cache = Arc::new(Mutex::new(Some(cache)));
         let (sender, receiver) = unbounded_channel();
+        let shift = overflow == Overflow::Shift;
+        // 加载了草稿模型时，采样与历史和步数无关的任务推测解码
+        let draft = self.handle.draft.get().filter(|_| {
+            constraint.is_none()
+                && processors.is_none()
+                && guidance.is_none()
+                && logprobs.is_none()
+                && sample
+                    .as_ref()
+                    .is_some_and(|args| args.history_len(usize::MAX) == 0 && args.seed.is_none())
+        });
+        let draft = draft.map(|draft| match &**draft {
+            Draft::Model { dispatcher, .. } => {
+                let cache = cache.lock().unwrap();
+                let cache = cache.as_ref().unwrap();
+                let tokens = cache.recent(cache.window_len()).to_vec();
+                Some(Cache::new(dispatcher, tokens))
+            }
+            Draft::Lookup { .. } => None,
+        });
+        let task = Task::new(
+            cache.clone(),
+            sample,
+            sender,
+            max_tokens,
+            shift,
+            constraint,
+            processors,
+        );
+        let task = match guidance {
+            Some((negative, scale)) => task.with_guidance(negative, scale),
+            None => task,
+        };
+        let (task, logprobs) = match logprobs {
+            Some(top) => {
+                let (sender, receiver) = unbounded_channel();
+                (task.with_logprobs(top, sender), Some(receiver))
+            }
+            None => (task, None),
+        };
+        let task = match draft {
+            Some(draft) => task.with_speculation(draft),
+            None => task,
+        };
         self.handle
             .batcher
-            .enq(Task::new(cache.clone(), sample, sender));
+            .enq(task.with_priority(priority), priority);
         TaskHandle {
             receiver: Some(receiver),
+            logprobs,
+            pending: vec![],
             cache,
-            buffer: Default::default(),
+            detokenizer: Detokenizer::new(),
+            stop: StopMatcher::new(stop),
+            skip: 0,
+            skip_special: false,
         }
     }
 
+    /// 启动计算 `cache` 中查询的任务，任务只求出下一个 token 中概率最大的 `k` 个候选。
+    pub(super) fn candidates(
+        &self,
+        cache: Cache<M::Storage>,
+        k: usize,
+        priority: Priority,
+    ) -> Candidates<M> {
+        let cache = Arc::new(Mutex::new(Some(cache)));
+        let (sender, receiver) = oneshot::channel();
+        let task = Task::candidates(cache.clone(), k, sender).with_priority(priority);
+        self.handle.batcher.enq(task, priority);
+        Candidates { cache, receiver }
+    }
+
+    /// 启动计算 `tokens` 的任务，任务只求出每个 token 最终归一化的隐藏状态，模型不支持时得到空。
+    pub(crate) fn hidden(
+        &self,
+        tokens: Vec<utok>,
+        priority: Priority,
+    ) -> oneshot::Receiver<Option<Vec<f32>>> {
+        let cache = Arc::new(Mutex::new(Some(Cache::new(&self.handle, tokens))));
+        let (sender, receiver) = oneshot::channel();
+        let task = Task::hidden(cache, sender).with_priority(priority);
+        self.handle.batcher.enq(task, priority);
+        receiver
+    }
+
+    /// 等待预填充任务完成。
+    pub(super) async fn wait(&self, x: &mut TaskHandle<M>) {
+        while x.receiver.as_mut().unwrap().recv().await.is_some() {}
+    }
+
     pub(super) async fn decode(&self, x: &mut TaskHandle<M>) -> Option<String> {
         loop {
-            let s = x.receiver.as_mut().unwrap().recv().await.map(|token| {
-                // detokenize and denormalize the token
-                let ServiceComponent {
-                    normalizer,
-                    tokenizer,
-                    ..
-                } = self;
+            if x.stop.matched() {
+                return None;
+            }
+            let Some(token) = x.receiver.as_mut().unwrap().recv().await else {
+                // 生成结束，输出扣留的文本
+                let s = x.detokenizer.flush();
+                let s = x.stop.push(&s) + &x.stop.flush();
+                return Some(s).filter(|s| !s.is_empty());
+            };
+            // detokenize and denormalize the token
+            let ServiceComponent {
+                normalizer,
+                tokenizer,
+                ..
+            } = self;
+            let s = if x.skip_special && tokenizer.is_special(token) {
+                "".into()
+            } else {
                 normalizer.decode(tokenizer.decode(token))
-            })?;
-            let s = x.buffer.push(s.as_bytes());
+            };
+            if let Some(Ok((logprob, top))) = x.logprobs.as_mut().map(|r| r.try_recv()) {
+                let top = top
+                    .into_iter()
+                    .map(|(t, p)| {
+                        let s = normalizer.decode(tokenizer.decode(t));
+                        (t, String::from_utf8_lossy(s.as_bytes()).into_owned(), p)
+                    })
+                    .collect();
+                x.pending.push(TokenLogprob {
+                    token,
+                    text: String::from_utf8_lossy(s.as_bytes()).into_owned(),
+                    logprob,
+                    top,
+                });
+            }
+            let skip = take(&mut x.skip).min(s.len());
+            let s = x.detokenizer.push(&s.as_bytes()[skip..]);
+            let s = x.stop.push(&s);
+            if x.stop.matched() {
+                // 关闭响应管道，推理任务随之停止
+                let _ = x.receiver.take();
+                return Some(s).filter(|s| !s.is_empty());
+            }
             if !s.is_empty() {
                 return Some(s);
             }
@@ -62,9 +280,85 @@ impl<M: CausalLM> ServiceComponent<M> {
     }
 }
 
+/// 求候选的任务，等待任务完成后取回缓存。
+pub(super) struct Candidates<M: CausalLM> {
+    cache: Arc<Mutex<Option<Cache<M::Storage>>>>,
+    receiver: oneshot::Receiver<Vec<(utok, f32)>>,
+}
+
+impl<M: CausalLM> Candidates<M> {
+    pub async fn wait(self) -> (Cache<M::Storage>, Vec<(utok, f32)>) {
+        let candidates = self.receiver.await.unwrap_or_default();
+        let cache = self.cache.lock().unwrap().take().unwrap();
+        (cache, candidates)
+    }
+}
+
 pub(crate) struct Dispatcher<M: CausalLM> {
     pub model: M,
+    /// 推测解码使用的草稿模型。
+    pub draft: OnceLock<Box<Draft<M>>>,
     pub(super) batcher: Batcher<Task<M::Storage>>,
+    pub(super) blocks: BlockCounter,
+    /// 每次前向计算至多合并的任务数。
+    pub max_batch: usize,
+    /// 分块预填充时每块的 token 数。
+    pub prefill_chunk: usize,
+    /// 推测解码的负载上限，一次计算的任务数与等待的任务数之和达到上限时不再推测。
+    pub speculation_limit: usize,
+}
+
+/// 推测解码生成草稿的方式，在推理线程中为推测的任务生成草稿。
+pub(crate) enum Draft<M: CausalLM> {
+    /// 用与目标模型词表相同的草稿模型生成草稿。
+    Model {
+        dispatcher: Dispatcher<M>,
+        num_draft: usize,
+    },
+    /// 在上下文中查找与末尾相同的 n-gram，以它之后的 token 作为草稿。
+    Lookup { num_draft: usize },
+}
+
+impl<M: CausalLM> Draft<M> {
+    /// 每步至多生成的草稿数。
+    #[inline]
+    pub fn num_draft(&self) -> usize {
+        match self {
+            Self::Model { num_draft, .. } | Self::Lookup { num_draft } => *num_draft,
+        }
+    }
+}
+
+/// 按负载减少每步的草稿数：负载达到 `limit` 时不推测，否则按负载占上限的比例减少，至少推测一个。
+///
+/// 负载高时未被接受的草稿浪费的计算会挤占其他任务，负载降低后草稿数随之恢复。
+fn throttle(num_draft: usize, load: usize, limit: usize) -> usize {
+    if load >= limit {
+        0
+    } else {
+        num_draft - num_draft * load / limit
+    }
+}
+
+/// 查找草稿时匹配的最长 n-gram。
+const MAX_NGRAM: usize = 3;
+
+/// 在 `tokens` 中查找与末尾的 n-gram 相同的最近片段，返回片段之后的至多 `n` 个 token。
+///
+/// 从最长的 n-gram 开始查找，找不到时缩短 n-gram，都找不到时返回空。
+fn lookup(tokens: &[utok], n: usize) -> Vec<utok> {
+    let len = tokens.len();
+    for ngram in (1..=MAX_NGRAM.min(len.saturating_sub(1))).rev() {
+        let tail = &tokens[len - ngram..];
+        if let Some(start) = (0..len - ngram)
+            .rev()
+            .find(|&i| &tokens[i..i + ngram] == tail)
+        {
+            let start = start + ngram;
+            return tokens[start..len.min(start + n)].to_vec();
+        }
+    }
+    vec![]
 }
 
 impl<M: CausalLM> From<M> for Dispatcher<M> {
@@ -72,7 +366,12 @@ impl<M: CausalLM> From<M> for Dispatcher<M> {
     fn from(model: M) -> Self {
         Self {
             model,
+            draft: OnceLock::new(),
             batcher: Batcher::new(),
+            blocks: Default::default(),
+            max_batch: usize::MAX,
+            prefill_chunk: usize::MAX,
+            speculation_limit: usize::MAX,
         }
     }
 }
@@ -83,6 +382,129 @@ impl<M: CausalLM> Dispatcher<M> {
     pub fn stop(&self) {
         self.batcher.shutdown();
     }
+
+    /// 推理线程是否仍在运行并接受任务。
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.batcher.is_alive()
+    }
+
+    /// 等待前向计算的任务数。
+    #[inline]
+    pub fn queue_len(&self) -> usize {
+        self.batcher.len()
+    }
+
+    /// 检查计算缓存泄漏。
+    ///
+    /// 所有会话和生成器释放后，不应有存活的缓存块。
+    #[inline]
+    pub fn check_leak(&self) {
+        let live = self.blocks.live();
+        if live > 0 {
+            error!("{live} kv blocks leaked");
+        }
+        debug_assert!(live == 0 || std::thread::panicking(), "kv blocks leaked");
+    }
+
+    /// 用贪心采样在 `cache` 上生成至多 `n` 个草稿，生成结束符时提前停止。
+    pub(super) fn draft(&self, cache: &mut Cache<M::Storage>, n: usize) -> Vec<utok> {
+        let max = self.model.max_seq_len() as usize;
+        cache.reset_within(max / 4, max - n);
+        let eos = self.model.eos_token();
+        let mut drafts = Vec::with_capacity(n);
+        for _ in 0..n {
+            let num_query = cache.query().len();
+            let token_embedded = self.model.token_embed(cache.query().iter().copied());
+            let hidden_state = self
+                .model
+                .forward([cache.as_ctx(&self.model, num_query)], token_embedded);
+            let decoding = [DecodingMeta {
+                num_query,
+                num_decode: 1,
+            }];
+            let logits = self.model.decode(decoding, hidden_state);
+            let args = [SampleMeta {
+                num_decode: 1,
+                args: Default::default(),
+                history: Default::default(),
+            }];
+            let token = self.model.sample(args, logits)[0];
+            if token == eos {
+                break;
+            }
+            cache.push(token);
+            drafts.push(token);
+        }
+        drafts
+    }
+
+    /// 同批计算求隐藏状态的任务的查询，向每个任务发送它的查询对应的行。
+    ///
+    /// 与预填充一样，查询超过 `prefill_chunk` 的任务每次只计算一块，然后回到队列末尾。
+    fn hidden(&self, tasks: Vec<Task<M::Storage>>) {
+        let tasks = tasks.into_iter().filter(Task::is_alive).collect::<Vec<_>>();
+        if tasks.is_empty() {
+            return;
+        }
+        let mut caches = tasks.iter().flat_map(Task::lock_caches).collect::<Vec<_>>();
+        let query_len = caches
+            .iter()
+            .map(|c| c.as_ref().unwrap().query().len())
+            .collect::<Vec<_>>();
+        let num_query = query_len
+            .iter()
+            .map(|&len| len.min(self.prefill_chunk))
+            .collect::<Vec<_>>();
+        let batch = tracing::debug_span!(
+            "hidden",
+            tasks = tasks.len(),
+            queries = num_query.iter().sum::<usize>(),
+        )
+        .entered();
+        let _steps = zip(&tasks, &num_query)
+            .map(|(t, &n)| {
+                let step = t.step(n, 0);
+                step.follows_from(&*batch);
+                step
+            })
+            .collect::<Vec<_>>();
+        let queries = zip(&caches, &num_query)
+            .flat_map(|(c, &n)| &c.as_ref().unwrap().query()[..n])
+            .copied();
+        let token_embedded = self.model.token_embed(queries);
+        let queries =
+            zip(&mut caches, &num_query).map(|(c, &n)| c.as_mut().unwrap().as_ctx(&self.model, n));
+        let hidden_state = self.model.forward(queries, token_embedded);
+        // 分块计算的任务将计算过的查询加入缓存
+        for (c, (&n, &len)) in zip(&mut caches, zip(&num_query, &query_len)) {
+            if n < len {
+                c.as_mut().unwrap().advance(n);
+            }
+        }
+        drop(caches);
+
+        let Some(hidden) = self.model.final_hidden(hidden_state) else {
+            for task in tasks {
+                task.send_hidden(None);
+            }
+            return;
+        };
+        let d = hidden.len() / num_query.iter().sum::<usize>();
+        let mut rows = &hidden[..];
+        for (mut task, (n, len)) in zip(tasks, zip(num_query, query_len)) {
+            let (head, tail) = rows.split_at(n * d);
+            rows = tail;
+            if n < len {
+                // 计算下一块
+                task.push_hidden(head);
+                let priority = task.priority();
+                self.batcher.enq(task, priority);
+            } else {
+                task.send_hidden(Some(head));
+            }
+        }
+    }
 }
 
 impl<M> Dispatcher<M>
@@ -90,88 +512,287 @@ where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
 {
+    /// 连续批处理：每次前向计算合并队列中最早的至多 `max_batch` 个任务，
+    /// 新任务在两次前向计算之间加入，结束的任务不再回到队列。
+    ///
+    /// 查询超过 `prefill_chunk` 的任务分块预填充，每次前向计算只计算一块，
+    /// 然后回到队列末尾，与其他任务的解码交替进行，直到最后一块再解码。
+    ///
+    /// 推理线程退出（包括崩溃）时关闭任务队列，之后入队的任务直接丢弃。
     pub fn run(self: Arc<Self>) {
-        while let Some(tasks) = Some(self.batcher.deq()).filter(|t| !t.is_empty()) {
-            // 锁定所有请求的缓存
-            let mut caches = tasks.iter().map(Task::lock_cache).collect::<Vec<_>>();
-            // 统计每个任务的查询长度
-            let num_query = caches
+        struct Shutdown<'a, T>(&'a Batcher<T>);
+        impl<T> Drop for Shutdown<'_, T> {
+            fn drop(&mut self) {
+                self.0.shutdown()
+            }
+        }
+        let _shutdown = Shutdown(&self.batcher);
+
+        while let Some(mut tasks) = Some(self.batcher.deq(self.max_batch)).filter(|t| !t.is_empty())
+        {
+            tasks.iter_mut().for_each(Task::schedule);
+            // 求隐藏状态的任务不解码，单独计算
+            let (hidden, tasks): (Vec<_>, Vec<_>) = tasks.into_iter().partition(Task::wants_hidden);
+            self.hidden(hidden);
+            if tasks.is_empty() {
+                continue;
+            }
+            // 锁定所有请求的缓存，引导的任务还有无条件上下文的缓存
+            let mut caches = tasks.iter().flat_map(Task::lock_caches).collect::<Vec<_>>();
+            // 每个任务解码的 token 数
+            let mut num_decode = tasks
+                .iter()
+                .map(|t| {
+                    if t.is_alive() && t.decodes() {
+                        t.num_decode()
+                    } else {
+                        0
+                    }
+                })
+                .collect::<Vec<_>>();
+            // 每个缓存所属的任务和解码的 token 数，无条件上下文只解码一个 token
+            let owners = zip(&tasks, &num_decode)
+                .enumerate()
+                .flat_map(|(i, (t, &n))| {
+                    once((i, n)).chain(t.guidance_scale().map(|_| (i, n.min(1))))
+                })
+                .collect::<Vec<_>>();
+            // 统计每个缓存这次计算的查询长度
+            let query_len = caches
                 .iter()
                 .map(|c| c.as_ref().map_or(0, |c| c.query().len()))
                 .collect::<Vec<_>>();
+            let (partial, num_query) =
+                split_chunks(&owners, &query_len, tasks.len(), self.prefill_chunk);
             if num_query.iter().all(|&n| n == 0) {
                 continue;
             }
+            let batch = tracing::debug_span!(
+                "batch",
+                tasks = tasks.len(),
+                queries = num_query.iter().sum::<usize>(),
+            )
+            .entered();
+            let start = Instant::now();
+            // 每个任务这次计算的跟踪范围，任务的查询长度取它的第一个缓存，即不含无条件上下文
+            let mut task_query = vec![0; tasks.len()];
+            for (&(i, _), &n) in zip(&owners, &num_query).rev() {
+                task_query[i] = n;
+            }
+            let steps = zip(&tasks, zip(task_query, &num_decode))
+                .filter(|(_, (n, _))| *n > 0)
+                .map(|(t, (n, &d))| {
+                    let step = t.step(n, d);
+                    step.follows_from(&*batch);
+                    step
+                })
+                .collect::<Vec<_>>();
             // 词嵌入
-            let queries = caches
-                .iter()
-                .filter_map(|c| c.as_ref().map(Cache::query).filter(|q| !q.is_empty()))
-                .flatten()
+            let queries = zip(&caches, &num_query)
+                .filter(|(_, &n)| n > 0)
+                .flat_map(|(c, &n)| &c.as_ref().unwrap().query()[..n])
                 .copied();
             let token_embedded = self.model.token_embed(queries);
             // 推理
-            let queries = caches
-                .iter_mut()
-                .filter_map(|c| c.as_mut().map(Cache::as_ctx).filter(|q| q.seq_len() > 0));
+            let queries = zip(&mut caches, &num_query)
+                .filter(|(_, &n)| n > 0)
+                .map(|(c, &n)| c.as_mut().unwrap().as_ctx(&self.model, n));
             let hidden_state = self.model.forward(queries, token_embedded);
+            // 分块预填充的任务将计算过的查询加入缓存
+            for (c, (&(i, _), &n)) in zip(&mut caches, zip(&owners, &num_query)) {
+                if partial[i] && n > 0 {
+                    c.as_mut().unwrap().advance(n);
+                }
+            }
             drop(caches);
             // 采样
-            let num_decode = tasks
-                .iter()
-                .map(|t| if t.is_alive() { 1 } else { 0 })
-                .collect::<Vec<_>>();
-            let decoding =
-                zip(num_query, &num_decode).map(|(num_query, &num_decode)| DecodingMeta {
-                    num_query,
-                    num_decode,
-                });
-            let logits = self.model.decode(decoding, hidden_state);
-            // 采样
-            let args = zip(&tasks, &num_decode).map(|(t, &num_decode)| SampleMeta {
+            for (n, &partial) in zip(&mut num_decode, &partial) {
+                if partial {
+                    *n = 0;
+                }
+            }
+            // 无条件上下文只解码一个 token
+            let decoding = zip(&tasks, &num_decode)
+                .flat_map(|(t, &n)| once(n).chain(t.guidance_scale().map(|_| n.min(1))));
+            let decoding = zip(num_query, decoding).map(|(num_query, num_decode)| DecodingMeta {
+                num_query,
                 num_decode,
-                args: t.sample().clone(),
+            });
+            let logits = self.model.decode(decoding, hidden_state);
+            // 求候选，引导需要无条件上下文中所有 token 的对数概率
+            // 返回对数概率也需要所有 token 的对数概率，以找到采样的 token
+            let full = zip(&tasks, &num_decode)
+                .any(|(t, &n)| n > 0 && (t.guidance_scale().is_some() || t.wants_logprobs()));
+            let k = zip(&tasks, &num_decode)
+                .filter(|(_, &n)| n > 0)
+                .filter_map(|(t, _)| t.num_candidates())
+                .max();
+            let k = if full { Some(usize::MAX) } else { k };
+            let candidates = k.map_or_else(Vec::new, |k| self.model.top_logprobs(&logits, k));
+            // 采样，无条件上下文采样的 token 不使用
+            let mut row = 0;
+            let args = zip(&tasks, &num_decode).flat_map(|(t, &num_decode)| {
+                let args = t.sample().cloned().unwrap_or_default();
+                let mut history = t.history(&args);
+                row += num_decode;
+                let negative = t.guidance_scale().map(|scale| {
+                    if num_decode > 0 {
+                        history.guidance = Some(Guidance {
+                            scale,
+                            unconditioned: dense(&candidates[row]),
+                        });
+                        row += 1;
+                    }
+                    SampleMeta {
+                        num_decode: num_decode.min(1),
+                        args: Default::default(),
+                        history: Default::default(),
+                    }
+                });
+                once(SampleMeta {
+                    num_decode,
+                    args,
+                    history,
+                })
+                .chain(negative)
             });
             let tokens = self.model.sample(args, logits);
-            // 为每次推理启动一个任务执行发射
-            let self_ = self.clone();
-            tokio::task::spawn_blocking(move || {
-                let eos = self_.model.eos_token();
-                let max = self_.model.max_seq_len() as usize;
-                let min = max / 4;
-                zip(tasks, num_decode)
-                    .filter(|(_, n)| *n > 0)
-                    .map(|(t, _)| t)
-                    .zip(tokens)
-                    .filter(|(_, token)| *token != eos)
-                    .for_each(|(mut task, token)| {
-                        if task.push(token, min, max) {
-                            self_.batcher.enq(task);
-                        }
-                    });
+            tracing::debug!(
+                latency_ms = start.elapsed().as_secs_f64() * 1e3,
+                "batch computed"
+            );
+            drop(steps);
+            // 发射，继续推理的任务在下一次前向计算之前回到队列，与新任务合批
+            let eos = self.model.eos_token();
+            let max = self.model.max_seq_len() as usize;
+            let min = max / 4;
+            let load = tasks.len() + self.queue_len();
+            let num_draft = self.draft.get().map_or(0, |draft| {
+                throttle(draft.num_draft(), load, self.speculation_limit)
             });
+            let mut tokens = tokens.into_iter();
+            let mut candidates = candidates.into_iter();
+            for ((mut task, num_decode), partial) in zip(zip(tasks, num_decode), partial) {
+                let priority = task.priority();
+                if partial {
+                    // 预填充下一块
+                    if task.is_alive() {
+                        self.batcher.enq(task, priority);
+                    }
+                    continue;
+                }
+                if num_decode == 0 {
+                    if task.sample().is_none() {
+                        // 预填充任务在此释放，响应管道随之关闭
+                        task.commit();
+                    }
+                    continue;
+                }
+                let rows = tokens.by_ref().take(num_decode).collect::<Vec<_>>();
+                let top = candidates.by_ref().take(num_decode).last();
+                if task.guidance_scale().is_some() {
+                    // 跳过无条件上下文的解码结果
+                    tokens.next();
+                    candidates.next();
+                }
+                if task.num_candidates().is_some() {
+                    task.send_candidates(top.unwrap());
+                    continue;
+                }
+                if let Some(draft) = self.draft.get().filter(|_| task.speculates()) {
+                    if task.push_speculated(&rows, eos, min, max) {
+                        task.draft(num_draft, max, |window, cache, n| match &**draft {
+                            Draft::Model { dispatcher, .. } => dispatcher.draft(cache.unwrap(), n),
+                            Draft::Lookup { .. } => lookup(window, n),
+                        });
+                        self.batcher.enq(task, priority);
+                    }
+                    continue;
+                }
+                let token = rows[0];
+                if let Some(top) = top.filter(|_| token != eos) {
+                    task.send_logprobs(token, top);
+                }
+                if token == eos {
+                    task.finish(eos);
+                } else if task.push(token, min, max) {
+                    self.batcher.enq(task, priority);
+                }
+            }
         }
     }
 }
 
-#[derive(Clone, Default, Debug)]
-struct Utf8Buffer(Vec<u8>);
-
-impl Utf8Buffer {
-    fn push(&mut self, bytes: impl AsRef<[u8]>) -> String {
-        self.0.extend_from_slice(bytes.as_ref());
-        let mut len = match str::from_utf8(&self.0) {
-            Ok(_) => self.0.len(),
-            Err(e) => e.valid_up_to(),
-        };
-        while len + size_of::<char>() <= self.0.len() {
-            len += 1;
-            match str::from_utf8(&self.0[len..]) {
-                Ok(s) => len += s.as_bytes().len(),
-                Err(e) => len += e.valid_up_to(),
-            }
+/// 求分块预填充时每个任务是否只预填充，以及每个缓存这次计算的查询长度。
+///
+/// `owners` 是每个缓存所属的任务和解码的 token 数，`query_len` 是每个缓存的查询长度。
+/// 有缓存的查询超过一块的任务这次只预填充，解码的 token 留到最后一块。
+fn split_chunks(
+    owners: &[(usize, usize)],
+    query_len: &[usize],
+    num_tasks: usize,
+    chunk: usize,
+) -> (Vec<bool>, Vec<usize>) {
+    let mut partial = vec![false; num_tasks];
+    for (&(i, d), &len) in zip(owners, query_len) {
+        if len > chunk.max(d) {
+            partial[i] = true;
         }
-        let s = self.0.split_off(len);
-        let s = replace(&mut self.0, s);
-        unsafe { String::from_utf8_unchecked(s) }
     }
+    let num_query = zip(owners, query_len)
+        .map(|(&(i, d), &len)| {
+            if partial[i] {
+                len.saturating_sub(d).min(chunk)
+            } else {
+                len
+            }
+        })
+        .collect();
+    (partial, num_query)
+}
+
+/// 把按概率排列的所有 token 的对数概率转换为按 token 序号排列。
+fn dense(logprobs: &[(utok, f32)]) -> Arc<[f32]> {
+    let mut ans = vec![f32::NEG_INFINITY; logprobs.len()];
+    for &(t, p) in logprobs {
+        ans[t as usize] = p;
+    }
+    ans.into()
+}
+
+#[test]
+fn test_lookup() {
+    assert_eq!(lookup(&[1, 2, 3, 4, 5, 1, 2, 3], 2), [4, 5]);
+    assert_eq!(lookup(&[1, 2, 3, 9, 2, 3, 7, 5, 2, 3], 5), [7, 5, 2, 3]);
+    assert_eq!(lookup(&[1, 2, 3, 4, 6, 4], 3), [6, 4]);
+    assert!(lookup(&[1, 2, 3], 3).is_empty());
+    assert!(lookup(&[1], 3).is_empty());
+}
+
+#[test]
+fn test_throttle() {
+    assert_eq!(throttle(4, 1, usize::MAX), 4);
+    assert_eq!(throttle(4, 1, 8), 4);
+    assert_eq!(throttle(4, 4, 8), 2);
+    assert_eq!(throttle(4, 7, 8), 1);
+    assert_eq!(throttle(4, 8, 8), 0);
+    assert_eq!(throttle(4, 12, 8), 0);
+}
+
+#[test]
+fn test_split_chunks() {
+    // 解码、只预填充、引导的任务
+    let owners = [(0, 1), (1, 0), (2, 1), (2, 1)];
+    let (partial, num_query) = split_chunks(&owners, &[10, 10, 3, 10], 3, 4);
+    assert_eq!(partial, [true, true, true]);
+    assert_eq!(num_query, [4, 4, 2, 4]);
+    // 最后一块
+    let (partial, num_query) = split_chunks(&owners, &[4, 3, 1, 1], 3, 4);
+    assert_eq!(partial, [false; 3]);
+    assert_eq!(num_query, [4, 3, 1, 1]);
+    // 验证的草稿不分块
+    let (partial, num_query) = split_chunks(&[(0, 3)], &[3], 1, 2);
+    assert_eq!((partial, num_query), (vec![false], vec![3]));
+    let (partial, num_query) = split_chunks(&[(0, 3)], &[6], 1, 2);
+    assert_eq!((partial, num_query), (vec![true], vec![2]));
 }
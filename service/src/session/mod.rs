@@ -1,31 +1,108 @@
 ﻿mod batcher;
+mod beam;
+mod block;
 mod cache;
 mod dialog;
 mod dispatch;
+mod stop;
 mod task;
 
-use crate::ServiceComponent;
+use crate::{
+    constraint::{Constraint, GrammarMatcher, HealingMatcher, RegexMatcher},
+    template::Message,
+    Grammar, Regex, ServiceComponent,
+};
 use cache::Cache;
-use causal_lm::{CausalLM, SampleArgs};
+use causal_lm::{CausalLM, ProcessorChain, SampleArgs};
+use common::utok;
 use dialog::Dialog;
-use dispatch::TaskHandle;
+use dispatch::{InferArgs, TaskHandle};
 use log::info;
 use std::{
     cmp::Ordering::{Equal, Greater, Less},
     error, fmt,
-    sync::Arc,
+    iter::zip,
+    mem::take,
+    sync::{Arc, Mutex},
+    time::SystemTime,
     vec,
 };
+use tokenizer::Detokenizer;
+
+pub use beam::BeamArgs;
+pub use dialog::{FinishReason, Role, Turn};
+pub(crate) use dispatch::{Dispatcher, Draft};
+
+/// 推理任务的优先级，每批的任务数有上限时，优先调度高优先级的任务。
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Priority {
+    /// 批量任务，可以等待。
+    Low,
+    #[default]
+    Normal,
+    /// 交互式对话，尽快响应。
+    High,
+}
 
-pub(crate) use dispatch::Dispatcher;
+/// 上下文超过模型最大序列长度时的处理方式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum Overflow {
+    /// 对话和生成的 token 数超过上限时拒绝推理，见 [`Session::check_context`]；生成达到上限时结束。
+    Error,
+    /// 推理开始时丢弃最早的 token，为生成留出空间；生成达到上限时结束。
+    TruncateLeft,
+    /// 缓存满时丢弃最早的 token，重新计算保留的部分，可以一直生成。
+    #[default]
+    Shift,
+}
 
 /// 会话。
 pub struct Session<M: CausalLM> {
-    component: Arc<ServiceComponent<M>>,
+    // 缓存必须先于组件释放，组件释放时会检查缓存泄漏
+    cache: Option<Cache<M::Storage>>,
+    dialog: Dialog,
+    adapter: Option<Arc<str>>,
+
     pub sample: SampleArgs,
+    /// 系统提示词，由对话模板（不套用模板时按原文）放在第一句用户发言之前，只在填充第一句用户发言时使用。
+    pub system: Option<String>,
+    /// 填充对话时是否由对话模板添加特殊词汇（如 BOS 和 `<|im_end|>`）。
+    ///
+    /// 为假时每句发言按原文编码，不套用对话模板，助手发言之后也不追加结束符，由调用者自行组织格式；
+    /// 系统提示词按原文放在第一句用户发言之前；原文中的特殊词汇仍编码为特殊 token。
+    pub add_special_tokens: bool,
+    /// 解码生成的文本时是否跳过特殊词汇。
+    pub skip_special_tokens: bool,
+    /// 停止序列，生成的文本中出现任一序列时截断并停止推理。
+    pub stop: Vec<String>,
+    /// 每次推理至多生成的 token 数，为空时不限制。
+    pub max_tokens: Option<usize>,
+    pub overflow: Overflow,
+    /// 约束生成的文法，生成的文本必须匹配文法，束搜索不受约束。
+    pub grammar: Option<Arc<Grammar>>,
+    /// 约束生成的正则表达式，生成的文本必须完整匹配，同时指定文法时只使用文法。
+    pub regex: Option<Arc<Regex>>,
+    /// 采样前处理 logits 的处理器链，分叉的会话共享同一个处理器链。
+    pub processors: Option<Arc<Mutex<ProcessorChain>>>,
+    /// 负面提示词，与 `guidance_scale` 一起启用无分类器引导。
+    ///
+    /// 引导时以负面提示词替换最后的提问作为无条件上下文，与对话同批计算并合并两者的分布。
+    pub negative_prompt: Option<String>,
+    /// 无分类器引导的系数，1 等于不引导，越大越远离负面提示词。
+    pub guidance_scale: f32,
+    /// 非空时记录每个生成的 token 的对数概率和概率最大的若干个候选，见 [`BusySession::take_logprobs`]。
+    pub logprobs: Option<usize>,
+    /// 推理任务的优先级。
+    pub priority: Priority,
+    component: Arc<ServiceComponent<M>>,
+}
 
+/// 预填充的对话模板，以模板开头的会话从模板的缓存分叉。
+pub(crate) struct Warm<Storage> {
+    system: Option<String>,
+    sentences: Vec<String>,
     dialog: Dialog,
-    cache: Option<Cache<M::Storage>>,
+    cache: Cache<Storage>,
 }
 
 /// 对话错误类型。
@@ -42,15 +119,76 @@ impl fmt::Display for ChatError {
     }
 }
 
+/// 生成的 token 及其对数概率。
+///
+/// 对数概率由模型直接输出的 logits 计算，不受采样参数影响。
+#[derive(Clone, PartialEq, Debug)]
+pub struct TokenLogprob {
+    pub token: utok,
+    /// token 解码得到的文本。
+    pub text: String,
+    pub logprob: f32,
+    /// 概率最大的若干个候选的 token、文本和对数概率，从大到小排列。
+    pub top: Vec<(utok, String, f32)>,
+}
+
+/// 会话的状态统计。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub struct SessionStats {
+    /// 对话中的句子数。
+    pub dialog_pos: usize,
+    /// 对话的 token 数。
+    pub num_tokens: usize,
+    /// 已计算缓存的 token 数。
+    pub cached_tokens: usize,
+    /// 计算缓存占用的字节数。
+    pub kv_bytes: usize,
+    /// 计算缓存是否与分叉的会话共享。
+    pub kv_shared: bool,
+}
+
+/// 上下文溢出错误，对话和要生成的 token 数超过了模型的最大序列长度。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ContextOverflow {
+    pub required: usize,
+    pub capacity: usize,
+}
+
+impl error::Error for ContextOverflow {}
+impl fmt::Display for ContextOverflow {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "context requires {} tokens but the model supports {}",
+            self.required, self.capacity
+        )
+    }
+}
+
 impl<M: CausalLM> From<Arc<ServiceComponent<M>>> for Session<M> {
     #[inline]
     fn from(component: Arc<ServiceComponent<M>>) -> Self {
         Self {
             component,
             sample: Default::default(),
+            system: None,
+            add_special_tokens: true,
+            skip_special_tokens: false,
+            stop: Default::default(),
+            max_tokens: None,
+            overflow: Default::default(),
+            grammar: None,
+            regex: None,
+            processors: None,
+            negative_prompt: None,
+            guidance_scale: 1.,
+            logprobs: None,
+            priority: Default::default(),
 
             dialog: Default::default(),
             cache: Default::default(),
+            adapter: None,
         }
     }
 }
@@ -61,16 +199,74 @@ impl<M: CausalLM> Session<M> {
         self.dialog.num_sentences()
     }
 
+    /// 会话的状态统计。
+    pub fn stats(&self) -> SessionStats {
+        let (cached_tokens, (kv_bytes, kv_shared)) = self
+            .cache
+            .as_ref()
+            .map_or((0, (0, false)), |c| (c.cached_len(), c.memory()));
+        SessionStats {
+            dialog_pos: self.dialog_pos(),
+            num_tokens: self.dialog.num_tokens(),
+            cached_tokens,
+            kv_bytes,
+            kv_shared,
+        }
+    }
+
+    /// 会话中的所有发言。
+    #[inline]
+    pub fn turns(&self) -> impl Iterator<Item = &Turn> {
+        self.dialog.turns()
+    }
+
     /// 复制当前会话。
+    ///
+    /// 新会话与当前会话共享计算缓存，任一会话继续推理时才复制。
     pub fn fork(&self) -> Self {
         Self {
             component: self.component.clone(),
             sample: self.sample.clone(),
+            system: self.system.clone(),
+            add_special_tokens: self.add_special_tokens,
+            skip_special_tokens: self.skip_special_tokens,
+            stop: self.stop.clone(),
+            max_tokens: self.max_tokens,
+            overflow: self.overflow,
+            grammar: self.grammar.clone(),
+            regex: self.regex.clone(),
+            processors: self.processors.clone(),
+            negative_prompt: self.negative_prompt.clone(),
+            guidance_scale: self.guidance_scale,
+            logprobs: self.logprobs,
+            priority: self.priority,
             dialog: self.dialog.clone(),
-            cache: self
-                .cache
-                .as_ref()
-                .map(|cache| cache.duplicate(&self.component.handle.model)),
+            cache: self.cache.as_ref().map(Cache::fork),
+            adapter: self.adapter.clone(),
+        }
+    }
+
+    /// 会话使用的 LoRA 适配器。
+    #[inline]
+    pub fn adapter(&self) -> Option<&str> {
+        self.adapter.as_deref()
+    }
+
+    /// 切换会话使用的 LoRA 适配器，为空时只使用基础模型。
+    ///
+    /// 适配器改变时，已有对话的缓存失效，下次推理时按新的适配器重新计算。
+    pub fn set_adapter(&mut self, adapter: Option<&str>) {
+        if self.adapter.as_deref() == adapter {
+            return;
+        }
+        self.adapter = adapter.map(Arc::from);
+        if let Some(cache) = &mut self.cache {
+            cache.set_adapter(self.adapter.clone());
+            if self.dialog.num_tokens() > 0 {
+                let len = self.component.handle.model.max_seq_len() as usize;
+                let (tokens, pos) = self.dialog.window(len);
+                cache.reset_with(tokens, pos);
+            }
         }
     }
 
@@ -96,50 +292,237 @@ impl<M: CausalLM> Session<M> {
     }
 
     /// 用 dialog 填充会话。
+    ///
+    /// 空白的会话以预填充的模板开头时，直接从最长的模板分叉，跳过模板部分的计算；
+    /// 模板的缓存不使用适配器，因此使用适配器的会话不分叉。
     pub fn extend<'a>(&mut self, dialog: impl IntoIterator<Item = &'a str>) {
+        let dialog = dialog.into_iter().collect::<Vec<_>>();
+        let mut dialog = &dialog[..];
+        if self.dialog.num_sentences() == 0 && self.adapter.is_none() && self.add_special_tokens {
+            if let Some((len, warm_dialog, cache)) =
+                self.component.fork_warm(self.system.as_deref(), dialog)
+            {
+                info!("Warm cache hit with {len} sentences");
+                self.dialog = warm_dialog;
+                self.cache = Some(cache);
+                dialog = &dialog[len..];
+            }
+        }
+
         let eos = self.component.handle.model.eos_token();
-        let cache = self
-            .cache
-            .get_or_insert_with(|| Cache::new(&self.component.handle.model, vec![]));
+        let cache = self.cache.get_or_insert_with(|| {
+            let mut cache = Cache::new(&self.component.handle, vec![]);
+            cache.set_adapter(self.adapter.clone());
+            cache
+        });
         // 填充对话
-        for s in dialog {
+        for &content in dialog {
             let prompt = self.dialog.num_sentences() % 2 == 0;
 
-            let s = if prompt {
-                self.component.template.apply_chat(s)
+            let s = if prompt && self.add_special_tokens {
+                let messages = messages(self.system.as_deref(), self.dialog.turns(), content);
+                self.component.template.apply_chat(&messages)
+            } else if prompt {
+                let first = self.dialog.num_sentences() == 0;
+                raw_prompt(self.system.as_deref().filter(|_| first), content)
             } else {
-                s.into()
+                content.into()
             };
             let s = self.component.normalizer.encode(&s);
             let mut s = self.component.tokenizer.encode(&s);
-            if !prompt {
+            if !prompt && self.add_special_tokens {
                 s.push(eos);
             }
 
             cache.extend(&s);
-            self.dialog.push(s);
+            self.dialog.push(s, content.into(), None, None);
             assert_eq!(cache.end(), self.dialog.num_tokens());
         }
     }
 
+    /// 按 [`Overflow::Error`] 检查对话和要生成的 token 数是否超过模型的最大序列长度，其他处理方式总是成功。
+    pub fn check_context(&self) -> Result<(), ContextOverflow> {
+        if self.overflow != Overflow::Error {
+            return Ok(());
+        }
+        let capacity = self.component.handle.model.max_seq_len() as usize;
+        let required = self.dialog.num_tokens() + self.max_tokens.unwrap_or(1);
+        if required > capacity {
+            Err(ContextOverflow { required, capacity })
+        } else {
+            Ok(())
+        }
+    }
+
     /// 启动推理任务，返回忙会话。
     pub fn chat(&mut self) -> BusySession<M> {
         let sample = self.sample.clone();
+        let constraint = match (&self.grammar, &self.regex) {
+            (Some(g), _) => Some(
+                Box::new(GrammarMatcher::new(g.clone(), self.component.vocab()))
+                    as Box<dyn Constraint>,
+            ),
+            (None, Some(r)) => Some(
+                Box::new(RegexMatcher::new(r.clone(), self.component.vocab()))
+                    as Box<dyn Constraint>,
+            ),
+            (None, None) => None,
+        };
+        let guidance = self.negative_context().map(|c| (c, self.guidance_scale));
         let cache = self.cache.take().unwrap();
-        let handle = self.component.infer(sample, cache);
+        let args = InferArgs {
+            sample: Some(sample),
+            stop: self.stop.clone(),
+            max_tokens: self.max_tokens,
+            overflow: self.overflow,
+            constraint,
+            processors: self.processors.clone(),
+            guidance,
+            logprobs: self.logprobs,
+            priority: self.priority,
+        };
+        let mut handle = self.component.infer(args, cache);
+        handle.skip_special(self.skip_special_tokens);
         BusySession {
             session: self,
             handle,
+            created: SystemTime::now(),
         }
     }
 
-    fn restore_cache(&mut self, mut cache: Cache<M::Storage>) {
+    /// 从对话分叉出无条件上下文：以负面提示词替换最后的提问。
+    fn negative_context(&self) -> Option<Cache<M::Storage>> {
+        let prompt = self
+            .negative_prompt
+            .as_deref()
+            .filter(|_| self.guidance_scale != 1.)?;
+        let mut cache = self.cache.as_ref()?.fork();
+        let last = self.dialog.last_prompt().map_or(0, <[_]>::len);
+        cache.revert(self.dialog.num_tokens() - last);
+        let turns = self.dialog.turns().take(self.dialog.num_sentences() - 1);
+        let s = if self.add_special_tokens {
+            let messages = messages(self.system.as_deref(), turns, prompt);
+            self.component.template.apply_chat(&messages)
+        } else {
+            let first = self.dialog.num_sentences() == 1;
+            raw_prompt(self.system.as_deref().filter(|_| first), prompt)
+        };
+        let s = self.component.normalizer.encode(&s);
+        cache.extend(&self.component.tokenizer.encode(&s));
+        Some(cache)
+    }
+
+    /// 用束搜索生成回答，回答加入对话后返回回答的文本。
+    ///
+    /// 束搜索完成后才有结果，不能流式输出；上下文过长时总是丢弃最早的 token，为生成留出空间。
+    /// 搜索中途取消时会话保持不变。
+    pub async fn beam_search(&mut self, args: BeamArgs) -> String {
+        let max = self.component.handle.model.max_seq_len() as usize;
+        let reserve = self.max_tokens.unwrap_or(max / 4).clamp(1, max - 1);
+        let mut cache = self.cache.as_ref().unwrap().fork();
+        cache.reset_within(max - reserve, max - reserve + 1);
+        cache.cleanup();
+        let max_tokens = max - cache.window_len();
+        let max_tokens = self.max_tokens.map_or(max_tokens, |n| n.min(max_tokens));
+
+        let created = SystemTime::now();
+        let beam = self
+            .component
+            .beam_search(cache, args, max_tokens, self.priority)
+            .await;
+
+        let eos = self.component.handle.model.eos_token();
+        let content = self
+            .component
+            .detokenize(&beam.tokens, self.skip_special_tokens);
+        let finish_reason = if beam.finished {
+            FinishReason::Stop
+        } else {
+            FinishReason::Length
+        };
+        let mut cache = beam.cache;
+        let mut tokens = beam.tokens;
+        tokens.push(eos);
+        cache.extend(&[eos]);
+        cache.cleanup();
+        self.dialog
+            .push(tokens, content.clone(), Some(created), Some(finish_reason));
+        assert_eq!(cache.end(), self.dialog.num_tokens());
+        self.cache = Some(cache);
+        content
+    }
+
+    /// 预填充会话，只计算对话的缓存，不生成新的句子。
+    pub async fn prefill(&mut self) {
+        let cache = self.cache.take().unwrap();
+        let args = InferArgs {
+            priority: self.priority,
+            ..Default::default()
+        };
+        let handle = self.component.infer(args, cache);
+        // 借用忙会话，即使等待被取消也能归还缓存
+        let mut busy = BusySession {
+            session: self,
+            handle,
+            created: SystemTime::now(),
+        };
+        busy.session.component.wait(&mut busy.handle).await;
+    }
+
+    /// 将会话预填充为模板，之后以模板开头的会话都从这个模板分叉。
+    pub(crate) async fn warm_up(mut self, template: Vec<String>) {
+        self.extend(template.iter().map(String::as_str));
+        self.prefill().await;
+
+        let warm = Warm {
+            system: self.system.clone(),
+            sentences: template,
+            dialog: take(&mut self.dialog),
+            cache: self.cache.take().unwrap(),
+        };
+        let mut list = self.component.warm.lock().unwrap();
+        list.retain(|w| w.system != warm.system || w.sentences != warm.sentences);
+        list.push(warm);
+    }
+
+    /// 归还缓存，`stopped` 是因停止序列结束时截断的输出。
+    fn restore_cache(
+        &mut self,
+        mut cache: Cache<M::Storage>,
+        created: SystemTime,
+        stopped: Option<String>,
+    ) {
         let end = self.dialog.num_tokens();
         if cache.end() > end {
-            // 无论忙会话为何丢弃，只要生成了新句子，就补充一个结束符
-            cache.push(self.component.handle.model.eos_token());
+            // 模型生成的结束符已加入缓存，否则忙会话提前丢弃，补充一个结束符
+            let eos = self.component.handle.model.eos_token();
+            let generated = cache.slice_tail(end).len();
+            let finish_reason = if cache.slice_tail(end).last() == Some(&eos) {
+                FinishReason::Stop
+            } else if stopped.is_some() {
+                cache.push(eos);
+                FinishReason::Stop
+            } else {
+                // 推理任务因长度限制结束，或者忙会话提前丢弃
+                let max = self.component.handle.model.max_seq_len() as usize;
+                let full = self.overflow != Overflow::Shift && cache.window_len() >= max;
+                let reason = if full || self.max_tokens.is_some_and(|n| generated >= n) {
+                    FinishReason::Length
+                } else {
+                    FinishReason::Abort
+                };
+                cache.push(eos);
+                reason
+            };
             // 只要忙会话收集到任何 token，就生成一个新的句子
-            self.dialog.push(cache.slice_tail(end).to_vec());
+            let tokens = cache.slice_tail(end).to_vec();
+            let content = stopped.unwrap_or_else(|| {
+                let generated = &tokens[..tokens.len() - 1];
+                self.component
+                    .detokenize(generated, self.skip_special_tokens)
+            });
+            self.dialog
+                .push(tokens, content, Some(created), Some(finish_reason));
         }
         cache.cleanup();
         info!("Cache restored at {} tokens", cache.end());
@@ -147,10 +530,73 @@ impl<M: CausalLM> Session<M> {
     }
 }
 
+impl<M: CausalLM> ServiceComponent<M> {
+    /// 将 token 序列解码为文本，`skip_special` 为真时跳过特殊词汇。
+    pub(crate) fn detokenize(&self, tokens: &[utok], skip_special: bool) -> String {
+        let mut detokenizer = Detokenizer::new();
+        let mut ans = tokens
+            .iter()
+            .filter(|&&t| !(skip_special && self.tokenizer.is_special(t)))
+            .map(|&t| detokenizer.decode(&*self.tokenizer, &*self.normalizer, t))
+            .collect::<String>();
+        ans.push_str(&detokenizer.flush());
+        ans
+    }
+
+    /// 找到系统提示词相同、`dialog` 开头最长的预填充模板，返回模板句子数和分叉的对话、缓存。
+    fn fork_warm(
+        &self,
+        system: Option<&str>,
+        dialog: &[&str],
+    ) -> Option<(usize, Dialog, Cache<M::Storage>)> {
+        self.warm
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|w| {
+                w.system.as_deref() == system
+                    && w.sentences.len() <= dialog.len()
+                    && zip(&w.sentences, dialog).all(|(a, b)| a == b)
+            })
+            .max_by_key(|w| w.sentences.len())
+            .map(|w| (w.sentences.len(), w.dialog.clone(), w.cache.fork()))
+    }
+}
+
+/// 不套用对话模板时用户发言的原文，系统提示词按原文放在第一句用户发言之前。
+fn raw_prompt(system: Option<&str>, content: &str) -> String {
+    match system {
+        Some(system) => format!("{system}{content}"),
+        None => content.into(),
+    }
+}
+
+/// 对话模板渲染的消息：系统提示词、`turns` 中的发言和最后一句用户发言。
+fn messages<'a>(
+    system: Option<&'a str>,
+    turns: impl IntoIterator<Item = &'a Turn>,
+    last: &'a str,
+) -> Vec<Message<'a>> {
+    let system = system.map(|content| Message {
+        role: Role::System,
+        content,
+    });
+    let turns = turns.into_iter().map(|t| Message {
+        role: t.role,
+        content: &t.content,
+    });
+    let last = Message {
+        role: Role::User,
+        content: last,
+    };
+    system.into_iter().chain(turns).chain([last]).collect()
+}
+
 /// 忙会话，表示会话正在处理推理任务，并可接收推理结果。
 pub struct BusySession<'a, M: CausalLM> {
     session: &'a mut Session<M>,
     handle: TaskHandle<M>,
+    created: SystemTime,
 }
 
 impl<M: CausalLM> BusySession<'_, M> {
@@ -159,12 +605,22 @@ impl<M: CausalLM> BusySession<'_, M> {
     pub async fn decode(&mut self) -> Option<String> {
         self.session.component.decode(&mut self.handle).await
     }
+
+    /// 取走已经解码的 token 的对数概率，会话没有设置 [`Session::logprobs`] 时总是为空。
+    ///
+    /// 每次解码的文本可能对应多个 token，也可能因为等待完整的字符或停止序列而暂缓输出。
+    #[inline]
+    pub fn take_logprobs(&mut self) -> Vec<TokenLogprob> {
+        self.handle.take_logprobs()
+    }
 }
 
 impl<M: CausalLM> Drop for BusySession<'_, M> {
     #[inline]
     fn drop(&mut self) {
-        self.session.restore_cache(self.handle.take());
+        let stopped = self.handle.stopped_output();
+        self.session
+            .restore_cache(self.handle.take(), self.created, stopped);
     }
 }
 
@@ -175,15 +631,39 @@ pub struct Generator<M: CausalLM> {
 }
 
 impl<M: CausalLM> Generator<M> {
+    /// `token_healing` 为真时回退提示词的最后一个 token，第一个生成的 token 必须以它的文本开头，
+    /// 以修复在 token 中间结束的提示词，重新生成的这部分文本不输出。
     pub(crate) fn new(
         component: Arc<ServiceComponent<M>>,
         prompt: impl AsRef<str>,
         sample: SampleArgs,
+        token_healing: bool,
     ) -> Self {
         let prompt = component.template.normalize(prompt.as_ref());
         let prompt = component.normalizer.encode(&prompt);
-        let tokens = component.tokenizer.encode(&prompt);
-        let handle = component.infer(sample, Cache::new(&component.handle.model, tokens));
+        let mut tokens = component.tokenizer.encode(&prompt);
+        let healed = if token_healing && tokens.len() > 1 {
+            tokens.pop()
+        } else {
+            None
+        };
+        let (constraint, skip) = match healed {
+            Some(t) => {
+                let vocab = component.vocab();
+                let prefix = &vocab.tokens[t as usize];
+                let matcher: Box<dyn Constraint> = Box::new(HealingMatcher::new(&vocab, prefix));
+                (Some(matcher), prefix.len())
+            }
+            None => (None, 0),
+        };
+        let cache = Cache::new(&component.handle, tokens);
+        let args = InferArgs {
+            sample: Some(sample),
+            constraint,
+            ..Default::default()
+        };
+        let mut handle = component.infer(args, cache);
+        handle.skip(skip);
         Self { handle, component }
     }
 
@@ -0,0 +1,89 @@
+use std::mem::{replace, take};
+
+/// 流式匹配停止序列。
+///
+/// 只扣留可能是某个停止序列开头的文本，其余文本立即输出。
+#[derive(Clone, Default, Debug)]
+pub(super) struct StopMatcher {
+    words: Vec<String>,
+    held: String,
+    /// 已输出的全部文本，没有停止序列时不记录。
+    output: String,
+    matched: bool,
+}
+
+impl StopMatcher {
+    #[inline]
+    pub fn new(words: Vec<String>) -> Self {
+        Self {
+            words: words.into_iter().filter(|w| !w.is_empty()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// 是否已经匹配到停止序列。
+    #[inline]
+    pub fn matched(&self) -> bool {
+        self.matched
+    }
+
+    /// 匹配到停止序列时，返回截断在停止序列之前的完整输出。
+    #[inline]
+    pub fn stopped_output(&self) -> Option<&str> {
+        self.matched.then_some(&*self.output)
+    }
+
+    /// 加入新解码的文本，返回可以输出的文本。
+    pub fn push(&mut self, s: &str) -> String {
+        if self.words.is_empty() {
+            return s.into();
+        }
+        self.held.push_str(s);
+        let ans = if let Some(i) = self.words.iter().filter_map(|w| self.held.find(&**w)).min() {
+            self.matched = true;
+            self.held.truncate(i);
+            take(&mut self.held)
+        } else {
+            // 保留可能是停止序列开头的最长后缀
+            let keep = self
+                .words
+                .iter()
+                .map(|w| {
+                    (1..w.len())
+                        .rev()
+                        .find(|&n| w.is_char_boundary(n) && self.held.ends_with(&w[..n]))
+                        .unwrap_or(0)
+                })
+                .max()
+                .unwrap_or(0);
+            let rest = self.held.split_off(self.held.len() - keep);
+            replace(&mut self.held, rest)
+        };
+        self.output.push_str(&ans);
+        ans
+    }
+
+    /// 生成结束，取出扣留的文本。
+    #[inline]
+    pub fn flush(&mut self) -> String {
+        let ans = take(&mut self.held);
+        self.output.push_str(&ans);
+        ans
+    }
+}
+
+#[test]
+fn test_stop_matcher() {
+    let mut stop = StopMatcher::new(vec!["\nUser:".into()]);
+    assert_eq!(stop.push("Hello"), "Hello");
+    assert_eq!(stop.push(" world\nUs"), " world");
+    assert_eq!(stop.push("ually"), "\nUsually");
+    assert_eq!(stop.push("\nU"), "");
+    assert_eq!(stop.push("ser: hi"), "");
+    assert!(stop.matched());
+    assert_eq!(stop.stopped_output(), Some("Hello world\nUsually"));
+
+    let mut stop = StopMatcher::new(vec![]);
+    assert_eq!(stop.push("\nUser:"), "\nUser:");
+    assert!(!stop.matched());
+}
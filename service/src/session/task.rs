@@ -1,50 +1,473 @@
-﻿use super::cache::Cache;
-use causal_lm::SampleArgs;
+﻿use super::{cache::Cache, Priority};
+use crate::constraint::Constraint;
+use causal_lm::{History, ProcessorChain, SampleArgs};
 use common::utok;
-use std::sync::{Arc, Mutex, MutexGuard};
-use tokio::sync::mpsc::UnboundedSender;
+use std::{
+    iter::{once, zip},
+    mem::take,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use tracing::Span;
 
 pub(super) struct Task<Storage> {
-    sample: SampleArgs,
-    sender: UnboundedSender<utok>,
+    /// 采样参数，没有采样参数的任务只预填充缓存。
+    sample: Option<SampleArgs>,
+    output: Output,
+    /// 本次推理已经生成的 token 数。
+    generated: usize,
+    /// 本次推理至多生成的 token 数。
+    max_tokens: Option<usize>,
+    /// 缓存满时是否滑动缓存窗口，否则结束推理。
+    shift: bool,
+    /// 约束生成的文本，没有约束时为空。
+    constraint: Option<Box<dyn Constraint>>,
+    /// 采样前处理 logits 的处理器链。
+    processors: Option<Arc<Mutex<ProcessorChain>>>,
+    /// 推测解码的状态，不推测时为空。
+    speculation: Option<Speculation<Storage>>,
+    /// 无分类器引导的无条件上下文，不引导时为空。
+    negative: Option<Negative<Storage>>,
+    /// 发送每个生成的 token 的对数概率，不需要时为空。
+    logprobs: Option<Logprobs>,
+    priority: Priority,
+    /// 创建任务时所在的跟踪范围，任务每次计算的跟踪范围都在其中。
+    span: Span,
+    /// 任务第一次计算之前排队的跟踪范围。
+    queue: Option<Span>,
 
     cache: Arc<Mutex<Option<Cache<Storage>>>>,
 }
 
+/// 推测解码的状态。
+struct Speculation<Storage> {
+    /// 草稿模型的缓存，与目标模型的缓存保持相同的 token 序列，不使用草稿模型时为空。
+    cache: Option<Cache<Storage>>,
+    /// 目标模型正在验证的草稿，位于目标模型缓存的末尾。
+    drafts: Vec<utok>,
+}
+
+/// 无分类器引导的无条件上下文，与任务的上下文同批计算，生成的 token 同时加入两者。
+struct Negative<Storage> {
+    cache: Mutex<Option<Cache<Storage>>>,
+    /// 引导系数。
+    scale: f32,
+}
+
+/// 生成的 token 的对数概率和概率最大的若干个候选。
+pub(super) type Logprob = (f32, Vec<(utok, f32)>);
+
+/// 在发送 token 之前发送它的对数概率和概率最大的 `top` 个候选。
+struct Logprobs {
+    top: usize,
+    sender: UnboundedSender<Logprob>,
+}
+
+/// 推理任务的输出。
+enum Output {
+    /// 逐个发送生成的 token，只预填充的任务完成时关闭。
+    Tokens(UnboundedSender<utok>),
+    /// 发送下一个 token 中概率最大的若干候选及其对数概率，然后结束任务。
+    Candidates(usize, oneshot::Sender<Vec<(utok, f32)>>),
+    /// 发送查询每个 token 最终归一化的隐藏状态，模型不支持时发送空，然后结束任务。
+    ///
+    /// 分块计算时先收集已计算的块的隐藏状态，最后一块算完后一起发送。
+    Hidden(oneshot::Sender<Option<Vec<f32>>>, Vec<f32>),
+}
+
 impl<Storage> Task<Storage> {
     #[inline]
     pub fn new(
         cache: Arc<Mutex<Option<Cache<Storage>>>>,
-        sample: SampleArgs,
+        sample: Option<SampleArgs>,
         sender: UnboundedSender<utok>,
+        max_tokens: Option<usize>,
+        shift: bool,
+        constraint: Option<Box<dyn Constraint>>,
+        processors: Option<Arc<Mutex<ProcessorChain>>>,
     ) -> Self {
         Self {
             sample,
-            sender,
+            output: Output::Tokens(sender),
+            generated: 0,
+            max_tokens,
+            shift,
+            constraint,
+            processors,
+            speculation: None,
+            negative: None,
+            logprobs: None,
+            priority: Default::default(),
+            span: Span::current(),
+            queue: Some(tracing::debug_span!("queue")),
+            cache,
+        }
+    }
+    /// 计算缓存中的查询，只求出下一个 token 中概率最大的 `k` 个候选。
+    #[inline]
+    pub fn candidates(
+        cache: Arc<Mutex<Option<Cache<Storage>>>>,
+        k: usize,
+        sender: oneshot::Sender<Vec<(utok, f32)>>,
+    ) -> Self {
+        Self {
+            sample: None,
+            output: Output::Candidates(k, sender),
+            generated: 0,
+            max_tokens: None,
+            shift: false,
+            constraint: None,
+            processors: None,
+            speculation: None,
+            negative: None,
+            logprobs: None,
+            priority: Default::default(),
+            span: Span::current(),
+            queue: Some(tracing::debug_span!("queue")),
+            cache,
+        }
+    }
+
+    /// 计算缓存中的查询，只求出最终归一化的隐藏状态，用于计算文本嵌入。
+    #[inline]
+    pub fn hidden(
+        cache: Arc<Mutex<Option<Cache<Storage>>>>,
+        sender: oneshot::Sender<Option<Vec<f32>>>,
+    ) -> Self {
+        Self {
+            sample: None,
+            output: Output::Hidden(sender, vec![]),
+            generated: 0,
+            max_tokens: None,
+            shift: false,
+            constraint: None,
+            processors: None,
+            speculation: None,
+            negative: None,
+            logprobs: None,
+            priority: Default::default(),
+            span: Span::current(),
+            queue: Some(tracing::debug_span!("queue")),
             cache,
         }
     }
 
+    /// 推测解码，`draft` 是草稿模型的缓存，不使用草稿模型时为空。
     #[inline]
-    pub fn sample(&self) -> &SampleArgs {
-        &self.sample
+    pub fn with_speculation(mut self, draft: Option<Cache<Storage>>) -> Self {
+        self.speculation = Some(Speculation {
+            cache: draft,
+            drafts: vec![],
+        });
+        self
+    }
+
+    /// 以 `cache` 为无条件上下文，按系数 `scale` 做无分类器引导。
+    #[inline]
+    pub fn with_guidance(mut self, cache: Cache<Storage>, scale: f32) -> Self {
+        self.negative = Some(Negative {
+            cache: Mutex::new(Some(cache)),
+            scale,
+        });
+        self
+    }
+
+    /// 为每个生成的 token 发送对数概率和概率最大的 `top` 个候选。
+    #[inline]
+    pub fn with_logprobs(mut self, top: usize, sender: UnboundedSender<Logprob>) -> Self {
+        self.logprobs = Some(Logprobs { top, sender });
+        self
+    }
+
+    #[inline]
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// 任务被取出计算，第一次取出时结束排队的跟踪范围。
+    #[inline]
+    pub fn schedule(&mut self) {
+        self.queue = None;
+    }
+
+    /// 开始一次计算的跟踪范围，计算的查询多于解码的 token 时是预填充，否则是解码。
+    pub fn step(&self, num_query: usize, num_decode: usize) -> Span {
+        if num_query > num_decode {
+            tracing::debug_span!(parent: &self.span, "prefill", tokens = num_query)
+        } else {
+            tracing::debug_span!(parent: &self.span, "decode", tokens = num_decode)
+        }
+    }
+    #[inline]
+    pub fn sample(&self) -> Option<&SampleArgs> {
+        self.sample.as_ref()
     }
     #[inline]
     pub fn is_alive(&self) -> bool {
-        !self.sender.is_closed()
+        match &self.output {
+            Output::Tokens(sender) => !sender.is_closed(),
+            Output::Candidates(_, sender) => !sender.is_closed(),
+            Output::Hidden(sender, _) => !sender.is_closed(),
+        }
+    }
+    /// 任务是否需要解码出 logits。
+    #[inline]
+    pub fn decodes(&self) -> bool {
+        self.sample.is_some() || self.num_candidates().is_some()
+    }
+    /// 任务是否推测解码。
+    #[inline]
+    pub fn speculates(&self) -> bool {
+        self.speculation.is_some()
     }
+    /// 每次解码的 token 数，推测的任务解码每个草稿和草稿之前的一个 token。
     #[inline]
-    pub fn lock_cache(&self) -> MutexGuard<Option<Cache<Storage>>> {
-        self.cache.lock().unwrap()
+    pub fn num_decode(&self) -> usize {
+        self.speculation.as_ref().map_or(1, |s| s.drafts.len() + 1)
+    }
+    /// 求候选的任务需要的候选数。
+    #[inline]
+    pub fn num_candidates(&self) -> Option<usize> {
+        match self.output {
+            Output::Tokens(_) | Output::Hidden(..) => None,
+            Output::Candidates(k, _) => Some(k),
+        }
+    }
+    /// 任务是否求隐藏状态，这样的任务单独计算。
+    #[inline]
+    pub fn wants_hidden(&self) -> bool {
+        matches!(self.output, Output::Hidden(..))
+    }
+    /// 任务是否需要生成的 token 的对数概率。
+    #[inline]
+    pub fn wants_logprobs(&self) -> bool {
+        self.logprobs.is_some()
+    }
+    /// 无分类器引导的系数，不引导时为空。
+    #[inline]
+    pub fn guidance_scale(&self) -> Option<f32> {
+        self.negative.as_ref().map(|n| n.scale)
+    }
+    /// 约束允许生成的 token。
+    #[inline]
+    fn mask(&self) -> Option<Arc<[bool]>> {
+        self.constraint.as_ref().map(|c| c.mask())
+    }
+    /// 按 `args` 的需要取出采样参考的历史，有处理器链时取出上下文窗口中的全部 token。
+    pub fn history(&self, args: &SampleArgs) -> History {
+        let len = if self.processors.is_some() {
+            usize::MAX
+        } else {
+            args.history_len(self.generated)
+        };
+        if len == 0 {
+            return History {
+                tokens: vec![],
+                generated: self.generated,
+                mask: self.mask(),
+                processors: None,
+                guidance: None,
+            };
+        }
+        let tokens = self
+            .cache
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or_else(Vec::new, |cache| cache.recent(len).to_vec());
+        History {
+            tokens,
+            generated: self.generated,
+            mask: self.mask(),
+            processors: self.processors.clone(),
+            guidance: None,
+        }
+    }
+    /// 锁定任务的所有缓存，引导的任务在自己的缓存之后还有无条件上下文的缓存。
+    #[inline]
+    pub fn lock_caches(&self) -> impl Iterator<Item = MutexGuard<'_, Option<Cache<Storage>>>> {
+        let negative = self.negative.as_ref().map(|n| n.cache.lock().unwrap());
+        once(self.cache.lock().unwrap()).chain(negative)
+    }
+
+    /// 预填充完成，将查询全部标记为已缓存。
+    #[inline]
+    pub fn commit(&self) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.commit();
+        }
     }
 
+    /// 模型生成了结束符，只加入缓存，不发送给会话。
     #[inline]
+    pub fn finish(&self, eos: utok) {
+        if let Some(cache) = self.cache.lock().unwrap().as_mut() {
+            cache.push(eos);
+        }
+    }
+
+    /// 从按概率排列的所有 token 的对数概率 `all` 中取出 `token` 的对数概率和候选并发送。
+    pub fn send_logprobs(&self, token: utok, mut all: Vec<(utok, f32)>) {
+        if let Some(Logprobs { top, sender }) = &self.logprobs {
+            let logprob = all
+                .iter()
+                .find(|(t, _)| *t == token)
+                .map_or(f32::NEG_INFINITY, |&(_, p)| p);
+            all.truncate(*top);
+            let _ = sender.send((logprob, all));
+        }
+    }
+
+    /// 查询已经计算完，发送候选并结束任务。
+    pub fn send_candidates(self, mut candidates: Vec<(utok, f32)>) {
+        self.commit();
+        if let Output::Candidates(k, sender) = self.output {
+            candidates.truncate(k);
+            let _ = sender.send(candidates);
+        }
+    }
+
+    /// 收集分块计算的一块查询的隐藏状态。
+    pub fn push_hidden(&mut self, rows: &[f32]) {
+        if let Output::Hidden(_, hidden) = &mut self.output {
+            hidden.extend_from_slice(rows);
+        }
+    }
+
+    /// 查询已经计算完，发送包括最后一块 `rows` 在内的全部隐藏状态并结束任务，缓存随任务释放；
+    /// `rows` 为空表示模型不支持。
+    pub fn send_hidden(self, rows: Option<&[f32]>) {
+        if let Output::Hidden(sender, mut hidden) = self.output {
+            let _ = sender.send(rows.map(|rows| {
+                hidden.extend_from_slice(rows);
+                hidden
+            }));
+        }
+    }
+
+    /// 推测的任务用 `draft` 生成至多 `num_draft` 个草稿，加入缓存等待验证。
+    ///
+    /// `draft` 的参数依次为上下文窗口中的 token、草稿模型的缓存和草稿数。
+    pub fn draft(
+        &mut self,
+        num_draft: usize,
+        max: usize,
+        draft: impl FnOnce(&[utok], Option<&mut Cache<Storage>>, usize) -> Vec<utok>,
+    ) {
+        let Some(spec) = &mut self.speculation else {
+            return;
+        };
+        let mut cache = self.cache.lock().unwrap();
+        let Some(cache) = cache.as_mut() else {
+            return;
+        };
+        // 验证时还会多生成一个 token，草稿不能超出上下文窗口和生成长度的限制
+        let remain = self.max_tokens.map_or(usize::MAX, |n| n - self.generated);
+        let n = num_draft
+            .min(max.saturating_sub(cache.window_len() + 1))
+            .min(remain - 1);
+        if n > 0 {
+            let window = cache.recent(cache.window_len());
+            spec.drafts = draft(window, spec.cache.as_mut(), n);
+            cache.extend(&spec.drafts);
+        }
+    }
+
+    /// 验证推测的草稿，发送接受的 token 并加入缓存，返回是否继续推理。
+    ///
+    /// `rows` 是目标模型在最后一个 token 和每个草稿上采样得到的 token。
+    /// 从头接受与采样一致的草稿，在第一个不一致处改用采样的 token，
+    /// 因此发送的每个 token 都是目标模型自己的采样，输出的分布与不推测时相同。
+    pub fn push_speculated(&mut self, rows: &[utok], eos: utok, min: usize, max: usize) -> bool {
+        let Self {
+            output: Output::Tokens(sender),
+            generated,
+            max_tokens,
+            shift,
+            speculation: Some(spec),
+            cache,
+            ..
+        } = self
+        else {
+            return false;
+        };
+        let mut cache = cache.lock().unwrap();
+        let Some(cache) = cache.as_mut() else {
+            return false;
+        };
+        let drafts = take(&mut spec.drafts);
+        let accepted = zip(&drafts, rows).take_while(|(d, r)| d == r).count();
+        // 草稿之前的位置，丢弃未接受的草稿后在这里加入新 token
+        let base = cache.end() - drafts.len();
+
+        let mut alive = true;
+        let mut n = 0;
+        for &token in &rows[..=accepted] {
+            if token == eos {
+                cache.truncate(base + n);
+                cache.push(eos);
+                return false;
+            }
+            if sender.send(token).is_err() {
+                cache.truncate(base + n);
+                return false;
+            }
+            n += 1;
+            *generated += 1;
+            if max_tokens.is_some_and(|m| *generated >= m) {
+                alive = false;
+                break;
+            }
+        }
+        let last = rows[n - 1];
+        cache.truncate(base + n - 1);
+        cache.push(last);
+        if let Some(draft) = &mut spec.cache {
+            draft.truncate(draft.end() - drafts.len() + n - 1);
+            draft.extend(&[last]);
+        }
+        if *shift {
+            cache.reset_within(min, max);
+        } else if cache.window_len() >= max {
+            return false;
+        }
+        alive
+    }
+
+    /// 发送新生成的 token 并加入缓存，返回是否继续推理。
     pub fn push(&mut self, token: utok, min: usize, max: usize) -> bool {
-        if self.sender.send(token).is_ok() {
+        let Output::Tokens(sender) = &self.output else {
+            return false;
+        };
+        if sender.send(token).is_ok() {
+            self.generated += 1;
+            if let Some(constraint) = &mut self.constraint {
+                constraint.advance(token);
+                if constraint.finished() {
+                    self.constraint = None;
+                }
+            }
             if let Some(cache) = self.cache.lock().unwrap().as_mut() {
                 cache.push(token);
-                cache.reset_within(min, max);
-                return true;
+                if let Some(negative) = &self.negative {
+                    if let Some(negative) = negative.cache.lock().unwrap().as_mut() {
+                        // 无条件上下文只用于引导，总是滑动窗口
+                        negative.push(token);
+                        negative.reset_within(min, max);
+                    }
+                }
+                if self.shift {
+                    cache.reset_within(min, max);
+                } else if cache.window_len() >= max {
+                    return false;
+                }
+                return self.max_tokens.is_none_or(|n| self.generated < n);
             }
         }
         false
@@ -0,0 +1,118 @@
+use super::{Message, Template};
+use crate::Role;
+use log::warn;
+use minijinja::{context, Environment, Error, ErrorKind, Value};
+use std::borrow::Cow;
+
+/// 由 tokenizer_config.json 中的 `chat_template` 定义的 jinja 对话模板。
+///
+/// 模板每次渲染整个对话，逐句填充对话时取两次渲染结果的差作为新发言的文本。
+pub(crate) struct Jinja {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl Jinja {
+    const NAME: &'static str = "chat";
+
+    pub fn new(source: String, bos_token: String, eos_token: String) -> Result<Self, Error> {
+        let mut env = Environment::new();
+        // 与 transformers 渲染对话模板的设置一致
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |msg: String| -> Result<Value, Error> {
+            Err(Error::new(ErrorKind::InvalidOperation, msg))
+        });
+        env.add_template_owned(Self::NAME, source)?;
+        Ok(Self {
+            env,
+            bos_token,
+            eos_token,
+        })
+    }
+
+    /// 渲染整个对话，`add_generation_prompt` 为真时在末尾加上助手发言的开头。
+    fn render(&self, messages: &[Message], add_generation_prompt: bool) -> Result<String, Error> {
+        let messages = messages
+            .iter()
+            .map(|m| context! { role => m.role.as_str(), content => m.content })
+            .collect::<Vec<_>>();
+        self.env.get_template(Self::NAME)?.render(context! {
+            messages,
+            bos_token => self.bos_token,
+            eos_token => self.eos_token,
+            add_generation_prompt,
+        })
+    }
+
+    /// 渲染最后一句用户发言之前已经加入对话的部分：上一轮的提问和助手的回答。
+    fn rendered(&self, messages: &[Message]) -> Result<Vec<String>, Error> {
+        let history = &messages[..messages.len() - 1];
+        match history {
+            [prompts @ .., answer] if answer.role == Role::Assistant => {
+                let prompt = self.render(prompts, true)?;
+                // 对话模板可能去掉回答两端的空白
+                Ok(vec![
+                    prompt.clone() + answer.content,
+                    prompt + answer.content.trim(),
+                ])
+            }
+            _ => Ok(vec![String::new()]),
+        }
+    }
+}
+
+impl Template for Jinja {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let last = messages.last().unwrap().content;
+        let full = match self.render(messages, true) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to render chat template: {e}");
+                return last.into();
+            }
+        };
+        let rendered = self.rendered(messages).unwrap_or_default();
+        // 助手的回答加入对话时已经带有结束符
+        if let Some(s) = rendered
+            .iter()
+            .find_map(|prefix| full.strip_prefix(&**prefix))
+        {
+            return s.strip_prefix(&*self.eos_token).unwrap_or(s).into();
+        }
+        // 模板对之前的对话渲染结果不同，只渲染最后一句发言
+        warn!("Chat template is not incremental, render the last message only");
+        self.render(&messages[messages.len() - 1..], true)
+            .unwrap_or_else(|_| last.into())
+    }
+}
+
+#[test]
+fn test_jinja() {
+    use super::{message, ChatML};
+
+    const CHAT_ML: &str = "\
+{% for message in messages %}\
+{{ '<|im_start|>' + message['role'] + '\n' + message['content'].strip() + '<|im_end|>' + '\n' }}\
+{% endfor %}\
+{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}";
+
+    let jinja = Jinja::new(CHAT_ML.into(), "".into(), "<|im_end|>".into()).unwrap();
+    let mut messages = vec![
+        message(Role::System, "Be brief."),
+        message(Role::User, "Hi"),
+    ];
+    assert_eq!(jinja.apply_chat(&messages), ChatML.apply_chat(&messages));
+    messages.extend([
+        message(Role::Assistant, "Hello "),
+        message(Role::User, "Bye"),
+    ]);
+    assert_eq!(jinja.apply_chat(&messages), ChatML.apply_chat(&messages));
+}
@@ -0,0 +1,190 @@
+﻿//! See tokenizer_config.json/chat_template.
+
+mod jinja;
+
+use crate::Role;
+use std::{borrow::Cow, error, fmt};
+
+pub(crate) use jinja::Jinja;
+
+/// 对话中的一条消息。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Message<'a> {
+    pub role: Role,
+    pub content: &'a str,
+}
+
+pub trait Template {
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str>;
+    /// 渲染对话中最后一句用户发言。
+    ///
+    /// `messages` 是到这句发言为止的完整对话，可能以系统提示词开头，之后用户与助手交替发言。
+    /// 助手发言加入对话时只追加结束符，因此模板在这里补上助手发言之后、用户发言之前的部分。
+    fn apply_chat(&self, messages: &[Message]) -> String;
+}
+
+/// 最后一句发言的内容，以及第一句用户发言之前的系统提示词。
+fn last_turn<'a>(messages: &[Message<'a>]) -> (&'a str, Option<&'a str>) {
+    let (last, history) = messages.split_last().expect("dialog must not be empty");
+    let system = match history {
+        [Message {
+            role: Role::System,
+            content,
+        }] => Some(*content),
+        _ => None,
+    };
+    (last.content, system)
+}
+
+/// 是否对话中的第一句用户发言。
+#[inline]
+fn is_first(messages: &[Message]) -> bool {
+    messages.iter().filter(|m| m.role != Role::System).count() == 1
+}
+
+pub struct ChatCPM;
+
+pub struct ChatTinyLlama;
+
+/// Llama 2 Chat 的对话模板。
+pub struct ChatLlama2;
+
+/// ChatML 对话模板（Qwen、Yi 等）。
+pub struct ChatML;
+
+/// Gemma 的对话模板，不支持系统提示词，系统提示词放在第一句用户发言之前。
+pub struct ChatGemma;
+
+/// 由使用者提供的 jinja 对话模板，覆盖模型自带的模板。
+///
+/// 语法与 tokenizer_config.json 中的 `chat_template` 相同，加载模型时与模型的 bos、eos 词汇一起编译为 [`Jinja`]。
+#[derive(Clone, Debug)]
+pub struct Custom(pub(crate) String);
+
+/// 使用者提供的对话模板无法编译。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ChatTemplateError(String);
+
+impl error::Error for ChatTemplateError {}
+impl fmt::Display for ChatTemplateError {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid chat template: {}", self.0)
+    }
+}
+
+impl Custom {
+    pub fn new(template: String) -> Result<Self, ChatTemplateError> {
+        match Jinja::new(template.clone(), String::new(), String::new()) {
+            Ok(_) => Ok(Self(template)),
+            Err(e) => Err(ChatTemplateError(e.to_string())),
+        }
+    }
+}
+
+impl Template for ChatCPM {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(format!("<s>{}", prompt.trim()))
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let (prompt, system) = last_turn(messages);
+        let system = system.map_or("", str::trim);
+        format!("<s>{system}<用户>{}<AI>", prompt.trim())
+    }
+}
+
+impl Template for ChatTinyLlama {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let (prompt, system) = last_turn(messages);
+        let system = system.map_or_else(String::new, |s| format!("<|system|>\n{s}</s>\n"));
+        format!("{system}<|user|>\n{prompt}</s><|assistant|>\n")
+    }
+}
+
+impl Template for ChatLlama2 {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let (prompt, system) = last_turn(messages);
+        let system = system.map_or_else(String::new, |s| {
+            format!("<<SYS>>\n{}\n<</SYS>>\n\n", s.trim())
+        });
+        format!("<s>[INST] {system}{} [/INST]", prompt.trim())
+    }
+}
+
+impl Template for ChatML {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(prompt)
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let (prompt, system) = last_turn(messages);
+        // 助手发言以结束符 <|im_end|> 结尾，补上之后的换行
+        let head = match system {
+            Some(s) => format!("<|im_start|>system\n{s}<|im_end|>\n"),
+            None if is_first(messages) => String::new(),
+            None => "\n".into(),
+        };
+        format!("{head}<|im_start|>user\n{prompt}<|im_end|>\n<|im_start|>assistant\n")
+    }
+}
+
+impl Template for ChatGemma {
+    #[inline]
+    fn normalize<'a>(&self, prompt: &'a str) -> Cow<'a, str> {
+        Cow::Owned(format!("<bos>{prompt}"))
+    }
+
+    fn apply_chat(&self, messages: &[Message]) -> String {
+        let (prompt, system) = last_turn(messages);
+        let head = if is_first(messages) { "<bos>" } else { "\n" };
+        let prompt = match system {
+            Some(s) => format!("{s}\n\n{prompt}"),
+            None => prompt.into(),
+        };
+        format!("{head}<start_of_turn>user\n{prompt}<end_of_turn>\n<start_of_turn>model\n")
+    }
+}
+
+#[cfg(test)]
+const fn message(role: Role, content: &str) -> Message<'_> {
+    Message { role, content }
+}
+
+#[test]
+fn test_custom() {
+    assert!(Custom::new("{% for message in messages %}".into()).is_err());
+    assert!(Custom::new("{{ messages[0]['content'] }}".into()).is_ok());
+}
+
+#[test]
+fn test_chat_ml() {
+    let mut messages = vec![
+        message(Role::System, "Be brief."),
+        message(Role::User, "Hi"),
+    ];
+    assert_eq!(
+        ChatML.apply_chat(&messages),
+        "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+    );
+    messages.extend([
+        message(Role::Assistant, "Hello"),
+        message(Role::User, "Bye"),
+    ]);
+    assert_eq!(
+        ChatML.apply_chat(&messages),
+        "\n<|im_start|>user\nBye<|im_end|>\n<|im_start|>assistant\n"
+    );
+}
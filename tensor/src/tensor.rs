@@ -116,7 +116,7 @@ impl<Physical> Tensor<Physical> {
             .enumerate()
             .rev()
             .scan(1 as idim, |mul, (i, &s)| {
-                if s == *mul || s == 0 {
+                if s == *mul || self.shape[i] == 1 {
                     *mul *= self.shape[i] as idim;
                     Some(())
                 } else {
@@ -289,3 +289,23 @@ fn test() {
     assert_eq!(t.contiguous_len(), 4);
     assert_eq!(t.is_contiguous(), false);
 }
+
+#[test]
+fn test_contiguous_len() {
+    use digit_layout::types::F32;
+
+    // 长度为 1 的维度不论步长都是连续的
+    let t = Tensor::new(F32, &[3, 1, 4], ());
+    assert_eq!(t.contiguous_len(), 3);
+    assert!(t.is_contiguous());
+
+    // 广播的维度步长为 0，不是连续的，拷贝时逐行读取同一段数据
+    let t = Tensor::new(F32, &[1, 4], (0..16).collect::<Vec<u8>>()).broadcast(&[3, 4]);
+    assert_eq!(t.pattern.0.as_slice(), &[0, 1, 0]);
+    assert_eq!(t.contiguous_len(), 1);
+    assert!(!t.is_contiguous());
+
+    let mut dst = Tensor::new(F32, &[3, 4], vec![0u8; 48]);
+    t.reform_to(&mut dst);
+    assert_eq!(dst.as_slice(), (0..48).map(|i| i % 16).collect::<Vec<u8>>());
+}
@@ -0,0 +1,135 @@
+use crate::Tokenizer;
+use common::utok;
+use std::collections::HashMap;
+
+/// 在分词器之外增加的词汇（如微调时加入的控制词汇）。
+///
+/// 增加的词汇在文本中原样匹配，优先匹配最长的词汇，其余文本由原分词器编码。
+pub struct AddedTokens<T> {
+    inner: T,
+    /// 按长度从长到短排列。
+    tokens: Vec<(String, utok)>,
+    pieces: HashMap<utok, (String, bool)>,
+    vocab_size: usize,
+}
+
+impl<T: Tokenizer> AddedTokens<T> {
+    /// `added` 是增加的词汇、序号和是否特殊词汇，序号相同时后出现的覆盖先出现的。
+    pub fn new(inner: T, added: impl IntoIterator<Item = (String, utok, bool)>) -> Self {
+        let pieces = added
+            .into_iter()
+            .filter(|(piece, _, _)| !piece.is_empty())
+            .map(|(piece, id, special)| (id, (piece, special)))
+            .collect::<HashMap<_, _>>();
+        let mut tokens = pieces
+            .iter()
+            .map(|(&id, (piece, _))| (piece.clone(), id))
+            .collect::<Vec<_>>();
+        tokens.sort_unstable_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then(a.cmp(b)));
+        let vocab_size = pieces
+            .keys()
+            .map(|&id| id as usize + 1)
+            .fold(inner.vocab_size(), usize::max);
+        Self {
+            inner,
+            tokens,
+            pieces,
+            vocab_size,
+        }
+    }
+
+    /// 增加的词汇数量。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+impl<T: Tokenizer> Tokenizer for AddedTokens<T> {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+    }
+
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        self.tokens
+            .first()
+            .map_or(0, |(piece, _)| piece.len())
+            .max(self.inner.max_piece_len())
+    }
+
+    fn encode(&self, text: &str) -> Vec<utok> {
+        let mut ans = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            let rest = &text[i..];
+            match self
+                .tokens
+                .iter()
+                .find(|(piece, _)| rest.starts_with(&**piece))
+            {
+                Some((piece, id)) => {
+                    if start < i {
+                        ans.extend(self.inner.encode(&text[start..i]));
+                    }
+                    ans.push(*id);
+                    i += piece.len();
+                    start = i;
+                }
+                None => i += rest.chars().next().unwrap().len_utf8(),
+            }
+        }
+        if start < text.len() {
+            ans.extend(self.inner.encode(&text[start..]));
+        }
+        ans
+    }
+
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        match self.pieces.get(&token) {
+            Some((piece, _)) => piece,
+            None if (token as usize) < self.inner.vocab_size() => self.inner.decode(token),
+            // 增加的词汇之间空缺的序号
+            None => "",
+        }
+    }
+
+    #[inline]
+    fn is_special(&self, token: utok) -> bool {
+        match self.pieces.get(&token) {
+            Some(&(_, special)) => special,
+            None => self.inner.is_special(token),
+        }
+    }
+}
+
+#[test]
+fn test_added_tokens() {
+    use crate::ByteLevel;
+
+    let tokenizer = AddedTokens::new(
+        ByteLevel::new(3),
+        [
+            ("<tool>".into(), 300, true),
+            ("<tool_call>".into(), 301, true),
+            ("你好".into(), 302, false),
+        ],
+    );
+    assert_eq!(tokenizer.vocab_size(), 302 + 1);
+    assert_eq!(
+        tokenizer.encode("a<tool_call>你好<tool>"),
+        [b'a' as utok + 3, 301, 302, 300]
+    );
+    assert_eq!(tokenizer.decode(301), "<tool_call>");
+    assert_eq!(tokenizer.decode(299), "");
+    assert!(tokenizer.is_special(300));
+    assert!(!tokenizer.is_special(302));
+}
@@ -1,4 +1,4 @@
-﻿use crate::{ByteDecoder, Tokenizer};
+﻿use crate::{ByteDecoder, ByteFallback, Tokenizer};
 use common::utok;
 use std::{io::Result, path::Path};
 
@@ -13,6 +13,8 @@ pub struct BPE {
     sorted_indices: Vec<utok>,
     max_piece_len: usize,
     byte_pieces: ByteDecoder,
+    /// 词表中没有的字符回退为字节词汇。
+    bytes: ByteFallback,
 }
 
 impl BPE {
@@ -42,13 +44,18 @@ impl BPE {
             std::str::from_utf8(&slice[1..][..len]).unwrap()
         });
         // 生成分词器
-        Ok(Self {
+        let mut bpe = Self {
             mmap,
             offsets,
             sorted_indices,
             max_piece_len: 0,
             byte_pieces: ByteDecoder::new(),
-        })
+            bytes: ByteFallback::offset(3),
+        };
+        if let Some(bytes) = ByteFallback::new(|piece| bpe.find_piece(piece)) {
+            bpe.bytes = bytes;
+        }
+        Ok(bpe)
     }
 
     /// 根据词汇查找代码。
@@ -94,13 +101,12 @@ impl Tokenizer for BPE {
         let text = text.replace(' ', "▁"); // FIXME: 从 tokenizer.json 读取 normalizer
         let mut tokens = Vec::new();
 
-        text.chars().map(|c| c.to_string()).for_each(|c| {
-            if let Some(index) = self.find_piece(&c) {
-                tokens.extend([index]);
-            } else {
-                tokens.extend(c.bytes().map(|c| c as utok + 3));
+        for c in text.chars() {
+            match self.find_piece(c.encode_utf8(&mut [0; 4])) {
+                Some(index) => tokens.push(index),
+                None => tokens.extend(self.bytes.encode(c)),
             }
-        });
+        }
 
         fn map_pair(bpe: &BPE, tokens: &[utok], i: usize) -> Option<(utok, f32)> {
             bpe.find_piece(&format!(
@@ -111,7 +117,7 @@ impl Tokenizer for BPE {
             .map(|tok| (tok, bpe.get_score(tok)))
         }
 
-        let mut merges = (0..tokens.len() - 1)
+        let mut merges = (0..tokens.len().saturating_sub(1))
             .map(|i| map_pair(self, &tokens, i))
             .collect::<Vec<_>>();
         while let Some((i, (tok, _))) = merges
@@ -126,7 +132,7 @@ impl Tokenizer for BPE {
             if let Some(i) = i.checked_sub(1) {
                 merges[i] = map_pair(self, &tokens, i);
             }
-            if i + 1 < merges.len() {
+            if i < merges.len() {
                 merges[i] = map_pair(self, &tokens, i);
             }
         }
@@ -0,0 +1,65 @@
+use crate::{ByteDecoder, Tokenizer};
+use common::utok;
+
+/// 字节级分词器，文本的每个字节对应一个 token，不需要词表文件。
+///
+/// 字节 `b` 对应的 token 是 `b + offset`，`offset` 之前的 token 留给特殊词汇。
+pub struct ByteLevel {
+    offset: utok,
+    byte_pieces: ByteDecoder,
+}
+
+impl ByteLevel {
+    #[inline]
+    pub fn new(offset: utok) -> Self {
+        Self {
+            offset,
+            byte_pieces: ByteDecoder::new(),
+        }
+    }
+}
+
+impl Tokenizer for ByteLevel {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.offset as usize + 256
+    }
+
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn encode(&self, text: &str) -> Vec<utok> {
+        text.bytes().map(|b| b as utok + self.offset).collect()
+    }
+
+    /// 特殊词汇解码为空字符串。
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        match token.checked_sub(self.offset) {
+            Some(b) if b < 256 => self.byte_pieces.byte(b as _),
+            _ => "",
+        }
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let tokenizer = ByteLevel::new(3);
+    let tokens = tokenizer.encode("Hi, 你好");
+    assert_eq!(
+        &tokens[..3],
+        &[b'H' as utok + 3, b'i' as utok + 3, b',' as utok + 3]
+    );
+    assert_eq!(tokens.len(), 10);
+
+    let bytes = tokens
+        .iter()
+        .flat_map(|&t| tokenizer.decode(t).as_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(String::from_utf8(bytes).unwrap(), "Hi, 你好");
+    assert_eq!(tokenizer.decode(1), "");
+}
@@ -0,0 +1,79 @@
+use crate::{Normalizer, Tokenizer};
+use common::utok;
+use std::{mem::take, str};
+
+/// 增量解码 token 序列。
+///
+/// 单个 token 可能只是 utf-8 字符的一部分（如回退字节），不完整的字符扣留到后续 token 补全后再输出，
+/// 非法的字节替换为 U+FFFD，输出的每段文本都是合法的 utf-8。
+#[derive(Clone, Default, Debug)]
+pub struct Detokenizer(Vec<u8>);
+
+impl Detokenizer {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 解码一个 token，返回新增的完整文本。
+    #[inline]
+    pub fn decode(
+        &mut self,
+        tokenizer: &(impl Tokenizer + ?Sized),
+        normalizer: &(impl Normalizer + ?Sized),
+        token: utok,
+    ) -> String {
+        self.push(normalizer.decode(tokenizer.decode(token)).as_bytes())
+    }
+
+    /// 追加解码得到的字节，返回其中完整的文本。
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.0.extend_from_slice(bytes);
+        let mut ans = String::new();
+        let mut rest = &self.0[..];
+        loop {
+            match str::from_utf8(rest) {
+                Ok(s) => {
+                    ans.push_str(s);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, tail) = rest.split_at(e.valid_up_to());
+                    ans.push_str(unsafe { str::from_utf8_unchecked(valid) });
+                    match e.error_len() {
+                        Some(len) => {
+                            ans.push(char::REPLACEMENT_CHARACTER);
+                            rest = &tail[len..];
+                        }
+                        None => {
+                            rest = tail;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.0 = rest.to_vec();
+        ans
+    }
+
+    /// 结束解码，输出扣留的字节，不完整的字符替换为 U+FFFD。
+    #[inline]
+    pub fn flush(&mut self) -> String {
+        String::from_utf8_lossy(&take(&mut self.0)).into_owned()
+    }
+}
+
+#[test]
+fn test_detokenizer() {
+    let mut detokenizer = Detokenizer::new();
+    let bytes = "你好".as_bytes();
+    assert_eq!(detokenizer.push(&bytes[..1]), "");
+    assert_eq!(detokenizer.push(&bytes[1..4]), "你");
+    assert_eq!(detokenizer.push(&bytes[4..]), "好");
+    // 非法字节替换为 U+FFFD，末尾不完整的字符扣留
+    assert_eq!(detokenizer.push(&[b'a', 0xff, b'b', 0xe4]), "a\u{FFFD}b");
+    assert_eq!(detokenizer.flush(), "\u{FFFD}");
+    assert_eq!(detokenizer.flush(), "");
+}
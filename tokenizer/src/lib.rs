@@ -1,5 +1,11 @@
+mod added_tokens;
 mod bpe;
+mod byte_level;
+mod detokenizer;
+mod merge;
 mod normalizer;
+mod sentencepiece;
+mod tokenizer_json;
 mod vocab_txt;
 
 use common::utok;
@@ -9,10 +15,43 @@ pub trait Tokenizer {
     fn max_piece_len(&self) -> usize;
     fn encode(&self, text: &str) -> Vec<utok>;
     fn decode(&self, token: utok) -> &str;
+    /// `token` 是否特殊词汇（如 BOS、EOS、`<|im_end|>`），解码时可以跳过。
+    #[inline]
+    fn is_special(&self, _token: utok) -> bool {
+        false
+    }
+}
+
+impl<T: Tokenizer + ?Sized> Tokenizer for Box<T> {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        (**self).vocab_size()
+    }
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        (**self).max_piece_len()
+    }
+    #[inline]
+    fn encode(&self, text: &str) -> Vec<utok> {
+        (**self).encode(text)
+    }
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        (**self).decode(token)
+    }
+    #[inline]
+    fn is_special(&self, token: utok) -> bool {
+        (**self).is_special(token)
+    }
 }
 
+pub use added_tokens::AddedTokens;
 pub use bpe::BPE;
+pub use byte_level::ByteLevel;
+pub use detokenizer::Detokenizer;
 pub use normalizer::{BPECommonNormalizer, Normalizer};
+pub use sentencepiece::SentencePiece;
+pub use tokenizer_json::TokenizerJson;
 pub use vocab_txt::VocabTxt;
 
 struct ByteDecoder([u8; 256]);
@@ -28,11 +67,91 @@ impl ByteDecoder {
 
     fn decode<'a>(&'a self, piece: &'a str) -> &'a str {
         if let Some(byte) = piece.strip_prefix("<0x").and_then(|s| s.strip_suffix('>')) {
-            let byte = u8::from_str_radix(byte, 16).unwrap();
-            let byte = std::slice::from_ref(&self.0[byte as usize]);
-            unsafe { std::str::from_utf8_unchecked(byte) }
+            self.byte(u8::from_str_radix(byte, 16).unwrap())
         } else {
             piece
         }
     }
+
+    /// 单个字节的片段，可能不是完整的 utf-8 字符，由解码端拼接。
+    fn byte(&self, byte: u8) -> &str {
+        let byte = std::slice::from_ref(&self.0[byte as usize]);
+        unsafe { std::str::from_utf8_unchecked(byte) }
+    }
+}
+
+/// 字节回退：词表中没有的字符按 utf-8 字节编码为 `<0xXX>` 词汇。
+struct ByteFallback(Box<[utok; 256]>);
+
+impl ByteFallback {
+    /// 在词表中查找全部 256 个字节词汇，缺少任何一个时返回空。
+    fn new(mut find: impl FnMut(&str) -> Option<utok>) -> Option<Self> {
+        let mut table = Box::new([0; 256]);
+        for (b, id) in table.iter_mut().enumerate() {
+            *id = find(&format!("<0x{b:02X}>"))?;
+        }
+        Some(Self(table))
+    }
+
+    /// 按 sentencepiece 的约定，字节 `b` 对应的 token 是 `b + offset`。
+    fn offset(offset: utok) -> Self {
+        let mut table = Box::new([0; 256]);
+        for (b, id) in table.iter_mut().enumerate() {
+            *id = b as utok + offset;
+        }
+        Self(table)
+    }
+
+    /// 把字符编码为字节词汇。
+    fn encode(&self, c: char) -> impl Iterator<Item = utok> + '_ {
+        let mut buf = [0; 4];
+        let len = c.encode_utf8(&mut buf).len();
+        (0..len).map(move |i| self.0[buf[i] as usize])
+    }
+}
+
+/// sentencepiece 用于替换空格的字符。
+const METASPACE: char = '▁';
+
+/// 解析 `<0xXX>` 形式的字节 token。
+fn byte_piece(piece: &str) -> Option<u8> {
+    let hex = piece.strip_prefix("<0x")?.strip_suffix('>')?;
+    if hex.len() == 2 {
+        u8::from_str_radix(hex, 16).ok()
+    } else {
+        None
+    }
+}
+
+/// 在每段 `▁` 之前切分单词，连续的 `▁` 保留在同一个单词中。
+fn split_metaspace(text: &str) -> Vec<&str> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut prev = METASPACE;
+    for (i, c) in text.char_indices() {
+        if c == METASPACE && prev != METASPACE && i > start {
+            words.push(&text[start..i]);
+            start = i;
+        }
+        prev = c;
+    }
+    if start < text.len() {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+#[test]
+fn test_byte_fallback() {
+    let find = |piece: &str| byte_piece(piece).map(|b| b as utok + 10);
+    let bytes = ByteFallback::new(find).unwrap();
+    assert_eq!(
+        bytes.encode('é').collect::<Vec<_>>(),
+        [0xc3 + 10, 0xa9 + 10]
+    );
+    assert_eq!(
+        ByteFallback::offset(3).encode('\u{ff}').collect::<Vec<_>>(),
+        [0xc3 + 3, 0xbf + 3]
+    );
+    assert!(ByteFallback::new(|piece| (piece != "<0xFF>").then_some(0)).is_none());
 }
@@ -0,0 +1,59 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+/// 反复合并优先级最高（`pair` 返回的 `P` 最小）的一对相邻符号，优先级相同时先合并左边的一对，直到不能合并。
+///
+/// 符号连成双向链表，候选的合并放在堆中，每次合并后只重新计算两侧的候选，复杂度为 O(n log n)。
+pub(crate) fn merge<T, P: Ord>(symbols: Vec<T>, pair: impl Fn(&T, &T) -> Option<(P, T)>) -> Vec<T> {
+    let n = symbols.len();
+    let mut symbols = symbols.into_iter().map(Some).collect::<Vec<_>>();
+    let mut prev = (0..n).map(|i| i.checked_sub(1)).collect::<Vec<_>>();
+    let mut next = (1..=n)
+        .map(|i| Some(i).filter(|&i| i < n))
+        .collect::<Vec<_>>();
+
+    // 候选的合并：(优先级, 左符号, 右符号)，符号变化后候选可能过期，弹出时重新计算
+    let mut heap = BinaryHeap::new();
+    let candidate = |symbols: &[Option<T>], l: usize, r: usize| {
+        pair(symbols[l].as_ref()?, symbols[r].as_ref()?)
+    };
+    for i in 1..n {
+        if let Some((p, _)) = candidate(&symbols, i - 1, i) {
+            heap.push(Reverse((p, i - 1, i)));
+        }
+    }
+    while let Some(Reverse((p, l, r))) = heap.pop() {
+        if next[l] != Some(r) {
+            continue;
+        }
+        let Some((_, merged)) = candidate(&symbols, l, r).filter(|(p_, _)| *p_ == p) else {
+            continue;
+        };
+        symbols[l] = Some(merged);
+        symbols[r] = None;
+        next[l] = next[r];
+        if let Some(nr) = next[r] {
+            prev[nr] = Some(l);
+        }
+        if let Some(pl) = prev[l] {
+            if let Some((p, _)) = candidate(&symbols, pl, l) {
+                heap.push(Reverse((p, pl, l)));
+            }
+        }
+        if let Some(nr) = next[l] {
+            if let Some((p, _)) = candidate(&symbols, l, nr) {
+                heap.push(Reverse((p, l, nr)));
+            }
+        }
+    }
+    symbols.into_iter().flatten().collect()
+}
+
+#[test]
+fn test_merge() {
+    // 相邻且和不超过 3 的数合并，和越小越先合并
+    let pair = |a: &u32, b: &u32| Some((a + b, a + b)).filter(|&(s, _)| s <= 3);
+    assert_eq!(merge(vec![1, 1, 1, 1], pair), [2, 2]);
+    assert_eq!(merge(vec![2, 1, 1], pair), [2, 2]);
+    assert_eq!(merge(vec![1, 2, 1, 1], pair), [3, 2]);
+    assert!(merge(Vec::<u32>::new(), pair).is_empty());
+}
@@ -0,0 +1,504 @@
+use crate::{byte_piece, merge::merge, split_metaspace, ByteFallback, Tokenizer, METASPACE};
+use common::utok;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::HashMap,
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+    str,
+};
+
+/// 由 sentencepiece 的 tokenizer.model 文件定义的分词器，支持 bpe 和 unigram 两种模型。
+///
+/// 文件是 protobuf 编码的 `ModelProto`，这里只解析分词需要的字段：
+///
+/// - `pieces`：词汇、评分和类型；
+/// - `trainer_spec.model_type`：分词算法；
+/// - `normalizer_spec`：是否在开头补空格、合并多余空格、把空格替换为 `▁`。
+///
+/// 规范化和解码都在分词器内部完成，不需要额外的规范化器。
+pub struct SentencePiece {
+    /// 每个 token 解码得到的字节。
+    pieces: Vec<Box<[u8]>>,
+    /// 普通词汇到序号的映射。
+    vocab: HashMap<String, utok>,
+    scores: Vec<f32>,
+    /// 控制词汇和用户定义词汇，整体匹配，按长度从长到短排列，标记是否控制词汇。
+    special: Vec<(String, utok, bool)>,
+    /// 回退字节对应的 token。
+    bytes: Option<ByteFallback>,
+    unk: Option<utok>,
+    /// unigram 模型中未知字符的评分。
+    unk_score: f32,
+    model: Model,
+    normalizer: NormalizerSpec,
+    /// 普通词汇的最大字符数。
+    max_piece_chars: usize,
+    max_piece_len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Model {
+    Unigram,
+    Bpe,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct NormalizerSpec {
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+    escape_whitespaces: bool,
+}
+
+impl Default for NormalizerSpec {
+    fn default() -> Self {
+        Self {
+            add_dummy_prefix: true,
+            remove_extra_whitespaces: true,
+            escape_whitespaces: true,
+        }
+    }
+}
+
+/// sentencepiece 中词汇的类型。
+mod piece_type {
+    pub const NORMAL: u64 = 1;
+    pub const UNKNOWN: u64 = 2;
+    pub const CONTROL: u64 = 3;
+    pub const USER_DEFINED: u64 = 4;
+    pub const BYTE: u64 = 6;
+}
+
+impl SentencePiece {
+    /// 打开 tokenizer.model 文件并构造分词器。
+    pub fn from_model_file(model_file: impl AsRef<Path>) -> Result<Self> {
+        Self::from_model(&std::fs::read(model_file)?)
+    }
+
+    /// 从 protobuf 编码的模型构造分词器。
+    pub fn from_model(proto: &[u8]) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut scores = Vec::new();
+        let mut vocab = HashMap::new();
+        let mut special = Vec::new();
+        let mut bytes = HashMap::new();
+        let mut unk = None;
+        let mut model = Model::Unigram;
+        let mut normalizer = NormalizerSpec::default();
+
+        let mut reader = Reader(proto);
+        while let Some((field, value)) = reader.next()? {
+            match (field, value) {
+                (1, Wire::Bytes(piece)) => {
+                    let id = pieces.len() as utok;
+                    let (piece, score, ty) = parse_piece(piece)?;
+                    pieces.push(match ty {
+                        piece_type::BYTE => {
+                            let b =
+                                byte_piece(piece).ok_or_else(|| invalid("invalid byte piece"))?;
+                            bytes.insert(piece.to_string(), id);
+                            Box::new([b]) as Box<[u8]>
+                        }
+                        piece_type::CONTROL | piece_type::USER_DEFINED => piece.as_bytes().into(),
+                        _ => piece.replace(METASPACE, " ").into_bytes().into(),
+                    });
+                    scores.push(score);
+                    match ty {
+                        piece_type::NORMAL => {
+                            vocab.insert(piece.to_string(), id);
+                        }
+                        piece_type::UNKNOWN => unk = Some(id),
+                        piece_type::CONTROL | piece_type::USER_DEFINED if !piece.is_empty() => {
+                            special.push((piece.to_string(), id, ty == piece_type::CONTROL))
+                        }
+                        _ => {}
+                    }
+                }
+                (2, Wire::Bytes(trainer_spec)) => {
+                    let mut reader = Reader(trainer_spec);
+                    while let Some((field, value)) = reader.next()? {
+                        if let (3, Wire::Varint(ty)) = (field, value) {
+                            model = match ty {
+                                1 => Model::Unigram,
+                                2 => Model::Bpe,
+                                _ => {
+                                    return Err(invalid("only bpe and unigram model are supported"))
+                                }
+                            };
+                        }
+                    }
+                }
+                (3, Wire::Bytes(normalizer_spec)) => {
+                    let mut reader = Reader(normalizer_spec);
+                    while let Some((field, value)) = reader.next()? {
+                        match (field, value) {
+                            (3, Wire::Varint(v)) => normalizer.add_dummy_prefix = v != 0,
+                            (4, Wire::Varint(v)) => normalizer.remove_extra_whitespaces = v != 0,
+                            (5, Wire::Varint(v)) => normalizer.escape_whitespaces = v != 0,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if pieces.is_empty() {
+            return Err(invalid("no piece found"));
+        }
+
+        let bytes = ByteFallback::new(|piece| bytes.get(piece).copied());
+        special.sort_by_key(|(piece, _, _)| std::cmp::Reverse(piece.len()));
+        let min_score = vocab
+            .values()
+            .map(|&id| scores[id as usize])
+            .fold(0., f32::min);
+        let max_piece_chars = vocab.keys().map(|p| p.chars().count()).max().unwrap_or(1);
+        let max_piece_len = pieces.iter().map(|p| p.len()).max().unwrap_or(0);
+        Ok(Self {
+            pieces,
+            vocab,
+            scores,
+            special,
+            bytes,
+            unk,
+            unk_score: min_score - 10.,
+            model,
+            normalizer,
+            max_piece_chars,
+            max_piece_len,
+        })
+    }
+
+    /// 按 `normalizer_spec` 规范化一段文本，`prefix` 为真时在开头补空格。
+    fn normalize(&self, text: &str, prefix: bool) -> String {
+        let NormalizerSpec {
+            add_dummy_prefix,
+            remove_extra_whitespaces,
+            escape_whitespaces,
+        } = self.normalizer;
+        let mut text = if remove_extra_whitespaces {
+            text.split(' ')
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            text.to_string()
+        };
+        if prefix && add_dummy_prefix && !text.is_empty() {
+            text.insert(0, ' ');
+        }
+        if escape_whitespaces {
+            text = text.replace(' ', "▁");
+        }
+        text
+    }
+
+    /// 把不在词表中的字符回退为字节，没有回退字节时使用未知 token。
+    fn push_unknown(&self, c: char, tokens: &mut Vec<utok>) {
+        match &self.bytes {
+            Some(bytes) => tokens.extend(bytes.encode(c)),
+            None => tokens.extend(self.unk),
+        }
+    }
+
+    /// bpe 模型：每次合并评分最高的一对，直到不能合并。
+    fn encode_bpe(&self, word: &str, tokens: &mut Vec<utok>) {
+        // 符号在单词中的字节范围，不在词表中的字符没有序号
+        let symbols = word
+            .char_indices()
+            .map(|(i, c)| {
+                let range = i..i + c.len_utf8();
+                let id = self.vocab.get(&word[range.clone()]).copied();
+                (range, id)
+            })
+            .collect::<Vec<_>>();
+        // 评分相同时先合并左边的一对，与 sentencepiece 一致
+        let symbols = merge(symbols, |(a, _), (b, _)| {
+            let range = a.start..b.end;
+            let &id = self.vocab.get(&word[range.clone()])?;
+            Some((Reverse(Score(self.scores[id as usize])), (range, Some(id))))
+        });
+        for (range, id) in symbols {
+            match id {
+                Some(id) => tokens.push(id),
+                None => self.push_unknown(word[range].chars().next().unwrap(), tokens),
+            }
+        }
+    }
+
+    /// unigram 模型：选择评分之和最高的切分方式。
+    fn encode_unigram(&self, word: &str, tokens: &mut Vec<utok>) {
+        let bounds = word
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([word.len()])
+            .collect::<Vec<_>>();
+        let n = bounds.len() - 1;
+        // 到每个位置的最高评分、上一个位置和最后一个 token，未知字符没有 token
+        let mut best = vec![(f32::NEG_INFINITY, 0, None); n + 1];
+        best[0].0 = 0.;
+        for i in 0..n {
+            let (score, _, _) = best[i];
+            let mut single = false;
+            for j in i + 1..=n.min(i + self.max_piece_chars) {
+                if let Some(&id) = self.vocab.get(&word[bounds[i]..bounds[j]]) {
+                    single |= j == i + 1;
+                    let score = score + self.scores[id as usize];
+                    if score > best[j].0 {
+                        best[j] = (score, i, Some(id));
+                    }
+                }
+            }
+            if !single && score + self.unk_score > best[i + 1].0 {
+                best[i + 1] = (score + self.unk_score, i, None);
+            }
+        }
+        let mut path = Vec::new();
+        let mut j = n;
+        while j > 0 {
+            let (_, i, id) = best[j];
+            path.push((i, id));
+            j = i;
+        }
+        for (i, id) in path.into_iter().rev() {
+            match id {
+                Some(id) => tokens.push(id),
+                None => self.push_unknown(word[bounds[i]..].chars().next().unwrap(), tokens),
+            }
+        }
+    }
+}
+
+impl Tokenizer for SentencePiece {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        self.max_piece_len
+    }
+
+    /// 文本开头和每个控制词汇之后的文本视为新的序列，按 `add_dummy_prefix` 补空格。
+    fn encode(&self, text: &str) -> Vec<utok> {
+        let mut tokens = Vec::new();
+        let mut text = text;
+        let mut prefix = true;
+        while !text.is_empty() {
+            // 找到最早出现的整体匹配词汇，之前的部分正常分词
+            let next = self
+                .special
+                .iter()
+                .filter_map(|(piece, id, control)| {
+                    text.find(&**piece).map(|i| (i, piece.len(), *id, *control))
+                })
+                .min_by_key(|&(i, _, _, _)| i);
+            let (plain, special) = match next {
+                Some((i, len, id, control)) => (&text[..i], Some((id, i + len, control))),
+                None => (text, None),
+            };
+            let plain = self.normalize(plain, prefix);
+            for word in split_metaspace(&plain) {
+                match self.model {
+                    Model::Bpe => self.encode_bpe(word, &mut tokens),
+                    Model::Unigram => self.encode_unigram(word, &mut tokens),
+                }
+            }
+            match special {
+                Some((id, end, control)) => {
+                    tokens.push(id);
+                    prefix = control;
+                    text = &text[end..];
+                }
+                None => break,
+            }
+        }
+        tokens
+    }
+
+    /// 单个 token 可能不是完整的 utf-8 字符，由解码端拼接。
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.pieces[token as usize]) }
+    }
+
+    /// 控制词汇是特殊词汇，用户定义的词汇是普通文本。
+    #[inline]
+    fn is_special(&self, token: utok) -> bool {
+        self.special
+            .iter()
+            .any(|&(_, id, control)| control && id == token)
+    }
+}
+
+/// 按 [`f32::total_cmp`] 排序的评分。
+struct Score(f32);
+
+impl PartialEq for Score {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[inline]
+fn invalid(msg: &str) -> Error {
+    Error::new(InvalidData, msg)
+}
+
+/// 解析 `SentencePiece` 消息，返回词汇、评分和类型。
+fn parse_piece(proto: &[u8]) -> Result<(&str, f32, u64)> {
+    let mut piece = "";
+    let mut score = 0.;
+    let mut ty = piece_type::NORMAL;
+    let mut reader = Reader(proto);
+    while let Some((field, value)) = reader.next()? {
+        match (field, value) {
+            (1, Wire::Bytes(s)) => {
+                piece = str::from_utf8(s).map_err(|e| Error::new(InvalidData, e))?;
+            }
+            (2, Wire::Fixed32(bits)) => score = f32::from_bits(bits),
+            (3, Wire::Varint(v)) => ty = v,
+            _ => {}
+        }
+    }
+    Ok((piece, score, ty))
+}
+
+/// protobuf 字段的值。
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Wire<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// 逐个读取 protobuf 消息中的字段。
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut ans = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .0
+                .split_first()
+                .ok_or_else(|| invalid("unexpected eof"))?;
+            self.0 = rest;
+            ans |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(ans);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("unexpected eof"));
+        }
+        let (ans, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(ans)
+    }
+
+    /// 读取下一个字段的编号和值，消息结束时返回空。
+    fn next(&mut self) -> Result<Option<(u64, Wire<'a>)>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 7 {
+            0 => Wire::Varint(self.varint()?),
+            1 => Wire::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.varint()? as usize;
+                Wire::Bytes(self.take(len)?)
+            }
+            5 => Wire::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(invalid("unsupported wire type")),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// 构造只包含词表和模型类型的 tokenizer.model。
+#[cfg(test)]
+fn model_proto(pieces: &[(&str, f32, u64)], model_type: u64) -> Vec<u8> {
+    fn varint(buf: &mut Vec<u8>, mut n: u64) {
+        while n >= 0x80 {
+            buf.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        buf.push(n as u8);
+    }
+    fn bytes(buf: &mut Vec<u8>, field: u64, payload: &[u8]) {
+        varint(buf, field << 3 | 2);
+        varint(buf, payload.len() as _);
+        buf.extend_from_slice(payload);
+    }
+
+    let mut ans = Vec::new();
+    for &(piece, score, ty) in pieces {
+        let mut msg = Vec::new();
+        bytes(&mut msg, 1, piece.as_bytes());
+        msg.push(2 << 3 | 5);
+        msg.extend_from_slice(&score.to_le_bytes());
+        msg.extend([3 << 3, ty as u8]);
+        bytes(&mut ans, 1, &msg);
+    }
+    bytes(&mut ans, 2, &[3 << 3, model_type as u8]);
+    ans
+}
+
+#[test]
+fn test_sentencepiece() {
+    let bytes = (0..=255u8)
+        .map(|b| format!("<0x{b:02X}>"))
+        .collect::<Vec<_>>();
+    let mut pieces = vec![("<unk>", 0., 2), ("<s>", 0., 3), ("</s>", 0., 3)];
+    pieces.extend(bytes.iter().map(|b| (b.as_str(), 0., 6)));
+    pieces.extend([
+        ("▁", -2., 1),
+        ("a", -1., 1),
+        ("b", -1., 1),
+        ("▁a", -1.5, 1),
+        ("ab", -1.2, 1),
+        ("▁ab", -1., 1),
+    ]);
+    // unigram 和 bpe 在这个词表上切分结果相同
+    for model_type in [1, 2] {
+        let sp = SentencePiece::from_model(&model_proto(&pieces, model_type)).unwrap();
+        assert_eq!(sp.vocab_size(), 265);
+        // 开头和控制词汇之后补 ▁
+        assert_eq!(sp.encode("ab a"), [264, 262]);
+        assert_eq!(sp.encode("<s>ab"), [1, 264]);
+        // 词表中没有的字符回退为字节
+        assert_eq!(sp.encode("é"), [259, 3 + 0xc3, 3 + 0xa9]);
+        let text = [1, 264, 262, 3 + 0xc3, 3 + 0xa9]
+            .iter()
+            .flat_map(|&t| sp.decode(t).as_bytes())
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(String::from_utf8(text).unwrap(), "<s> ab aé");
+        assert!(sp.is_special(1) && !sp.is_special(264));
+    }
+}
@@ -0,0 +1,478 @@
+use crate::{byte_piece, merge::merge, split_metaspace, ByteFallback, Tokenizer, METASPACE};
+use common::utok;
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{Error, ErrorKind::InvalidData, Result},
+    path::Path,
+    str,
+};
+
+/// 由 HuggingFace 的 tokenizer.json 文件定义的 bpe 分词器。
+///
+/// 支持两类常见的配置：
+///
+/// - 字节级（GPT-2、Qwen、Llama 3 等）：按空白、字母、数字和符号切分单词，单词的字节映射为可见字符后合词；
+/// - 空格替换为 `▁`（Llama、Mistral 等由 sentencepiece 转换而来的分词器），词表中没有的字符回退为 `<0xXX>` 字节 token。
+///
+/// 规范化和解码都在分词器内部完成，不需要额外的规范化器。
+pub struct TokenizerJson {
+    /// 每个 token 解码得到的字节。
+    pieces: Vec<Box<[u8]>>,
+    /// 合词规则，从一对 token 映射到优先级和合成的 token，优先级数值越小越先合并。
+    merges: HashMap<(utok, utok), (usize, utok)>,
+    /// 单个字符对应的 token。
+    chars: HashMap<char, utok>,
+    /// 不参与合词、整体匹配的词汇，按长度从长到短排列。
+    added: Vec<(String, utok)>,
+    /// 整体匹配的词汇中的特殊词汇。
+    special: HashSet<utok>,
+    /// 回退字节对应的 token。
+    bytes: Option<ByteFallback>,
+    unk: Option<utok>,
+    mode: Mode,
+    max_piece_len: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mode {
+    /// 字节级分词，`prefix_space` 为真时在文本前补一个空格。
+    ByteLevel { prefix_space: bool },
+    /// 空格替换为 `▁`，`prefix_space` 为真时在文本前补一个 `▁`。
+    Metaspace { prefix_space: bool },
+}
+
+impl TokenizerJson {
+    /// 打开 tokenizer.json 文件并构造分词器。
+    pub fn from_json_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    /// 从 tokenizer.json 的内容构造分词器。
+    pub fn from_json(json: &str) -> Result<Self> {
+        let json: Value = serde_json::from_str(json).map_err(|e| Error::new(InvalidData, e))?;
+        let model = &json["model"];
+        if model["type"].as_str().is_some_and(|t| t != "BPE") {
+            return Err(invalid("only BPE model is supported"));
+        }
+        let vocab = model["vocab"]
+            .as_object()
+            .ok_or_else(|| invalid("vocab not found"))?
+            .iter()
+            .map(|(piece, id)| Some((piece.as_str(), id.as_u64()? as utok)))
+            .collect::<Option<HashMap<_, _>>>()
+            .ok_or_else(|| invalid("token id must be an integer"))?;
+        let added = json["added_tokens"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .map(|t| {
+                Some((
+                    t["content"].as_str()?.to_string(),
+                    t["id"].as_u64()? as utok,
+                    t["special"].as_bool().unwrap_or(false),
+                ))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("invalid added token"))?;
+        let special = added
+            .iter()
+            .filter(|(_, _, special)| *special)
+            .map(|&(_, id, _)| id)
+            .collect();
+        let added = added
+            .into_iter()
+            .map(|(content, id, _)| (content, id))
+            .collect::<Vec<_>>();
+        let mode = mode(&json);
+
+        // 生成每个 token 解码的字节
+        let size = vocab
+            .values()
+            .chain(added.iter().map(|(_, id)| id))
+            .max()
+            .map_or(0, |&id| id as usize + 1);
+        let mut pieces = vec![Box::<[u8]>::default(); size];
+        let decoder = ByteLevelDecoder::new();
+        for (&piece, &id) in &vocab {
+            pieces[id as usize] = match mode {
+                Mode::ByteLevel { .. } => decoder.decode(piece),
+                Mode::Metaspace { .. } => match byte_piece(piece) {
+                    Some(b) => Box::new([b]),
+                    None => piece.replace(METASPACE, " ").into_bytes().into(),
+                },
+            };
+        }
+        for (content, id) in &added {
+            pieces[*id as usize] = content.as_bytes().into();
+        }
+
+        // 解析合词规则
+        let mut merges = HashMap::new();
+        for (rank, merge) in model["merges"]
+            .as_array()
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .enumerate()
+        {
+            let (a, b) = match merge {
+                Value::String(s) => s.split_once(' ').ok_or_else(|| invalid("invalid merge"))?,
+                Value::Array(pair) => match &pair[..] {
+                    [Value::String(a), Value::String(b)] => (a.as_str(), b.as_str()),
+                    _ => return Err(invalid("invalid merge")),
+                },
+                _ => return Err(invalid("invalid merge")),
+            };
+            if let (Some(&a_), Some(&b_), Some(&ab)) =
+                (vocab.get(a), vocab.get(b), vocab.get(&*format!("{a}{b}")))
+            {
+                merges.entry((a_, b_)).or_insert((rank, ab));
+            }
+        }
+
+        let chars = vocab
+            .iter()
+            .filter_map(|(piece, &id)| {
+                let mut chars = piece.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some((c, id)),
+                    _ => None,
+                }
+            })
+            .collect();
+        let bytes = if model["byte_fallback"].as_bool() == Some(true) {
+            let bytes = ByteFallback::new(|piece| vocab.get(piece).copied())
+                .ok_or_else(|| invalid("byte fallback token not found"))?;
+            Some(bytes)
+        } else {
+            None
+        };
+        let unk = model["unk_token"]
+            .as_str()
+            .and_then(|s| vocab.get(s))
+            .copied();
+        let mut added = added;
+        added.sort_by_key(|(content, _)| std::cmp::Reverse(content.len()));
+        let max_piece_len = pieces.iter().map(|p| p.len()).max().unwrap_or(0);
+        Ok(Self {
+            pieces,
+            merges,
+            chars,
+            added,
+            special,
+            bytes,
+            unk,
+            mode,
+            max_piece_len,
+        })
+    }
+
+    /// 对一个单词合词，单词中的字符已经映射为词表中的形式。
+    fn encode_word(&self, word: &str, tokens: &mut Vec<utok>) {
+        let mut symbols = Vec::with_capacity(word.len());
+        for c in word.chars() {
+            if let Some(&id) = self.chars.get(&c) {
+                symbols.push(id);
+            } else if let Some(bytes) = &self.bytes {
+                symbols.extend(bytes.encode(c));
+            } else {
+                symbols.extend(self.unk);
+            }
+        }
+        // 每次合并优先级最高的一对，直到不能合并
+        tokens.extend(merge(symbols, |&a, &b| self.merges.get(&(a, b)).copied()));
+    }
+
+    /// 对不含整体匹配词汇的文本分词。
+    fn encode_text(&self, text: &str, tokens: &mut Vec<utok>) {
+        match self.mode {
+            Mode::ByteLevel { .. } => {
+                let encoder = ByteLevelEncoder::new();
+                for word in split_words(text) {
+                    self.encode_word(&encoder.encode(word), tokens);
+                }
+            }
+            Mode::Metaspace { .. } => {
+                let text = text.replace(' ', "▁");
+                for word in split_metaspace(&text) {
+                    self.encode_word(word, tokens);
+                }
+            }
+        }
+    }
+}
+
+impl Tokenizer for TokenizerJson {
+    #[inline]
+    fn vocab_size(&self) -> usize {
+        self.pieces.len()
+    }
+
+    #[inline]
+    fn max_piece_len(&self) -> usize {
+        self.max_piece_len
+    }
+
+    fn encode(&self, text: &str) -> Vec<utok> {
+        let mut tokens = Vec::new();
+        let mut text = text;
+        let mut first = true;
+        while !text.is_empty() {
+            // 找到最早出现的整体匹配词汇，之前的部分正常分词
+            let next = self
+                .added
+                .iter()
+                .filter_map(|(content, id)| text.find(&**content).map(|i| (i, content.len(), *id)))
+                .min_by_key(|&(i, _, _)| i);
+            let (plain, added) = match next {
+                Some((i, len, id)) => (&text[..i], Some((id, i + len))),
+                None => (text, None),
+            };
+            if !plain.is_empty() {
+                let prefix = match self.mode {
+                    Mode::ByteLevel { prefix_space } if first && prefix_space => " ",
+                    Mode::Metaspace { prefix_space } if first && prefix_space => "▁",
+                    _ => "",
+                };
+                if prefix.is_empty() || plain.starts_with(' ') {
+                    self.encode_text(plain, &mut tokens);
+                } else {
+                    self.encode_text(&format!("{prefix}{plain}"), &mut tokens);
+                }
+            }
+            first = false;
+            match added {
+                Some((id, end)) => {
+                    tokens.push(id);
+                    text = &text[end..];
+                }
+                None => break,
+            }
+        }
+        tokens
+    }
+
+    /// 单个 token 可能不是完整的 utf-8 字符，由解码端拼接。
+    #[inline]
+    fn decode(&self, token: utok) -> &str {
+        unsafe { str::from_utf8_unchecked(&self.pieces[token as usize]) }
+    }
+
+    #[inline]
+    fn is_special(&self, token: utok) -> bool {
+        self.special.contains(&token)
+    }
+}
+
+#[inline]
+fn invalid(msg: &str) -> Error {
+    Error::new(InvalidData, msg)
+}
+
+/// 从预分词器和规范化器的配置判断分词方式。
+fn mode(json: &Value) -> Mode {
+    /// 在嵌套的配置中查找指定类型的配置。
+    fn find<'a>(config: &'a Value, ty: &str) -> Option<&'a Value> {
+        if config["type"] == ty {
+            return Some(config);
+        }
+        ["pretokenizers", "normalizers"]
+            .iter()
+            .filter_map(|key| config[key].as_array())
+            .flatten()
+            .find_map(|c| find(c, ty))
+    }
+    let pre_tokenizer = &json["pre_tokenizer"];
+    let normalizer = &json["normalizer"];
+    if let Some(config) = find(pre_tokenizer, "ByteLevel") {
+        Mode::ByteLevel {
+            prefix_space: config["add_prefix_space"].as_bool() == Some(true),
+        }
+    } else if let Some(config) = find(pre_tokenizer, "Metaspace") {
+        let prefix_space = match config["prepend_scheme"].as_str() {
+            Some(scheme) => scheme != "never",
+            None => config["add_prefix_space"].as_bool() != Some(false),
+        };
+        Mode::Metaspace { prefix_space }
+    } else if find(normalizer, "Replace").is_some() {
+        Mode::Metaspace {
+            prefix_space: find(normalizer, "Prepend").is_some(),
+        }
+    } else {
+        Mode::ByteLevel {
+            prefix_space: false,
+        }
+    }
+}
+
+/// 按 GPT-2 的规则切分单词：缩写、可带一个前导空格的字母串、数字串、符号串，以及空白。
+///
+/// 连续的空白中最后一个空格留给后面的单词。
+fn split_words(text: &str) -> Vec<&str> {
+    const CONTRACTIONS: [&str; 7] = ["'s", "'t", "'re", "'ve", "'m", "'ll", "'d"];
+    fn class(c: char) -> u8 {
+        if c.is_whitespace() {
+            0
+        } else if c.is_alphabetic() {
+            1
+        } else if c.is_numeric() {
+            2
+        } else {
+            3
+        }
+    }
+    fn word_len(s: &str) -> usize {
+        if let Some(c) = CONTRACTIONS.iter().find(|c| s.starts_with(**c)) {
+            return c.len();
+        }
+        let mut chars = s.chars();
+        let first = chars.next().unwrap();
+        let (start, c) = match chars.next() {
+            Some(c) if first == ' ' && class(c) != 0 => (1, c),
+            _ => (0, first),
+        };
+        let k = class(c);
+        let end = s[start..]
+            .char_indices()
+            .find(|&(_, c)| class(c) != k)
+            .map_or(s.len(), |(i, _)| start + i);
+        if k != 0 || end == s.len() {
+            return end;
+        }
+        let last = s[..end].chars().next_back().unwrap().len_utf8();
+        if end > last {
+            end - last
+        } else {
+            end
+        }
+    }
+
+    let mut words = Vec::new();
+    let mut text = text;
+    while !text.is_empty() {
+        let len = word_len(text);
+        words.push(&text[..len]);
+        text = &text[len..];
+    }
+    words
+}
+
+/// GPT-2 把字节映射为可见字符：可见的 ascii 和 latin-1 字符映射为自身，其他字节依次映射到 256 之后。
+fn byte_to_char() -> [char; 256] {
+    let mut ans = ['\0'; 256];
+    let mut n = 0;
+    for (b, c) in ans.iter_mut().enumerate() {
+        let visible = matches!(b, 0x21..=0x7e | 0xa1..=0xac | 0xae..=0xff);
+        *c = if visible {
+            b as u8 as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        };
+    }
+    ans
+}
+
+struct ByteLevelEncoder([char; 256]);
+
+impl ByteLevelEncoder {
+    #[inline]
+    fn new() -> Self {
+        Self(byte_to_char())
+    }
+
+    #[inline]
+    fn encode(&self, text: &str) -> String {
+        text.bytes().map(|b| self.0[b as usize]).collect()
+    }
+}
+
+struct ByteLevelDecoder(HashMap<char, u8>);
+
+impl ByteLevelDecoder {
+    #[inline]
+    fn new() -> Self {
+        Self(
+            byte_to_char()
+                .into_iter()
+                .enumerate()
+                .map(|(b, c)| (c, b as u8))
+                .collect(),
+        )
+    }
+
+    /// 词表中不是字节映射的字符（如特殊词汇）保持原样。
+    fn decode(&self, piece: &str) -> Box<[u8]> {
+        let mut ans = Vec::with_capacity(piece.len());
+        for c in piece.chars() {
+            match self.0.get(&c) {
+                Some(&b) => ans.push(b),
+                None => ans.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        ans.into()
+    }
+}
+
+#[test]
+fn test_byte_level() {
+    let json = r#"{
+        "added_tokens": [{ "id": 9, "content": "<|end|>", "special": true }],
+        "pre_tokenizer": { "type": "ByteLevel", "add_prefix_space": false },
+        "model": {
+            "type": "BPE",
+            "vocab": { "h": 0, "i": 1, "Ġ": 2, "hi": 3, "Ġhi": 4, "!": 5, "Ċ": 6, "Ġh": 7 },
+            "merges": ["Ġ h", "h i", "Ġh i"]
+        }
+    }"#;
+    let tokenizer = TokenizerJson::from_json(json).unwrap();
+    assert_eq!(tokenizer.vocab_size(), 10);
+    assert_eq!(tokenizer.encode("hi hi!<|end|>\n"), [3, 4, 5, 9, 6]);
+    let text = [3, 4, 5, 9, 6]
+        .iter()
+        .map(|&t| tokenizer.decode(t))
+        .collect::<String>();
+    assert_eq!(text, "hi hi!<|end|>\n");
+    assert!(tokenizer.is_special(9) && !tokenizer.is_special(3));
+}
+
+#[test]
+fn test_metaspace() {
+    // 前 256 个 token 是回退字节
+    let bytes = (0..256)
+        .map(|b| format!(r#""<0x{b:02X}>": {b}"#))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let json = format!(
+        r#"{{
+        "normalizer": {{ "type": "Sequence", "normalizers": [
+            {{ "type": "Prepend", "prepend": "▁" }},
+            {{ "type": "Replace", "pattern": {{ "String": " " }}, "content": "▁" }}
+        ] }},
+        "model": {{
+            "type": "BPE",
+            "byte_fallback": true,
+            "vocab": {{ {bytes}, "▁": 256, "a": 257, "b": 258, "▁a": 259, "ab": 260, "▁ab": 261 }},
+            "merges": ["a b", "▁ a", "▁ ab"]
+        }}
+    }}"#
+    );
+    let tokenizer = TokenizerJson::from_json(&json).unwrap();
+    // 开头补一个 ▁，词表中没有的字符回退为字节
+    assert_eq!(tokenizer.encode("ab a"), [261, 259]);
+    assert_eq!(tokenizer.encode("ab é"), [261, 256, 0xc3, 0xa9]);
+    let text = [261, 256, 0xc3, 0xa9]
+        .iter()
+        .flat_map(|&t| tokenizer.decode(t).as_bytes())
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(String::from_utf8(text).unwrap(), " ab é");
+}
+
+#[test]
+fn test_split_words() {
+    assert_eq!(
+        split_words("Hello  world's 42!\n"),
+        ["Hello", " ", " world", "'s", " 42", "!", "\n"]
+    );
+}
@@ -1,4 +1,4 @@
-﻿use crate::{ByteDecoder, Tokenizer};
+﻿use crate::{ByteDecoder, ByteFallback, Tokenizer};
 use common::utok;
 use memmap2::Mmap;
 use patricia_tree::PatriciaMap;
@@ -14,6 +14,8 @@ pub struct VocabTxt {
     max_piece_len: usize,
     /// 单字节词汇转义。
     byte_pieces: ByteDecoder,
+    /// 词表中没有的字符回退为字节词汇。
+    bytes: ByteFallback,
 }
 
 impl VocabTxt {
@@ -31,11 +33,15 @@ impl VocabTxt {
             words.push(piece.to_string());
             trie.insert(piece, i as _);
         }
+        // 词表中没有字节词汇时，按字节 token 从 3 开始的约定回退
+        let bytes = ByteFallback::new(|piece| trie.get(piece).copied())
+            .unwrap_or_else(|| ByteFallback::offset(3));
         Ok(Self {
             words,
             trie,
             max_piece_len,
             byte_pieces: ByteDecoder::new(),
+            bytes,
         })
     }
 }
@@ -60,7 +66,7 @@ impl Tokenizer for VocabTxt {
             } else {
                 let mut chars = text.chars();
                 let char = chars.next().unwrap();
-                tokens.extend(char.to_string().bytes().map(|b| (b + 3) as utok));
+                tokens.extend(self.bytes.encode(char));
                 text = chars.as_str();
             }
         }
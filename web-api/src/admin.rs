@@ -0,0 +1,66 @@
+//! 管理接口，使用与推理接口分开的管理密钥。
+
+use crate::{
+    manager::{ModelLoader, ServiceManager},
+    response::{empty, error, json},
+    schemas::Error,
+    ApiKeys, RespFuture,
+};
+use causal_lm::CausalLM;
+use http_body_util::BodyExt;
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, WWW_AUTHENTICATE},
+    Method, Request, StatusCode,
+};
+use std::sync::Arc;
+
+/// 管理接口的设置。
+pub struct Admin<M: CausalLM> {
+    /// 管理密钥，请求通过 `Authorization: Bearer <key>` 携带，推理接口的密钥不能调用管理接口。
+    pub keys: ApiKeys,
+    /// 重新加载模型时从模型目录加载模型的所有副本。
+    pub loader: ModelLoader<M>,
+}
+
+impl<M> Admin<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    /// 处理 `/admin/` 下的请求。
+    pub(crate) fn route(
+        &self,
+        manager: Arc<ServiceManager<M>>,
+        req: Request<Incoming>,
+    ) -> RespFuture {
+        match self.keys.check(req.headers()) {
+            Some(caller) => info!("{} {} by {}", req.method(), req.uri().path(), caller.0),
+            None => {
+                warn!("Unauthorized request to {}", req.uri().path());
+                let mut res = error(Error::Unauthorized);
+                res.headers_mut()
+                    .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                return Box::pin(async move { Ok(res) });
+            }
+        }
+
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/admin/reload") => {
+                let loader = self.loader.clone();
+                Box::pin(async move {
+                    let whole_body = req.collect().await?.to_bytes();
+                    Ok(match serde_json::from_slice(&whole_body) {
+                        // 在单独的任务中加载，客户端断开连接不会中断加载
+                        Ok(req) => tokio::spawn(manager.reload(req, loader))
+                            .await
+                            .unwrap_or_else(|e| Err(Error::ReloadFailed(e.to_string())))
+                            .map_or_else(error, json),
+                        Err(e) => error(Error::WrongJson(e)),
+                    })
+                })
+            }
+            _ => Box::pin(async move { Ok(empty(StatusCode::NOT_FOUND)) }),
+        }
+    }
+}
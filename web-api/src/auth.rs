@@ -0,0 +1,112 @@
+//! API 密钥认证。
+
+use hyper::{header::AUTHORIZATION, HeaderMap};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, ErrorKind::InvalidData},
+    path::Path,
+    sync::Arc,
+};
+
+/// 服务接受的 API 密钥，请求通过 `Authorization: Bearer <key>` 携带密钥。
+///
+/// 每个密钥有一个名字，用于在日志中区分调用者。
+#[derive(Clone, Default)]
+pub struct ApiKeys(HashMap<String, Arc<str>>);
+
+/// 通过认证的调用者，即请求携带的密钥的名字，放在请求的扩展中。
+#[derive(Clone, Debug)]
+pub(crate) struct Caller(pub Arc<str>);
+
+impl ApiKeys {
+    /// 从 json 文件加载密钥，文件内容是名字到密钥的映射：
+    ///
+    /// ```json
+    /// { "alice": "sk-0123", "batch-jobs": "sk-4567" }
+    /// ```
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let keys: HashMap<String, String> =
+            serde_json::from_reader(file).map_err(|e| io::Error::new(InvalidData, e))?;
+        let mut ans = Self::default();
+        for (name, key) in keys {
+            ans.insert(name, key);
+        }
+        Ok(ans)
+    }
+
+    /// 从环境变量 `var` 加载密钥，变量不存在时返回 `None`。
+    ///
+    /// 变量的值是逗号分隔的密钥，每个密钥可以带有 `名字:` 前缀，没有名字的密钥按位置命名为 `env-<序号>`。
+    pub fn from_env(var: &str) -> Option<Self> {
+        let value = std::env::var(var).ok()?;
+        let mut ans = Self::default();
+        for (i, item) in value.split(',').map(str::trim).enumerate() {
+            match item.split_once(':') {
+                Some((name, key)) => ans.insert(name.trim().into(), key.trim().into()),
+                None => ans.insert(format!("env-{i}"), item.into()),
+            }
+        }
+        Some(ans)
+    }
+
+    /// 加入另一组密钥，相同的密钥使用 `other` 中的名字。
+    #[inline]
+    pub fn extend(&mut self, other: Self) {
+        self.0.extend(other.0)
+    }
+
+    /// 密钥的数量。
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[inline]
+    fn insert(&mut self, name: String, key: String) {
+        if !key.is_empty() {
+            self.0.insert(key, name.into());
+        }
+    }
+
+    /// 检查请求携带的密钥，返回通过认证的调用者。
+    pub(crate) fn check(&self, headers: &HeaderMap) -> Option<Caller> {
+        let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, key) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        self.0.get(key.trim()).cloned().map(Caller)
+    }
+}
+
+#[test]
+fn test_api_keys() {
+    use hyper::header::HeaderValue;
+
+    const VAR: &str = "INFINI_TEST_API_KEYS";
+    assert!(ApiKeys::from_env(VAR).is_none());
+    std::env::set_var(VAR, "alice: sk-1, sk-2,, batch-jobs:sk-3");
+    let keys = ApiKeys::from_env(VAR).unwrap();
+    std::env::remove_var(VAR);
+    // 空的密钥被忽略
+    assert_eq!(keys.len(), 3);
+
+    let check = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        keys.check(&headers).map(|Caller(name)| name.to_string())
+    };
+    assert_eq!(check("Bearer sk-1").as_deref(), Some("alice"));
+    assert_eq!(check("bearer sk-2").as_deref(), Some("env-1"));
+    assert_eq!(check("Bearer sk-3").as_deref(), Some("batch-jobs"));
+    assert_eq!(check("Bearer sk-4"), None);
+    assert_eq!(check("Basic sk-1"), None);
+    assert!(keys.check(&HeaderMap::new()).is_none());
+}
@@ -0,0 +1,82 @@
+//! 服务的配置，可以从展开分组后的配置文件直接反序列化。
+
+use crate::{
+    ApiKeys, Cors, Limits, Listen, RateLimits, SamplePresets, SessionPolicy, ShutdownPolicy,
+};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{path::PathBuf, time::Duration};
+
+/// 启动服务的配置。
+///
+/// 反序列化时各部分的字段平铺在一起，键与 `xtask service` 的参数名相同（如 `port`、`max_concurrent`），
+/// 时长以秒、计算缓存预算以 MiB 为单位，列表是逗号分隔的字符串，`sample_presets` 和 `api_keys` 是 json 文件的路径。
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ServiceConfig {
+    /// 服务监听的地址。
+    #[serde(flatten)]
+    pub listen: Listen,
+    /// 会话缓存的淘汰策略。
+    #[serde(flatten)]
+    pub session: SessionPolicy,
+    /// 服务的负载上限。
+    #[serde(flatten)]
+    pub limits: Limits,
+    /// 每个调用者的速率上限。
+    #[serde(flatten)]
+    pub rate_limits: RateLimits,
+    /// 非空时允许其中的来源跨域调用服务。
+    #[serde(flatten)]
+    pub cors: Option<Cors>,
+    /// 收到停止信号后停止服务的方式。
+    #[serde(flatten)]
+    pub shutdown: ShutdownPolicy,
+    /// 采样预设。
+    #[serde(rename = "sample_presets", deserialize_with = "presets")]
+    pub presets: SamplePresets,
+    /// 非空时所有请求都必须携带其中的一个密钥。
+    #[serde(deserialize_with = "api_keys")]
+    pub api_keys: Option<ApiKeys>,
+    /// 作为只读副本，只提供不使用会话的接口。
+    pub replica: bool,
+}
+
+/// 以秒为单位的时长。
+pub(crate) fn secs<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    u64::deserialize(d).map(Duration::from_secs)
+}
+
+/// 以秒为单位的可选时长。
+pub(crate) fn opt_secs<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    Option::<u64>::deserialize(d).map(|s| s.map(Duration::from_secs))
+}
+
+/// 以 MiB 为单位的可选字节数。
+pub(crate) fn opt_mib<'de, D: Deserializer<'de>>(d: D) -> Result<Option<usize>, D::Error> {
+    Option::<usize>::deserialize(d).map(|mib| mib.map(|mib| mib << 20))
+}
+
+/// 逗号分隔的列表，忽略空项。
+pub(crate) fn list<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<String>, D::Error> {
+    Ok(String::deserialize(d)?
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(Into::into)
+        .collect())
+}
+
+/// 从 json 文件加载的采样预设。
+fn presets<'de, D: Deserializer<'de>>(d: D) -> Result<SamplePresets, D::Error> {
+    let path = PathBuf::deserialize(d)?;
+    SamplePresets::load(&path)
+        .map_err(|e| D::Error::custom(format!("failed to load {}: {e}", path.display())))
+}
+
+/// 从 json 文件加载的密钥。
+fn api_keys<'de, D: Deserializer<'de>>(d: D) -> Result<Option<ApiKeys>, D::Error> {
+    let path = PathBuf::deserialize(d)?;
+    ApiKeys::load(&path)
+        .map(Some)
+        .map_err(|e| D::Error::custom(format!("failed to load {}: {e}", path.display())))
+}
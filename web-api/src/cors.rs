@@ -0,0 +1,199 @@
+//! 跨域资源共享，允许浏览器中的前端直接调用服务。
+
+use hyper::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    HeaderMap, Method,
+};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// 跨域请求的设置。
+#[derive(Clone, Debug, Deserialize)]
+pub struct Cors {
+    /// 允许的来源，如 `https://chat.example.com`，包含 `*` 时允许任意来源。
+    #[serde(rename = "cors_origins", deserialize_with = "crate::config::list")]
+    pub allowed_origins: Vec<String>,
+    /// 允许的方法。
+    #[serde(
+        rename = "cors_methods",
+        deserialize_with = "crate::config::list",
+        default = "default_methods"
+    )]
+    pub allowed_methods: Vec<String>,
+    /// 允许的请求头，包含 `*` 时允许预检请求声明的任意请求头。
+    #[serde(
+        rename = "cors_headers",
+        deserialize_with = "crate::config::list",
+        default = "default_headers"
+    )]
+    pub allowed_headers: Vec<String>,
+    /// 浏览器缓存预检结果的时长。
+    #[serde(
+        rename = "cors_max_age",
+        deserialize_with = "crate::config::opt_secs",
+        default
+    )]
+    pub max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            allowed_methods: default_methods(),
+            allowed_headers: default_headers(),
+            max_age: None,
+        }
+    }
+}
+
+fn default_methods() -> Vec<String> {
+    vec!["GET".into(), "POST".into()]
+}
+
+fn default_headers() -> Vec<String> {
+    vec!["authorization".into(), "content-type".into()]
+}
+
+impl Cors {
+    /// 判断请求是否是预检请求。
+    #[inline]
+    pub(crate) fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+        method == Method::OPTIONS
+            && headers.contains_key(ORIGIN)
+            && headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// 请求的来源被允许时返回响应中 `Access-Control-Allow-Origin` 的值。
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let s = origin.to_str().ok()?.trim_end_matches('/');
+        self.allowed_origins
+            .iter()
+            .any(|o| o.trim_end_matches('/').eq_ignore_ascii_case(s))
+            .then(|| origin.clone())
+    }
+
+    /// 为来自 `origin` 的跨域请求的响应添加响应头，来源不被允许时不添加，由浏览器拒绝。
+    pub(crate) fn apply(&self, origin: &HeaderValue, res: &mut HeaderMap) {
+        let Some(origin) = self.allow_origin(origin) else {
+            return;
+        };
+        if origin != "*" {
+            res.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        res.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        res.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static("Retry-After, X-Request-Id"),
+        );
+    }
+
+    /// 为预检请求的响应添加响应头。
+    pub(crate) fn preflight(&self, req: &HeaderMap, res: &mut HeaderMap) {
+        let Some(origin) = req.get(ORIGIN).and_then(|o| self.allow_origin(o)) else {
+            return;
+        };
+        if origin != "*" {
+            res.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        res.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if let Ok(methods) = HeaderValue::from_str(&self.allowed_methods.join(", ")) {
+            res.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let headers = if self.allowed_headers.iter().any(|h| h == "*") {
+            req.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else {
+            HeaderValue::from_str(&self.allowed_headers.join(", ")).ok()
+        };
+        if let Some(headers) = headers {
+            res.insert(ACCESS_CONTROL_ALLOW_HEADERS, headers);
+        }
+        if let Some(max_age) = self.max_age {
+            res.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+    }
+}
+
+#[test]
+fn test_allow_origin() {
+    let cors = Cors {
+        allowed_origins: vec!["https://Chat.Example.com/".into()],
+        ..Default::default()
+    };
+    let allow = |origin: &'static str| cors.allow_origin(&HeaderValue::from_static(origin));
+    // 忽略末尾的斜杠和大小写，返回请求的来源
+    assert_eq!(
+        allow("https://chat.example.com").unwrap(),
+        "https://chat.example.com"
+    );
+    assert!(allow("https://chat.example.com/").is_some());
+    assert!(allow("https://evil.example.com").is_none());
+
+    let cors = Cors {
+        allowed_origins: vec!["*".into()],
+        ..Default::default()
+    };
+    assert_eq!(
+        cors.allow_origin(&HeaderValue::from_static("https://any.example.com"))
+            .unwrap(),
+        "*"
+    );
+}
+
+#[test]
+fn test_preflight() {
+    let mut req = HeaderMap::new();
+    req.insert(ORIGIN, HeaderValue::from_static("https://chat.example.com"));
+    req.insert(
+        ACCESS_CONTROL_REQUEST_METHOD,
+        HeaderValue::from_static("POST"),
+    );
+    req.insert(
+        ACCESS_CONTROL_REQUEST_HEADERS,
+        HeaderValue::from_static("x-custom, content-type"),
+    );
+    assert!(Cors::is_preflight(&Method::OPTIONS, &req));
+
+    // 允许任意请求头时回显预检请求声明的请求头
+    let cors = Cors {
+        allowed_origins: vec!["https://chat.example.com".into()],
+        allowed_headers: vec!["*".into()],
+        max_age: Some(Duration::from_secs(600)),
+        ..Default::default()
+    };
+    let mut res = HeaderMap::new();
+    cors.preflight(&req, &mut res);
+    assert_eq!(res[ACCESS_CONTROL_ALLOW_ORIGIN], "https://chat.example.com");
+    assert_eq!(res[VARY], "Origin");
+    assert_eq!(res[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+    assert_eq!(res[ACCESS_CONTROL_ALLOW_HEADERS], "x-custom, content-type");
+    assert_eq!(res[ACCESS_CONTROL_MAX_AGE], "600");
+
+    // 否则返回配置的请求头
+    let cors = Cors {
+        allowed_origins: vec!["https://chat.example.com".into()],
+        ..Default::default()
+    };
+    let mut res = HeaderMap::new();
+    cors.preflight(&req, &mut res);
+    assert_eq!(
+        res[ACCESS_CONTROL_ALLOW_HEADERS],
+        "authorization, content-type"
+    );
+
+    // 不允许的来源不添加响应头
+    let cors = Cors {
+        allowed_origins: vec!["https://other.example.com".into()],
+        ..Default::default()
+    };
+    let mut res = HeaderMap::new();
+    cors.preflight(&req, &mut res);
+    assert!(res.is_empty());
+}
@@ -0,0 +1,220 @@
+//! gRPC 推理服务，协议定义在 `proto/infer.proto` 中。
+
+use crate::{
+    manager::{Output, ServiceManager},
+    ratelimit::{retry_after, Client, Quota},
+    schemas::{finish_reason, Drop, Error, Fork, Infer, Priority, Sentence},
+    App,
+};
+use causal_lm::CausalLM;
+use std::{io, net::SocketAddr, pin::Pin, sync::Arc};
+use tokio::sync::watch;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+use tonic::{transport::Server, Code, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("infinilm");
+}
+
+use proto::{
+    infer_response::Event,
+    inference_server::{Inference, InferenceServer},
+    DropRequest, DropResponse, Finish, ForkRequest, ForkResponse, InferRequest, InferResponse,
+    Piece, TokenLogprob, TopLogprob,
+};
+
+/// 在 `addr` 上提供 gRPC 服务，与 HTTP 服务共用会话、密钥和速率限制。
+///
+/// `stop` 变为真后不再接受连接，等待进行中的调用结束后返回。
+pub(crate) async fn serve<M>(
+    addr: SocketAddr,
+    app: App<M>,
+    mut stop: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    info!("start grpc service at {addr}");
+    Server::builder()
+        .add_service(InferenceServer::new(Grpc(app)))
+        .serve_with_shutdown(addr, async move {
+            let _ = stop.wait_for(|&stop| stop).await;
+        })
+        .await
+        .map_err(io::Error::other)
+}
+
+struct Grpc<M: CausalLM>(App<M>);
+
+impl<M> Grpc<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    #[inline]
+    fn manager(&self) -> &Arc<ServiceManager<M>> {
+        &self.0.manager
+    }
+
+    /// 检查请求携带的密钥并计入调用者的速率，返回调用者的配额。
+    fn admit<T>(&self, req: &Request<T>) -> Result<Option<Quota>, Status> {
+        let client = match &self.0.api_keys {
+            Some(keys) => match keys.check(&req.metadata().clone().into_headers()) {
+                Some(caller) => Some(Client::Key(caller.0)),
+                None => return Err(status(Error::Unauthorized)),
+            },
+            None => req.remote_addr().map(|addr| Client::Ip(addr.ip())),
+        };
+        match (&self.0.limiter, client) {
+            (Some(limiter), Some(client)) => match limiter.admit(client.clone()) {
+                Ok(quota) => Ok(Some(quota)),
+                Err(wait) => {
+                    let secs = retry_after(wait);
+                    warn!("Rate limit exceeded by {client}, retry after {secs}s");
+                    let mut s = status(Error::RateLimited(secs));
+                    s.metadata_mut().insert("retry-after", secs.into());
+                    Err(s)
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+type ResponseStream = Pin<Box<dyn Stream<Item = Result<InferResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<M> Inference for Grpc<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    type InferStream = ResponseStream;
+
+    async fn infer(
+        &self,
+        req: Request<InferRequest>,
+    ) -> Result<Response<Self::InferStream>, Status> {
+        let quota = self.admit(&req)?;
+        let receiver = self
+            .manager()
+            .run(req.into_inner().into(), quota)
+            .map_err(status)?;
+        let stream = UnboundedReceiverStream::new(receiver).map(|output| {
+            let event = match output {
+                Output::Error(e) => return Err(status(e)),
+                Output::Piece(content, logprobs) => Event::Piece(Piece {
+                    content,
+                    logprobs: logprobs
+                        .into_iter()
+                        .flatten()
+                        .map(|t| TokenLogprob {
+                            id: t.token,
+                            token: t.text,
+                            logprob: t.logprob,
+                            top_logprobs: t
+                                .top
+                                .into_iter()
+                                .map(|(id, token, logprob)| TopLogprob { id, token, logprob })
+                                .collect(),
+                        })
+                        .collect(),
+                }),
+                Output::Finish {
+                    reason,
+                    prompt_tokens,
+                    completion_tokens,
+                } => Event::Finish(Finish {
+                    finish_reason: finish_reason(reason).into(),
+                    prompt_tokens: prompt_tokens as _,
+                    completion_tokens: completion_tokens as _,
+                    total_tokens: (prompt_tokens + completion_tokens) as _,
+                }),
+            };
+            Ok(InferResponse { event: Some(event) })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn fork(&self, req: Request<ForkRequest>) -> Result<Response<ForkResponse>, Status> {
+        self.admit(&req)?;
+        let ForkRequest {
+            session_id,
+            new_session_id,
+        } = req.into_inner();
+        self.manager()
+            .fork(Fork {
+                session_id,
+                new_session_id,
+            })
+            .map(|_| Response::new(ForkResponse {}))
+            .map_err(status)
+    }
+
+    async fn drop(&self, req: Request<DropRequest>) -> Result<Response<DropResponse>, Status> {
+        self.admit(&req)?;
+        let DropRequest { session_id } = req.into_inner();
+        self.manager()
+            .drop_(Drop { session_id })
+            .map(|_| Response::new(DropResponse {}))
+            .map_err(status)
+    }
+}
+
+impl From<InferRequest> for Infer {
+    fn from(req: InferRequest) -> Self {
+        let priority = match req.priority() {
+            proto::Priority::Normal => Priority::Normal,
+            proto::Priority::Low => Priority::Low,
+            proto::Priority::High => Priority::High,
+        };
+        Self {
+            inputs: req
+                .inputs
+                .into_iter()
+                .map(|s| Sentence {
+                    role: s.role,
+                    content: s.content,
+                })
+                .collect(),
+            session_id: req.session_id,
+            dialog_pos: req.dialog_pos.map(|n| n as _),
+            model: req.model,
+            preset: req.preset,
+            adapter: req.adapter,
+            temperature: req.temperature,
+            top_k: req.top_k.map(|n| n as _),
+            top_p: req.top_p,
+            min_p: req.min_p,
+            repetition_penalty: req.repetition_penalty,
+            seed: req.seed,
+            stop: (!req.stop.is_empty()).then_some(req.stop),
+            max_tokens: req.max_tokens.map(|n| n as _),
+            logprobs: req.logprobs.then_some(true),
+            top_logprobs: req.top_logprobs.map(|n| n as _),
+            priority: Some(priority),
+            timeout: req.timeout,
+            echo: req.echo.then_some(true),
+            ..Default::default()
+        }
+    }
+}
+
+/// 按 HTTP 状态码把错误映射为 gRPC 状态码，消息与 HTTP 接口相同。
+fn status(e: Error) -> Status {
+    let code = match e.status().as_u16() {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        404 | 410 => Code::NotFound,
+        406 => Code::FailedPrecondition,
+        409 => Code::AlreadyExists,
+        413 | 416 => Code::OutOfRange,
+        429 => Code::ResourceExhausted,
+        503 => Code::Unavailable,
+        504 => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    let message = e.body()["message"].as_str().unwrap_or_default().to_string();
+    Status::new(code, message)
+}
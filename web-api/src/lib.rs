@@ -1,88 +1,625 @@
 #![doc = include_str!("../README.md")]
 
+mod admin;
+mod auth;
+mod config;
+mod cors;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod manager;
+mod metrics;
+mod openai;
+mod presets;
+mod ratelimit;
 mod response;
 mod schemas;
+mod tls;
+mod trace;
+mod websocket;
 
 use causal_lm::CausalLM;
-use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use http_body_util::{combinators::BoxBody, BodyExt};
 use hyper::{
     body::{Bytes, Incoming},
+    header::{HeaderValue, ORIGIN, RETRY_AFTER, WWW_AUTHENTICATE},
     server::conn::http1,
     service::Service as HyperService,
     Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
 use manager::ServiceManager;
-use response::{error, success, text_stream};
+use openai::Reply;
+use ratelimit::{retry_after, Client, RateLimiter};
+use response::{empty, error, json, openai_error, prometheus, success, text_stream};
+use serde::Deserialize;
 use std::{
     future::Future,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
     pin::Pin,
     sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::net::TcpListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::watch,
+};
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{field::Empty, Instrument};
+
+pub use admin::Admin;
+pub use auth::ApiKeys;
+pub use config::ServiceConfig;
+pub use cors::Cors;
+pub use manager::{Limits, Model, ModelLoader, SessionPolicy};
+pub use presets::SamplePresets;
+pub use ratelimit::RateLimits;
+pub use tls::Tls;
 
 #[macro_use]
 extern crate log;
 
+/// 检查会话是否空闲超时或超出计算缓存预算的间隔。
+const EVICT_INTERVAL: Duration = Duration::from_secs(1);
+/// 清理空闲调用者速率状态的间隔。
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// 停止服务时检查推理是否都已结束的间隔。
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// 中止推理后等待推理和连接结束的时长。
+const ABORT_GRACE: Duration = Duration::from_secs(5);
+/// 只读副本提供的接口，都不使用会话，探针和管理接口之外的请求只能访问这些接口。
+const REPLICA_ROUTES: [&str; 5] = [
+    "/v1/embeddings",
+    "/v1/models",
+    "/tokenize",
+    "/detokenize",
+    "/metrics",
+];
+
+/// 服务监听的地址，至少指定一个。
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct Listen {
+    /// 监听所有网卡的 TCP 端口。
+    pub port: Option<u16>,
+    /// Unix 域套接字的路径，与 TCP 端口同时指定时两者都接受连接。
+    pub unix_socket: Option<PathBuf>,
+    /// TCP 连接的 TLS 设置，非空时以 HTTPS 提供服务。
+    #[serde(flatten)]
+    pub tls: Option<Tls>,
+    /// gRPC 服务的 TCP 端口，需要启用 `grpc` 特性。
+    pub grpc_port: Option<u16>,
+}
+
+/// 收到 SIGTERM 或 SIGINT 后停止服务的方式。
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ShutdownPolicy {
+    /// 等待进行中的推理结束的时长，超时后中止剩余的推理。
+    #[serde(deserialize_with = "config::secs")]
+    pub drain_timeout: Duration,
+    /// 保存会话的 json 文件，停止时写入空闲的具名会话，启动时从中恢复。
+    #[serde(rename = "session_snapshot")]
+    pub snapshot: Option<PathBuf>,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(30),
+            snapshot: None,
+        }
+    }
+}
+
+/// 启动推理服务，`models` 是以名字区分的多个模型，请求以 `model` 字段选择模型，不指定时使用第一个模型。
+///
+/// 每个模型可以有多个独立副本（如分别加载到不同的 GPU 上），新会话轮流分配到各个副本。
+///
+/// `admin` 非空时提供需要管理密钥的管理接口，如不停机重新加载模型。
+///
+/// `config.replica` 为真时作为只读副本，只提供文本嵌入、分词、模型列表和指标等不使用会话的接口，
+/// 与主服务进程加载同一个模型目录即可共享以内存映射加载的权重。
+///
+/// 收到停止信号后不再接受连接和推理，按 `config.shutdown` 等待进行中的推理结束后返回。
 pub async fn start_infer_service<M>(
-    service: service::Service<M>,
-    port: u16,
-    session_capacity: Option<usize>,
-) -> std::io::Result<()>
+    models: Vec<Model<M>>,
+    admin: Option<Admin<M>>,
+    config: ServiceConfig,
+) -> io::Result<()>
 where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
 {
-    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
-    info!("start service at {addr}");
+    let ServiceConfig {
+        listen,
+        session,
+        limits,
+        rate_limits,
+        cors,
+        shutdown,
+        presets,
+        api_keys,
+        replica,
+    } = config;
+    if listen.port.is_none() && listen.unix_socket.is_none() && listen.grpc_port.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "neither port nor unix socket is specified",
+        ));
+    }
+    if cfg!(not(feature = "grpc")) && listen.grpc_port.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "grpc is not enabled in this build",
+        ));
+    }
+    if replica && (listen.grpc_port.is_some() || shutdown.snapshot.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a replica has no sessions to serve over grpc or save to a snapshot",
+        ));
+    }
+    let acceptor = listen.tls.as_ref().map(Tls::acceptor).transpose()?;
+
+    if replica {
+        info!("serving as a read-only replica");
+    }
+    if let Some(keys) = &api_keys {
+        info!("{} api keys accepted", keys.len());
+    }
+    if let Some(admin) = &admin {
+        info!("{} admin keys accepted", admin.keys.len());
+    }
+    let app = App {
+        manager: Arc::new(ServiceManager::new(models, session, limits, presets)),
+        api_keys: api_keys.map(Arc::new),
+        limiter: (!rate_limits.is_empty()).then(|| Arc::new(RateLimiter::new(rate_limits))),
+        cors: cors.map(Arc::new),
+        admin: admin.map(Arc::new),
+        replica,
+        peer: None,
+    };
+    if let Some(path) = &shutdown.snapshot {
+        match std::fs::read(path) {
+            Ok(bytes) => {
+                let snapshot = serde_json::from_slice(&bytes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let n = app.manager.restore_sessions(snapshot);
+                info!("{n} sessions restored from {}", path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let policy = app.manager.policy();
+    if policy.ttl.is_some() || policy.kv_budget.is_some() {
+        let manager = app.manager.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EVICT_INTERVAL);
+            loop {
+                interval.tick().await;
+                manager.evict();
+            }
+        });
+    }
+    if let Some(limiter) = app.limiter.clone() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                limiter.prune();
+            }
+        });
+    }
 
-    let app = App(Arc::new(ServiceManager::new(service, session_capacity)));
-    let listener = TcpListener::bind(addr).await?;
+    let tcp = match listen.port {
+        Some(port) => {
+            let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+            let scheme = if acceptor.is_some() { "https" } else { "http" };
+            info!("start service at {scheme}://{addr}");
+            Some(TcpListener::bind(addr).await?)
+        }
+        None => None,
+    };
+    let unix = match &listen.unix_socket {
+        Some(path) => {
+            info!("start service at unix:{}", path.display());
+            Some(bind_unix(path)?)
+        }
+        None => None,
+    };
+    // 每个监听器和连接持有一个接收端，所有接收端释放时连接都已关闭
+    let (stop, _) = watch::channel(false);
+    let serve = async {
+        tokio::try_join!(
+            async {
+                match tcp {
+                    Some(listener) => {
+                        serve_tcp(listener, app.clone(), acceptor, stop.subscribe()).await
+                    }
+                    None => Ok(()),
+                }
+            },
+            async {
+                match unix {
+                    Some(listener) => serve_unix(listener, app.clone(), stop.subscribe()).await,
+                    None => Ok(()),
+                }
+            },
+            async {
+                match listen.grpc_port {
+                    #[cfg(feature = "grpc")]
+                    Some(port) => {
+                        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+                        grpc::serve(addr, app.clone(), stop.subscribe()).await
+                    }
+                    _ => Ok(()),
+                }
+            },
+        )
+        .map(|_| ())
+    };
+    tokio::pin!(serve);
+    tokio::select! {
+        r = &mut serve => return r,
+        _ = shutdown_signal() => {}
+    }
+
+    info!(
+        "Shutting down, waiting up to {:?} for {} generations",
+        shutdown.drain_timeout,
+        app.manager.busy(),
+    );
+    app.manager.drain();
+    stop.send_replace(true);
+    let drained = async {
+        if let Err(e) = (&mut serve).await {
+            warn!("Error stopping listeners: {e}");
+        }
+        stop.closed().await;
+        while app.manager.busy() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    };
+    tokio::pin!(drained);
+    if tokio::time::timeout(shutdown.drain_timeout, &mut drained)
+        .await
+        .is_err()
+    {
+        warn!(
+            "Drain timed out, aborting {} generations",
+            app.manager.busy()
+        );
+        app.manager.abort_all();
+        if tokio::time::timeout(ABORT_GRACE, drained).await.is_err() {
+            warn!("Connections still open after aborting, closed forcibly");
+        }
+    }
+
+    if let Some(path) = &shutdown.snapshot {
+        let snapshot = app.manager.snapshot();
+        std::fs::write(path, serde_json::to_vec(&snapshot)?)?;
+        info!("{} sessions saved to {}", snapshot.len(), path.display());
+    }
+    info!("Service stopped");
+    Ok(())
+}
+
+/// 等待 SIGTERM 或 SIGINT。
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            },
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// 接受 TCP 连接，`acceptor` 非空时先完成 TLS 握手；`stop` 变为真后不再接受连接。
+async fn serve_tcp<M>(
+    listener: TcpListener,
+    app: App<M>,
+    acceptor: Option<TlsAcceptor>,
+    mut stop: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
     loop {
-        let app = app.clone();
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            r = listener.accept() => r?,
+            _ = stop.wait_for(|&stop| stop) => return Ok(()),
+        };
+        let app = App {
+            peer: Some(peer.ip()),
+            ..app.clone()
+        };
+        let acceptor = acceptor.clone();
+        let stop = stop.clone();
         tokio::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(TokioIo::new(stream), app)
-                .await
-            {
-                warn!("Error serving connection: {err:?}");
+            match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, app, stop).await,
+                    Err(e) => warn!("TLS handshake with {peer} failed: {e}"),
+                },
+                None => serve_connection(stream, app, stop).await,
             }
         });
     }
 }
 
-struct App<M: CausalLM>(Arc<ServiceManager<M>>);
+/// 绑定 Unix 域套接字，移除上次运行遗留的套接字文件。
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_: &std::path::Path) -> io::Result<std::convert::Infallible> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix socket is not supported on this platform",
+    ))
+}
+
+/// 接受 Unix 域套接字连接，这些连接没有客户端地址；`stop` 变为真后不再接受连接。
+#[cfg(unix)]
+async fn serve_unix<M>(
+    listener: tokio::net::UnixListener,
+    app: App<M>,
+    mut stop: watch::Receiver<bool>,
+) -> io::Result<()>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    loop {
+        let (stream, _) = tokio::select! {
+            r = listener.accept() => r?,
+            _ = stop.wait_for(|&stop| stop) => return Ok(()),
+        };
+        tokio::spawn(serve_connection(stream, app.clone(), stop.clone()));
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix<M: CausalLM>(
+    listener: std::convert::Infallible,
+    _: App<M>,
+    _: watch::Receiver<bool>,
+) -> io::Result<()> {
+    match listener {}
+}
+
+/// 在连接上处理 HTTP 请求，直到连接关闭；`stop` 变为真后处理完当前的请求（包括流式响应）就关闭连接。
+///
+/// 已升级为 WebSocket 的连接不受影响，其中的推理同样被等待或中止。
+async fn serve_connection<M, I>(io: I, app: App<M>, mut stop: watch::Receiver<bool>)
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let conn = http1::Builder::new()
+        .serve_connection(TokioIo::new(io), app)
+        .with_upgrades();
+    tokio::pin!(conn);
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            r = conn.as_mut() => {
+                if let Err(err) = r {
+                    warn!("Error serving connection: {err:?}");
+                }
+                break;
+            }
+            _ = stop.wait_for(|&stop| stop), if !stopping => {
+                stopping = true;
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+struct App<M: CausalLM> {
+    manager: Arc<ServiceManager<M>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    limiter: Option<Arc<RateLimiter>>,
+    cors: Option<Arc<Cors>>,
+    admin: Option<Arc<Admin<M>>>,
+    /// 是否是只读副本。
+    replica: bool,
+    /// 连接的客户端地址。
+    peer: Option<IpAddr>,
+}
 
 impl<M: CausalLM> Clone for App<M> {
     #[inline]
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            manager: self.manager.clone(),
+            api_keys: self.api_keys.clone(),
+            limiter: self.limiter.clone(),
+            cors: self.cors.clone(),
+            admin: self.admin.clone(),
+            replica: self.replica,
+            peer: self.peer,
+        }
     }
 }
 
+type Resp = Response<BoxBody<Bytes, hyper::Error>>;
+type RespFuture = Pin<Box<dyn Future<Output = Result<Resp, hyper::Error>> + Send>>;
+
 impl<M> HyperService<Request<Incoming>> for App<M>
 where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
 {
-    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Response = Resp;
     type Error = hyper::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Future = RespFuture;
 
+    /// 以请求标识开启一个跟踪范围，处理请求期间的日志都在这个范围中，响应头返回请求标识。
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let manager = self.0.clone();
+        let start = Instant::now();
+        let manager = self.manager.clone();
+        let route = metrics::route(req.uri().path());
+        let id = trace::request_id(req.headers());
+        let span = tracing::info_span!(
+            "request",
+            id = %id,
+            method = %req.method(),
+            path = req.uri().path(),
+            client = self.peer.map(tracing::field::display),
+            status = Empty,
+        );
+        let future = span.in_scope(|| self.cors(req));
+        let response = async move {
+            let mut res = future.await?;
+            let status = res.status().as_u16();
+            manager.metrics().request(route, status);
+            tracing::Span::current().record("status", status);
+            // 标识是可见的 ASCII 字符，总是合法的响应头
+            res.headers_mut()
+                .insert(&trace::X_REQUEST_ID, HeaderValue::from_str(&id).unwrap());
+            tracing::info!(
+                status,
+                latency_ms = start.elapsed().as_secs_f64() * 1e3,
+                "request completed",
+            );
+            Ok(res)
+        };
+        Box::pin(response.instrument(span))
+    }
+}
+
+impl<M> App<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    /// 应答预检请求，为跨域请求的响应添加响应头。
+    fn cors(&self, req: Request<Incoming>) -> RespFuture {
+        let Some(cors) = self.cors.clone() else {
+            return self.route(req);
+        };
+        // 预检请求不携带密钥，在认证之前应答
+        if Cors::is_preflight(req.method(), req.headers()) {
+            let mut res = empty(StatusCode::NO_CONTENT);
+            cors.preflight(req.headers(), res.headers_mut());
+            return Box::pin(async move { Ok(res) });
+        }
+        let Some(origin) = req.headers().get(ORIGIN).cloned() else {
+            return self.route(req);
+        };
+        let future = self.route(req);
+        Box::pin(async move {
+            let mut res = future.await?;
+            cors.apply(&origin, res.headers_mut());
+            Ok(res)
+        })
+    }
+
+    fn route(&self, mut req: Request<Incoming>) -> RespFuture {
+        let manager = self.manager.clone();
+
+        // 探针不需要认证，也不计入速率
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/healthz") => {
+                let ret = success(schemas::HealthSuccess);
+                return Box::pin(async move { Ok(ret) });
+            }
+            (&Method::GET, "/readyz") => {
+                let ready = manager.ready();
+                let status = if ready.ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                let mut ret = json(ready);
+                *ret.status_mut() = status;
+                return Box::pin(async move { Ok(ret) });
+            }
+            _ => {}
+        }
+
+        // 管理接口使用单独的密钥，不计入速率
+        if req.uri().path().starts_with("/admin/") {
+            return match &self.admin {
+                Some(admin) => admin.route(manager, req),
+                None => Box::pin(async move { Ok(empty(StatusCode::NOT_FOUND)) }),
+            };
+        }
+
+        if let Some(keys) = &self.api_keys {
+            match keys.check(req.headers()) {
+                Some(caller) => {
+                    debug!("{} {} from {}", req.method(), req.uri().path(), caller.0);
+                    req.extensions_mut().insert(caller);
+                }
+                None => {
+                    warn!("Unauthorized request to {}", req.uri().path());
+                    let mut res = reject(&req, schemas::Error::Unauthorized);
+                    res.headers_mut()
+                        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                    return Box::pin(async move { Ok(res) });
+                }
+            }
+        }
+
+        // 认证时按密钥的名字区分调用者，否则按客户端地址区分
+        let client = match req.extensions().get::<auth::Caller>() {
+            Some(caller) => Some(Client::Key(caller.0.clone())),
+            None => self.peer.map(Client::Ip),
+        };
+        let quota = match (&self.limiter, client) {
+            (Some(limiter), Some(client)) => match limiter.admit(client.clone()) {
+                Ok(quota) => Some(quota),
+                Err(wait) => {
+                    let secs = retry_after(wait);
+                    warn!("Rate limit exceeded by {client}, retry after {secs}s");
+                    let mut res = reject(&req, schemas::Error::RateLimited(secs));
+                    res.headers_mut().insert(RETRY_AFTER, secs.into());
+                    return Box::pin(async move { Ok(res) });
+                }
+            },
+            _ => None,
+        };
+
+        if self.replica && !REPLICA_ROUTES.contains(&req.uri().path()) {
+            let res = reject(&req, schemas::Error::ReadOnlyReplica);
+            return Box::pin(async move { Ok(res) });
+        }
 
         macro_rules! response {
-            ($method:ident; $f:expr) => {
+            ($method:ident $(, $arg:expr)*; $f:expr) => {
                 Box::pin(async move {
                     let whole_body = req.collect().await?.to_bytes();
                     let req = serde_json::from_slice(&whole_body);
                     Ok(match req {
-                        Ok(req) => match manager.$method(req) {
+                        Ok(req) => match manager.$method(req $(, $arg)*) {
                             Ok(ret) => $f(ret),
                             Err(e) => error(e),
                         },
@@ -92,23 +629,88 @@ where
             };
         }
 
+        macro_rules! openai {
+            ($method:ident) => {
+                Box::pin(async move {
+                    let whole_body = req.collect().await?.to_bytes();
+                    let req = serde_json::from_slice(&whole_body);
+                    Ok(match req {
+                        Ok(req) => match manager.$method(req, quota).await {
+                            Ok(Reply::Json(body)) => json(body),
+                            Ok(Reply::Stream(events)) => {
+                                text_stream(UnboundedReceiverStream::new(events))
+                            }
+                            Err(e) => openai_error(e),
+                        },
+                        Err(e) => openai_error(schemas::Error::WrongJson(e)),
+                    })
+                })
+            };
+        }
+
         match (req.method(), req.uri().path()) {
             (&Method::POST, "/infer") => {
-                response!(infer; |ret| text_stream(UnboundedReceiverStream::new(ret)))
+                response!(infer, quota; text_stream)
             }
             (&Method::POST, "/fork") => response!(fork ; success),
             (&Method::POST, "/drop") => response!(drop_; success),
+            (&Method::POST, "/abort") => response!(abort; success),
+            (&Method::POST, "/history") => response!(history; json),
+            (&Method::POST, "/warm_up") => response!(warm_up; success),
+            (&Method::POST, "/tokenize") => response!(tokenize; json),
+            (&Method::POST, "/detokenize") => response!(detokenize; json),
+            (&Method::POST, "/v1/chat/completions") => openai!(chat_completions),
+            (&Method::POST, "/v1/completions") => openai!(completions),
+            (&Method::POST, "/v1/embeddings") => openai!(embeddings),
+            (&Method::GET, "/v1/models") => {
+                let ret = json(manager.list_models());
+                Box::pin(async move { Ok(ret) })
+            }
+            (&Method::GET, "/ws") => {
+                Box::pin(async move { Ok(websocket::upgrade(manager, req, quota)) })
+            }
+            (&Method::GET, "/metrics") => {
+                let ret = prometheus(manager.export_metrics());
+                Box::pin(async move { Ok(ret) })
+            }
+            (&Method::GET, "/sessions") => {
+                let ret = json(manager.sessions());
+                Box::pin(async move { Ok(ret) })
+            }
+            (&Method::GET, path) if path.starts_with("/sessions/") => {
+                let ret = match percent_decode(&path["/sessions/".len()..]) {
+                    Some(id) => manager.session(id).map_or_else(error, json),
+                    None => error(schemas::Error::SessionNotFound),
+                };
+                Box::pin(async move { Ok(ret) })
+            }
             // Return 404 Not Found for other routes.
-            _ => Box::pin(async move {
-                Ok(Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .body(
-                        Empty::<Bytes>::new()
-                            .map_err(|never| match never {})
-                            .boxed(),
-                    )
-                    .unwrap())
-            }),
+            _ => Box::pin(async move { Ok(empty(StatusCode::NOT_FOUND)) }),
+        }
+    }
+}
+
+/// 拒绝请求，`/v1/` 下的接口按 OpenAI 的格式返回错误。
+fn reject<B>(req: &Request<B>, e: schemas::Error) -> Resp {
+    if req.uri().path().starts_with("/v1/") {
+        openai_error(e)
+    } else {
+        error(e)
+    }
+}
+
+/// 解码路径中的百分号编码，编码无效或不是 UTF-8 时返回 `None`。
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = |b: u8| (b as char).to_digit(16);
+            let (hi, lo) = (hex(iter.next()?)?, hex(iter.next()?)?);
+            bytes.push((hi * 16 + lo) as u8);
+        } else {
+            bytes.push(b);
         }
     }
+    String::from_utf8(bytes).ok()
 }
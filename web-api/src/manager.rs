@@ -1,19 +1,209 @@
-use crate::schemas::{Drop, DropSuccess, Error, Fork, ForkSuccess, Infer, Sentence};
+use crate::{
+    metrics::{Gauges, Metrics},
+    presets::SamplePresets,
+    ratelimit::Quota,
+    schemas::{
+        finish_reason, Abort, AbortSuccess, Detokenize, DetokenizeResponse, Drop, DropSuccess,
+        EmbedInput, Error, Fork, ForkSuccess, History, HistoryResponse, Infer, InferDone,
+        ModelCard, ModelsResponse, Piece, ReadyResponse, Reload, ReloadResponse, ResponseFormat,
+        Sentence, SessionDetail, SessionInfo, SessionSnapshot, SessionsResponse, Tokenize,
+        TokenizeResponse, Usage, WarmUp, WarmUpSuccess,
+    },
+};
 use causal_lm::CausalLM;
 use lru::LruCache;
-use service::{Service, Session};
+use serde::Deserialize;
+use service::{
+    BeamArgs, EmbedArgs, EmbedError, FinishReason, Grammar, Overflow, Priority, Regex, Service,
+    Session, SessionStats, TokenLogprob,
+};
 use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
     num::NonZeroUsize,
+    path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver},
+    Notify, OwnedSemaphorePermit, Semaphore,
+};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+use tracing::Instrument;
+
+/// 每个 token 至多返回的候选数。
+const MAX_TOP_LOGPROBS: usize = 20;
+/// 至多记住的已淘汰会话数，用于区分会话过期和会话不存在。
+const MAX_EXPIRED: usize = 4096;
+
+/// 会话缓存的淘汰策略，被淘汰的会话释放计算缓存。
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct SessionPolicy {
+    /// 缓存的会话数上限，超出时淘汰最久未使用的会话。
+    #[serde(rename = "max_cache")]
+    pub capacity: Option<usize>,
+    /// 会话空闲时长上限，超出时淘汰会话。
+    #[serde(rename = "session_ttl", deserialize_with = "crate::config::opt_secs")]
+    pub ttl: Option<Duration>,
+    /// 所有会话计算缓存的总字节数上限，超出时从最久未使用的空闲会话开始淘汰。
+    #[serde(deserialize_with = "crate::config::opt_mib")]
+    pub kv_budget: Option<usize>,
+}
+
+/// 以名字区分的模型，`services` 是同一模型的多个独立副本（如分别加载到不同的 GPU 上）。
+pub struct Model<M: CausalLM> {
+    pub name: String,
+    /// 模型目录，重新加载时默认从这里加载。
+    pub dir: PathBuf,
+    pub services: Vec<Service<M>>,
+}
+
+/// 从模型目录加载名为第一个参数的模型的所有副本，用于重新加载模型。
+pub type ModelLoader<M> = Arc<dyn Fn(&str, &Path) -> Vec<Service<M>> + Send + Sync>;
+
+/// 服务中的模型，重新加载时整体替换所有副本。
+struct Served<M: CausalLM> {
+    name: String,
+    replicas: RwLock<Replicas<M>>,
+}
+
+struct Replicas<M: CausalLM> {
+    services: Arc<[Service<M>]>,
+    dir: PathBuf,
+    /// 模型被重新加载的次数。
+    generation: usize,
+    /// 副本加载完成的时间。
+    loaded: SystemTime,
+}
+
+impl<M: CausalLM> Served<M> {
+    #[inline]
+    fn services(&self) -> Arc<[Service<M>]> {
+        self.replicas.read().unwrap().services.clone()
+    }
+
+    #[inline]
+    fn generation(&self) -> usize {
+        self.replicas.read().unwrap().generation
+    }
+}
+
+/// 服务的负载上限，超出时拒绝请求。
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// 同时存在的会话数上限，包括匿名会话。
+    pub max_sessions: Option<usize>,
+    /// 同时进行的推理数上限。
+    pub max_concurrent: Option<usize>,
+    /// 推理时对话的 token 数上限。
+    pub max_prompt_tokens: Option<usize>,
+    /// 推理开始后等待第一段输出的时限，包括排队和预填充，超时时停止推理。
+    #[serde(deserialize_with = "crate::config::opt_secs")]
+    pub queue_timeout: Option<Duration>,
+    /// 一次推理的总时限，请求可以指定更短的时限，超时时停止推理。
+    #[serde(deserialize_with = "crate::config::opt_secs")]
+    pub generation_timeout: Option<Duration>,
+}
 
 pub(crate) struct ServiceManager<M: CausalLM> {
-    service: Service<M>,
-    pending: Mutex<LruCache<SessionId, Option<Session<M>>>>,
+    /// 服务的模型，会话属于创建它的模型，新会话轮流分配到模型的各个副本上。
+    models: Vec<Served<M>>,
+    next: AtomicUsize,
+    presets: SamplePresets,
+    pending: Mutex<LruCache<SessionId, Entry<M>>>,
+    /// 正在推理的会话的中止信号。
+    aborts: Mutex<HashMap<SessionId, Arc<Notify>>>,
+    policy: SessionPolicy,
+    limits: Limits,
+    /// 限制同时进行的推理数。
+    concurrent: Option<Arc<Semaphore>>,
+    /// 最近被淘汰的会话。
+    expired: Mutex<LruCache<String, ()>>,
+    metrics: Metrics,
+    /// 服务正在停止，不再接受新的推理。
+    draining: AtomicBool,
+    /// 正在重新加载模型。
+    reloading: AtomicBool,
+}
+
+/// 推理任务的输出。
+pub(crate) enum Output {
+    /// 解码得到的一段文本，会话设置了对数概率时带有这段文本新解码的 token 的对数概率。
+    Piece(String, Option<Vec<TokenLogprob>>),
+    /// 推理结束，回答已加入对话。
+    Finish {
+        reason: FinishReason,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    },
+    /// 推理因错误（如超时）中途停止，已生成的部分已加入对话。
+    Error(Error),
+}
+
+/// 缓存中的会话，推理期间会话被取走。
+struct Entry<M: CausalLM> {
+    session: Option<Session<M>>,
+    /// 会话所属的模型的序号。
+    model: usize,
+    /// 会话所在的副本属于模型的第几次加载，落后时会话在空闲后迁移到新的副本上。
+    generation: usize,
+    /// 会话被取走时的状态。
+    stats: SessionStats,
+    /// 最近一次取走或归还会话的时间。
+    active: SystemTime,
+}
+
+impl<M: CausalLM> Entry<M> {
+    #[inline]
+    fn new(session: Session<M>, model: usize, generation: usize) -> Self {
+        Self {
+            stats: session.stats(),
+            session: Some(session),
+            model,
+            generation,
+            active: SystemTime::now(),
+        }
+    }
+
+    /// 取走空闲的会话。
+    #[inline]
+    fn take(&mut self) -> Option<Session<M>> {
+        let session = self.session.take()?;
+        self.stats = session.stats();
+        self.active = SystemTime::now();
+        Some(session)
+    }
+
+    /// 归还会话。
+    #[inline]
+    fn restore(&mut self, session: Session<M>) {
+        assert!(self.session.replace(session).is_none());
+        self.active = SystemTime::now();
+    }
+
+    /// 会话的状态，推理期间是会话被取走时的状态。
+    #[inline]
+    fn stats(&self) -> SessionStats {
+        self.session.as_ref().map_or(self.stats, Session::stats)
+    }
+
+    #[inline]
+    fn info(&self, session_id: &str, model: &str) -> SessionInfo {
+        SessionInfo::new(
+            session_id.into(),
+            model.into(),
+            self.session.is_none(),
+            self.stats(),
+            self.active,
+        )
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Debug)]
@@ -32,14 +222,447 @@ enum SessionId {
     Temporary(AnonymousSessionId),
 }
 
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Permanent(id) => write!(f, "{id}"),
+            Self::Temporary(AnonymousSessionId(n)) => write!(f, "anonymous-{n}"),
+        }
+    }
+}
+
 impl<M: CausalLM> ServiceManager<M> {
     #[inline]
-    pub fn new(service: Service<M>, capacity: Option<usize>) -> Self {
-        let cap =
-            capacity.map(|c| NonZeroUsize::new(c).expect("Session capacity must be non-zero"));
+    pub fn new(
+        models: Vec<Model<M>>,
+        policy: SessionPolicy,
+        limits: Limits,
+        presets: SamplePresets,
+    ) -> Self {
+        assert!(!models.is_empty(), "At least one model is required");
+        for (i, model) in models.iter().enumerate() {
+            assert!(
+                !model.services.is_empty(),
+                "Model {} has no service",
+                model.name
+            );
+            assert!(
+                models[..i].iter().all(|m| m.name != model.name),
+                "Model {} is duplicated",
+                model.name
+            );
+        }
+        let cap = policy
+            .capacity
+            .map(|c| NonZeroUsize::new(c).expect("Session capacity must be non-zero"));
         Self {
-            service,
+            models: models
+                .into_iter()
+                .map(
+                    |Model {
+                         name,
+                         dir,
+                         services,
+                     }| Served {
+                        name,
+                        replicas: RwLock::new(Replicas {
+                            services: services.into(),
+                            dir,
+                            generation: 0,
+                            loaded: SystemTime::now(),
+                        }),
+                    },
+                )
+                .collect(),
+            next: AtomicUsize::new(0),
+            presets,
             pending: Mutex::new(cap.map(LruCache::new).unwrap_or_else(LruCache::unbounded)),
+            aborts: Default::default(),
+            policy,
+            concurrent: limits.max_concurrent.map(|n| Arc::new(Semaphore::new(n))),
+            limits,
+            expired: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_EXPIRED).unwrap())),
+            metrics: Metrics::new(),
+            draining: AtomicBool::new(false),
+            reloading: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// 检查每个副本是否就绪，服务停止期间总是不就绪。
+    pub fn ready(&self) -> ReadyResponse {
+        let replicas = self
+            .models
+            .iter()
+            .flat_map(|m| {
+                m.services()
+                    .iter()
+                    .map(Service::is_ready)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        ReadyResponse {
+            ready: !self.draining.load(Ordering::Relaxed) && replicas.iter().all(|&r| r),
+            replicas,
+        }
+    }
+
+    /// 开始停止服务，之后拒绝新的推理，进行中的推理不受影响。
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// 正在推理的会话数，包括匿名会话。
+    pub fn busy(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.session.is_none())
+            .count()
+    }
+
+    /// 中止所有正在进行的推理，已生成的部分加入对话。
+    pub fn abort_all(&self) {
+        for abort in self.aborts.lock().unwrap().values() {
+            abort.notify_one();
+        }
+    }
+
+    /// 导出所有空闲的具名会话，最久未使用的在前。
+    pub fn snapshot(&self) -> Vec<SessionSnapshot> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter_map(|(id, entry)| match (id, &entry.session) {
+                (SessionId::Permanent(id), Some(session)) => Some(SessionSnapshot {
+                    session_id: id.clone(),
+                    model: Some(self.models[entry.model].name.clone()),
+                    system: session.system.clone(),
+                    adapter: session.adapter().map(Into::into),
+                    add_special_tokens: session.add_special_tokens,
+                    turns: session.turns().map(|t| t.content.clone()).collect(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 恢复导出的会话，只填充对话，计算缓存在下次推理时重新计算；已存在的会话和使用未知适配器的会话被跳过。
+    pub fn restore_sessions(&self, snapshot: Vec<SessionSnapshot>) -> usize {
+        let mut sessions = self.pending.lock().unwrap();
+        let mut n = 0;
+        for SessionSnapshot {
+            session_id,
+            model,
+            system,
+            adapter,
+            add_special_tokens,
+            turns,
+        } in snapshot
+        {
+            let id = SessionId::Permanent(session_id);
+            if sessions.contains(&id) {
+                warn!("{id:?} already exists, snapshot skipped");
+                continue;
+            }
+            let Ok(model) = self.model(model.as_deref()) else {
+                warn!("{id:?} belongs to unknown model {model:?}, snapshot skipped");
+                continue;
+            };
+            let model = model.unwrap_or(0);
+            if let Some(name) = adapter
+                .as_ref()
+                .filter(|name| !self.models[model].services()[0].has_adapter(name))
+            {
+                warn!("{id:?} uses unknown adapter \"{name}\", snapshot skipped");
+                continue;
+            }
+            let (mut session, generation) = self.launch(model);
+            session.system = system;
+            session.add_special_tokens = add_special_tokens;
+            session.set_adapter(adapter.as_deref());
+            session.extend(turns.iter().map(String::as_str));
+            self.insert(&mut sessions, id, Entry::new(session, model, generation));
+            n += 1;
+        }
+        n
+    }
+
+    /// 以 Prometheus 文本格式导出服务指标。
+    pub fn export_metrics(&self) -> String {
+        let mut gauges = Gauges {
+            sessions: 0,
+            busy_sessions: 0,
+            kv_bytes: 0,
+            queue_depth: self
+                .models
+                .iter()
+                .map(|m| m.services().iter().map(Service::queue_len).sum::<usize>())
+                .sum(),
+        };
+        for (_, entry) in self.pending.lock().unwrap().iter() {
+            gauges.sessions += 1;
+            gauges.busy_sessions += entry.session.is_none() as usize;
+            gauges.kv_bytes += entry.stats().kv_bytes;
+        }
+        self.metrics.export(gauges)
+    }
+
+    #[inline]
+    pub fn policy(&self) -> &SessionPolicy {
+        &self.policy
+    }
+
+    /// 按名字选择模型，不指定时返回 `None`。
+    ///
+    /// 只服务一个模型时不检查名字，兼容随意填写 `model` 的 OpenAI 客户端。
+    fn model(&self, name: Option<&str>) -> Result<Option<usize>, Error> {
+        match name {
+            None => Ok(None),
+            Some(_) if self.models.len() == 1 => Ok(Some(0)),
+            Some(name) => self
+                .models
+                .iter()
+                .position(|m| m.name == name)
+                .map(Some)
+                .ok_or_else(|| Error::UnknownModel(name.into())),
+        }
+    }
+
+    /// 按名字选择模型的名字，不指定时是第一个模型。
+    pub fn model_name(&self, name: Option<&str>) -> Result<&str, Error> {
+        Ok(&self.models[self.model(name)?.unwrap_or(0)].name)
+    }
+
+    /// 以 OpenAI 的格式列出所有模型，第一个是不指定模型时使用的模型。
+    pub fn list_models(&self) -> ModelsResponse {
+        let data = self
+            .models
+            .iter()
+            .map(|m| {
+                let replicas = m.replicas.read().unwrap();
+                let info = replicas.services[0].model_info();
+                ModelCard {
+                    id: m.name.clone(),
+                    object: "model",
+                    created: replicas
+                        .loaded
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                    owned_by: "infinilm",
+                    root: replicas.dir.display().to_string(),
+                    context_length: replicas.services[0].max_seq_len(),
+                    dtype: info.dtype,
+                    quantization: info.quantization,
+                    devices: replicas
+                        .services
+                        .iter()
+                        .filter_map(|s| s.model_info().device)
+                        .collect(),
+                }
+            })
+            .collect();
+        ModelsResponse {
+            object: "list",
+            data,
+        }
+    }
+
+    /// 按名字选择模型的所有副本，第一个副本用于不涉及会话的请求。
+    fn services(&self, name: Option<&str>) -> Result<Arc<[Service<M>]>, Error> {
+        Ok(self.models[self.model(name)?.unwrap_or(0)].services())
+    }
+
+    /// 会话所属的模型，会话不存在时是新会话将使用的模型。
+    fn model_of(&self, session_id: Option<&str>, model: Option<usize>) -> usize {
+        session_id
+            .and_then(|id| {
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .peek(&SessionId::Permanent(id.into()))
+                    .map(|entry| entry.model)
+            })
+            .or(model)
+            .unwrap_or(0)
+    }
+
+    /// 检查请求指定的模型是否是会话所属的模型。
+    fn check_model(&self, entry: &Entry<M>, model: Option<usize>) -> Result<(), Error> {
+        match model {
+            Some(m) if m != entry.model => {
+                Err(Error::ModelMismatch(self.models[entry.model].name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 在模型的下一个副本上启动会话，分叉的会话留在原会话所在的副本上。
+    ///
+    /// 返回会话和副本属于模型的第几次加载。
+    fn launch(&self, model: usize) -> (Session<M>, usize) {
+        let replicas = self.models[model].replicas.read().unwrap();
+        (self.next_replica(&replicas).launch(), replicas.generation)
+    }
+
+    #[inline]
+    fn next_replica<'a>(&self, replicas: &'a Replicas<M>) -> &'a Service<M> {
+        let services = &replicas.services;
+        &services[self.next.fetch_add(1, Ordering::Relaxed) % services.len()]
+    }
+
+    /// 在模型当前的副本上重建会话，只填充对话，计算缓存在下次推理时重新计算。
+    ///
+    /// 新的副本没有会话使用的适配器时，重建的会话只使用基础模型。
+    fn migrate(&self, model: usize, old: &Session<M>) -> (Session<M>, usize) {
+        let replicas = self.models[model].replicas.read().unwrap();
+        let service = self.next_replica(&replicas);
+        let mut session = service.launch();
+        session.sample = old.sample.clone();
+        session.system = old.system.clone();
+        session.add_special_tokens = old.add_special_tokens;
+        match old.adapter() {
+            Some(name) if !service.has_adapter(name) => {
+                warn!("Adapter \"{name}\" is missing after reload, base model used")
+            }
+            adapter => session.set_adapter(adapter),
+        }
+        session.extend(old.turns().map(|t| t.content.as_str()));
+        (session, replicas.generation)
+    }
+
+    /// 取走会话，会话不存在时在 `model` 上创建新会话，不指定时使用第一个模型。
+    fn take_or_launch(
+        &self,
+        session_id: &SessionId,
+        model: Option<usize>,
+    ) -> Result<Session<M>, Error> {
+        let mut sessions = self.pending.lock().unwrap();
+        if !sessions.contains(session_id) {
+            self.check_sessions(&sessions)?;
+            let model = model.unwrap_or(0);
+            info!(
+                "{session_id:?} created on model {}",
+                self.models[model].name
+            );
+            let (session, generation) = self.launch(model);
+            let entry = Entry::new(session, model, generation);
+            self.insert(&mut sessions, session_id.clone(), entry);
+        }
+        let entry = sessions.get_mut(session_id).unwrap();
+        self.check_model(entry, model)?;
+        entry.take().ok_or(Error::SessionBusy)
+    }
+
+    /// 检查是否还能创建新会话。
+    fn check_sessions(&self, sessions: &LruCache<SessionId, Entry<M>>) -> Result<(), Error> {
+        match self.limits.max_sessions {
+            Some(max) if sessions.len() >= max => {
+                warn!("Session rejected because {max} sessions exist");
+                Err(Error::TooManySessions(max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 占用一个推理名额，名额在推理结束时释放。
+    fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, Error> {
+        self.concurrent
+            .clone()
+            .map(|s| s.try_acquire_owned())
+            .transpose()
+            .map_err(|_| Error::TooManyRequests(self.limits.max_concurrent.unwrap()))
+    }
+
+    /// 加入新会话，缓存已满时淘汰最久未使用的会话。
+    fn insert(
+        &self,
+        sessions: &mut LruCache<SessionId, Entry<M>>,
+        session_id: SessionId,
+        entry: Entry<M>,
+    ) {
+        if let SessionId::Permanent(id) = &session_id {
+            self.expired.lock().unwrap().pop(id);
+        }
+        if let Some((out, _)) = sessions.push(session_id, entry) {
+            warn!("{out:?} dropped because LRU cache is full");
+            self.expire(out);
+        }
+    }
+
+    #[inline]
+    fn expire(&self, session_id: SessionId) {
+        if let SessionId::Permanent(id) = session_id {
+            self.expired.lock().unwrap().put(id, ());
+        }
+    }
+
+    /// 会话不存在时的错误，会话曾被淘汰时返回会话过期错误。
+    fn not_found(&self, session_id: &str) -> Error {
+        if self.expired.lock().unwrap().contains(session_id) {
+            Error::SessionExpired
+        } else {
+            Error::SessionNotFound
+        }
+    }
+
+    /// 按淘汰策略淘汰空闲的会话。
+    ///
+    /// 先淘汰空闲超时的会话，再从最久未使用的会话开始淘汰，直到计算缓存的总占用不超过预算。
+    /// 正在推理的会话不会被淘汰，最近使用的会话不会因超出预算被淘汰。
+    pub fn evict(&self) {
+        let mut sessions = self.pending.lock().unwrap();
+        let mut out = Vec::new();
+        if let Some(ttl) = self.policy.ttl {
+            let now = SystemTime::now();
+            out.extend(
+                sessions
+                    .iter()
+                    .filter(|(_, entry)| {
+                        entry.session.is_some()
+                            && now
+                                .duration_since(entry.active)
+                                .is_ok_and(|idle| idle > ttl)
+                    })
+                    .map(|(id, _)| id.clone()),
+            );
+            for id in &out {
+                sessions.pop(id);
+                info!("{id:?} expired after idle for {ttl:?}");
+            }
+        }
+        if let Some(budget) = self.policy.kv_budget {
+            // 与分叉的会话共享的计算缓存在每个会话中都计入
+            let mut total = sessions
+                .iter()
+                .map(|(_, entry)| entry.stats().kv_bytes)
+                .sum::<usize>();
+            let candidates = sessions
+                .iter()
+                .rev()
+                .take(sessions.len().saturating_sub(1))
+                .filter(|(_, entry)| entry.session.is_some())
+                .map(|(id, entry)| (id.clone(), entry.stats().kv_bytes))
+                .collect::<Vec<_>>();
+            for (id, bytes) in candidates {
+                if total <= budget {
+                    break;
+                }
+                total -= bytes;
+                sessions.pop(&id);
+                info!("{id:?} evicted because kv cache exceeds budget");
+                out.push(id);
+            }
+        }
+        for id in out {
+            self.expire(id);
         }
     }
 }
@@ -49,97 +672,296 @@ where
     M: CausalLM + Send + Sync + 'static,
     M::Storage: Send,
 {
+    /// 推理并返回文本流，返回对数概率或用量时每个片段是一行 json，用量在最后一行。
     pub fn infer(
+        self: &Arc<Self>,
+        req: Infer,
+        quota: Option<Quota>,
+    ) -> Result<impl Stream<Item = String> + Send + Sync + 'static, Error> {
+        let include_usage = req.include_usage.unwrap_or(false);
+        let lines = include_usage || req.logprobs == Some(true) || req.top_logprobs.is_some();
+        let receiver = self.run(req, quota)?;
+        Ok(
+            UnboundedReceiverStream::new(receiver).filter_map(move |output| match output {
+                Output::Piece(content, None) if !include_usage => Some(content),
+                Output::Piece(content, logprobs) => {
+                    let piece = Piece {
+                        content,
+                        logprobs: logprobs.map(|list| list.into_iter().map(Into::into).collect()),
+                    };
+                    Some(serde_json::to_string(&piece).unwrap() + "\n")
+                }
+                Output::Finish {
+                    reason,
+                    prompt_tokens,
+                    completion_tokens,
+                } => include_usage.then(|| {
+                    let done = InferDone {
+                        finish_reason: finish_reason(reason),
+                        usage: Usage::new(prompt_tokens, completion_tokens),
+                    };
+                    serde_json::to_string(&done).unwrap() + "\n"
+                }),
+                // 纯文本的流无法表示错误，直接结束
+                Output::Error(e) => lines.then(|| {
+                    serde_json::to_string(&serde_json::json!({ "error": e.body() })).unwrap() + "\n"
+                }),
+            }),
+        )
+    }
+
+    /// 按请求设置会话并在后台推理，返回推理的输出；推理结束后从 `quota` 中扣除生成的 token。
+    pub fn run(
         self: &Arc<Self>,
         Infer {
             inputs: messages,
             session_id,
             dialog_pos,
+            model,
+            preset,
+            adapter,
             temperature,
             top_k,
             top_p,
+            min_p,
+            typical_p,
+            repetition_penalty,
+            repetition_window,
+            frequency_penalty,
+            presence_penalty,
+            seed,
+            logit_bias,
+            stop,
+            max_tokens,
+            context_overflow,
+            beam_width,
+            length_penalty,
+            early_stopping,
+            grammar,
+            regex,
+            response_format,
+            negative_prompt,
+            guidance_scale,
+            logprobs,
+            top_logprobs,
+            add_special_tokens,
+            skip_special_tokens,
+            priority,
+            timeout,
+            include_usage: _,
+            echo,
         }: Infer,
-    ) -> Result<UnboundedReceiver<String>, Error> {
-        async fn infer<M: CausalLM>(
-            session_id: &SessionId,
-            session: &mut Session<M>,
-            messages: Vec<Sentence>,
-            temperature: Option<f32>,
-            top_k: Option<usize>,
-            top_p: Option<f32>,
-            sender: mpsc::UnboundedSender<String>,
-        ) {
+        quota: Option<Quota>,
+    ) -> Result<UnboundedReceiver<Output>, Error> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(Error::ShuttingDown);
+        }
+        let (system, messages) = split_system(&messages, dialog_pos.unwrap_or(0))?;
+        let preset = match preset {
+            Some(name) => match self.presets.get(&name) {
+                Some(args) => Some(args.clone()),
+                None => return Err(Error::UnknownPreset(name)),
+            },
+            None => None,
+        };
+        let model = self.model(model.as_deref())?;
+        if let Some(name) = adapter.as_ref().filter(|name| {
+            let m = self.model_of(session_id.as_deref(), model);
+            !self.models[m].services()[0].has_adapter(name)
+        }) {
+            return Err(Error::UnknownAdapter(name.clone()));
+        }
+
+        // 文法、正则表达式和 JSON Schema 至多指定一个，束搜索不支持任何约束和停止序列
+        let schema = match response_format {
+            Some(ResponseFormat::JsonSchema { schema }) => Some(schema),
+            Some(ResponseFormat::Text) | None => None,
+        };
+        let constraints = [grammar.is_some(), regex.is_some(), schema.is_some()]
+            .into_iter()
+            .filter(|&b| b)
+            .count();
+        let stops = stop.as_ref().is_some_and(|s| !s.is_empty());
+        if constraints > 1 || (beam_width.is_some() && (constraints > 0 || stops)) {
+            return Err(Error::ConflictingConstraints);
+        }
+        let grammar = match (grammar, schema) {
+            (Some(src), _) => Some(Grammar::parse(&src)),
+            (None, Some(schema)) => Some(Grammar::from_json_schema(&schema)),
+            (None, None) => None,
+        };
+        let grammar = grammar
+            .transpose()
+            .map_err(Error::InvalidGrammar)?
+            .map(Arc::new);
+        let regex = regex
+            .map(|src| Regex::new(&src).map(Arc::new))
+            .transpose()
+            .map_err(Error::InvalidRegex)?;
+        let overflow = context_overflow.map_or_else(Default::default, Overflow::from);
+        let beam = beam_width.map(|width| {
+            let default = BeamArgs::default();
+            BeamArgs {
+                width,
+                length_penalty: length_penalty.unwrap_or(default.length_penalty),
+                early_stopping: early_stopping.unwrap_or(default.early_stopping),
+            }
+        });
+        // 先展开预设，再用单独指定的参数覆盖，然后设置会话
+        let configure = move |session: &mut Session<M>| {
+            let sample = &mut session.sample;
+            if let Some(preset) = preset {
+                *sample = preset;
+            }
             if let Some(temperature) = temperature {
-                session.sample.temperature = temperature;
+                sample.temperature = temperature;
             }
             if let Some(top_k) = top_k {
-                session.sample.top_k = top_k;
+                sample.top_k = top_k;
             }
             if let Some(top_p) = top_p {
-                session.sample.top_p = top_p;
+                sample.top_p = top_p;
+            }
+            if let Some(min_p) = min_p {
+                sample.min_p = min_p;
+            }
+            if let Some(typical_p) = typical_p {
+                sample.typical_p = typical_p;
+            }
+            if let Some(repetition_penalty) = repetition_penalty {
+                sample.repetition_penalty = repetition_penalty;
+            }
+            if let Some(repetition_window) = repetition_window {
+                sample.repetition_window = repetition_window;
             }
+            if let Some(frequency_penalty) = frequency_penalty {
+                sample.frequency_penalty = frequency_penalty;
+            }
+            if let Some(presence_penalty) = presence_penalty {
+                sample.presence_penalty = presence_penalty;
+            }
+            if seed.is_some() {
+                sample.seed = seed;
+            }
+            if let Some(logit_bias) = logit_bias {
+                // 与 OpenAI 一致，-100 及以下视作禁止生成
+                sample.logit_bias = logit_bias
+                    .into_iter()
+                    .map(|(tok, bias)| {
+                        (
+                            tok,
+                            if bias <= -100. {
+                                f32::NEG_INFINITY
+                            } else {
+                                bias
+                            },
+                        )
+                    })
+                    .collect();
+            }
+            session.stop = stop.unwrap_or_default();
+            session.max_tokens = max_tokens;
+            session.overflow = overflow;
+            session.grammar = grammar;
+            session.regex = regex;
+            session.negative_prompt = negative_prompt;
+            session.guidance_scale = guidance_scale.unwrap_or(1.);
+            session.logprobs = (logprobs == Some(true) || top_logprobs.is_some())
+                .then(|| top_logprobs.unwrap_or(0).min(MAX_TOP_LOGPROBS));
+            session.add_special_tokens = add_special_tokens.unwrap_or(true);
+            session.skip_special_tokens = skip_special_tokens.unwrap_or(false);
+            session.priority = priority.map_or_else(Default::default, Into::into);
+            session.set_adapter(adapter.as_deref());
+        };
 
-            session.extend(messages.iter().map(|s| s.content.as_str()));
+        let max_prompt_tokens = self.limits.max_prompt_tokens;
+        /// 设置会话并填充对话，需要推理时检查对话长度和上下文长度。
+        fn prepare<M: CausalLM>(
+            session: &mut Session<M>,
+            system: Option<&str>,
+            messages: &[&str],
+            configure: impl FnOnce(&mut Session<M>),
+            max_prompt_tokens: Option<usize>,
+        ) -> Result<(), Error> {
+            configure(session);
+            // 新对话才设置系统提示词
+            if session.dialog_pos() == 0 {
+                session.system = system.map(Into::into);
+            }
+            session.extend(messages.iter().copied());
             if session.dialog_pos() % 2 == 1 {
-                info!("{session_id:?} inference started");
-                let mut busy = session.chat();
-                while let Some(s) = busy.decode().await {
-                    if let Err(e) = sender.send(s) {
-                        warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
-                        break;
-                    }
+                let tokens = session.stats().num_tokens;
+                if let Some(limit) = max_prompt_tokens.filter(|&limit| tokens > limit) {
+                    return Err(Error::PromptTooLong { tokens, limit });
                 }
-                info!("{session_id:?} inference stopped");
-            } else {
-                info!("{session_id:?} inference skipped");
+                session.check_context().map_err(Error::ContextOverflow)?;
             }
+            Ok(())
         }
 
+        // 请求只能缩短服务的时限，不是正数的时限被忽略
+        let timeout = timeout
+            .filter(|t| t.is_finite() && *t > 0.)
+            .map(Duration::from_secs_f64);
+        let timeouts = Timeouts {
+            queue: self.limits.queue_timeout,
+            generation: match (timeout, self.limits.generation_timeout) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        };
+        let options = InferOptions {
+            beam,
+            timeouts,
+            quota,
+        };
+
+        // 最后一个消息是用户发言时才会推理，需要占用推理名额
+        let infers = (dialog_pos.unwrap_or(0) + messages.len()) % 2 == 1;
+        let permit = if infers { self.acquire()? } else { None };
+        // 回显时在生成的内容之前输出要回答的消息
+        let echo = messages
+            .last()
+            .filter(|_| infers && echo == Some(true))
+            .map(|s| s.to_string());
+        let channel = || {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            if let Some(s) = &echo {
+                sender.send(Output::Piece(s.clone(), None)).unwrap();
+            }
+            (sender, receiver)
+        };
         match (session_id, dialog_pos.unwrap_or(0)) {
             (Some(session_id_str), 0) => {
                 let session_id = SessionId::Permanent(session_id_str);
-                let mut session = self
-                    .pending
-                    .lock()
-                    .unwrap()
-                    .get_or_insert_mut(session_id.clone(), || {
-                        info!("{:?} created", &session_id);
-                        Some(self.service.launch())
-                    })
-                    .take()
-                    .ok_or(Error::SessionBusy)?;
-
-                let (sender, receiver) = mpsc::unbounded_channel();
-                let self_ = self.clone();
-                tokio::spawn(async move {
-                    session.revert(0).unwrap();
+                let mut session = self.take_or_launch(&session_id, model)?;
 
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
-
-                    self_.restore(&session_id, session);
-                });
+                session.revert(0).unwrap();
+                if let Err(e) = prepare(
+                    &mut session,
+                    system,
+                    &messages,
+                    configure,
+                    max_prompt_tokens,
+                ) {
+                    self.restore(&session_id, session);
+                    return Err(e);
+                }
 
+                let (sender, receiver) = channel();
+                self.spawn_infer(session_id, session, options, sender, permit);
                 Ok(receiver)
             }
             (Some(session_id_str), p) => {
+                let mut session = {
+                    let mut sessions = self.pending.lock().unwrap();
+                    let entry = sessions
+                        .get_mut(&SessionId::Permanent(session_id_str.clone()))
+                        .ok_or_else(|| self.not_found(&session_id_str))?;
+                    self.check_model(entry, model)?;
+                    entry.take().ok_or(Error::SessionBusy)?
+                };
                 let session_id = SessionId::Permanent(session_id_str);
-                let mut session = self
-                    .pending
-                    .lock()
-                    .unwrap()
-                    .get_mut(&session_id)
-                    .ok_or(Error::SessionNotFound)?
-                    .take()
-                    .ok_or(Error::SessionBusy)?;
 
                 if session.revert(p).is_err() {
                     let current = session.dialog_pos();
@@ -149,56 +971,62 @@ where
                     self.restore(&session_id, session);
                     return Err(Error::InvalidDialogPos(current));
                 }
+                info!("{session_id:?} reverted to {p}");
+                if let Err(e) = prepare(
+                    &mut session,
+                    system,
+                    &messages,
+                    configure,
+                    max_prompt_tokens,
+                ) {
+                    self.restore(&session_id, session);
+                    return Err(e);
+                }
 
-                let (sender, receiver) = mpsc::unbounded_channel();
-                let self_ = self.clone();
-                tokio::spawn(async move {
-                    info!("{session_id:?} reverted to {p}");
-
-                    infer(
-                        &session_id,
-                        &mut session,
-                        messages,
-                        temperature,
-                        top_k,
-                        top_p,
-                        sender,
-                    )
-                    .await;
-
-                    self_.restore(&session_id, session);
-                });
-
+                let (sender, receiver) = channel();
+                self.spawn_infer(session_id, session, options, sender, permit);
                 Ok(receiver)
             }
             (None, 0) => {
                 let session_id = SessionId::Temporary(AnonymousSessionId::new());
-                let mut session = self
-                    .pending
-                    .lock()
-                    .unwrap()
-                    .get_or_insert_mut(session_id.clone(), || {
-                        info!("{:?} created", &session_id);
-                        Some(self.service.launch())
-                    })
-                    .take()
-                    .ok_or(Error::SessionNotFound)?;
-                let (sender, receiver) = mpsc::unbounded_channel();
+                let mut session = self.take_or_launch(&session_id, model)?;
+                let (sender, receiver) = channel();
                 let self_ = self.clone();
                 if messages.len() % 2 == 1 {
-                    tokio::spawn(async move {
+                    if let Err(e) = prepare(
+                        &mut session,
+                        system,
+                        &messages,
+                        configure,
+                        max_prompt_tokens,
+                    ) {
+                        self.drop_with_session_id(session_id).unwrap();
+                        return Err(e);
+                    }
+                    // 匿名会话不能被单独中止，登记中止信号只为停止服务时中止所有推理
+                    let abort = Arc::new(Notify::new());
+                    self.aborts
+                        .lock()
+                        .unwrap()
+                        .insert(session_id.clone(), abort.clone());
+                    let span = tracing::info_span!("inference", session_id = %session_id);
+                    let future = async move {
                         infer(
                             &session_id,
                             &mut session,
-                            messages,
-                            temperature,
-                            top_k,
-                            top_p,
+                            options,
                             sender,
+                            &abort,
+                            &self_.metrics,
                         )
                         .await;
+                        drop(permit);
+                        self_.aborts.lock().unwrap().remove(&session_id);
                         self_.drop_with_session_id(session_id).unwrap();
-                    });
+                    };
+                    tokio::spawn(future.instrument(span));
+                } else {
+                    self.drop_with_session_id(session_id).unwrap();
                 }
                 Ok(receiver)
             }
@@ -209,10 +1037,76 @@ where
         }
     }
 
+    /// 在后台推理，推理期间会话可以被中止，推理结束后归还会话。
+    fn spawn_infer(
+        self: &Arc<Self>,
+        session_id: SessionId,
+        mut session: Session<M>,
+        options: InferOptions,
+        sender: mpsc::UnboundedSender<Output>,
+        permit: Option<OwnedSemaphorePermit>,
+    ) {
+        let abort = Arc::new(Notify::new());
+        self.aborts
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), abort.clone());
+        let self_ = self.clone();
+        let span = tracing::info_span!("inference", session_id = %session_id);
+        let future = async move {
+            infer(
+                &session_id,
+                &mut session,
+                options,
+                sender,
+                &abort,
+                &self_.metrics,
+            )
+            .await;
+            drop(permit);
+            self_.aborts.lock().unwrap().remove(&session_id);
+            self_.restore(&session_id, session);
+        };
+        tokio::spawn(future.instrument(span));
+    }
+
+    /// 中止会话正在进行的推理，已生成的部分加入对话；会话空闲时什么也不做。
+    pub fn abort(&self, Abort { session_id }: Abort) -> Result<AbortSuccess, Error> {
+        if !self
+            .pending
+            .lock()
+            .unwrap()
+            .contains(&SessionId::Permanent(session_id.clone()))
+        {
+            return Err(self.not_found(&session_id));
+        }
+        if let Some(abort) = self
+            .aborts
+            .lock()
+            .unwrap()
+            .get(&SessionId::Permanent(session_id))
+        {
+            abort.notify_one();
+        }
+        Ok(AbortSuccess)
+    }
+
+    /// 归还会话，会话的计算缓存可能增长，超出预算时淘汰会话。
     #[inline]
     fn restore(&self, session_id: &SessionId, session: Session<M>) {
-        if let Some(option) = self.pending.lock().unwrap().get_mut(session_id) {
-            assert!(option.replace(session).is_none());
+        if let Some(entry) = self.pending.lock().unwrap().get_mut(session_id) {
+            let session = if entry.generation == self.models[entry.model].generation() {
+                session
+            } else {
+                let (new, generation) = self.migrate(entry.model, &session);
+                info!("{session_id:?} migrated to reloaded model");
+                entry.generation = generation;
+                new
+            };
+            entry.restore(session);
+        }
+        if self.policy.kv_budget.is_some() {
+            self.evict();
         }
     }
 
@@ -226,17 +1120,16 @@ where
         let mut sessions = self.pending.lock().unwrap();
         let new_session_id_warped = SessionId::Permanent(new_session_id.clone());
         if !sessions.contains(&new_session_id_warped) {
-            let new = sessions
+            self.check_sessions(&sessions)?;
+            let entry = sessions
                 .get_mut(&SessionId::Permanent(session_id.clone()))
-                .ok_or(Error::SessionNotFound)?
-                .as_ref()
-                .ok_or(Error::SessionBusy)?
-                .fork();
+                .ok_or_else(|| self.not_found(&session_id))?;
+            let (model, generation) = (entry.model, entry.generation);
+            let new = entry.session.as_ref().ok_or(Error::SessionBusy)?.fork();
 
             info!("{new_session_id} is forked from {session_id:?}");
-            if let Some((out, _)) = sessions.push(new_session_id_warped, Some(new)) {
-                warn!("{out:?} dropped because LRU cache is full");
-            }
+            let entry = Entry::new(new, model, generation);
+            self.insert(&mut sessions, new_session_id_warped, entry);
             Ok(ForkSuccess)
         } else {
             warn!("Fork failed because {new_session_id} already exists");
@@ -244,9 +1137,241 @@ where
         }
     }
 
+    /// 查看会话中的所有发言，不影响会话在 LRU 缓存中的顺序。
+    pub fn history(&self, History { session_id }: History) -> Result<HistoryResponse, Error> {
+        let sessions = self.pending.lock().unwrap();
+        let session = sessions
+            .peek(&SessionId::Permanent(session_id.clone()))
+            .ok_or_else(|| self.not_found(&session_id))?
+            .session
+            .as_ref()
+            .ok_or(Error::SessionBusy)?;
+        Ok(HistoryResponse {
+            dialog_pos: session.dialog_pos(),
+            turns: session.turns().map(Into::into).collect(),
+        })
+    }
+
+    /// 列出所有会话，最近活跃的在前，不影响会话在 LRU 缓存中的顺序。
+    pub fn sessions(&self) -> SessionsResponse {
+        let sessions = self.pending.lock().unwrap();
+        SessionsResponse {
+            sessions: sessions
+                .iter()
+                .filter_map(|(id, entry)| match id {
+                    SessionId::Permanent(id) => {
+                        Some(entry.info(id, &self.models[entry.model].name))
+                    }
+                    SessionId::Temporary(_) => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// 查看会话的详细信息，不影响会话在 LRU 缓存中的顺序。
+    pub fn session(&self, session_id: String) -> Result<SessionDetail, Error> {
+        let sessions = self.pending.lock().unwrap();
+        let entry = sessions
+            .peek(&SessionId::Permanent(session_id.clone()))
+            .ok_or_else(|| self.not_found(&session_id))?;
+        let session = entry.session.as_ref();
+        Ok(SessionDetail {
+            info: entry.info(&session_id, &self.models[entry.model].name),
+            system: session.and_then(|s| s.system.clone()),
+            adapter: session.and_then(|s| s.adapter().map(Into::into)),
+            turns: session.map(|s| s.turns().map(Into::into).collect()),
+        })
+    }
+
+    /// 用模型的分词器编码文本，不套用对话模板。
+    pub fn tokenize(&self, Tokenize { text, model }: Tokenize) -> Result<TokenizeResponse, Error> {
+        let tokens = self.services(model.as_deref())?[0].tokenize(&text);
+        Ok(TokenizeResponse {
+            count: tokens.len(),
+            tokens,
+        })
+    }
+
+    /// 用模型的分词器把 token 序列解码为文本。
+    pub fn detokenize(
+        &self,
+        Detokenize {
+            tokens,
+            skip_special_tokens,
+            model,
+        }: Detokenize,
+    ) -> Result<DetokenizeResponse, Error> {
+        let services = self.services(model.as_deref())?;
+        let service = &services[0];
+        if let Some(&t) = tokens.iter().find(|&&t| t as usize >= service.vocab_size()) {
+            return Err(Error::InvalidToken(t));
+        }
+        Ok(DetokenizeResponse {
+            text: service.detokenize(&tokens, skip_special_tokens.unwrap_or(false)),
+        })
+    }
+
+    /// 计算每个输入的文本嵌入，所有输入在模型的同一个副本上同批计算，不使用会话。
+    ///
+    /// 返回每个输入的向量和输入的总 token 数，计算完成后从 `quota` 中扣除输入的 token。
+    pub async fn embed(
+        &self,
+        model: Option<&str>,
+        inputs: Vec<EmbedInput>,
+        args: EmbedArgs,
+        priority: Priority,
+        quota: Option<Quota>,
+    ) -> Result<(Vec<Vec<f32>>, usize), Error> {
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(Error::ShuttingDown);
+        }
+        if inputs.is_empty() {
+            return Err(Error::EmptyInput(0));
+        }
+        let services = self.services(model)?;
+        let service = &services[self.next.fetch_add(1, Ordering::Relaxed) % services.len()];
+        let mut tokens = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let input = match input {
+                EmbedInput::Text(text) => service.tokenize(&text),
+                EmbedInput::Tokens(input) => {
+                    if let Some(&t) = input.iter().find(|&&t| t as usize >= service.vocab_size()) {
+                        return Err(Error::InvalidToken(t));
+                    }
+                    input
+                }
+            };
+            if let Some(limit) = self.limits.max_prompt_tokens {
+                if input.len() > limit {
+                    let tokens = input.len();
+                    return Err(Error::PromptTooLong { tokens, limit });
+                }
+            }
+            tokens.push(input);
+        }
+        let total = tokens.iter().map(Vec::len).sum();
+
+        let _permit = self.acquire()?;
+        let embeddings = service
+            .embed(tokens, args, priority)
+            .await
+            .map_err(|e| match e {
+                EmbedError::Unsupported => Error::Unsupported("embeddings with this model"),
+                EmbedError::EmptyInput(i) => Error::EmptyInput(i),
+                EmbedError::ContextOverflow(_, e) => Error::ContextOverflow(e),
+                EmbedError::Dimensions(d) => Error::InvalidDimensions(d),
+                EmbedError::Stopped => Error::ReplicaDown,
+            })?;
+        if let Some(quota) = quota {
+            quota.charge(total);
+        }
+        Ok((embeddings, total))
+    }
+
+    /// 在后台为模型的所有副本预填充对话模板，之后以模板开头的请求直接复用模板的缓存。
+    pub fn warm_up(
+        self: &Arc<Self>,
+        WarmUp { inputs, model }: WarmUp,
+    ) -> Result<WarmUpSuccess, Error> {
+        let model = self.model(model.as_deref())?.unwrap_or(0);
+        let (system, template) = split_system(&inputs, 0)?;
+        if template.is_empty() || template.len() % 2 == 1 {
+            return Err(Error::InvalidTemplate);
+        }
+        let system = system.map(String::from);
+        let template = template.into_iter().map(String::from).collect::<Vec<_>>();
+        let len = template.len();
+        let self_ = self.clone();
+        tokio::spawn(async move {
+            for service in self_.models[model].services().iter() {
+                service.warm_up(system.clone(), template.clone()).await;
+            }
+            info!("Template with {len} sentences warmed up");
+        });
+        Ok(WarmUpSuccess)
+    }
+
+    /// 从模型目录重新加载模型，加载期间旧的副本照常服务，加载完成后新的会话使用新的副本。
+    ///
+    /// 空闲的会话立即迁移到新的副本上，正在推理的会话在推理结束后迁移；
+    /// 预填充的模板不会迁移，需要重新预填充。
+    pub async fn reload(
+        self: Arc<Self>,
+        Reload { model, path }: Reload,
+        loader: ModelLoader<M>,
+    ) -> Result<ReloadResponse, Error> {
+        let model = self.model(model.as_deref())?.unwrap_or(0);
+        if self.reloading.swap(true, Ordering::Relaxed) {
+            return Err(Error::ReloadInProgress);
+        }
+        let served = &self.models[model];
+        let dir = path.map_or_else(
+            || served.replicas.read().unwrap().dir.clone(),
+            PathBuf::from,
+        );
+        info!("Reloading model {} from {}", served.name, dir.display());
+        let loaded = tokio::task::spawn_blocking({
+            let name = served.name.clone();
+            let dir = dir.clone();
+            move || loader(&name, &dir)
+        })
+        .await;
+        let result = match loaded {
+            Ok(services) if services.is_empty() => {
+                Err(Error::ReloadFailed("no replica loaded".into()))
+            }
+            Ok(services) => {
+                let replicas = services.len();
+                Ok((replicas, self.replace(model, services, dir.clone())))
+            }
+            Err(e) => Err(Error::ReloadFailed(
+                e.try_into_panic()
+                    .map_or_else(|e| e.to_string(), panic_message),
+            )),
+        };
+        self.reloading.store(false, Ordering::Relaxed);
+        let (replicas, migrated) = result.inspect_err(|e| warn!("Reload failed: {e:?}"))?;
+        info!(
+            "Model {} reloaded with {replicas} replicas, {migrated} sessions migrated",
+            served.name
+        );
+        Ok(ReloadResponse {
+            model: served.name.clone(),
+            path: dir.display().to_string(),
+            replicas,
+            migrated,
+        })
+    }
+
+    /// 替换模型的所有副本，并把空闲的会话迁移到新的副本上，返回迁移的会话数。
+    fn replace(&self, model: usize, services: Vec<Service<M>>, dir: PathBuf) -> usize {
+        let generation = {
+            let mut replicas = self.models[model].replicas.write().unwrap();
+            replicas.services = services.into();
+            replicas.dir = dir;
+            replicas.generation += 1;
+            replicas.loaded = SystemTime::now();
+            replicas.generation
+        };
+        let mut sessions = self.pending.lock().unwrap();
+        let mut n = 0;
+        for (_, entry) in sessions.iter_mut() {
+            if entry.model != model || entry.generation == generation {
+                continue;
+            }
+            if let Some(old) = entry.session.take() {
+                let (new, generation) = self.migrate(model, &old);
+                entry.session = Some(new);
+                entry.generation = generation;
+                n += 1;
+            }
+        }
+        n
+    }
+
     pub fn drop_(&self, Drop { session_id }: Drop) -> Result<DropSuccess, Error> {
-        let session_id = SessionId::Permanent(session_id);
-        self.drop_with_session_id(session_id)
+        self.drop_with_session_id(SessionId::Permanent(session_id.clone()))
+            .map_err(|_| self.not_found(&session_id))
     }
 
     fn drop_with_session_id(&self, session_id: SessionId) -> Result<DropSuccess, Error> {
@@ -258,3 +1383,183 @@ where
         }
     }
 }
+
+/// 加载模型时崩溃的原因。
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => "model loader panicked".into(),
+        },
+    }
+}
+
+/// 检查消息的角色并取出系统提示词。
+///
+/// 系统提示词只能是新对话的第一条消息，之后用户与助手从第 `dialog_pos` 个句子起交替发言。
+fn split_system(
+    messages: &[Sentence],
+    dialog_pos: usize,
+) -> Result<(Option<&str>, Vec<&str>), Error> {
+    let (system, rest) = match messages {
+        [first, rest @ ..] if dialog_pos == 0 && first.role == "system" => {
+            (Some(first.content.as_str()), rest)
+        }
+        _ => (None, messages),
+    };
+    let offset = messages.len() - rest.len();
+    for (i, s) in rest.iter().enumerate() {
+        let expected = if (dialog_pos + i).is_multiple_of(2) {
+            "user"
+        } else {
+            "assistant"
+        };
+        if s.role != expected {
+            return Err(Error::InvalidRole(offset + i));
+        }
+    }
+    Ok((system, rest.iter().map(|s| s.content.as_str()).collect()))
+}
+
+/// 推理并发送输出，客户端断开连接或请求中止时立即停止。
+///
+/// 超时时同样停止，已生成的部分加入对话，以超时错误代替推理结束的输出。
+async fn infer<M: CausalLM>(
+    session_id: &SessionId,
+    session: &mut Session<M>,
+    options: InferOptions,
+    sender: mpsc::UnboundedSender<Output>,
+    abort: &Notify,
+    metrics: &Metrics,
+) {
+    let InferOptions {
+        beam,
+        timeouts,
+        quota,
+    } = options;
+    let start = Instant::now();
+    let mut first = None;
+    let stopped = async {
+        tokio::select! {
+            _ = sender.closed() => info!("{session_id:?} disconnected"),
+            _ = abort.notified() => info!("{session_id:?} aborted"),
+        }
+    };
+    tokio::pin!(stopped);
+    let queue = until(timeouts.queue.map(|t| start + t));
+    tokio::pin!(queue);
+    let generation = until(timeouts.generation.map(|t| start + t));
+    tokio::pin!(generation);
+    let mut timeout = None;
+
+    if let Some(beam) = beam.filter(|_| session.dialog_pos() % 2 == 1) {
+        info!("{session_id:?} beam search started");
+        // 中途取消的束搜索不改变会话
+        let s = tokio::select! {
+            s = session.beam_search(beam) => s,
+            _ = &mut stopped => return,
+            _ = &mut generation => {
+                let t = timeouts.generation.unwrap();
+                warn!("{session_id:?} beam search timed out after {t:?}");
+                let _ = sender.send(Output::Error(Error::GenerationTimeout(t)));
+                return;
+            }
+        };
+        if let Err(e) = sender.send(Output::Piece(s, None)) {
+            warn!("Failed to send result to {session_id:?} with error \"{e}\"");
+        }
+        info!("{session_id:?} beam search stopped");
+    } else if session.dialog_pos() % 2 == 1 {
+        info!("{session_id:?} inference started");
+        let logprobs = session.logprobs.is_some();
+        let mut busy = session.chat();
+        loop {
+            let s = tokio::select! {
+                s = busy.decode() => s,
+                _ = &mut stopped => break,
+                // 排队的时限只限制第一段输出
+                _ = &mut queue, if first.is_none() => {
+                    timeout = timeouts.queue.map(Error::QueueTimeout);
+                    break;
+                }
+                _ = &mut generation => {
+                    timeout = timeouts.generation.map(Error::GenerationTimeout);
+                    break;
+                }
+            };
+            let Some(s) = s else { break };
+            first.get_or_insert_with(|| start.elapsed());
+            let logprobs = logprobs.then(|| busy.take_logprobs());
+            if let Err(e) = sender.send(Output::Piece(s, logprobs)) {
+                warn!("Failed to send piece to {session_id:?} with error \"{e}\"");
+                break;
+            }
+        }
+        drop(busy);
+        info!("{session_id:?} inference stopped");
+    } else {
+        info!("{session_id:?} inference skipped");
+        return;
+    }
+    // 回答加入对话时追加了结束符，不计入生成的 token
+    let turn = session.turns().last().unwrap();
+    if let Some(reason) = turn.finish_reason {
+        let completion_tokens = turn.tokens.len().saturating_sub(1);
+        if let Some(quota) = &quota {
+            quota.charge(completion_tokens);
+        }
+        if let Some(ttft) = first {
+            metrics.generation(turn.tokens.start, completion_tokens, ttft, start.elapsed());
+        }
+        tracing::info!(
+            prompt_tokens = turn.tokens.start,
+            completion_tokens,
+            ttft_ms = first.map(|t| t.as_secs_f64() * 1e3),
+            latency_ms = start.elapsed().as_secs_f64() * 1e3,
+            finish_reason = if timeout.is_some() {
+                "timeout"
+            } else {
+                finish_reason(reason)
+            },
+            "inference finished",
+        );
+        if let Some(e) = timeout {
+            warn!("{session_id:?} timed out after {:?}", start.elapsed());
+            let _ = sender.send(Output::Error(e));
+            return;
+        }
+        let _ = sender.send(Output::Finish {
+            reason,
+            prompt_tokens: turn.tokens.start,
+            completion_tokens,
+        });
+    }
+}
+
+/// 一次推理的请求选项，随推理移入后台。
+struct InferOptions {
+    /// 束搜索的参数，为空时逐个采样。
+    beam: Option<BeamArgs>,
+    /// 推理的时限。
+    timeouts: Timeouts,
+    /// 推理结束后扣除生成的 token 的配额。
+    quota: Option<Quota>,
+}
+
+/// 一次推理的时限，为空时不限制。
+#[derive(Clone, Copy)]
+struct Timeouts {
+    /// 等待第一段输出的时限，包括排队和预填充。
+    queue: Option<Duration>,
+    /// 推理的总时限。
+    generation: Option<Duration>,
+}
+
+/// 等到 `deadline`，没有期限时一直等待。
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
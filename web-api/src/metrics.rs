@@ -0,0 +1,208 @@
+//! 以 Prometheus 文本格式导出的服务指标。
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// 首 token 延迟的分桶上界（秒）。
+const TTFT_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30., 60.];
+/// 每个输出 token 耗时的分桶上界（秒）。
+const TPOT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.];
+
+/// 累计的指标，瞬时的指标在导出时读取。
+pub(crate) struct Metrics {
+    /// 按路由和状态码统计的请求数。
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    prompt_tokens: AtomicU64,
+    generated_tokens: AtomicU64,
+    ttft: Histogram,
+    tpot: Histogram,
+}
+
+/// 导出时读取的瞬时指标。
+pub(crate) struct Gauges {
+    pub sessions: usize,
+    pub busy_sessions: usize,
+    pub kv_bytes: usize,
+    pub queue_depth: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Default::default(),
+            prompt_tokens: AtomicU64::new(0),
+            generated_tokens: AtomicU64::new(0),
+            ttft: Histogram::new(TTFT_BUCKETS),
+            tpot: Histogram::new(TPOT_BUCKETS),
+        }
+    }
+
+    /// 记录一个请求，`route` 由 [`route`] 归一化，避免标签数量无限增长。
+    #[inline]
+    pub fn request(&self, route: &'static str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route, status))
+            .or_default() += 1;
+    }
+
+    /// 记录一次推理：提示词和生成的 token 数、首 token 延迟和总耗时。
+    pub fn generation(&self, prompt: usize, generated: usize, ttft: Duration, total: Duration) {
+        self.prompt_tokens.fetch_add(prompt as _, Relaxed);
+        self.generated_tokens.fetch_add(generated as _, Relaxed);
+        self.ttft.observe(ttft.as_secs_f64());
+        if generated > 1 {
+            let decode = total.saturating_sub(ttft).as_secs_f64();
+            self.tpot.observe(decode / (generated - 1) as f64);
+        }
+    }
+
+    /// 以 Prometheus 文本格式导出所有指标。
+    pub fn export(&self, gauges: Gauges) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "requests_total",
+            "counter",
+            "HTTP requests handled.",
+        );
+        for ((route, status), n) in &*self.requests.lock().unwrap() {
+            writeln!(
+                out,
+                "infinilm_requests_total{{route=\"{route}\",status=\"{status}\"}} {n}"
+            )
+            .unwrap();
+        }
+        for (name, help, value) in [
+            (
+                "prompt_tokens_total",
+                "Dialog tokens generations started from.",
+                &self.prompt_tokens,
+            ),
+            (
+                "generated_tokens_total",
+                "Tokens generated.",
+                &self.generated_tokens,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            writeln!(out, "infinilm_{name} {}", value.load(Relaxed)).unwrap();
+        }
+
+        self.ttft.export(
+            &mut out,
+            "time_to_first_token_seconds",
+            "Time from the start of a generation to its first output.",
+        );
+        self.tpot.export(
+            &mut out,
+            "time_per_output_token_seconds",
+            "Average time per generated token after the first one.",
+        );
+
+        let Gauges {
+            sessions,
+            busy_sessions,
+            kv_bytes,
+            queue_depth,
+        } = gauges;
+        for (name, help, value) in [
+            ("sessions", "Sessions cached, including anonymous ones.", sessions),
+            ("busy_sessions", "Sessions generating.", busy_sessions),
+            (
+                "kv_cache_bytes",
+                "Bytes of kv cache held by sessions, caches shared by forked sessions are counted for each.",
+                kv_bytes,
+            ),
+            ("queue_depth", "Inference tasks waiting for a forward pass.", queue_depth),
+        ] {
+            header(&mut out, name, "gauge", help);
+            writeln!(out, "infinilm_{name} {value}").unwrap();
+        }
+        out
+    }
+}
+
+/// 归一化请求路径作为指标的标签。
+pub(crate) fn route(path: &str) -> &'static str {
+    const ROUTES: &[&str] = &[
+        "/infer",
+        "/fork",
+        "/drop",
+        "/abort",
+        "/history",
+        "/warm_up",
+        "/tokenize",
+        "/detokenize",
+        "/v1/chat/completions",
+        "/v1/completions",
+        "/v1/models",
+        "/v1/embeddings",
+        "/ws",
+        "/sessions",
+        "/metrics",
+        "/healthz",
+        "/readyz",
+        "/admin/reload",
+    ];
+    match ROUTES.iter().find(|r| **r == path) {
+        Some(r) => r,
+        None if path.starts_with("/sessions/") => "/sessions/{session_id}",
+        None => "other",
+    }
+}
+
+#[inline]
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    writeln!(out, "# HELP infinilm_{name} {help}").unwrap();
+    writeln!(out, "# TYPE infinilm_{name} {ty}").unwrap();
+}
+
+/// 直方图，每个桶记录不超过上界的观测数。
+struct Histogram {
+    bounds: &'static [f64],
+    /// 各个桶（不累加）的观测数、观测值之和与观测数。
+    state: Mutex<(Vec<u64>, f64, u64)>,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            state: Mutex::new((vec![0; bounds.len()], 0., 0)),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        let (buckets, sum, count) = &mut *state;
+        if let Some(i) = self.bounds.iter().position(|&b| value <= b) {
+            buckets[i] += 1;
+        }
+        *sum += value;
+        *count += 1;
+    }
+
+    fn export(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        let (buckets, sum, count) = &*self.state.lock().unwrap();
+        let mut acc = 0;
+        for (bound, n) in self.bounds.iter().zip(buckets) {
+            acc += n;
+            writeln!(out, "infinilm_{name}_bucket{{le=\"{bound}\"}} {acc}").unwrap();
+        }
+        writeln!(out, "infinilm_{name}_bucket{{le=\"+Inf\"}} {count}").unwrap();
+        writeln!(out, "infinilm_{name}_sum {sum}").unwrap();
+        writeln!(out, "infinilm_{name}_count {count}").unwrap();
+    }
+}
@@ -0,0 +1,615 @@
+//! OpenAI 兼容的 `/v1/chat/completions`、`/v1/completions` 和 `/v1/embeddings`。
+
+use crate::{
+    manager::{Output, ServiceManager},
+    ratelimit::Quota,
+    response::openai_error_body,
+    schemas::{EmbedInput, Error, Infer, Priority, ResponseFormat, Sentence, Usage},
+};
+use causal_lm::CausalLM;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use service::{EmbedArgs, FinishReason, Pooling, TokenLogprob};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+/// OpenAI 旧版文本补全的默认生成长度。
+const DEFAULT_COMPLETION_TOKENS: usize = 16;
+
+#[derive(Deserialize)]
+pub(crate) struct ChatCompletions {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(flatten)]
+    pub common: Common,
+    pub max_completion_tokens: Option<usize>,
+    pub response_format: Option<ChatResponseFormat>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+    /// 不是 OpenAI 的参数，不套用对话模板，每个消息按原文编码。
+    pub raw: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Completions {
+    pub model: Option<String>,
+    pub prompt: Prompt,
+    #[serde(flatten)]
+    pub common: Common,
+    /// 旧版接口的 `logprobs` 是每个位置返回的候选数。
+    pub logprobs: Option<usize>,
+    /// 返回的文本以提示词开头。
+    pub echo: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct Embeddings {
+    pub model: Option<String>,
+    pub input: EmbeddingInput,
+    pub encoding_format: Option<EncodingFormat>,
+    pub dimensions: Option<usize>,
+    /// 不是 OpenAI 的参数，池化方式，默认为 `mean`。
+    pub pooling: Option<EmbeddingPooling>,
+    /// 不是 OpenAI 的参数，是否归一化为单位向量，默认为真。
+    pub normalize: Option<bool>,
+    /// 不是 OpenAI 的参数，与 `POST /infer` 的 `priority` 相同。
+    pub priority: Option<Priority>,
+}
+
+/// 输入是文本、token 序列或它们的列表。
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+    Tokens(Vec<u32>),
+    ManyTokens(Vec<Vec<u32>>),
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EncodingFormat {
+    Float,
+    /// 小端序 `f32` 数组的 base64 编码。
+    Base64,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EmbeddingPooling {
+    Mean,
+    Last,
+    #[serde(alias = "cls")]
+    First,
+}
+
+impl From<EmbeddingPooling> for Pooling {
+    #[inline]
+    fn from(value: EmbeddingPooling) -> Self {
+        match value {
+            EmbeddingPooling::Mean => Self::Mean,
+            EmbeddingPooling::Last => Self::Last,
+            EmbeddingPooling::First => Self::First,
+        }
+    }
+}
+
+/// 两个接口共有的参数。
+#[derive(Deserialize)]
+pub(crate) struct Common {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub stream: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
+    pub stop: Option<Stop>,
+    pub seed: Option<u64>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    pub n: Option<usize>,
+    /// 不是 OpenAI 的参数，与 `POST /infer` 的 `priority` 相同。
+    pub priority: Option<Priority>,
+    /// 不是 OpenAI 的参数，与 `POST /infer` 的 `timeout` 相同。
+    pub timeout: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct StreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ChatMessage {
+    pub role: String,
+    pub content: Content,
+}
+
+/// 消息内容是字符串或内容片段的列表，只支持文本片段。
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ContentPart {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub text: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ChatResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Deserialize)]
+pub(crate) struct JsonSchema {
+    pub schema: Value,
+}
+
+/// OpenAI 接口的回复，非流式请求是完整的 json，流式请求是 SSE 事件流。
+pub(crate) enum Reply {
+    Json(Value),
+    Stream(UnboundedReceiver<String>),
+}
+
+impl Common {
+    /// 转换为推理请求，`max_tokens` 是两个接口分别确定的生成长度。
+    fn into_infer(self, max_tokens: Option<usize>) -> Result<Infer, Error> {
+        if self.n.is_some_and(|n| n != 1) {
+            return Err(Error::Unsupported("n other than 1"));
+        }
+        Ok(Infer {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens,
+            stop: self.stop.map(|stop| match stop {
+                Stop::One(s) => vec![s],
+                Stop::Many(list) => list,
+            }),
+            seed: self.seed,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            logit_bias: self.logit_bias,
+            priority: self.priority,
+            timeout: self.timeout,
+            ..Default::default()
+        })
+    }
+}
+
+/// 一次请求的回复的公共部分。
+struct Meta {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    chat: bool,
+    stream: bool,
+    include_usage: bool,
+}
+
+impl Meta {
+    fn new(chat: bool, model: String, common: &Common) -> Self {
+        let stream = common.stream.unwrap_or(false);
+        let include_usage = common
+            .stream_options
+            .as_ref()
+            .and_then(|o| o.include_usage)
+            .unwrap_or(false);
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let (prefix, object) = match (chat, stream) {
+            (true, false) => ("chatcmpl", "chat.completion"),
+            (true, true) => ("chatcmpl", "chat.completion.chunk"),
+            (false, _) => ("cmpl", "text_completion"),
+        };
+        Self {
+            id: format!("{prefix}-{n}"),
+            object,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            model,
+            chat,
+            stream,
+            include_usage,
+        }
+    }
+
+    fn body(&self, choices: Value, usage: Option<Usage>) -> Value {
+        let mut body = json!({
+            "id": self.id,
+            "object": self.object,
+            "created": self.created,
+            "model": self.model,
+            "choices": choices,
+        });
+        if let Some(usage) = usage {
+            body["usage"] = json!(usage);
+        }
+        body
+    }
+
+    /// 一段输出对应的选项，`delta` 为真时是流式的增量。
+    fn choice(
+        &self,
+        content: Option<String>,
+        logprobs: Option<Value>,
+        finish_reason: Option<FinishReason>,
+        delta: bool,
+    ) -> Value {
+        let finish_reason = finish_reason.map(|r| match r {
+            FinishReason::Length => "length",
+            FinishReason::Stop | FinishReason::Abort => "stop",
+        });
+        let mut choice = json!({
+            "index": 0,
+            "logprobs": logprobs,
+            "finish_reason": finish_reason,
+        });
+        match (self.chat, delta) {
+            (true, true) => choice["delta"] = json!({ "content": content }),
+            (true, false) => choice["message"] = json!({ "role": "assistant", "content": content }),
+            (false, _) => choice["text"] = json!(content.unwrap_or_default()),
+        }
+        choice
+    }
+
+    fn logprobs(&self, list: Vec<TokenLogprob>, offset: &mut usize) -> Value {
+        if self.chat {
+            let content = list
+                .into_iter()
+                .map(|t| {
+                    let top = t
+                        .top
+                        .into_iter()
+                        .map(|(_, token, logprob)| {
+                            json!({ "bytes": token.as_bytes(), "token": token, "logprob": logprob })
+                        })
+                        .collect::<Vec<_>>();
+                    json!({
+                        "bytes": t.text.as_bytes(),
+                        "token": t.text,
+                        "logprob": t.logprob,
+                        "top_logprobs": top,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({ "content": content })
+        } else {
+            let mut tokens = Vec::with_capacity(list.len());
+            let mut token_logprobs = Vec::with_capacity(list.len());
+            let mut top_logprobs = Vec::with_capacity(list.len());
+            let mut text_offset = Vec::with_capacity(list.len());
+            for t in list {
+                text_offset.push(*offset);
+                *offset += t.text.len();
+                token_logprobs.push(t.logprob);
+                top_logprobs.push(
+                    t.top
+                        .into_iter()
+                        .map(|(_, token, logprob)| (token, logprob))
+                        .collect::<HashMap<_, _>>(),
+                );
+                tokens.push(t.text);
+            }
+            json!({
+                "tokens": tokens,
+                "token_logprobs": token_logprobs,
+                "top_logprobs": top_logprobs,
+                "text_offset": text_offset,
+            })
+        }
+    }
+}
+
+/// 合并多段对数概率。
+fn merge_logprobs(acc: &mut Option<Value>, part: Value) {
+    let Some(acc) = acc else {
+        *acc = Some(part);
+        return;
+    };
+    if let (Value::Object(acc), Value::Object(part)) = (acc, part) {
+        for (k, v) in part {
+            if let (Some(Value::Array(a)), Value::Array(b)) = (acc.get_mut(&k), v) {
+                a.extend(b);
+            }
+        }
+    }
+}
+
+impl<M> ServiceManager<M>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    pub async fn chat_completions(
+        self: &Arc<Self>,
+        ChatCompletions {
+            model,
+            messages,
+            common,
+            max_completion_tokens,
+            response_format,
+            logprobs,
+            top_logprobs,
+            raw,
+        }: ChatCompletions,
+        quota: Option<Quota>,
+    ) -> Result<Reply, Error> {
+        let inputs = messages
+            .into_iter()
+            .map(|m| {
+                let content = match m.content {
+                    Content::Text(s) => s,
+                    Content::Parts(parts) => parts
+                        .into_iter()
+                        .filter(|p| p.ty == "text")
+                        .filter_map(|p| p.text)
+                        .collect(),
+                };
+                // developer 是新版接口中系统提示词的角色
+                let role = match m.role.as_str() {
+                    "developer" => "system".into(),
+                    _ => m.role,
+                };
+                Sentence { role, content }
+            })
+            .collect::<Vec<_>>();
+        // 最后一个消息必须是用户的提问
+        match inputs.last() {
+            Some(s) if s.role == "user" => {}
+            _ => return Err(Error::InvalidRole(inputs.len().saturating_sub(1))),
+        }
+        let response_format = response_format.map(|f| match f {
+            ChatResponseFormat::Text => ResponseFormat::Text,
+            ChatResponseFormat::JsonObject => ResponseFormat::JsonSchema {
+                schema: json!({ "type": "object" }),
+            },
+            ChatResponseFormat::JsonSchema { json_schema } => ResponseFormat::JsonSchema {
+                schema: json_schema.schema,
+            },
+        });
+        let model = self.model_name(model.as_deref())?.to_string();
+        let meta = Meta::new(true, model.clone(), &common);
+        let max_tokens = max_completion_tokens.or(common.max_tokens);
+        let infer = Infer {
+            inputs,
+            model: Some(model),
+            response_format,
+            logprobs,
+            top_logprobs,
+            add_special_tokens: raw.map(|raw| !raw),
+            ..common.into_infer(max_tokens)?
+        };
+        self.reply(meta, infer, quota).await
+    }
+
+    pub async fn completions(
+        self: &Arc<Self>,
+        Completions {
+            model,
+            prompt,
+            common,
+            logprobs,
+            echo,
+        }: Completions,
+        quota: Option<Quota>,
+    ) -> Result<Reply, Error> {
+        // 回显的提示词没有对数概率
+        if echo == Some(true) && logprobs.is_some() {
+            return Err(Error::Unsupported("echo with logprobs"));
+        }
+        let prompt = match prompt {
+            Prompt::One(s) => s,
+            Prompt::Many(list) => match <[_; 1]>::try_from(list) {
+                Ok([s]) => s,
+                Err(_) => return Err(Error::Unsupported("multiple prompts")),
+            },
+        };
+        let model = self.model_name(model.as_deref())?.to_string();
+        let meta = Meta::new(false, model.clone(), &common);
+        let max_tokens = common.max_tokens.or(Some(DEFAULT_COMPLETION_TOKENS));
+        // 文本补全不套用对话模板
+        let infer = Infer {
+            model: Some(model),
+            inputs: vec![Sentence {
+                role: "user".into(),
+                content: prompt,
+            }],
+            add_special_tokens: Some(false),
+            top_logprobs: logprobs,
+            echo,
+            ..common.into_infer(max_tokens)?
+        };
+        self.reply(meta, infer, quota).await
+    }
+
+    pub async fn embeddings(
+        self: &Arc<Self>,
+        Embeddings {
+            model,
+            input,
+            encoding_format,
+            dimensions,
+            pooling,
+            normalize,
+            priority,
+        }: Embeddings,
+        quota: Option<Quota>,
+    ) -> Result<Reply, Error> {
+        let inputs = match input {
+            EmbeddingInput::One(s) => vec![EmbedInput::Text(s)],
+            EmbeddingInput::Many(list) => list.into_iter().map(EmbedInput::Text).collect(),
+            EmbeddingInput::Tokens(tokens) => vec![EmbedInput::Tokens(tokens)],
+            EmbeddingInput::ManyTokens(list) => list.into_iter().map(EmbedInput::Tokens).collect(),
+        };
+        let args = EmbedArgs {
+            pooling: pooling.map_or_else(Default::default, Into::into),
+            normalize: normalize.unwrap_or(true),
+            dimensions,
+        };
+        let priority = priority.map_or_else(Default::default, Into::into);
+        let name = self.model_name(model.as_deref())?.to_string();
+        let (embeddings, total) = self
+            .embed(model.as_deref(), inputs, args, priority, quota)
+            .await?;
+        let data = embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| {
+                let embedding = match encoding_format.unwrap_or(EncodingFormat::Float) {
+                    EncodingFormat::Float => json!(embedding),
+                    EncodingFormat::Base64 => json!(base64(&embedding)),
+                };
+                json!({ "object": "embedding", "index": index, "embedding": embedding })
+            })
+            .collect::<Vec<_>>();
+        Ok(Reply::Json(json!({
+            "object": "list",
+            "data": data,
+            "model": name,
+            "usage": { "prompt_tokens": total, "total_tokens": total },
+        })))
+    }
+
+    async fn reply(
+        self: &Arc<Self>,
+        meta: Meta,
+        infer: Infer,
+        quota: Option<Quota>,
+    ) -> Result<Reply, Error> {
+        let mut receiver = self.run(infer, quota)?;
+
+        if !meta.stream {
+            let mut content = String::new();
+            let mut logprobs = None;
+            let mut offset = 0;
+            let mut finish = None;
+            while let Some(output) = receiver.recv().await {
+                match output {
+                    Output::Piece(s, list) => {
+                        content.push_str(&s);
+                        if let Some(list) = list {
+                            merge_logprobs(&mut logprobs, meta.logprobs(list, &mut offset));
+                        }
+                    }
+                    Output::Finish {
+                        reason,
+                        prompt_tokens,
+                        completion_tokens,
+                    } => finish = Some((reason, Usage::new(prompt_tokens, completion_tokens))),
+                    Output::Error(e) => return Err(e),
+                }
+            }
+            let (reason, usage) = finish.unzip();
+            let choice = meta.choice(Some(content), logprobs, reason, false);
+            return Ok(Reply::Json(meta.body(json!([choice]), usage)));
+        }
+
+        let (sender, events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let send = |body: Value| sender.send(format!("data: {body}\n\n")).is_ok();
+            // 对话的第一个增量带有角色
+            if meta.chat {
+                let mut choice = meta.choice(Some(String::new()), None, None, true);
+                choice["delta"]["role"] = json!("assistant");
+                if !send(meta.body(json!([choice]), None)) {
+                    return;
+                }
+            }
+            let mut offset = 0;
+            while let Some(output) = receiver.recv().await {
+                let body = match output {
+                    Output::Piece(s, list) => {
+                        let logprobs = list.map(|list| meta.logprobs(list, &mut offset));
+                        meta.body(json!([meta.choice(Some(s), logprobs, None, true)]), None)
+                    }
+                    Output::Finish {
+                        reason,
+                        prompt_tokens,
+                        completion_tokens,
+                    } => {
+                        let usage = Usage::new(prompt_tokens, completion_tokens);
+                        let mut choice = meta.choice(None, None, Some(reason), true);
+                        // 对话结束的增量不带内容
+                        if meta.chat {
+                            choice["delta"] = json!({});
+                        }
+                        // 结束的增量总是带有用量
+                        if !send(meta.body(json!([choice]), Some(usage))) {
+                            return;
+                        }
+                        if !meta.include_usage {
+                            continue;
+                        }
+                        meta.body(json!([]), Some(usage))
+                    }
+                    // 以错误事件结束流，不再发送 [DONE]
+                    Output::Error(e) => {
+                        send(openai_error_body(&e));
+                        return;
+                    }
+                };
+                if !send(body) {
+                    return;
+                }
+            }
+            let _ = sender.send("data: [DONE]\n\n".into());
+        });
+        Ok(Reply::Stream(events))
+    }
+}
+
+/// 以标准 base64 编码小端序的 `f32` 数组。
+fn base64(data: &[f32]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let bytes = data
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut ans = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        // 不足 3 字节的块以 `=` 补齐
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ans.push(TABLE[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                ans.push('=');
+            }
+        }
+    }
+    ans
+}
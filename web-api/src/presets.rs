@@ -0,0 +1,81 @@
+use causal_lm::SampleArgs;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, ErrorKind::InvalidData},
+    path::Path,
+};
+
+/// 服务支持的采样预设，请求中的 `preset` 字段在服务端展开为完整的采样参数。
+#[derive(Clone, Debug)]
+pub struct SamplePresets(HashMap<String, SampleArgs>);
+
+impl Default for SamplePresets {
+    fn default() -> Self {
+        Self(
+            sample::PRESETS
+                .iter()
+                .map(|&name| (name.into(), SampleArgs::preset(name).unwrap()))
+                .collect(),
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Preset {
+    temperature: Option<f32>,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    min_p: Option<f32>,
+    typical_p: Option<f32>,
+    repetition_penalty: Option<f32>,
+    repetition_window: Option<usize>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+}
+
+impl SamplePresets {
+    /// 从 json 文件加载运维定义的预设，与内置预设同名时覆盖内置预设。
+    ///
+    /// 文件内容是名字到采样参数的映射，缺省的参数取 [`SampleArgs`] 的默认值：
+    ///
+    /// ```json
+    /// { "code": { "temperature": 0.1, "top_k": 5 } }
+    /// ```
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let presets: HashMap<String, Preset> =
+            serde_json::from_reader(file).map_err(|e| io::Error::new(InvalidData, e))?;
+
+        let mut ans = Self::default();
+        let default = SampleArgs::default();
+        for (name, preset) in presets {
+            let args = SampleArgs {
+                temperature: preset.temperature.unwrap_or(default.temperature),
+                top_k: preset.top_k.unwrap_or(default.top_k),
+                top_p: preset.top_p.unwrap_or(default.top_p),
+                min_p: preset.min_p.unwrap_or(default.min_p),
+                typical_p: preset.typical_p.unwrap_or(default.typical_p),
+                repetition_penalty: preset
+                    .repetition_penalty
+                    .unwrap_or(default.repetition_penalty),
+                repetition_window: preset
+                    .repetition_window
+                    .unwrap_or(default.repetition_window),
+                frequency_penalty: preset
+                    .frequency_penalty
+                    .unwrap_or(default.frequency_penalty),
+                presence_penalty: preset.presence_penalty.unwrap_or(default.presence_penalty),
+                seed: None,
+                logit_bias: Default::default(),
+            };
+            ans.0.insert(name, args);
+        }
+        Ok(ans)
+    }
+
+    #[inline]
+    pub(crate) fn get(&self, name: &str) -> Option<&SampleArgs> {
+        self.0.get(name)
+    }
+}
@@ -0,0 +1,238 @@
+//! 按调用者限制请求速率和生成速率。
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// 每个调用者的速率上限，认证时按密钥的名字区分调用者，否则按客户端的 IP 地址区分。
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// 每分钟的请求数上限。
+    pub requests_per_min: Option<usize>,
+    /// 每分钟生成的 token 数上限。
+    pub tokens_per_min: Option<usize>,
+}
+
+impl RateLimits {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.requests_per_min.is_none() && self.tokens_per_min.is_none()
+    }
+}
+
+/// 响应中 `Retry-After` 的值，即向上取整的等待秒数。
+#[inline]
+pub(crate) fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.) as u64
+}
+
+/// 调用者。
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Client {
+    Key(Arc<str>),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(name) => write!(f, "key {name}"),
+            Self::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// 令牌桶，以每分钟 `per_min` 的速率匀速补充，至多积累 `per_min` 个。
+struct Bucket {
+    level: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    #[inline]
+    fn full(per_min: usize, now: Instant) -> Self {
+        Self {
+            level: per_min as f64,
+            last: now,
+        }
+    }
+
+    #[inline]
+    fn refill(&mut self, per_min: usize, now: Instant) {
+        let rate = per_min as f64 / 60.;
+        let dt = now.duration_since(self.last).as_secs_f64();
+        self.level = (self.level + rate * dt).min(per_min as f64);
+        self.last = now;
+    }
+
+    /// 桶中至少有 `amount` 个令牌时返回 `None`，否则返回补充到 `amount` 个需要等待的时间。
+    #[inline]
+    fn wait(&self, per_min: usize, amount: f64) -> Option<Duration> {
+        if self.level >= amount {
+            None
+        } else {
+            let rate = per_min as f64 / 60.;
+            Some(Duration::from_secs_f64((amount - self.level) / rate))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// 一个调用者的配额，推理结束后按生成的 token 数扣除。
+#[derive(Clone)]
+pub(crate) struct Quota {
+    tokens_per_min: Option<usize>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl Quota {
+    /// 扣除生成的 token，允许透支，透支期间拒绝这个调用者的请求。
+    pub fn charge(&self, tokens: usize) {
+        let Some(per_min) = self.tokens_per_min else {
+            return;
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .tokens
+            .get_or_insert_with(|| Bucket::full(per_min, now));
+        bucket.refill(per_min, now);
+        bucket.level -= tokens as f64;
+    }
+}
+
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    clients: Mutex<HashMap<Client, Arc<Mutex<Buckets>>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RateLimits {
+                requests_per_min: limits.requests_per_min.filter(|&n| n > 0),
+                tokens_per_min: limits.tokens_per_min.filter(|&n| n > 0),
+            },
+            clients: Default::default(),
+        }
+    }
+
+    /// 接受调用者的一个请求并返回调用者的配额，超出速率上限时返回需要等待的时间。
+    pub fn admit(&self, client: Client) -> Result<Quota, Duration> {
+        let now = Instant::now();
+        let buckets = self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(client)
+            .or_default()
+            .clone();
+        {
+            let mut guard = buckets.lock().unwrap();
+            let Buckets { requests, tokens } = &mut *guard;
+            let mut wait = None;
+            if let Some(per_min) = self.limits.tokens_per_min {
+                let bucket = tokens.get_or_insert_with(|| Bucket::full(per_min, now));
+                bucket.refill(per_min, now);
+                // 生成的 token 数在推理结束后才知道，只要没有透支就接受请求
+                wait = bucket.wait(per_min, 0.);
+            }
+            if let Some(per_min) = self.limits.requests_per_min {
+                let bucket = requests.get_or_insert_with(|| Bucket::full(per_min, now));
+                bucket.refill(per_min, now);
+                match bucket.wait(per_min, 1.) {
+                    Some(w) => wait = Some(wait.map_or(w, |v| v.max(w))),
+                    None if wait.is_none() => bucket.level -= 1.,
+                    None => {}
+                }
+            }
+            if let Some(wait) = wait {
+                return Err(wait);
+            }
+        }
+        Ok(Quota {
+            tokens_per_min: self.limits.tokens_per_min,
+            buckets,
+        })
+    }
+
+    /// 移除令牌桶已满且没有进行中请求的调用者，这些调用者的状态与初始状态相同。
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let RateLimits {
+            requests_per_min,
+            tokens_per_min,
+        } = self.limits;
+        let idle = |bucket: &Option<Bucket>, per_min: Option<usize>| match (bucket, per_min) {
+            (Some(b), Some(per_min)) => {
+                b.level + per_min as f64 / 60. * now.duration_since(b.last).as_secs_f64()
+                    >= per_min as f64
+            }
+            _ => true,
+        };
+        self.clients.lock().unwrap().retain(|_, buckets| {
+            Arc::strong_count(buckets) > 1 || {
+                let buckets = buckets.lock().unwrap();
+                !(idle(&buckets.requests, requests_per_min)
+                    && idle(&buckets.tokens, tokens_per_min))
+            }
+        });
+    }
+}
+
+#[test]
+fn test_bucket() {
+    let now = Instant::now();
+    let mut bucket = Bucket::full(60, now);
+    bucket.level = 0.;
+    // 每秒补充 1 个
+    bucket.refill(60, now + Duration::from_secs(10));
+    assert_eq!(bucket.level, 10.);
+    assert_eq!(bucket.wait(60, 10.), None);
+    assert_eq!(bucket.wait(60, 12.5), Some(Duration::from_millis(2500)));
+    // 至多积累 `per_min` 个
+    bucket.refill(60, now + Duration::from_secs(100));
+    assert_eq!(bucket.level, 60.);
+
+    assert_eq!(retry_after(Duration::from_millis(2500)), 3);
+    assert_eq!(retry_after(Duration::ZERO), 1);
+}
+
+#[test]
+fn test_rate_limiter() {
+    let client = Client::Ip(IpAddr::from([127, 0, 0, 1]));
+    let other = Client::Key("other".into());
+
+    // 每 30 秒补充一个请求
+    let limiter = RateLimiter::new(RateLimits {
+        requests_per_min: Some(2),
+        tokens_per_min: None,
+    });
+    assert!(limiter.admit(client.clone()).is_ok());
+    assert!(limiter.admit(client.clone()).is_ok());
+    let wait = limiter.admit(client.clone()).err().unwrap();
+    assert_eq!(retry_after(wait), 30);
+    assert!(limiter.admit(other.clone()).is_ok());
+
+    // 透支 token 配额后拒绝请求，直到补充回来
+    let limiter = RateLimiter::new(RateLimits {
+        requests_per_min: None,
+        tokens_per_min: Some(60),
+    });
+    let quota = limiter.admit(client.clone()).unwrap();
+    quota.charge(90);
+    let wait = limiter.admit(client.clone()).err().unwrap();
+    assert_eq!(retry_after(wait), 30);
+    assert!(limiter.admit(other).is_ok());
+}
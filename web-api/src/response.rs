@@ -1,7 +1,7 @@
 //! All HttpResponses in this App.
 
 use crate::schemas;
-use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full, StreamBody};
 use hyper::{
     body::{Bytes, Frame},
     header::CONTENT_TYPE,
@@ -36,6 +36,29 @@ pub fn success(success: impl schemas::Success) -> Response<BoxBody<Bytes, hyper:
         .unwrap()
 }
 
+pub fn prometheus(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(full(body))
+        .unwrap()
+}
+
+pub fn empty(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .body(Empty::new().map_err(|never| match never {}).boxed())
+        .unwrap()
+}
+
+pub fn json(body: impl Serialize) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(serde_json::to_string(&body).unwrap()))
+        .unwrap()
+}
+
 pub fn error(e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
         .status(e.status())
@@ -44,6 +67,27 @@ pub fn error(e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
         .unwrap()
 }
 
+/// OpenAI 兼容接口的错误，格式与 OpenAI 的错误一致。
+pub fn openai_error(e: schemas::Error) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(e.status())
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(openai_error_body(&e).to_string()))
+        .unwrap()
+}
+
+/// OpenAI 格式的错误内容，也用于流中的错误事件。
+pub fn openai_error_body(e: &schemas::Error) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "message": e.body()["message"],
+            "type": "invalid_request_error",
+            "param": null,
+            "code": null,
+        }
+    })
+}
+
 #[inline]
 fn full(chunk: impl Into<Bytes>) -> BoxBody<Bytes, hyper::Error> {
     Full::new(chunk.into())
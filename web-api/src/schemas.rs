@@ -1,18 +1,102 @@
 use hyper::StatusCode;
+use service::{FinishReason, SessionStats};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-#[derive(serde::Deserialize)]
+#[derive(Default, serde::Deserialize)]
 pub(crate) struct Infer {
     pub inputs: Vec<Sentence>,
     pub session_id: Option<String>,
     pub dialog_pos: Option<usize>,
+    pub model: Option<String>,
+    pub preset: Option<String>,
+    pub adapter: Option<String>,
     pub temperature: Option<f32>,
     pub top_k: Option<usize>,
     pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub repetition_window: Option<usize>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub seed: Option<u64>,
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    pub stop: Option<Vec<String>>,
+    pub max_tokens: Option<usize>,
+    pub context_overflow: Option<OverflowPolicy>,
+    pub beam_width: Option<usize>,
+    pub length_penalty: Option<f32>,
+    pub early_stopping: Option<bool>,
+    pub grammar: Option<String>,
+    pub regex: Option<String>,
+    pub response_format: Option<ResponseFormat>,
+    pub negative_prompt: Option<String>,
+    pub guidance_scale: Option<f32>,
+    pub logprobs: Option<bool>,
+    pub top_logprobs: Option<usize>,
+    pub add_special_tokens: Option<bool>,
+    pub skip_special_tokens: Option<bool>,
+    pub priority: Option<Priority>,
+    /// 推理的时限（秒）。
+    pub timeout: Option<f64>,
+    pub include_usage: Option<bool>,
+    /// 在生成的内容之前输出要回答的消息。
+    pub echo: Option<bool>,
+}
+
+/// 推理的优先级。
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl From<Priority> for service::Priority {
+    #[inline]
+    fn from(value: Priority) -> Self {
+        match value {
+            Priority::Low => Self::Low,
+            Priority::Normal => Self::Normal,
+            Priority::High => Self::High,
+        }
+    }
+}
+
+/// 回答的格式。
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ResponseFormat {
+    Text,
+    JsonSchema { schema: serde_json::Value },
+}
+
+/// 上下文超过模型最大序列长度时的处理方式。
+#[derive(Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OverflowPolicy {
+    Error,
+    TruncateLeft,
+    Shift,
+}
+
+impl From<OverflowPolicy> for service::Overflow {
+    #[inline]
+    fn from(value: OverflowPolicy) -> Self {
+        match value {
+            OverflowPolicy::Error => Self::Error,
+            OverflowPolicy::TruncateLeft => Self::TruncateLeft,
+            OverflowPolicy::Shift => Self::Shift,
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
 pub(crate) struct Sentence {
-    #[allow(unused)]
     pub role: String,
     pub content: String,
 }
@@ -28,8 +112,293 @@ pub(crate) struct Drop {
     pub session_id: String,
 }
 
+#[derive(serde::Deserialize)]
+pub(crate) struct Abort {
+    pub session_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct WarmUp {
+    pub inputs: Vec<Sentence>,
+    pub model: Option<String>,
+}
+
+/// 重新加载模型，不指定路径时从模型原来的目录加载。
+#[derive(serde::Deserialize)]
+pub(crate) struct Reload {
+    pub model: Option<String>,
+    pub path: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct ReloadResponse {
+    pub model: String,
+    pub path: String,
+    /// 加载的副本数。
+    pub replicas: usize,
+    /// 立即迁移的空闲会话数，正在推理的会话在推理结束后迁移。
+    pub migrated: usize,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct History {
+    pub session_id: String,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Tokenize {
+    pub text: String,
+    pub model: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct TokenizeResponse {
+    pub tokens: Vec<u32>,
+    pub count: usize,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct Detokenize {
+    pub tokens: Vec<u32>,
+    pub skip_special_tokens: Option<bool>,
+    pub model: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct DetokenizeResponse {
+    pub text: String,
+}
+
+/// 求文本嵌入的一个输入，文本按原文编码，也可以直接给出 token 序列。
+pub(crate) enum EmbedInput {
+    Text(String),
+    Tokens(Vec<u32>),
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct HistoryResponse {
+    pub dialog_pos: usize,
+    pub turns: Vec<Turn>,
+}
+
+/// 会话的状态，推理期间是推理开始时的状态，时间是 Unix 毫秒时间戳。
+#[derive(serde::Serialize)]
+pub(crate) struct SessionInfo {
+    pub session_id: String,
+    /// 会话所属的模型。
+    pub model: String,
+    /// 会话是否正在推理。
+    pub busy: bool,
+    pub dialog_pos: usize,
+    pub num_tokens: usize,
+    pub cached_tokens: usize,
+    pub kv_bytes: usize,
+    pub kv_shared: bool,
+    pub last_active: u64,
+}
+
+impl SessionInfo {
+    pub fn new(
+        session_id: String,
+        model: String,
+        busy: bool,
+        stats: SessionStats,
+        active: SystemTime,
+    ) -> Self {
+        Self {
+            session_id,
+            model,
+            busy,
+            dialog_pos: stats.dialog_pos,
+            num_tokens: stats.num_tokens,
+            cached_tokens: stats.cached_tokens,
+            kv_bytes: stats.kv_bytes,
+            kv_shared: stats.kv_shared,
+            last_active: millis(active),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct SessionsResponse {
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// 服务是否就绪，所有副本的推理线程都在运行时就绪。
+#[derive(serde::Serialize)]
+pub(crate) struct ReadyResponse {
+    pub ready: bool,
+    /// 每个副本是否就绪，按模型的顺序排列。
+    pub replicas: Vec<bool>,
+}
+
+/// `GET /v1/models` 的回复。
+#[derive(serde::Serialize)]
+pub(crate) struct ModelsResponse {
+    pub object: &'static str,
+    pub data: Vec<ModelCard>,
+}
+
+/// OpenAI 格式的模型，另外报告模型加载的方式。
+#[derive(serde::Serialize)]
+pub(crate) struct ModelCard {
+    pub id: String,
+    pub object: &'static str,
+    /// 模型当前的副本加载完成的 Unix 时间戳（秒）。
+    pub created: u64,
+    pub owned_by: &'static str,
+    /// 模型目录。
+    pub root: String,
+    pub context_length: usize,
+    pub dtype: Option<String>,
+    pub quantization: Option<String>,
+    /// 每个副本加载到的设备。
+    pub devices: Vec<String>,
+}
+
+/// 会话的详细信息，会话正在推理时没有系统提示词、适配器和发言。
+#[derive(serde::Serialize)]
+pub(crate) struct SessionDetail {
+    #[serde(flatten)]
+    pub info: SessionInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub turns: Option<Vec<Turn>>,
+}
+
+/// 停止服务时保存的会话，`turns` 是对话中的发言原文，用户与助手交替。
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct SessionSnapshot {
+    pub session_id: String,
+    /// 会话所属的模型，不存在时恢复到第一个模型。
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+    pub turns: Vec<String>,
+}
+
+#[inline]
+const fn default_true() -> bool {
+    true
+}
+
+/// 会话中的一轮发言，时间是 Unix 毫秒时间戳。
+#[derive(serde::Serialize)]
+pub(crate) struct Turn {
+    role: &'static str,
+    content: String,
+    token_span: [usize; 2],
+    created: u64,
+    completed: u64,
+    finish_reason: Option<&'static str>,
+}
+
+impl From<&service::Turn> for Turn {
+    fn from(turn: &service::Turn) -> Self {
+        Self {
+            role: turn.role.as_str(),
+            content: turn.content.clone(),
+            token_span: [turn.tokens.start, turn.tokens.end],
+            created: millis(turn.created),
+            completed: millis(turn.completed),
+            finish_reason: turn.finish_reason.map(finish_reason),
+        }
+    }
+}
+
+#[inline]
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as _)
+}
+
+#[inline]
+pub(crate) const fn finish_reason(reason: FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Stop => "stop",
+        FinishReason::Length => "length",
+        FinishReason::Abort => "abort",
+    }
+}
+
+/// 返回对数概率或用量时推理输出的一个片段。
+#[derive(serde::Serialize)]
+pub(crate) struct Piece {
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprob>>,
+}
+
+/// 返回用量时推理输出的最后一行。
+#[derive(serde::Serialize)]
+pub(crate) struct InferDone {
+    pub finish_reason: &'static str,
+    pub usage: Usage,
+}
+
+/// 一次推理的 token 用量，按分词器编码的 token 计数。
+#[derive(Clone, Copy, serde::Serialize)]
+pub(crate) struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    #[inline]
+    pub const fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct TokenLogprob {
+    id: u32,
+    token: String,
+    logprob: f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(serde::Serialize)]
+struct TopLogprob {
+    id: u32,
+    token: String,
+    logprob: f32,
+}
+
+impl From<service::TokenLogprob> for TokenLogprob {
+    fn from(value: service::TokenLogprob) -> Self {
+        Self {
+            id: value.token,
+            token: value.text,
+            logprob: value.logprob,
+            top_logprobs: value
+                .top
+                .into_iter()
+                .map(|(id, token, logprob)| TopLogprob { id, token, logprob })
+                .collect(),
+        }
+    }
+}
+
 pub(crate) struct ForkSuccess;
 pub(crate) struct DropSuccess;
+pub(crate) struct WarmUpSuccess;
+pub(crate) struct AbortSuccess;
+pub(crate) struct HealthSuccess;
 
 pub(crate) trait Success {
     fn msg(&self) -> &str;
@@ -45,14 +414,67 @@ impl Success for DropSuccess {
         "drop success"
     }
 }
+impl Success for WarmUpSuccess {
+    fn msg(&self) -> &str {
+        "warm up started"
+    }
+}
+impl Success for AbortSuccess {
+    fn msg(&self) -> &str {
+        "abort success"
+    }
+}
+impl Success for HealthSuccess {
+    fn msg(&self) -> &str {
+        "alive"
+    }
+}
 
 #[derive(Debug)]
 pub(crate) enum Error {
+    Unauthorized,
     SessionBusy,
     SessionDuplicate,
     SessionNotFound,
+    SessionExpired,
     WrongJson(serde_json::Error),
     InvalidDialogPos(usize),
+    UnknownPreset(String),
+    UnknownAdapter(String),
+    UnknownModel(String),
+    /// 会话属于另一个模型。
+    ModelMismatch(String),
+    InvalidTemplate,
+    InvalidRole(usize),
+    InvalidToken(u32),
+    Unsupported(&'static str),
+    ContextOverflow(service::ContextOverflow),
+    /// 第若干个输入为空。
+    EmptyInput(usize),
+    /// 嵌入的维度不在 1 到隐藏状态的维度之间。
+    InvalidDimensions(usize),
+    PromptTooLong {
+        tokens: usize,
+        limit: usize,
+    },
+    TooManySessions(usize),
+    TooManyRequests(usize),
+    /// 超出调用者的速率上限，需要等待的秒数。
+    RateLimited(u64),
+    InvalidGrammar(service::GrammarError),
+    InvalidRegex(service::RegexError),
+    ConflictingConstraints,
+    ShuttingDown,
+    /// 超过时限仍没有开始输出。
+    QueueTimeout(Duration),
+    /// 推理超过时限。
+    GenerationTimeout(Duration),
+    /// 副本的推理线程已退出。
+    ReplicaDown,
+    /// 只读副本不提供请求的接口。
+    ReadOnlyReplica,
+    ReloadInProgress,
+    ReloadFailed(String),
 }
 
 #[derive(serde::Serialize)]
@@ -66,11 +488,38 @@ impl Error {
     #[inline]
     pub const fn status(&self) -> StatusCode {
         match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::SessionNotFound => StatusCode::NOT_FOUND,
+            Self::SessionExpired => StatusCode::GONE,
             Self::SessionBusy => StatusCode::NOT_ACCEPTABLE,
             Self::SessionDuplicate => StatusCode::CONFLICT,
             Self::WrongJson(_) => StatusCode::BAD_REQUEST,
             Self::InvalidDialogPos(_) => StatusCode::RANGE_NOT_SATISFIABLE,
+            Self::UnknownPreset(_) => StatusCode::BAD_REQUEST,
+            Self::UnknownAdapter(_) => StatusCode::BAD_REQUEST,
+            Self::UnknownModel(_) => StatusCode::NOT_FOUND,
+            Self::ModelMismatch(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidTemplate => StatusCode::BAD_REQUEST,
+            Self::InvalidRole(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            Self::Unsupported(_) => StatusCode::BAD_REQUEST,
+            Self::ContextOverflow(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::EmptyInput(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidDimensions(_) => StatusCode::BAD_REQUEST,
+            Self::PromptTooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::TooManySessions(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidGrammar(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidRegex(_) => StatusCode::BAD_REQUEST,
+            Self::ConflictingConstraints => StatusCode::BAD_REQUEST,
+            Self::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Self::QueueTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::GenerationTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::ReplicaDown => StatusCode::SERVICE_UNAVAILABLE,
+            Self::ReadOnlyReplica => StatusCode::FORBIDDEN,
+            Self::ReloadInProgress => StatusCode::CONFLICT,
+            Self::ReloadFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
@@ -91,11 +540,111 @@ impl Error {
             serde_json::to_value(v).unwrap()
         }
 
+        /// 超出服务负载上限的错误带有上限值。
+        #[derive(serde::Serialize)]
+        struct ErrorBodyLimit {
+            #[serde(flatten)]
+            common: ErrorBody,
+            limit: usize,
+        }
+
+        /// 超时的错误带有时限的秒数。
+        #[derive(serde::Serialize)]
+        struct ErrorBodyTimeout {
+            #[serde(flatten)]
+            common: ErrorBody,
+            timeout: f64,
+        }
+
         match self {
+            Self::Unauthorized => json(error!(0, "Invalid or missing API key")),
             Self::SessionNotFound => json(error!(0, "Session not found")),
+            Self::SessionExpired => json(error!(0, "Session expired")),
             Self::SessionBusy => json(error!(0, "Session is busy")),
             Self::SessionDuplicate => json(error!(0, "Session ID already exists")),
             Self::WrongJson(e) => json(error!(0, e.to_string())),
+            Self::UnknownPreset(name) => json(error!(0, format!("Unknown preset \"{name}\""))),
+            Self::UnknownAdapter(name) => json(error!(0, format!("Unknown adapter \"{name}\""))),
+            Self::UnknownModel(name) => json(error!(0, format!("Unknown model \"{name}\""))),
+            Self::ModelMismatch(name) => json(error!(
+                0,
+                format!("Session belongs to model \"{name}\"")
+            )),
+            Self::InvalidTemplate => json(error!(0, "Template must consist of complete turns")),
+            Self::InvalidRole(i) => json(error!(
+                0,
+                format!("Unexpected role of message {i}, only the first message of a new dialog can be system, then user and assistant alternate")
+            )),
+            Self::InvalidToken(t) => json(error!(0, format!("Token {t} out of vocabulary"))),
+            Self::Unsupported(what) => json(error!(0, format!("Unsupported: {what}"))),
+            Self::ContextOverflow(e) => json(error!(0, e.to_string())),
+            Self::EmptyInput(i) => json(error!(0, format!("Input {i} is empty"))),
+            Self::InvalidDimensions(d) => json(error!(
+                0,
+                format!("Dimensions must be between 1 and {d}")
+            )),
+            Self::InvalidGrammar(e) => json(error!(0, format!("Invalid grammar: {e}"))),
+            Self::InvalidRegex(e) => json(error!(0, format!("Invalid regex: {e}"))),
+            Self::ConflictingConstraints => json(error!(
+                0,
+                "Only one of grammar, regex and json schema response format can be specified, \
+                and none of them or stop can be combined with beam search"
+            )),
+            Self::ShuttingDown => json(error!(0, "Service is shutting down")),
+            Self::ReplicaDown => json(error!(0, "Model replica is not running")),
+            Self::ReadOnlyReplica => json(error!(
+                0,
+                "Read-only replica only serves embeddings, tokenization, models and metrics"
+            )),
+            &Self::QueueTimeout(t) => json(ErrorBodyTimeout {
+                common: error!(
+                    0,
+                    format!("No output within {} seconds", t.as_secs_f64())
+                ),
+                timeout: t.as_secs_f64(),
+            }),
+            &Self::GenerationTimeout(t) => json(ErrorBodyTimeout {
+                common: error!(
+                    0,
+                    format!("Generation exceeded {} seconds", t.as_secs_f64())
+                ),
+                timeout: t.as_secs_f64(),
+            }),
+            Self::ReloadInProgress => json(error!(0, "Another reload is in progress")),
+            Self::ReloadFailed(e) => json(error!(0, format!("Failed to reload model: {e}"))),
+            &Self::PromptTooLong { tokens, limit } => json(ErrorBodyLimit {
+                common: error!(
+                    0,
+                    format!("Prompt has {tokens} tokens but at most {limit} are allowed")
+                ),
+                limit,
+            }),
+            &Self::TooManySessions(limit) => json(ErrorBodyLimit {
+                common: error!(0, format!("Too many sessions, at most {limit} are allowed")),
+                limit,
+            }),
+            &Self::TooManyRequests(limit) => json(ErrorBodyLimit {
+                common: error!(
+                    0,
+                    format!("Too many concurrent generations, at most {limit} are allowed")
+                ),
+                limit,
+            }),
+            &Self::RateLimited(retry_after) => {
+                #[derive(serde::Serialize)]
+                struct ErrorBodyRetry {
+                    #[serde(flatten)]
+                    common: ErrorBody,
+                    retry_after: u64,
+                }
+                json(ErrorBodyRetry {
+                    common: error!(
+                        0,
+                        format!("Rate limit exceeded, retry after {retry_after} seconds")
+                    ),
+                    retry_after,
+                })
+            }
             &Self::InvalidDialogPos(current_dialog_pos) => {
                 #[derive(serde::Serialize)]
                 struct ErrorBodyExtra {
@@ -0,0 +1,50 @@
+//! HTTPS 服务的 TLS 设置。
+
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind::InvalidData},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+/// TLS 证书链和私钥，均为 PEM 格式的文件。
+#[derive(Clone, Debug, Deserialize)]
+pub struct Tls {
+    /// 证书链文件，服务端证书在前，中间证书在后。
+    #[serde(rename = "tls_cert")]
+    pub cert: PathBuf,
+    /// 私钥文件，支持 PKCS#1、PKCS#8 和 SEC1 格式。
+    #[serde(rename = "tls_key")]
+    pub key: PathBuf,
+}
+
+impl Tls {
+    /// 加载证书和私钥，构造 TLS 握手的接收器。
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .collect::<io::Result<Vec<_>>>()?;
+        if certs.is_empty() {
+            return Err(io::Error::new(
+                InvalidData,
+                format!("no certificate found in {}", self.cert.display()),
+            ));
+        }
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+            .ok_or_else(|| {
+                io::Error::new(
+                    InvalidData,
+                    format!("no private key found in {}", self.key.display()),
+                )
+            })?;
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(InvalidData, e))?;
+        // 服务只支持 HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
@@ -0,0 +1,40 @@
+//! 请求标识和跟踪，使一次请求的日志能够串联起来。
+
+use hyper::{header::HeaderName, HeaderMap};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        OnceLock,
+    },
+};
+
+/// 携带请求标识的请求头和响应头。
+pub(crate) static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// 客户端指定的请求标识的最大长度，更长的标识被忽略。
+const MAX_LEN: usize = 128;
+
+/// 取出客户端以 `X-Request-Id` 指定的请求标识，未指定或不是合法的标识时生成一个。
+///
+/// 合法的标识不长于 128 个字节，只包含可见的 ASCII 字符。
+pub(crate) fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map_or_else(generate, Into::into)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// 生成进程内唯一的请求标识，以每个进程随机的前缀区分不同进程（如多个实例）生成的标识。
+fn generate() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let prefix = PREFIX.get_or_init(|| RandomState::new().build_hasher().finish() as _);
+    format!("{prefix:08x}-{:012x}", COUNTER.fetch_add(1, Relaxed))
+}
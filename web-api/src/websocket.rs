@@ -0,0 +1,213 @@
+//! WebSocket 流式推理。
+
+use crate::{
+    manager::{Output, ServiceManager},
+    ratelimit::Quota,
+    schemas::{finish_reason, Error, Infer, TokenLogprob},
+};
+use causal_lm::CausalLM;
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    upgrade::Upgraded,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use service::FinishReason;
+use std::{future::pending, sync::Arc};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::Instrument;
+
+/// 客户端发送的消息。
+#[derive(serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// 开始推理，参数与 `POST /infer` 相同。
+    Infer(Box<Infer>),
+    /// 取消正在进行的推理。
+    Cancel,
+}
+
+/// 服务端发送的事件。
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    Piece {
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        logprobs: Option<Vec<TokenLogprob>>,
+    },
+    Done {
+        finish_reason: Option<&'static str>,
+        prompt_tokens: Option<usize>,
+        completion_tokens: Option<usize>,
+        total_tokens: Option<usize>,
+    },
+    Error(serde_json::Value),
+}
+
+impl Event {
+    #[inline]
+    fn done(finish: Option<(&'static str, usize, usize)>) -> Self {
+        let (finish_reason, prompt_tokens, completion_tokens) = match finish {
+            Some((r, p, c)) => (Some(r), Some(p), Some(c)),
+            None => (None, None, None),
+        };
+        Self::Done {
+            finish_reason,
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens.zip(completion_tokens).map(|(p, c)| p + c),
+        }
+    }
+
+    #[inline]
+    fn error(e: Error) -> Self {
+        Self::Error(e.body())
+    }
+
+    #[inline]
+    fn message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+}
+
+/// 完成 WebSocket 握手，连接升级后在后台处理这个连接上的推理请求。
+pub(crate) fn upgrade<M>(
+    manager: Arc<ServiceManager<M>>,
+    mut req: Request<Incoming>,
+    quota: Option<Quota>,
+) -> Response<BoxBody<Bytes, hyper::Error>>
+where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let key = req.headers().get(SEC_WEBSOCKET_KEY).cloned();
+    let empty = || {
+        Empty::<Bytes>::new()
+            .map_err(|never| match never {})
+            .boxed()
+    };
+    let Some(key) = key.filter(|_| is_websocket) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(empty())
+            .unwrap();
+    };
+
+    tokio::spawn(
+        async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => {
+                    let ws = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    serve(manager, ws, quota).await;
+                }
+                Err(e) => warn!("WebSocket upgrade failed: {e}"),
+            }
+        }
+        .in_current_span(),
+    );
+
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()))
+        .body(empty())
+        .unwrap()
+}
+
+/// 一个连接同时只进行一次推理，取消或推理结束后才能开始下一次推理。
+///
+/// 连接上的所有推理共用升级请求的配额。
+async fn serve<M>(
+    manager: Arc<ServiceManager<M>>,
+    ws: WebSocketStream<TokioIo<Upgraded>>,
+    quota: Option<Quota>,
+) where
+    M: CausalLM + Send + Sync + 'static,
+    M::Storage: Send,
+{
+    let (mut sink, mut stream) = ws.split();
+    let mut current: Option<UnboundedReceiver<Output>> = None;
+    let mut finish = None;
+
+    async fn recv(current: &mut Option<UnboundedReceiver<Output>>) -> Option<Output> {
+        match current {
+            Some(receiver) => receiver.recv().await,
+            None => pending().await,
+        }
+    }
+
+    loop {
+        let event = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(ClientMessage::Infer(_)) if current.is_some() => {
+                        Event::error(Error::SessionBusy)
+                    }
+                    Ok(ClientMessage::Infer(req)) => match manager.run(*req, quota.clone()) {
+                        Ok(receiver) => {
+                            current = Some(receiver);
+                            finish = None;
+                            continue;
+                        }
+                        Err(e) => Event::error(e),
+                    },
+                    // 丢弃推理的输出，推理随之停止
+                    Ok(ClientMessage::Cancel) => match current.take() {
+                        Some(_) => Event::Done {
+                            finish_reason: Some(finish_reason(FinishReason::Abort)),
+                            prompt_tokens: None,
+                            completion_tokens: None,
+                            total_tokens: None,
+                        },
+                        None => continue,
+                    },
+                    Err(e) => Event::error(Error::WrongJson(e)),
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            output = recv(&mut current) => match output {
+                Some(Output::Piece(content, logprobs)) => Event::Piece {
+                    content,
+                    logprobs: logprobs.map(|list| list.into_iter().map(Into::into).collect()),
+                },
+                Some(Output::Error(e)) => Event::error(e),
+                Some(Output::Finish {
+                    reason,
+                    prompt_tokens,
+                    completion_tokens,
+                }) => {
+                    finish = Some((finish_reason(reason), prompt_tokens, completion_tokens));
+                    continue;
+                }
+                None => {
+                    current = None;
+                    Event::done(finish.take())
+                }
+            },
+        };
+        if let Err(e) = sink.send(event.message()).await {
+            warn!("Failed to send WebSocket event: {e}");
+            break;
+        }
+    }
+}
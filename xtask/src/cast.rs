@@ -1,5 +1,6 @@
 ﻿use std::{fs, path::PathBuf, time::Instant};
 
+use common::{F8_E4M3, F8_E5M2};
 use digit_layout::types::{BF16, F16, F32};
 
 #[derive(Args, Default)]
@@ -21,6 +22,8 @@ impl CastArgs {
             Some("f32") | Some("float") | Some("float32") | None => F32,
             Some("f16") | Some("half") | Some("float16") => F16,
             Some("bf16") | Some("bfloat16") => BF16,
+            Some("f8e4m3") | Some("float8_e4m3fn") => F8_E4M3,
+            Some("f8e5m2") | Some("float8_e5m2") => F8_E5M2,
             Some(ty) => panic!("Unknown data type: \"{ty}\""),
         };
         let model_dir = PathBuf::from(self.model);
@@ -21,7 +21,11 @@ impl Task for ChatArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        let (mut service, _handle) = Service::<M>::load_with_options(
+            &self.inference.model,
+            meta,
+            self.inference.load_options(),
+        );
         service.default_sample = self.inference.sample_args();
         Chatting {
             service,
@@ -0,0 +1,26 @@
+use common::safe_tensors::{SafeTensors, CHECKSUMS_FILE};
+use std::{fs, path::PathBuf, time::Instant};
+
+#[derive(Args, Default)]
+pub(crate) struct ChecksumArgs {
+    /// Model directory.
+    #[clap(short, long)]
+    model: String,
+}
+
+impl ChecksumArgs {
+    pub fn invode(self) {
+        let model_dir = PathBuf::from(self.model);
+
+        let time = Instant::now();
+        let model = SafeTensors::load_from_dir_unchecked(&model_dir).unwrap();
+        println!("load model ... {:?}", time.elapsed());
+
+        let time = Instant::now();
+        let checksums = model.checksums();
+        println!("checksum ... {:?}", time.elapsed());
+
+        let json = serde_json::to_string_pretty(&checksums).unwrap();
+        fs::write(model_dir.join(CHECKSUMS_FILE), json).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! 服务的配置文件和环境变量，展开为等价的命令行参数后由命令行解析。
+
+use crate::Cli;
+use clap::CommandFactory;
+use std::{env, ffi::OsString, fs};
+use toml::{Table, Value};
+
+/// 覆盖配置文件的环境变量的前缀，变量名是前缀加上大写的参数名，如 `INFINILM_MAX_CONCURRENT`。
+const ENV_PREFIX: &str = "INFINILM_";
+/// 不从同名环境变量读取的参数，这些环境变量另有含义，如 `INFINILM_API_KEYS` 直接提供密钥。
+const NOT_FROM_ENV: [&str; 3] = ["config", "api-keys", "admin-keys"];
+
+/// `service` 命令的一个参数。
+struct Opt {
+    /// 参数的长名字，如 `max-concurrent`。
+    long: String,
+    /// 参数是否带有值，不带值的是开关。
+    takes_value: bool,
+}
+
+/// 返回进程的命令行参数，`service` 命令的配置文件和环境变量展开为参数插在命令之后。
+///
+/// 优先级从低到高依次是配置文件、环境变量和命令行，后出现的参数覆盖先出现的同名参数；
+/// 可以重复的参数（如 `--extra-model`）则合并，开关只能打开不能关闭。
+pub(crate) fn args() -> Vec<OsString> {
+    let args = env::args_os().collect::<Vec<_>>();
+    if args.get(1).and_then(|a| a.to_str()) != Some("service") {
+        return args;
+    }
+    let cmd = Cli::command();
+    let opts = cmd
+        .find_subcommand("service")
+        .unwrap()
+        .get_arguments()
+        .filter_map(|arg| {
+            Some(Opt {
+                long: arg.get_long()?.into(),
+                takes_value: arg.get_action().takes_values(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut ans = args[..2].to_vec();
+    if let Some(path) = config_path(&args[2..]) {
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read config file {path}: {e}"));
+        let table = text
+            .parse::<Table>()
+            .unwrap_or_else(|e| panic!("Failed to parse config file {path}: {e}"));
+        for (key, value) in flatten(table) {
+            let opt = find(&opts, &key)
+                .unwrap_or_else(|| panic!("Unknown option `{key}` in config file {path}"));
+            from_toml(opt, &key, value, &mut ans);
+        }
+    }
+    for opt in opts
+        .iter()
+        .filter(|o| !NOT_FROM_ENV.contains(&o.long.as_str()))
+    {
+        let name = format!("{ENV_PREFIX}{}", opt.long.to_uppercase().replace('-', "_"));
+        if let Some(value) = env::var_os(&name) {
+            if !opt.takes_value {
+                let value = value.to_string_lossy().to_lowercase();
+                if value == "1" || value == "true" {
+                    ans.push(format!("--{}", opt.long).into());
+                }
+            } else {
+                let mut arg = OsString::from(format!("--{}=", opt.long));
+                arg.push(value);
+                ans.push(arg);
+            }
+        }
+    }
+    ans.extend_from_slice(&args[2..]);
+    ans
+}
+
+/// 命令行中 `--config` 指定的配置文件。
+fn config_path(args: &[OsString]) -> Option<String> {
+    let mut iter = args.iter().filter_map(|a| a.to_str());
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            return iter.next().map(Into::into);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+/// 展开配置文件中的表，表名只用于分组，表中的键同样是参数名。
+fn flatten(table: Table) -> Vec<(String, Value)> {
+    let mut ans = Vec::new();
+    for (key, value) in table {
+        match value {
+            Value::Table(group) => ans.extend(group),
+            value => ans.push((key, value)),
+        }
+    }
+    ans
+}
+
+/// 按名字找到参数，名字中的 `_` 和 `-` 等价。
+fn find<'a>(opts: &'a [Opt], key: &str) -> Option<&'a Opt> {
+    let key = key.replace('_', "-");
+    opts.iter().find(|o| o.long == key)
+}
+
+/// 把配置文件中的一项转为参数，数组中的每个元素是一次参数。
+fn from_toml(opt: &Opt, key: &str, value: Value, args: &mut Vec<OsString>) {
+    match value {
+        Value::Boolean(on) if !opt.takes_value => {
+            if on {
+                args.push(format!("--{}", opt.long).into());
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                from_toml(opt, key, value, args);
+            }
+        }
+        Value::String(s) => args.push(format!("--{}={s}", opt.long).into()),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => {
+            args.push(format!("--{}={value}", opt.long).into())
+        }
+        Value::Datetime(_) | Value::Table(_) => {
+            panic!("Unsupported value of `{key}` in config file: {value}")
+        }
+    }
+}
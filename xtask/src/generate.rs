@@ -27,7 +27,11 @@ impl Task for GenerateArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (service, _handle) = Service::<M>::load(&self.inference.model, meta);
+        let (service, _handle) = Service::<M>::load_with_options(
+            &self.inference.model,
+            meta,
+            self.inference.load_options(),
+        );
 
         let prompt = if Path::new(&self.prompt).is_file() {
             println!("prompt from file: {}", self.prompt);
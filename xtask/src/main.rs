@@ -1,14 +1,18 @@
 mod cast;
 mod chat;
+mod checksum;
+mod config;
 mod deploy;
 mod generate;
+mod otel;
 mod service;
 
+use ::service::{ChatTemplate, LoadOptions};
 use causal_lm::{CausalLM, SampleArgs};
 use clap::Parser;
 use deploy::DeployArgs;
 use service::ServiceArgs;
-use std::{ffi::c_int, fmt};
+use std::{ffi::c_int, fmt, fs, path::Path, process::exit};
 use time::UtcOffset;
 
 #[macro_use]
@@ -16,9 +20,10 @@ extern crate clap;
 
 fn main() {
     use Commands::*;
-    match Cli::parse().command {
+    match Cli::parse_from(config::args()).command {
         Deploy(deploy) => deploy.deploy(),
         Cast(cast) => cast.invode(),
+        Checksum(checksum) => checksum.invode(),
         Generate(args) => args.run(),
         Chat(chat) => chat.run(),
         Service(service) => service.run(),
@@ -39,12 +44,14 @@ enum Commands {
     Deploy(DeployArgs),
     /// Cast model
     Cast(cast::CastArgs),
+    /// Record checksums of model tensors
+    Checksum(checksum::ChecksumArgs),
     /// Generate following text
     Generate(generate::GenerateArgs),
     /// Chat locally
     Chat(chat::ChatArgs),
     /// Start the service
-    Service(ServiceArgs),
+    Service(Box<ServiceArgs>),
 }
 
 #[derive(Args, Default)]
@@ -56,9 +63,42 @@ struct InferenceArgs {
     #[clap(long)]
     model_type: Option<String>,
 
+    /// Jinja chat template overriding the one shipped with the model, a file path or the template itself.
+    /// Written like `chat_template` in tokenizer_config.json, rendered with the model's bos and eos tokens.
+    #[clap(long)]
+    chat_template: Option<String>,
+    /// Map each byte of the text to a token instead of loading the tokenizer file.
+    /// Also used when the model has no tokenizer file.
+    #[clap(long)]
+    byte_tokenizer: bool,
+    /// Speculate up to this many tokens per step by looking up the recent n-gram in the context.
+    #[clap(long)]
+    prompt_lookup: Option<usize>,
+    /// Back up the last prompt token and constrain the first generated token to extend its text.
+    #[clap(long)]
+    token_healing: bool,
+    /// Maximum number of inference tasks merged into one forward pass.
+    #[clap(long)]
+    max_batch_size: Option<usize>,
+    /// Prefill long prompts in chunks of this many tokens, interleaved with other sessions' decoding.
+    #[clap(long)]
+    prefill_chunk: Option<usize>,
+    /// Stop speculating when the running and waiting tasks reach this many,
+    /// and draft fewer tokens as the load approaches it. Defaults to `--max-batch-size`.
+    #[clap(long)]
+    speculation_limit: Option<usize>,
+
     /// Log level, may be "off", "trace", "debug", "info" or "error".
     #[clap(long)]
     log: Option<String>,
+    /// Log format, "text" by default or "json" for one object per line,
+    /// carrying the request ID and other fields of the request and inference spans.
+    #[clap(long)]
+    log_format: Option<String>,
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector, e.g. `http://localhost:4318`, requires the `otel` feature.
+    /// Request, inference, queue, prefill and decode spans are exported to it.
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
 
     /// Random sample temperature.
     #[clap(long)]
@@ -69,11 +109,39 @@ struct InferenceArgs {
     /// Random sample top-p.
     #[clap(long)]
     top_p: Option<f32>,
+    /// Random sample min-p, drop tokens less probable than min-p times the most probable one.
+    #[clap(long)]
+    min_p: Option<f32>,
+    /// Locally typical sampling threshold, in (0, 1) to enable.
+    #[clap(long)]
+    typical_p: Option<f32>,
+    /// Penalty on tokens appearing in the recent context, greater than 1 to enable.
+    #[clap(long)]
+    repetition_penalty: Option<f32>,
+    /// Random seed for reproducible sampling.
+    #[clap(long)]
+    seed: Option<u64>,
 
     #[cfg(detected_cuda)]
     /// Use Nvidia GPU, specify device IDs separated by comma, e.g. `0` or `0,1`.
     #[clap(long)]
     nvidia: Option<String>,
+    #[cfg(detected_cuda)]
+    /// Number of layers whose weights stay resident on a single Nvidia GPU, all by default.
+    /// The other layers' weights stay in pinned host memory and are copied to the GPU in turn on every step.
+    /// This is weight streaming, not llama.cpp's `-ngl`: all layers are still computed on the GPU.
+    #[clap(long)]
+    resident_layers: Option<usize>,
+    #[cfg(detected_cuda)]
+    /// Load an independent model replica on each of the Nvidia GPUs instead of splitting one model across them.
+    /// Only supported by the service, new sessions are assigned to the replicas in turn.
+    #[clap(long)]
+    data_parallel: bool,
+    #[cfg(detected_cuda)]
+    /// File caching operator launch configurations tuned for the model shapes on Nvidia GPU.
+    /// Configurations missing from the file are tuned on startup and saved to it.
+    #[clap(long)]
+    autotune: Option<String>,
 }
 
 /// TODO 应该根据参数自动识别模型
@@ -84,7 +152,8 @@ enum ModelType {
 }
 
 impl InferenceArgs {
-    fn init_log(&self) {
+    /// 初始化日志器，返回导出跟踪范围的守卫，释放守卫时导出剩余的跟踪范围。
+    fn init_log(&self) -> Option<otel::Exporter> {
         use log::LevelFilter;
         use simple_logger::SimpleLogger;
 
@@ -101,6 +170,37 @@ impl InferenceArgs {
             })
             .unwrap_or(LevelFilter::Warn);
 
+        let json = self
+            .log_format
+            .as_deref()
+            .is_some_and(|f| f.eq_ignore_ascii_case("json"));
+        if json || self.otlp_endpoint.is_some() {
+            use tracing_subscriber::{
+                filter::LevelFilter as Filter, fmt, layer::SubscriberExt, util::SubscriberInitExt,
+                Layer,
+            };
+            let level = match log {
+                LevelFilter::Off => Filter::OFF,
+                LevelFilter::Error => Filter::ERROR,
+                LevelFilter::Warn => Filter::WARN,
+                LevelFilter::Info => Filter::INFO,
+                LevelFilter::Debug => Filter::DEBUG,
+                LevelFilter::Trace => Filter::TRACE,
+            };
+            let fmt = if json {
+                fmt::layer().json().boxed()
+            } else {
+                fmt::layer().boxed()
+            };
+            let (otel, exporter) = otel::layer(self.otlp_endpoint.as_deref());
+            // 同时接收 `log` 的日志，使它们带有所在的跟踪范围
+            tracing_subscriber::registry()
+                .with(fmt.with_filter(level))
+                .with(otel)
+                .init();
+            return exporter;
+        }
+
         const EAST8: UtcOffset = match UtcOffset::from_hms(8, 0, 0) {
             Ok(it) => it,
             Err(_) => unreachable!(),
@@ -110,6 +210,7 @@ impl InferenceArgs {
             .with_utc_offset(UtcOffset::current_local_offset().unwrap_or(EAST8))
             .init()
             .unwrap();
+        None
     }
 
     #[cfg(detected_cuda)]
@@ -148,6 +249,26 @@ impl InferenceArgs {
         vec![]
     }
 
+    /// 在 `devices` 中的每个 Nvidia GPU 上加载一个副本的元数据。
+    #[cfg(detected_cuda)]
+    fn nvidia_metas(&self, devices: Vec<c_int>) -> Metas<llama_nv::Transformer> {
+        let load_layers = self.resident_layers;
+        let tune_cache = self.autotune.clone();
+        Box::new(move || {
+            devices
+                .iter()
+                .map(|&n| {
+                    let mut meta = llama_nv::ModelLoadMeta::load_all_to(n);
+                    if let Some(layers) = load_layers {
+                        meta.load_layers = layers;
+                    }
+                    meta.tune_cache = tune_cache.as_ref().map(Into::into);
+                    meta
+                })
+                .collect()
+        })
+    }
+
     #[inline]
     fn model_type(&self) -> ModelType {
         if let Some(model_type) = self.model_type.as_ref() {
@@ -161,16 +282,52 @@ impl InferenceArgs {
         }
     }
 
+    fn load_options(&self) -> LoadOptions {
+        LoadOptions {
+            chat_template: self.chat_template.as_deref().map(chat_template),
+            byte_tokenizer: self.byte_tokenizer,
+            prompt_lookup: self.prompt_lookup,
+            token_healing: self.token_healing,
+            max_batch_size: self.max_batch_size,
+            prefill_chunk: self.prefill_chunk,
+            speculation_limit: self.speculation_limit,
+        }
+    }
+
     #[inline]
     fn sample_args(&self) -> SampleArgs {
         SampleArgs {
             temperature: self.temperature.unwrap_or(0.),
             top_k: self.top_k.unwrap_or(usize::MAX),
             top_p: self.top_p.unwrap_or(1.),
+            min_p: self.min_p.unwrap_or(0.),
+            typical_p: self.typical_p.unwrap_or(1.),
+            repetition_penalty: self.repetition_penalty.unwrap_or(1.),
+            seed: self.seed,
+            ..Default::default()
         }
     }
 }
 
+/// 从文件或参数本身读取对话模板，模板无效时打印错误并退出。
+fn chat_template(arg: &str) -> ChatTemplate {
+    let template = if Path::new(arg).is_file() {
+        fs::read_to_string(arg).unwrap_or_else(|e| {
+            eprintln!("Failed to read chat template {arg}: {e}");
+            exit(1)
+        })
+    } else {
+        arg.into()
+    };
+    ChatTemplate::new(template).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        exit(1)
+    })
+}
+
+/// 生成模型每个副本的加载元数据，每次调用得到一组新的元数据，用于加载多个模型或重新加载模型。
+type Metas<M> = Box<dyn Fn() -> Vec<<M as causal_lm::Model>::Meta> + Send + Sync>;
+
 /// 模型相关的推理任务。
 trait Task: Sized {
     /// 解析推理参数。
@@ -185,9 +342,26 @@ trait Task: Sized {
         M::Storage: Send,
         M::Error: fmt::Debug;
 
+    /// 在指定类型的模型的多个独立副本上调用推理任务，`metas` 生成每个副本的加载元数据。
+    ///
+    /// 默认只支持一个副本，以它的元数据调用 [`typed`](Task::typed)。
+    async fn replicated<M>(self, metas: Metas<M>)
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+        M::Error: fmt::Debug,
+    {
+        let mut metas = metas();
+        assert!(
+            metas.len() == 1,
+            "Data parallel is only supported by the service"
+        );
+        self.typed::<M>(metas.pop().unwrap()).await
+    }
+
     fn run(self) {
-        // 初始化日志器
-        self.inference().init_log();
+        // 初始化日志器，退出时导出剩余的跟踪范围
+        let _exporter = self.inference().init_log();
         // 启动 tokio 运行时
         let runtime = tokio::runtime::Runtime::new().unwrap();
         // 如果感知到 cuda 环境则初始化
@@ -206,19 +380,27 @@ trait Task: Sized {
             ModelType::Llama => match nvidia.as_slice() {
                 [] => {
                     use llama_cpu::Transformer as M;
-                    runtime.block_on(self.typed::<M>(()));
+                    runtime.block_on(self.replicated::<M>(Box::new(|| vec![()])));
+                }
+                #[cfg(detected_cuda)]
+                replicas if self.inference().data_parallel => {
+                    use llama_nv::Transformer as M;
+                    let metas = self.inference().nvidia_metas(replicas.to_vec());
+                    runtime.block_on(self.replicated::<M>(metas));
                 }
                 #[cfg(detected_cuda)]
                 &[n] => {
-                    use llama_nv::{ModelLoadMeta, Transformer as M};
-                    let meta = ModelLoadMeta::load_all_to(n);
-                    runtime.block_on(self.typed::<M>(meta));
+                    use llama_nv::Transformer as M;
+                    let metas = self.inference().nvidia_metas(vec![n]);
+                    runtime.block_on(self.replicated::<M>(metas));
                 }
                 #[cfg(detected_nccl)]
                 distribute => {
                     use llama_nv_distributed::{cuda::Device, Transformer as M};
-                    let meta = distribute.iter().copied().map(Device::new).collect();
-                    runtime.block_on(self.typed::<M>(meta));
+                    let devices = distribute.to_vec();
+                    let metas: Metas<M> =
+                        Box::new(move || vec![devices.iter().copied().map(Device::new).collect()]);
+                    runtime.block_on(self.replicated::<M>(metas));
                 }
                 #[cfg(not(all(detected_cuda, detected_nccl)))]
                 _ => panic!("Device not detected"),
@@ -226,7 +408,7 @@ trait Task: Sized {
             ModelType::Mixtral => match nvidia.as_slice() {
                 [] => {
                     use mixtral_cpu::MixtralCPU as M;
-                    runtime.block_on(self.typed::<M>(()));
+                    runtime.block_on(self.replicated::<M>(Box::new(|| vec![()])));
                 }
                 _ => panic!("Unsupported device"),
             },
@@ -0,0 +1,66 @@
+//! 以 OTLP 向 OpenTelemetry 的收集器导出跟踪范围。
+
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+/// 导出跟踪范围的后台线程，释放时导出剩余的跟踪范围。
+#[cfg(feature = "otel")]
+pub(crate) struct Exporter(SdkTracerProvider);
+
+#[cfg(not(feature = "otel"))]
+pub(crate) enum Exporter {}
+
+#[cfg(feature = "otel")]
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            eprintln!("Failed to export remaining spans: {e}");
+        }
+    }
+}
+
+/// 向 `endpoint` 处的收集器导出跟踪范围的层，`endpoint` 为空时不导出。
+///
+/// 除了请求和推理的跟踪范围，还导出推理引擎中 `debug` 级别的排队、预填充和解码的跟踪范围。
+#[cfg(feature = "otel")]
+pub(crate) fn layer<S>(endpoint: Option<&str>) -> (Option<impl Layer<S>>, Option<Exporter>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::filter::LevelFilter;
+
+    let Some(endpoint) = endpoint else {
+        return (None, None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()
+        .unwrap();
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("infini-lm").build())
+        .build();
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("infini-lm"))
+        .with_filter(LevelFilter::DEBUG);
+    (Some(layer), Some(Exporter(provider)))
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn layer<S>(endpoint: Option<&str>) -> (Option<impl Layer<S>>, Option<Exporter>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    assert!(
+        endpoint.is_none(),
+        "OTLP export is not enabled in this build, rebuild with the `otel` feature"
+    );
+    (None::<tracing_subscriber::layer::Identity>, None)
+}
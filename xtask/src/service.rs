@@ -1,19 +1,143 @@
-﻿use crate::{InferenceArgs, Task};
+﻿use crate::{chat_template, InferenceArgs, Metas, Task};
 use causal_lm::CausalLM;
-use service::Service;
-use std::fmt::Debug;
-use web_api::start_infer_service;
+use common::safe_tensors::SafeTensors;
+use service::{ChatTemplate, LoadOptions, Service};
+use std::{collections::HashMap, fmt::Debug, path::Path, sync::Arc, time::Duration};
+use web_api::{
+    start_infer_service, Admin, ApiKeys, Cors, Limits, Listen, Model, ModelLoader, RateLimits,
+    SamplePresets, ServiceConfig, SessionPolicy, ShutdownPolicy, Tls,
+};
+
+/// Environment variable listing extra api keys, separated by commas, each optionally prefixed with `name:`.
+const API_KEYS_ENV: &str = "INFINILM_API_KEYS";
+/// Environment variable listing admin keys, in the same format as [`API_KEYS_ENV`].
+const ADMIN_KEYS_ENV: &str = "INFINILM_ADMIN_KEYS";
 
 #[derive(Args, Default)]
+#[command(args_override_self = true)]
 pub struct ServiceArgs {
+    /// TOML file setting any of the other options, keyed by option names like `port` or `max_concurrent`.
+    /// `INFINILM_<OPTION>` environment variables override it, and the command line overrides both.
+    #[clap(long)]
+    pub config: Option<String>,
     #[clap(flatten)]
     pub inference: InferenceArgs,
     /// Port to bind the service to
-    #[clap(short, long)]
-    pub port: u16,
+    #[clap(short, long, required_unless_present_any = ["unix_socket", "grpc_port"])]
+    pub port: Option<u16>,
+    /// Unix socket path to bind the service to, instead of or in addition to the port.
+    #[clap(long)]
+    pub unix_socket: Option<String>,
+    /// Port to serve the grpc api on, requires the `grpc` feature.
+    #[clap(long)]
+    pub grpc_port: Option<u16>,
+    /// Name selecting the model in requests, the name of the model directory by default.
+    #[clap(long)]
+    pub model_name: Option<String>,
+    /// Another model to serve as `name=directory[,chat_template]`, loaded on the same devices as the main model.
+    /// The optional chat template overrides the one shipped with this model, like `--chat-template` does for the main model.
+    /// May be repeated, requests select a model by the `model` field.
+    #[clap(long)]
+    pub extra_model: Vec<String>,
     /// Maximum number of sessions to cache in memory.
     #[clap(long)]
     pub max_cache: Option<usize>,
+    /// Seconds a session may stay idle before it is evicted.
+    #[clap(long)]
+    pub session_ttl: Option<u64>,
+    /// Total MiB of kv cache kept by idle sessions, least recently used sessions are evicted beyond it.
+    #[clap(long)]
+    pub kv_budget: Option<usize>,
+    /// Maximum number of sessions existing at the same time, new sessions are rejected beyond it.
+    #[clap(long)]
+    pub max_sessions: Option<usize>,
+    /// Maximum number of generations running at the same time, new requests are rejected beyond it.
+    #[clap(long)]
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of dialog tokens to generate from, longer requests are rejected.
+    #[clap(long)]
+    pub max_prompt_tokens: Option<usize>,
+    /// Seconds a generation may wait for its first output, including queueing and prefill, before it is stopped.
+    #[clap(long)]
+    pub queue_timeout: Option<u64>,
+    /// Seconds a generation may run before it is stopped, requests may set a shorter `timeout`.
+    #[clap(long)]
+    pub generation_timeout: Option<u64>,
+    /// Maximum number of requests per minute from each api key, or each client address without authentication.
+    #[clap(long)]
+    pub requests_per_min: Option<usize>,
+    /// Maximum number of generated tokens per minute for each api key, or each client address without authentication.
+    #[clap(long)]
+    pub tokens_per_min: Option<usize>,
+    /// Origins allowed to call the service from browsers, separated by commas, `*` allows any origin.
+    #[clap(long)]
+    pub cors_origins: Option<String>,
+    /// Methods allowed in cross-origin requests, separated by commas.
+    #[clap(long)]
+    pub cors_methods: Option<String>,
+    /// Headers allowed in cross-origin requests, separated by commas, `*` allows any header.
+    #[clap(long)]
+    pub cors_headers: Option<String>,
+    /// Seconds browsers may cache the result of a preflight request.
+    #[clap(long)]
+    pub cors_max_age: Option<u64>,
+    /// PEM file of the TLS certificate chain, the service is served over HTTPS with it.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+    /// PEM file of the TLS private key.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+    /// Json file defining extra sampling presets, selected by the `preset` field of requests.
+    #[clap(long)]
+    pub sample_presets: Option<String>,
+    /// Json file listing conversation templates to prefill before serving, each a list of strings.
+    #[clap(long)]
+    pub warm_up: Option<String>,
+    /// Json file mapping names to api keys, requests must carry one of them as a bearer token.
+    /// Keys in the `INFINILM_API_KEYS` environment variable are accepted as well.
+    #[clap(long)]
+    pub api_keys: Option<String>,
+    /// Json file mapping names to admin keys, enabling the admin api (such as `/admin/reload`) for them.
+    /// Keys in the `INFINILM_ADMIN_KEYS` environment variable are accepted as well.
+    #[clap(long)]
+    pub admin_keys: Option<String>,
+    /// Seconds to wait for running generations on SIGTERM or SIGINT before aborting them.
+    #[clap(long)]
+    pub drain_timeout: Option<u64>,
+    /// Json file to save idle sessions to on shutdown and restore them from on startup.
+    #[clap(long)]
+    pub session_snapshot: Option<String>,
+    /// Serve as a read-only replica: only embeddings, tokenization, model listing and metrics, no sessions.
+    /// The weights must be saved by `cargo cast`, so that loading the same model directory as the primary service
+    /// maps them without conversion and both processes share them through the page cache.
+    #[clap(long, conflicts_with_all = ["grpc_port", "warm_up", "session_snapshot"])]
+    pub replica: bool,
+}
+
+impl ServiceArgs {
+    /// 服务的模型的名字、目录和覆盖的对话模板，主模型在前。
+    fn models(&self) -> Vec<(String, String, Option<ChatTemplate>)> {
+        let dir = &self.inference.model;
+        let name = self.model_name.clone().unwrap_or_else(|| {
+            Path::new(dir)
+                .file_name()
+                .map_or_else(|| dir.clone(), |n| n.to_string_lossy().into())
+        });
+        let template = self.inference.chat_template.as_deref().map(chat_template);
+        let extra = self.extra_model.iter().map(|s| {
+            let (name, rest) = s
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Extra model must be name=directory: {s}"));
+            let (dir, template) = match rest.split_once(',') {
+                Some((dir, template)) => (dir, Some(chat_template(template.trim()))),
+                None => (rest, None),
+            };
+            (name.trim().to_string(), dir.trim().to_string(), template)
+        });
+        std::iter::once((name, dir.clone(), template))
+            .chain(extra)
+            .collect()
+    }
 }
 
 impl Task for ServiceArgs {
@@ -27,10 +151,143 @@ impl Task for ServiceArgs {
         M::Storage: Send,
         M::Error: Debug,
     {
-        let (mut service, _handle) = Service::<M>::load(&self.inference.model, meta);
-        service.default_sample = self.inference.sample_args();
-        start_infer_service(service, self.port, self.max_cache.filter(|&c| c < 256))
-            .await
-            .unwrap();
+        let _ = meta;
+        unreachable!("The service is always started with replicas")
+    }
+
+    async fn replicated<M>(self, metas: Metas<M>)
+    where
+        M: CausalLM + Send + Sync + 'static,
+        M::Storage: Send,
+        M::Error: Debug,
+    {
+        let default_sample = self.inference.sample_args();
+        let options = self.inference.load_options();
+        let models = self.models();
+        if self.replica {
+            for (_, dir, _) in &models {
+                check_replica_weights(dir);
+            }
+        }
+        // 每个模型使用自己的对话模板，重新加载时同样如此
+        let templates = models
+            .iter()
+            .filter_map(|(name, _, t)| Some((name.clone(), t.clone()?)))
+            .collect::<HashMap<_, _>>();
+        // 每个模型在同一组设备上加载，重新加载时同样如此
+        let loader: ModelLoader<M> = Arc::new(move |name: &str, dir: &Path| {
+            let options = LoadOptions {
+                chat_template: templates.get(name).cloned(),
+                ..options.clone()
+            };
+            metas()
+                .into_iter()
+                .map(|meta| {
+                    let (mut service, _handle) =
+                        Service::<M>::load_with_options(dir, meta, options.clone());
+                    service.default_sample = default_sample.clone();
+                    service
+                })
+                .collect()
+        });
+        let models = models
+            .into_iter()
+            .map(|(name, dir, _)| Model {
+                services: loader(&name, Path::new(&dir)),
+                dir: dir.into(),
+                name,
+            })
+            .collect::<Vec<_>>();
+        let presets = self
+            .sample_presets
+            .map_or_else(Default::default, |path| SamplePresets::load(path).unwrap());
+        let mut api_keys = self.api_keys.map(|path| ApiKeys::load(path).unwrap());
+        if let Some(keys) = ApiKeys::from_env(API_KEYS_ENV) {
+            api_keys.get_or_insert_with(Default::default).extend(keys);
+        }
+        let mut admin_keys = self.admin_keys.map(|path| ApiKeys::load(path).unwrap());
+        if let Some(keys) = ApiKeys::from_env(ADMIN_KEYS_ENV) {
+            admin_keys.get_or_insert_with(Default::default).extend(keys);
+        }
+        let admin = admin_keys
+            .filter(|keys| !keys.is_empty())
+            .map(|keys| Admin { keys, loader });
+        let split = |s: String| -> Vec<String> {
+            s.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(Into::into)
+                .collect()
+        };
+        let cors = self.cors_origins.map(|origins| {
+            let default = Cors::default();
+            Cors {
+                allowed_origins: split(origins),
+                allowed_methods: self.cors_methods.map_or(default.allowed_methods, split),
+                allowed_headers: self.cors_headers.map_or(default.allowed_headers, split),
+                max_age: self.cors_max_age.map(Duration::from_secs),
+            }
+        });
+        if let Some(path) = self.warm_up {
+            let templates: Vec<Vec<String>> =
+                serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+            for template in templates {
+                for service in models.iter().flat_map(|m| &m.services) {
+                    service.warm_up(None, template.clone()).await;
+                }
+            }
+        }
+        let config = ServiceConfig {
+            listen: Listen {
+                port: self.port,
+                unix_socket: self.unix_socket.map(Into::into),
+                tls: self.tls_cert.zip(self.tls_key).map(|(cert, key)| Tls {
+                    cert: cert.into(),
+                    key: key.into(),
+                }),
+                grpc_port: self.grpc_port,
+            },
+            session: SessionPolicy {
+                capacity: self.max_cache.filter(|&c| c < 256),
+                ttl: self.session_ttl.map(Duration::from_secs),
+                kv_budget: self.kv_budget.map(|mib| mib << 20),
+            },
+            limits: Limits {
+                max_sessions: self.max_sessions,
+                max_concurrent: self.max_concurrent,
+                max_prompt_tokens: self.max_prompt_tokens,
+                queue_timeout: self.queue_timeout.map(Duration::from_secs),
+                generation_timeout: self.generation_timeout.map(Duration::from_secs),
+            },
+            rate_limits: RateLimits {
+                requests_per_min: self.requests_per_min,
+                tokens_per_min: self.tokens_per_min,
+            },
+            cors,
+            shutdown: ShutdownPolicy {
+                drain_timeout: self
+                    .drain_timeout
+                    .map_or(ShutdownPolicy::default().drain_timeout, Duration::from_secs),
+                snapshot: self.session_snapshot.map(Into::into),
+            },
+            presets,
+            api_keys,
+            replica: self.replica,
+        };
+        start_infer_service(models, admin, config).await.unwrap();
+    }
+}
+
+/// 只读副本要求权重是 `cargo cast` 保存的格式，加载时直接使用内存映射的文件，才能与主服务共享物理内存。
+fn check_replica_weights(dir: &str) {
+    let transformed = SafeTensors::load_from_dir_unchecked(dir)
+        .map(|weights| weights.iter().all(|(_, t)| t.format == "rs"))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to load weights from {dir}: {e:?}");
+            std::process::exit(1)
+        });
+    if !transformed {
+        eprintln!("A replica needs weights saved by `cargo cast` to share memory, {dir} is not");
+        std::process::exit(1)
     }
 }